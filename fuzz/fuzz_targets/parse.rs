@@ -0,0 +1,10 @@
+//! `cargo fuzz run parse`: arbitrary bytes into the parser, the fixes and the
+//! `parse -> to_source -> parse` round trip.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = matthiashihic::fuzz_parse(data);
+});
@@ -0,0 +1,1215 @@
+//! The parsed form of a source file and its canonical source rendering.
+//!
+//! `parse(program.to_source())` always yields `program` again.
+//! `parser::format_program` checks it for `fmt` and the fuzz harness, and the
+//! tests below hold every construct to it.
+
+use crate::front_matter::FrontMatter;
+
+/// Type annotation on a placeholder (`€1:int`), checked at runtime before
+/// the value is substituted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgType {
+    /// An integer; surrounding whitespace and leading zeros are normalized away.
+    Int,
+    /// Any finite number, decimals and exponents included; only trimmed.
+    Number,
+    /// A path that has to exist.
+    Path,
+    /// Any JSON value; re-serialized compactly.
+    Json,
+}
+
+impl ArgType {
+    pub fn parse(name: &str) -> Option<ArgType> {
+        match name {
+            "int" => Some(ArgType::Int),
+            "number" => Some(ArgType::Number),
+            "path" => Some(ArgType::Path),
+            "json" => Some(ArgType::Json),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ArgType::Int => "int",
+            ArgType::Number => "number",
+            ArgType::Path => "path",
+            ArgType::Json => "json",
+        }
+    }
+}
+
+/// `as json {colors: [string]}` after a statement: the shape its answer has
+/// to have. The model is asked for exactly that, and the program checks it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schema {
+    String,
+    Number,
+    Integer,
+    Boolean,
+    Array(Box<Schema>),
+    /// Fields in the order written; every one is required.
+    Object(Vec<(String, Schema)>),
+}
+
+impl Schema {
+    /// The schema as written after `as json`. Keys that aren't identifiers
+    /// are quoted.
+    pub fn to_source(&self) -> String {
+        match self {
+            Schema::String => "string".to_string(),
+            Schema::Number => "number".to_string(),
+            Schema::Integer => "integer".to_string(),
+            Schema::Boolean => "boolean".to_string(),
+            Schema::Array(items) => format!("[{}]", items.to_source()),
+            Schema::Object(fields) => {
+                let fields = fields
+                    .iter()
+                    .map(|(key, schema)| {
+                        let plain = key.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                        let key = if plain { key.clone() } else { format!("\"{}\"", key.replace('\\', "\\\\").replace('"', "\\\"")) };
+                        format!("{}: {}", key, schema.to_source())
+                    })
+                    .collect::<Vec<_>>();
+                format!("{{{}}}", fields.join(", "))
+            }
+        }
+    }
+
+    /// The schema as JSON Schema, the strict kind OpenAI's structured outputs
+    /// take: every field required, no others allowed.
+    pub fn to_json_schema(&self) -> String {
+        match self {
+            Schema::String => "{\"type\":\"string\"}".to_string(),
+            Schema::Number => "{\"type\":\"number\"}".to_string(),
+            Schema::Integer => "{\"type\":\"integer\"}".to_string(),
+            Schema::Boolean => "{\"type\":\"boolean\"}".to_string(),
+            Schema::Array(items) => format!("{{\"type\":\"array\",\"items\":{}}}", items.to_json_schema()),
+            Schema::Object(fields) => {
+                let properties = fields
+                    .iter()
+                    .map(|(key, schema)| format!("{}:{}", crate::json_string(key), schema.to_json_schema()))
+                    .collect::<Vec<_>>();
+                let required = fields.iter().map(|(key, _)| crate::json_string(key)).collect::<Vec<_>>();
+                format!(
+                    "{{\"type\":\"object\",\"properties\":{{{}}},\"required\":[{}],\"additionalProperties\":false}}",
+                    properties.join(","),
+                    required.join(",")
+                )
+            }
+        }
+    }
+}
+
+/// How predictable a statement's answer should be: the API's temperature.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Temperature {
+    /// `(chill)`: about the same answer every time.
+    Chill,
+    /// `(wild)`: something else every time.
+    Wild,
+    /// `temperature 0.7`, between 0 and 2.
+    Exact(f64),
+}
+
+impl Temperature {
+    pub fn value(self) -> f64 {
+        match self {
+            Temperature::Chill => 0.2,
+            Temperature::Wild => 1.2,
+            Temperature::Exact(value) => value,
+        }
+    }
+
+    pub fn to_source(self) -> String {
+        match self {
+            Temperature::Chill => "(chill)".to_string(),
+            Temperature::Wild => "(wild)".to_string(),
+            Temperature::Exact(value) => format!("temperature {}", value),
+        }
+    }
+}
+
+/// A piece of a statement's text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    /// Literal text, with escapes already resolved.
+    Text(String),
+    /// `€n` or `€n:type`, filled from the n-th (1-based) stdin line at runtime,
+    /// or with its `or "default"` when that line is missing.
+    Placeholder { index: usize, ty: Option<ArgType>, default: Option<String> },
+    /// `€secret(NAME)`, filled from the environment variable NAME at runtime
+    /// and never printed by the generated program.
+    Secret(String),
+    /// `€env.NAME`: the environment variable NAME, read when the program
+    /// starts. Unlike a secret, it's fine to print.
+    Env(String),
+    /// `€file(n)` or `€file(n, pages=a-b)`: the n-th stdin line is a path, and
+    /// the file's text goes into the prompt. PDFs and DOCX files are extracted.
+    File { index: usize, pages: Option<(u32, u32)> },
+    /// `€dir(n, glob="**/*.rs", max_bytes=200k)`: the n-th stdin line is a
+    /// directory; the matching files go into the prompt, each under its name.
+    Dir { index: usize, glob: Option<String>, max_bytes: Option<usize> },
+    /// `€gitdiff`: the staged changes of the repository the program runs in.
+    GitDiff,
+    /// `€gitlog(n)`: the last n commits of the repository the program runs in.
+    GitLog(usize),
+    /// `€name`: the answer of an earlier statement bound with `-> name`.
+    Var(String),
+    /// `€prev`: the answer of the statement that ran right before.
+    Prev,
+    /// `€name` of a constant from `stuff:`, its value filled in at compile time.
+    Const { name: String, value: String },
+    /// `€{name}`: a labelled value, given as `--name <value>` or a `name=value`
+    /// stdin line when the program runs.
+    Named(String),
+    /// `€*`: every stdin line, one per line, the ones `€n` take included.
+    AllArgs,
+    /// `name(arg, ...)` calling an `extern` program; always the only segment of
+    /// its statement. Each argument becomes one stdin line of the callee.
+    Call { name: String, args: Vec<Vec<Segment>> },
+    /// `do name with arg, ...` using a `recipe`; always the only segment of its
+    /// statement. Each argument is the recipe's €n.
+    Recipe { name: String, args: Vec<Vec<Segment>> },
+    /// `€name(arg, ...)` of a `macro`, with what it expands to. Linking puts
+    /// the expansion in its place; `fmt` keeps the call.
+    Macro { name: String, args: Vec<Vec<Segment>>, expansion: Vec<Segment> },
+}
+
+/// Whether any of `segments`, or of the arguments in them, passes `test`.
+fn uses(segments: &[Segment], test: &dyn Fn(&Segment) -> bool) -> bool {
+    segments.iter().any(|segment| match segment {
+        Segment::Call { args, .. } | Segment::Recipe { args, .. } => args.iter().any(|arg| uses(arg, test)),
+        Segment::Macro { expansion, .. } => uses(expansion, test),
+        segment => test(segment),
+    })
+}
+
+/// Whether `segments` are the same in every run: text, constants, and
+/// macros that expand to nothing else.
+fn all_static(segments: &[Segment]) -> bool {
+    segments.iter().all(|segment| match segment {
+        Segment::Text(_) | Segment::Const { .. } => true,
+        Segment::Macro { expansion, .. } => all_static(expansion),
+        _ => false,
+    })
+}
+
+/// `segments` with every macro use replaced by its expansion, and the text
+/// around it merged.
+pub fn expand_macros(segments: Vec<Segment>) -> Vec<Segment> {
+    let mut out = Vec::new();
+    for segment in segments {
+        let expanded = match segment {
+            Segment::Macro { expansion, .. } => expand_macros(expansion),
+            Segment::Call { name, args } => vec![Segment::Call { name, args: args.into_iter().map(expand_macros).collect() }],
+            Segment::Recipe { name, args } => vec![Segment::Recipe { name, args: args.into_iter().map(expand_macros).collect() }],
+            segment => vec![segment],
+        };
+        for segment in expanded {
+            match (out.last_mut(), segment) {
+                (Some(Segment::Text(before)), Segment::Text(text)) => before.push_str(&text),
+                (_, segment) => out.push(segment),
+            }
+        }
+    }
+    out
+}
+
+/// A quoted string statement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub segments: Vec<Segment>,
+    /// `"..." -> name`: later statements can use the answer as `€name`.
+    pub binding: Option<String>,
+    /// `"..." using gpt-4o-mini`: the model it goes to instead of `--model`.
+    pub model: Option<String>,
+    /// `"..." (wild)`: how predictable the answer is, instead of the
+    /// front-matter's temperature.
+    pub temperature: Option<Temperature>,
+    /// `"..." as json {...}`: the answer is JSON of this shape.
+    pub schema: Option<Schema>,
+    /// `"..." or try again 3 times`: how often it's asked again if it fails.
+    pub retries: Option<u32>,
+    /// `"..." write it to "haiku.txt"`: the file the answer goes into instead
+    /// of stdout.
+    pub write_to: Option<String>,
+    /// `better be "..."` on the line after it: what the answer has to be when
+    /// the program runs with `--check`.
+    pub expect: Option<String>,
+    /// Written as `r"..."` or `r#"..."#`, without escapes, and formatted that
+    /// way again.
+    pub raw: bool,
+    /// `shell "..."`: the text is a command for `sh`, whose output is `€shell`
+    /// for the statements after it.
+    pub shell: bool,
+}
+
+impl Statement {
+    /// The statement text with `{ARG_n}` markers where placeholders go.
+    pub fn pseudocode(&self) -> String {
+        segments_pseudocode(&self.segments)
+    }
+
+    /// True if the statement has no placeholders, so its answer never changes
+    /// between runs (as far as a language model's answers ever don't).
+    pub fn is_static(&self) -> bool {
+        all_static(&self.segments)
+    }
+
+    /// Whether the statement says `€prev`, itself or in an argument.
+    pub fn uses_prev(&self) -> bool {
+        uses(&self.segments, &|segment| *segment == Segment::Prev)
+    }
+
+    /// Whether the statement says `€*`, itself or in an argument.
+    pub fn uses_all_args(&self) -> bool {
+        uses(&self.segments, &|segment| *segment == Segment::AllArgs)
+    }
+
+    /// Whether the statement says `€name` of a binding, itself or in an
+    /// argument.
+    pub fn uses_var(&self, name: &str) -> bool {
+        uses(&self.segments, &|segment| matches!(segment, Segment::Var(var) if var == name))
+    }
+
+    /// The statement as written: raw if it was and still fits on a line, in
+    /// triple quotes if it spans lines and that's how it can be written, in
+    /// quotes otherwise.
+    pub fn to_source(&self) -> String {
+        if let Some(raw) = self.raw_string() {
+            return format!("{}{}{}", self.keyword(), raw, self.suffix());
+        }
+        let Some(block) = self.block().filter(|_| !self.shell) else {
+            return self.to_line();
+        };
+        format!("\"\"\"\n{}\n\"\"\"{}", block, self.suffix())
+    }
+
+    /// The statement in quotes, line breaks escaped, for listings that have
+    /// one line per statement.
+    pub fn to_line(&self) -> String {
+        format!("{}\"{}\"{}", self.keyword(), segments_to_source(&self.segments), self.suffix())
+    }
+
+    /// What goes before the opening quote: `shell ` or nothing.
+    fn keyword(&self) -> &'static str {
+        if self.shell {
+            "shell "
+        } else {
+            ""
+        }
+    }
+
+    /// The `better be "..."` line that goes after the statement, if it has one.
+    pub fn expectation_source(&self) -> Option<String> {
+        let expected = self.expect.as_ref()?;
+        Some(format!("better be \"{}\"", segments_to_source(&[Segment::Text(expected.clone())])))
+    }
+
+    /// What follows the closing quote: ` using model`, ` (chill)`,
+    /// ` as json {...}`, ` or try again 3 times`, ` write it to "..."`, then
+    /// ` -> name`.
+    fn suffix(&self) -> String {
+        let mut out = String::new();
+        if let Some(model) = &self.model {
+            out.push_str(&format!(" using {}", model));
+        }
+        if let Some(temperature) = self.temperature {
+            out.push_str(&format!(" {}", temperature.to_source()));
+        }
+        if let Some(schema) = &self.schema {
+            out.push_str(&format!(" as json {}", schema.to_source()));
+        }
+        match self.retries {
+            Some(1) => out.push_str(" or try again 1 time"),
+            Some(times) => out.push_str(&format!(" or try again {} times", times)),
+            None => {}
+        }
+        if let Some(path) = &self.write_to {
+            out.push_str(&format!(" write it to \"{}\"", segments_to_source(&[Segment::Text(path.clone())])));
+        }
+        if let Some(name) = &self.binding {
+            out.push_str(&format!(" -> {}", name));
+        }
+        out
+    }
+
+    /// `r"..."`, with as many `#`s as it takes for no quote in it to end it,
+    /// for a raw statement without line breaks.
+    fn raw_string(&self) -> Option<String> {
+        if !self.raw {
+            return None;
+        }
+        let text = render_segments(&self.segments, true);
+        if text.contains(['\n', '\r']) {
+            return None;
+        }
+        let hashes = (0..).map(|n| "#".repeat(n)).find(|hashes| !text.contains(&format!("\"{}", hashes)))?;
+        Some(format!("r{}\"{}\"{}", hashes, text, hashes))
+    }
+
+    /// What goes between the triple quotes, for a statement with line breaks
+    /// that has no `"""`, carriage returns or control characters that need
+    /// escaping.
+    fn block(&self) -> Option<String> {
+        if !self.segments.iter().any(|segment| matches!(segment, Segment::Text(text) if text.contains('\n'))) {
+            return None;
+        }
+        let block = render_segments(&self.segments, true);
+        let escaped = |c: char| c.is_control() && c != '\n' && c != '\t';
+        (!block.contains("\"\"\"") && !block.chars().any(escaped)).then_some(block)
+    }
+}
+
+/// Segments with `{ARG_n}`, `{ALL_ARGS}`, `{NAMED_name}`, `{SECRET_NAME}`, `{ENV_NAME}` and `{VAR_name}` markers.
+pub fn segments_pseudocode(segments: &[Segment]) -> String {
+    let mut out = String::new();
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => out.push_str(text),
+            Segment::Placeholder { index, .. } => out.push_str(&format!("{{ARG_{}}}", index)),
+            Segment::Secret(name) => out.push_str(&format!("{{SECRET_{}}}", name)),
+            Segment::Env(name) => out.push_str(&format!("{{ENV_{}}}", name)),
+            Segment::File { index, pages } => out.push_str(&format!("{{{}}}", file_key(*index, *pages))),
+            Segment::Dir { index, glob, max_bytes } => {
+                out.push_str(&format!("{{{}}}", dir_key(*index, glob.as_deref(), *max_bytes)))
+            }
+            Segment::GitDiff => out.push_str("{GIT_DIFF}"),
+            Segment::GitLog(count) => out.push_str(&format!("{{GIT_LOG_{}}}", count)),
+            Segment::Var(name) => out.push_str(&format!("{{VAR_{}}}", name)),
+            Segment::Prev => out.push_str("{VAR_prev}"),
+            Segment::Const { value, .. } => out.push_str(value),
+            Segment::Named(name) => out.push_str(&format!("{{NAMED_{}}}", name)),
+            Segment::AllArgs => out.push_str("{ALL_ARGS}"),
+            Segment::Call { name, args } => {
+                let args = args.iter().map(|arg| segments_pseudocode(arg)).collect::<Vec<_>>();
+                out.push_str(&format!("{}({})", name, args.join(", ")));
+            }
+            Segment::Recipe { name, args } => {
+                let args = args.iter().map(|arg| segments_pseudocode(arg)).collect::<Vec<_>>();
+                out.push_str(&recipe_call(name, &args));
+            }
+            Segment::Macro { expansion, .. } => out.push_str(&segments_pseudocode(expansion)),
+        }
+    }
+    out
+}
+
+/// `do name` or `do name with a, b`.
+fn recipe_call(name: &str, args: &[String]) -> String {
+    if args.is_empty() {
+        format!("do {}", name)
+    } else {
+        format!("do {} with {}", name, args.join(", "))
+    }
+}
+
+/// Marker key of a `€file(...)` in pseudocode, e.g. `FILE_1` or `FILE_1_PAGES_2_5`.
+pub fn file_key(index: usize, pages: Option<(u32, u32)>) -> String {
+    match pages {
+        Some((first, last)) => format!("FILE_{}_PAGES_{}_{}", index, first, last),
+        None => format!("FILE_{}", index),
+    }
+}
+
+/// Marker key of a `€dir(...)`. The glob is hex-encoded, since it may contain
+/// braces, e.g. `DIR_1` or `DIR_1_GLOB_2a2e7273_MAX_204800`.
+pub fn dir_key(index: usize, glob: Option<&str>, max_bytes: Option<usize>) -> String {
+    let mut key = format!("DIR_{}", index);
+    if let Some(glob) = glob {
+        key.push_str("_GLOB_");
+        key.extend(glob.bytes().map(|b| format!("{:02x}", b)));
+    }
+    if let Some(max_bytes) = max_bytes {
+        key.push_str(&format!("_MAX_{}", max_bytes));
+    }
+    key
+}
+
+/// `max_bytes` as written in `€dir(...)`: `200k`, `1m` or plain bytes.
+pub fn format_size(bytes: usize) -> String {
+    if bytes > 0 && bytes.is_multiple_of(1024 * 1024) {
+        format!("{}m", bytes / (1024 * 1024))
+    } else if bytes > 0 && bytes.is_multiple_of(1024) {
+        format!("{}k", bytes / 1024)
+    } else {
+        bytes.to_string()
+    }
+}
+
+/// Segments as they'd be written between the quotes of a statement.
+pub fn segments_to_source(segments: &[Segment]) -> String {
+    render_segments(segments, false)
+}
+
+/// Segments as written between quotes, or between triple quotes (`block`),
+/// where nothing but € is escaped.
+fn render_segments(segments: &[Segment], block: bool) -> String {
+    let mut out = String::new();
+    for (i, segment) in segments.iter().enumerate() {
+        match segment {
+            Segment::Text(text) => {
+                // A placeholder right after the text starts with €, too
+                let following = match segments.get(i + 1) {
+                    Some(Segment::Text(_) | Segment::Call { .. } | Segment::Recipe { .. }) | None => None,
+                    Some(_) => Some('€'),
+                };
+                for (pos, ch) in text.char_indices() {
+                    let rest = &text[pos + ch.len_utf8()..];
+                    let next = rest.chars().next().or(following);
+                    match ch {
+                        '\\' | '"' | '\n' | '\r' | '\t' if block => out.push(ch),
+                        '\\' => out.push_str("\\\\"),
+                        '"' => out.push_str("\\\""),
+                        '\n' => out.push_str("\\n"),
+                        '\r' => out.push_str("\\r"),
+                        '\t' => out.push_str("\\t"),
+                        '€' if next.is_some_and(|c| c == '€' || c == '{' || c == '*' || c.is_ascii_digit()) => out.push_str("€€"),
+                        // €secret(, €gitdiff and friends, and whatever may be bound later
+                        '€' if next.is_some_and(|c| c.is_ascii_alphabetic() || c == '_') => out.push_str("€€"),
+                        c => out.push(c),
+                    }
+                }
+            }
+            Segment::Placeholder { index, ty, default } => {
+                out.push_str(&format!("€{}", index));
+                if let Some(ty) = ty {
+                    out.push_str(&format!(":{}", ty.name()));
+                }
+                if let Some(default) = default {
+                    let quote = if block { "\"" } else { "\\\"" };
+                    out.push_str(&format!(" or {}{}{}", quote, render_segments(&[Segment::Text(default.clone())], block), quote));
+                }
+            }
+            Segment::Secret(name) => out.push_str(&format!("€secret({})", name)),
+            Segment::Env(name) => out.push_str(&format!("€env.{}", name)),
+            Segment::File { index, pages: None } => out.push_str(&format!("€file({})", index)),
+            Segment::File { index, pages: Some((first, last)) } if first == last => {
+                out.push_str(&format!("€file({}, pages={})", index, first))
+            }
+            Segment::File { index, pages: Some((first, last)) } => {
+                out.push_str(&format!("€file({}, pages={}-{})", index, first, last))
+            }
+            Segment::Dir { index, glob, max_bytes } => {
+                out.push_str(&format!("€dir({}", index));
+                if let Some(glob) = glob {
+                    // Inside a statement, so the quotes are escaped, unless it's triple quoted
+                    let quote = if block { "\"" } else { "\\\"" };
+                    out.push_str(&format!(", glob={}{}{}", quote, glob, quote));
+                }
+                if let Some(max_bytes) = max_bytes {
+                    out.push_str(&format!(", max_bytes={}", format_size(*max_bytes)));
+                }
+                out.push(')');
+            }
+            Segment::GitDiff => out.push_str("€gitdiff"),
+            Segment::GitLog(count) => out.push_str(&format!("€gitlog({})", count)),
+            Segment::Var(name) => out.push_str(&format!("€{}", name)),
+            Segment::Prev => out.push_str("€prev"),
+            Segment::Const { name, .. } => out.push_str(&format!("€{}", name)),
+            Segment::Named(name) => out.push_str(&format!("€{{{}}}", name)),
+            Segment::AllArgs => out.push_str("€*"),
+            Segment::Call { name, args } => {
+                let args = args.iter().map(|arg| render_segments(arg, block)).collect::<Vec<_>>();
+                out.push_str(&format!("{}({})", name, args.join(", ")));
+            }
+            Segment::Recipe { name, args } => {
+                let args = args.iter().map(|arg| render_segments(arg, block)).collect::<Vec<_>>();
+                out.push_str(&recipe_call(name, &args));
+            }
+            Segment::Macro { name, args, .. } => {
+                let args = args.iter().map(|arg| render_segments(arg, block)).collect::<Vec<_>>();
+                out.push_str(&format!("€{}({})", name, args.join(", ")));
+            }
+        }
+    }
+    out
+}
+
+/// `recipe name:`, its statements, `serve it!`: statements a program uses
+/// more than once, as `"do name with €1, ..."`. In the statements, €n is the
+/// n-th argument of the use rather than a stdin line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recipe {
+    pub name: String,
+    pub statements: Vec<Statement>,
+    /// `hm,` lines between its statements; `before` counts in `statements`.
+    pub comments: Vec<Comment>,
+}
+
+/// `macro name(a, b) = "..."`: text the compiler puts in place of every
+/// `€name(x, y)`, with `€a` and `€b` in it replaced by `x` and `y`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Macro {
+    pub name: String,
+    pub params: Vec<String>,
+    /// Between the quotes, escapes resolved but every `€` as written.
+    pub body: String,
+}
+
+impl Macro {
+    pub fn to_source(&self) -> String {
+        format!("macro {}({}) = \"{}\"", self.name, self.params.join(", "), escape_quoted(&self.body))
+    }
+}
+
+/// `text` for between quotes, with its escapes but every `€` as it is.
+fn escape_quoted(text: &str) -> String {
+    let mut out = String::new();
+    for ch in text.chars() {
+        match ch {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// `hm, ...`: a line the parser skips wherever it is, kept for `fmt`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment {
+    /// Position of the statement it comes before, or the number of statements
+    /// if it comes after the last. `None` for one before the first statement,
+    /// which `fmt` puts at the top.
+    pub before: Option<usize>,
+    pub text: String,
+}
+
+impl Comment {
+    pub fn to_source(&self) -> String {
+        if self.text.is_empty() {
+            "hm,".to_string()
+        } else {
+            format!("hm, {}", self.text)
+        }
+    }
+}
+
+/// `again! N times`, its statements, `enough!`: statements the program runs
+/// N times over, with the round (from 1) as `€loop`. N is a number, or a
+/// placeholder or variable that says how many when the program runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Loop {
+    /// Position of the first statement in `Program::statements`.
+    pub first: usize,
+    /// Number of statements in the loop.
+    pub len: usize,
+    pub count: Vec<Segment>,
+}
+
+impl Loop {
+    pub fn contains(&self, index: usize) -> bool {
+        (self.first..self.first + self.len).contains(&index)
+    }
+
+    /// The number of rounds, if it's written down rather than read at runtime.
+    pub fn rounds(&self) -> Option<usize> {
+        match self.count.as_slice() {
+            [Segment::Text(count) | Segment::Const { value: count, .. }] => count.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// `again! €1 times`, as in the source.
+    pub fn header(&self) -> String {
+        format!("again! {} times", segments_to_source(&self.count))
+    }
+}
+
+/// `if the vibe is "..."`, its statements, optionally `otherwise` and more
+/// statements, `done!`: the first statements run if the answer the program got
+/// last is the vibe (trimmed, in any case), the others if it isn't.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Branch {
+    /// Position of the first statement in `Program::statements`.
+    pub first: usize,
+    /// Number of statements before `otherwise`, and after it.
+    pub then_len: usize,
+    pub else_len: usize,
+    /// Between the quotes, escapes resolved. A `€` is just a `€`.
+    pub vibe: String,
+}
+
+impl Branch {
+    pub fn contains(&self, index: usize) -> bool {
+        (self.first..self.end()).contains(&index)
+    }
+
+    /// Position of the first statement after `done!`.
+    pub fn end(&self) -> usize {
+        self.first + self.then_len + self.else_len
+    }
+
+    /// `if the vibe is "yes"`, as in the source.
+    pub fn header(&self) -> String {
+        format!("if the vibe is \"{}\"", escape_quoted(&self.vibe))
+    }
+}
+
+/// `all at once!`, its statements, `together!`: statements the program asks
+/// about at the same time, none waiting for another's answer, with the
+/// answers printed in source order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parallel {
+    /// Position of the first statement in `Program::statements`.
+    pub first: usize,
+    /// Number of statements in the block.
+    pub len: usize,
+}
+
+impl Parallel {
+    pub fn contains(&self, index: usize) -> bool {
+        (self.first..self.first + self.len).contains(&index)
+    }
+}
+
+/// `for every line`, its statements, `that's all!`: statements the program
+/// asks again for each line of stdin, with `€line` the line it's on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EveryLine {
+    /// Position of the first statement in `Program::statements`.
+    pub first: usize,
+    /// Number of statements in the block.
+    pub len: usize,
+}
+
+impl EveryLine {
+    pub fn contains(&self, index: usize) -> bool {
+        (self.first..self.first + self.len).contains(&index)
+    }
+}
+
+/// `nom nom "other.matthiashihic"`: another file's statements, read in its
+/// place. The statements are in `Program::statements` like any others; this
+/// only remembers where they came from, so formatting puts the line back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Include {
+    /// Position of the first statement in `Program::statements`.
+    pub first: usize,
+    /// Number of statements the file brought, its own includes' included.
+    pub len: usize,
+    /// The path as written, relative to the including file.
+    pub path: String,
+}
+
+impl Include {
+    pub fn contains(&self, index: usize) -> bool {
+        (self.first..self.first + self.len).contains(&index)
+    }
+
+    /// `nom nom "other.matthiashihic"`, as in the source.
+    pub fn to_source(&self) -> String {
+        format!("nom nom \"{}\"", self.path.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// `variant A: "...", variant B: "..."`: a statement with two wordings, one of
+/// which each run picks. Arm A is the statement in `Program::statements`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Experiment {
+    /// Position of the statement in `Program::statements`.
+    pub index: usize,
+    pub b: Statement,
+    /// Share of runs, in percent, that get arm A.
+    pub split: u8,
+}
+
+impl Experiment {
+    /// Shares as written only when they aren't the default 50/50.
+    pub fn to_source(&self, a: &Statement) -> String {
+        if self.split == 50 {
+            format!("variant A: {}, variant B: {}", a.to_source(), self.b.to_source())
+        } else {
+            format!(
+                "variant A ({}%): {}, variant B ({}%): {}",
+                self.split,
+                a.to_source(),
+                100 - self.split,
+                self.b.to_source()
+            )
+        }
+    }
+}
+
+/// `persona "..."` before the first statement: who the model is for the whole
+/// program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Persona {
+    pub text: String,
+    /// `persona only "..."`: the persona is the whole system prompt, instead of
+    /// something added to the one every program gets.
+    pub only: bool,
+}
+
+impl Persona {
+    pub fn to_source(&self) -> String {
+        let only = if self.only { "only " } else { "" };
+        format!("persona {}\"{}\"", only, segments_to_source(&[Segment::Text(self.text.clone())]))
+    }
+}
+
+/// Identity of a program from its `name:`, `about:` and `version:` directives.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct ProgramInfo {
+    pub name: Option<String>,
+    pub about: Option<String>,
+    pub version: Option<String>,
+}
+
+impl ProgramInfo {
+    pub fn slot(&mut self, key: &str) -> Option<&mut Option<String>> {
+        match key {
+            "name" => Some(&mut self.name),
+            "about" => Some(&mut self.about),
+            "version" => Some(&mut self.version),
+            _ => None,
+        }
+    }
+
+    /// Sets `key` (name, about or version); each may only be declared once.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value = value.trim();
+        let slot = self
+            .slot(key)
+            .ok_or_else(|| format!("Unknown program directive '{}'", key))?;
+        if slot.is_some() {
+            return Err(format!("'{}' is declared more than once", key));
+        }
+        if value.is_empty() {
+            return Err(format!("'{}' must not be empty", key));
+        }
+        if key == "version" && !is_semver(value) {
+            return Err(format!("version must look like 1.2.3, got '{}'", value));
+        }
+        *slot = Some(value.to_string());
+        Ok(())
+    }
+}
+
+/// MAJOR.MINOR.PATCH with an optional -prerelease, as Cargo requires.
+fn is_semver(version: &str) -> bool {
+    let core = version.split_once('-').map(|(core, _)| core).unwrap_or(version);
+    let parts: Vec<&str> = core.split('.').collect();
+    parts.len() == 3 && parts.iter().all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// The grammar a source is written in, chosen by its header. An edition is
+/// frozen once the next one comes along: `hihi!` is the grammar as it was
+/// when editions were added, and syntax added since needs the header of the
+/// edition it came with, e.g. `hihi! v2`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Edition {
+    V1,
+    /// `speak <language>!` and `macro`.
+    V2,
+}
+
+impl Edition {
+    /// The newest edition this compiler knows.
+    pub const LATEST: Edition = Edition::V2;
+
+    /// The edition a `hihi!`, `hihi! v1` or `hihi! vN` header asks for, as
+    /// its number, or `None` if `line` isn't a header.
+    pub fn requested(line: &str) -> Option<u32> {
+        let rest = line.trim().strip_prefix("hihi!")?.trim();
+        if rest.is_empty() {
+            return Some(1);
+        }
+        rest.strip_prefix('v').filter(|n| n.bytes().all(|b| b.is_ascii_digit())).and_then(|n| n.parse().ok()).filter(|&n| n > 0)
+    }
+
+    pub fn from_number(number: u32) -> Option<Edition> {
+        match number {
+            1 => Some(Edition::V1),
+            2 => Some(Edition::V2),
+            _ => None,
+        }
+    }
+
+    pub fn number(self) -> u32 {
+        match self {
+            Edition::V1 => 1,
+            Edition::V2 => 2,
+        }
+    }
+
+    /// The header that starts a source of this edition.
+    pub fn header(self) -> String {
+        match self {
+            Edition::V1 => "hihi!".to_string(),
+            edition => format!("hihi! v{}", edition.number()),
+        }
+    }
+}
+
+/// A parsed source file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    /// The `hihi!` header's edition.
+    pub edition: Edition,
+    pub front_matter: FrontMatter,
+    /// `name:`/`about:`/`version:`/`translate output to:` lines in source order (front-matter keys are
+    /// kept in `front_matter`).
+    pub directives: Vec<(String, String)>,
+    pub statements: Vec<Statement>,
+    /// Everything after `eat that java!`, verbatim but for a `\r` it ends with.
+    pub epilogue: String,
+    /// Directives and front-matter identity keys, merged.
+    pub info: ProgramInfo,
+    /// Sorted placeholder indices the statements use.
+    pub required_args: Vec<usize>,
+    /// `€{name}` placeholders, in the order they first appear.
+    pub named_args: Vec<String>,
+    /// Placeholders with a type annotation, sorted by index.
+    pub arg_types: Vec<(usize, ArgType)>,
+    /// Placeholders with an `or "default"`, as (index, default), sorted by index.
+    pub arg_defaults: Vec<(usize, String)>,
+    /// Environment variables used by `€secret(NAME)`, sorted.
+    pub secrets: Vec<String>,
+    /// Environment variables used by `€env.NAME`, sorted.
+    pub env_vars: Vec<String>,
+    /// `extern name = "path"` declarations, as (name, path) in source order.
+    pub externs: Vec<(String, String)>,
+    /// `recipe name:` blocks in source order; linking replaces their uses.
+    pub recipes: Vec<Recipe>,
+    /// `macro` definitions in source order. Their uses are expanded already.
+    pub macros: Vec<Macro>,
+    /// Language from `translate output to: <language>`; the final answer is
+    /// translated by a separate call after the program ran.
+    pub translate_to: Option<String>,
+    /// `persona "..."`, for every model call the program makes.
+    pub persona: Option<Persona>,
+    /// Language from `speak <language>!`; every answer is written in it.
+    pub speak: Option<String>,
+    /// `remember!`: each stdin record continues the conversation of the ones
+    /// before it, instead of starting over.
+    pub remember: bool,
+    /// The program's one `variant A/B` statement, if it has one.
+    pub experiment: Option<Experiment>,
+    /// `again! N times` blocks in source order; they don't overlap.
+    pub loops: Vec<Loop>,
+    /// `if the vibe is` blocks in source order. They don't overlap, and may
+    /// be in a loop but don't contain one.
+    pub branches: Vec<Branch>,
+    /// `all at once!` blocks in source order. They don't overlap, and may be
+    /// in a loop or a branch but don't contain either.
+    pub parallels: Vec<Parallel>,
+    /// `for every line` blocks in source order. They don't overlap, and are
+    /// neither in nor around any other block.
+    pub every_line: Vec<EveryLine>,
+    /// `nom nom` lines in source order, the ones in included files left out.
+    pub includes: Vec<Include>,
+    /// `hm,` lines in source order, except those in recipes.
+    pub comments: Vec<Comment>,
+    /// `name = "value"` lines of the `stuff:` section, in source order.
+    pub constants: Vec<(String, String)>,
+}
+
+impl Program {
+    /// Renders the program in canonical form: header, front-matter as written,
+    /// comments from before the first statement, directives, externs, `stuff:`, recipes,
+    /// one statement per line (indented in blocks, included ones as their
+    /// `nom nom` line) with the comments before it, terminator, then the
+    /// epilogue untouched.
+    pub fn to_source(&self) -> String {
+        let mut out = format!("{}\n", self.edition.header());
+        if !self.front_matter.lines.is_empty() {
+            out.push_str("---\n");
+            out.push_str(&self.front_matter.to_source());
+            out.push_str("---\n");
+        }
+        for comment in self.comments.iter().filter(|comment| comment.before.is_none()) {
+            out.push_str(&format!("{}\n", comment.to_source()));
+        }
+        for (key, value) in &self.directives {
+            if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+                out.push_str(&format!("{}: \"{}\"\n", key, value));
+            } else {
+                out.push_str(&format!("{}: {}\n", key, value));
+            }
+        }
+        if let Some(persona) = &self.persona {
+            out.push_str(&format!("{}\n", persona.to_source()));
+        }
+        if let Some(language) = &self.speak {
+            out.push_str(&format!("speak {}!\n", language));
+        }
+        if self.remember {
+            out.push_str("remember!\n");
+        }
+        for (name, path) in &self.externs {
+            out.push_str(&format!("extern {} = \"{}\"\n", name, path));
+        }
+        if !self.constants.is_empty() {
+            out.push_str("stuff:\n");
+            for (name, value) in &self.constants {
+                out.push_str(&format!("    {} = \"{}\"\n", name, segments_to_source(&[Segment::Text(value.clone())])));
+            }
+        }
+        for definition in &self.macros {
+            out.push_str(&format!("{}\n", definition.to_source()));
+        }
+        for recipe in &self.recipes {
+            out.push_str(&format!("recipe {}:\n", recipe.name));
+            for (index, statement) in recipe.statements.iter().enumerate() {
+                for comment in recipe.comments.iter().filter(|comment| comment.before == Some(index)) {
+                    out.push_str(&format!("    {}\n", comment.to_source()));
+                }
+                out.push_str(&format!("    {}\n", statement.to_source()));
+                if let Some(expectation) = statement.expectation_source() {
+                    out.push_str(&format!("    {}\n", expectation));
+                }
+            }
+            for comment in recipe.comments.iter().filter(|comment| comment.before == Some(recipe.statements.len())) {
+                out.push_str(&format!("    {}\n", comment.to_source()));
+            }
+            out.push_str("serve it!\n\n");
+        }
+        for (index, statement) in self.statements.iter().enumerate() {
+            let looped = self.loop_of(index);
+            let branch = self.branch_of(index);
+            let every_line = self.every_line_of(index);
+            if every_line.is_some_and(|every_line| every_line.first == index) {
+                out.push_str("for every line\n");
+            }
+            let outer = if looped.is_some() || every_line.is_some() { "    " } else { "" };
+            if let Some(looped) = looped.filter(|looped| looped.first == index) {
+                out.push_str(&format!("{}\n", looped.header()));
+            }
+            if let Some(branch) = branch {
+                if branch.first == index {
+                    out.push_str(&format!("{}{}\n", outer, branch.header()));
+                } else if branch.first + branch.then_len == index {
+                    out.push_str(&format!("{}otherwise\n", outer));
+                }
+            }
+            let inner = format!("{}{}", outer, if branch.is_some() { "    " } else { "" });
+            let parallel = self.parallel_of(index);
+            if parallel.is_some_and(|parallel| parallel.first == index) {
+                out.push_str(&format!("{}all at once!\n", inner));
+            }
+            let indent = format!("{}{}", inner, if parallel.is_some() { "    " } else { "" });
+            for comment in self.comments.iter().filter(|comment| comment.before == Some(index)) {
+                out.push_str(&format!("{}{}\n", indent, comment.to_source()));
+            }
+            let included = self.includes.iter().find(|include| include.contains(index));
+            if included.is_none_or(|include| include.first == index) {
+                out.push_str(&indent);
+                match (&self.experiment, included) {
+                    (_, Some(include)) => out.push_str(&include.to_source()),
+                    (Some(experiment), _) if experiment.index == index => out.push_str(&experiment.to_source(statement)),
+                    _ => out.push_str(&statement.to_source()),
+                }
+                out.push('\n');
+            }
+            if let Some(expectation) = statement.expectation_source().filter(|_| included.is_none()) {
+                out.push_str(&format!("{}{}\n", indent, expectation));
+            }
+            if parallel.is_some_and(|parallel| parallel.first + parallel.len == index + 1) {
+                out.push_str(&format!("{}together!\n", inner));
+            }
+            if branch.is_some_and(|branch| branch.end() == index + 1) {
+                out.push_str(&format!("{}done!\n", outer));
+            }
+            if looped.is_some_and(|looped| looped.first + looped.len == index + 1) {
+                out.push_str("enough!\n");
+            }
+            if every_line.is_some_and(|every_line| every_line.first + every_line.len == index + 1) {
+                out.push_str("that's all!\n");
+            }
+        }
+        for comment in self.comments.iter().filter(|comment| comment.before == Some(self.statements.len())) {
+            out.push_str(&format!("{}\n", comment.to_source()));
+        }
+        out.push_str("eat that java!\n");
+        if !self.epilogue.is_empty() {
+            out.push_str(&self.epilogue);
+            out.push('\n');
+        }
+        out
+    }
+
+    /// The loop the statement at `index` is in, if any.
+    pub fn loop_of(&self, index: usize) -> Option<&Loop> {
+        self.loops.iter().find(|looped| looped.contains(index))
+    }
+
+    /// The branch the statement at `index` is in, if any.
+    pub fn branch_of(&self, index: usize) -> Option<&Branch> {
+        self.branches.iter().find(|branch| branch.contains(index))
+    }
+
+    /// The `all at once!` block the statement at `index` is in, if any.
+    pub fn parallel_of(&self, index: usize) -> Option<&Parallel> {
+        self.parallels.iter().find(|parallel| parallel.contains(index))
+    }
+
+    /// The `for every line` block the statement at `index` is in, if any.
+    pub fn every_line_of(&self, index: usize) -> Option<&EveryLine> {
+        self.every_line.iter().find(|every_line| every_line.contains(index))
+    }
+
+    /// Whether a step has to start at `index`: where a loop, a branch or one
+    /// of its parts, an `all at once!` or a `for every line` block starts or ends, or at the statement whose answer an `if`
+    /// looks at, so that answer is all it sees.
+    pub fn starts_block(&self, index: usize) -> bool {
+        self.loops.iter().any(|looped| looped.first == index || looped.first + looped.len == index)
+            || self.branches.iter().any(|branch| {
+                [branch.first - 1, branch.first, branch.first + branch.then_len, branch.end()].contains(&index)
+            })
+            || self.parallels.iter().any(|parallel| parallel.first == index || parallel.first + parallel.len == index)
+            || self.every_line.iter().any(|every_line| every_line.first == index || every_line.first + every_line.len == index)
+    }
+
+    /// Every statement, arm B of an experiment included.
+    pub fn all_statements(&self) -> impl Iterator<Item = &Statement> {
+        self.statements.iter().chain(self.experiment.as_ref().map(|experiment| &experiment.b))
+    }
+
+    /// The program as arm B of its experiment runs it.
+    pub fn arm_b(&self) -> Option<Program> {
+        let experiment = self.experiment.as_ref()?;
+        let mut program = self.clone();
+        program.statements[experiment.index] = experiment.b.clone();
+        program.experiment = None;
+        Some(program)
+    }
+}
+
+/// The given statements joined by newlines, with `{ARG_n}` placeholder markers.
+pub fn pseudocode_of(statements: &[Statement]) -> String {
+    statements
+        .iter()
+        .map(Statement::pseudocode)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::parser::{format_program, parse_file, parse_matthiashihic};
+
+    /// Parses `source`, formats it, and checks that the formatted source
+    /// parses to the same program and formats to itself.
+    fn round_trip(source: &str) {
+        let (program, _) = parse_matthiashihic(source).unwrap_or_else(|e| panic!("doesn't parse: {}\n{}", e, source));
+        let formatted = format_program(&program, None).unwrap_or_else(|e| panic!("{}", e));
+        let (again, _) = parse_matthiashihic(&formatted).expect("formatted source parses");
+        assert_eq!(format_program(&again, None).as_deref(), Ok(formatted.as_str()), "formatting isn't stable");
+    }
+
+    #[test]
+    fn plain_statements() {
+        round_trip("hihi!\n\"say hello\"\n\"tell \\\"€1\\\" apart from €€1\\tand \\\\ and €{name}\"\neat that java!\n");
+        round_trip("hihi!\n\"greet €1 or \\\"World\\\"\" -> greeting\n\"shout €greeting, €prev\"\neat that java!\n");
+        round_trip("hihi!\n\"count €1:int in €2:number, read €3:path as €4:json\"\neat that java!\n");
+        round_trip("hihi!\n\"summarize €file(1, pages=2-4) and €dir(2, glob=\\\"*.rs\\\", max_bytes=200k)\"\neat that java!\n");
+        round_trip("hihi!\n\"review €gitdiff against €gitlog(5), using €secret(TOKEN) on €env.HOST\"\neat that java!\n");
+        round_trip("hihi!\n\"count lines of €*, not €€*\"\neat that java!\n");
+    }
+
+    #[test]
+    fn statement_options() {
+        round_trip("hihi!\n\"list three colors\" using gpt-4o-mini (chill) as json {colors: [string]} or try again 2 times -> colors\neat that java!\n");
+        round_trip("hihi!\n\"write a poem\" write it to \"poem.txt\"\n\"what is 6*7\"\nbetter be \"42\"\neat that java!\n");
+        round_trip("hihi!\nshell \"git log -5\"\n\"summarize €shell\"\neat that java!\n");
+    }
+
+    #[test]
+    fn triple_quoted_statements() {
+        round_trip("hihi!\n\"\"\"\nTranslate this:\n  \"€1\"\nkeep the indent\n\"\"\" -> translated\neat that java!\n");
+        // A carriage return can't be in a block, so it stays escaped
+        round_trip("hihi!\n\"line one\\nline\\rtwo\"\neat that java!\n");
+    }
+
+    #[test]
+    fn raw_statements() {
+        round_trip("hihi!\nr\"Does C:\\Users\\€1 match ^[A-Z]:\\\\.*$ ?\"\neat that java!\n");
+        round_trip("hihi!\nr#\"Explain \"\\d+\" to a pirate\"#\neat that java!\n");
+        round_trip("hihi!\nr##\"a \"# in the middle\"##\neat that java!\n");
+        // There'd be no writing it back raw
+        assert!(parse_matthiashihic("hihi!\nr\"a\rb\"\neat that java!\n").is_err());
+    }
+
+    #[test]
+    fn loops() {
+        round_trip("hihi!\nagain! 3 times\n    \"idea number €loop\"\nenough!\neat that java!\n");
+        round_trip("hihi!\nagain! €1 times\n    \"improve €prev\"\nenough!\neat that java!\n");
+    }
+
+    #[test]
+    fn branches() {
+        round_trip("hihi!\n\"is €1 spam? yes or no\"\nif the vibe is \"yes\"\n    \"write a polite no\"\notherwise\n    \"write a reply\"\ndone!\neat that java!\n");
+        round_trip("hihi!\n\"pick one\"\nif the vibe is \"€5, €gitlog and \\\"quotes\\\"\"\n    \"it was\"\ndone!\neat that java!\n");
+    }
+
+    #[test]
+    fn parallel_blocks() {
+        round_trip("hihi!\nall at once!\n    \"a haiku about €1\"\n    \"a limerick about €1\"\ntogether!\neat that java!\n");
+    }
+
+    #[test]
+    fn every_line_blocks() {
+        round_trip("hihi! v2\nfor every line\n    \"translate €line\"\nthat's all!\neat that java!\n");
+    }
+
+    #[test]
+    fn recipes() {
+        round_trip("hihi!\nrecipe brief:\n    hm, for managers\n    \"explain €1 in two sentences\"\n    \"now in one\"\nserve it!\n\"do brief with rust\"\n\"do brief with €1\"\neat that java!\n");
+    }
+
+    #[test]
+    fn macros() {
+        round_trip("hihi! v2\nmacro shout(x) = \"say €x in capitals\"\nmacro twice(a, b) = \"€shout(€a), then \\\"€b\\\": €shout(€a)\"\n\"€twice(€1 and €€, quietly)\"\neat that java!\n");
+    }
+
+    #[test]
+    fn constants() {
+        round_trip("hihi!\nstuff:\n    greeting = \"hello \\\"there\\\"\"\n    price = \"5 €€\"\n\"say €greeting for €price\"\neat that java!\n");
+    }
+
+    #[test]
+    fn comments() {
+        round_trip("hihi!\nhm, before everything\n\"first\"\nhm, between\n\"second\"\neat that java!\nwhatever comes after the terminator\n");
+        round_trip("hihi!\n\"a\"\neat that java!\nfoo\r");
+    }
+
+    #[test]
+    fn preamble() {
+        round_trip("hihi!\n---\nmodel: gpt-4o\ntemperature: 0.3\n---\nname: greeter\nabout: \"says hi\"\npersona \"you are a pirate\"\nextern upper = \"upper.matthiashihic\"\n\"say hi\"\neat that java!\n");
+        round_trip("hihi! v2\nspeak German!\nremember!\n\"say hi to €1\"\neat that java!\n");
+    }
+
+    #[test]
+    fn editions() {
+        round_trip("hihi!\n\"v1\"\neat that java!\n");
+        round_trip("hihi! v2\n\"v2\"\neat that java!\n");
+        // What came before editions is v1; what came after needs v2
+        round_trip("hihi!\nremember!\nfor every line\n    \"translate €line\"\nthat's all!\neat that java!\n");
+        assert!(parse_matthiashihic("hihi!\nspeak German!\n\"hi\"\neat that java!\n").is_err());
+        assert!(parse_matthiashihic("hihi!\nmacro m(x) = \"€x\"\n\"€m(a)\"\neat that java!\n").is_err());
+    }
+
+    #[test]
+    fn includes() {
+        let dir = std::env::temp_dir().join(format!("matthiashihic-round-trip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).expect("temp dir");
+        std::fs::write(dir.join("part.matthiashihic"), "hihi!\n\"included €1\"\neat that java!\n").expect("write part");
+        let main = dir.join("main.matthiashihic");
+        let source = "hihi!\n\"before\"\nnom nom \"part.matthiashihic\"\n\"after\"\neat that java!\n";
+        let (program, _) = parse_file(source, &main).unwrap_or_else(|e| panic!("doesn't parse: {}", e));
+        let formatted = format_program(&program, Some(Path::new(&main))).unwrap_or_else(|e| panic!("{}", e));
+        assert!(formatted.contains("nom nom \"part.matthiashihic\""), "the include is kept:\n{}", formatted);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
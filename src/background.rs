@@ -0,0 +1,69 @@
+//! `--background`: the program hands its question to the provider as a
+//! background response and exits with the job's ID; `--poll <id>` gets the
+//! answer later. For reasoning models that think for ten minutes, which no
+//! stream survives.
+//!
+//! A job is one request, so the program has to plan to exactly one model step
+//! on OpenAI, with nothing around it that needs the answer while it runs.
+
+use crate::ast::Program;
+use crate::planner::Route;
+use crate::{CompileOptions, StdinFormat};
+
+/// Why `program`, after linking, can't run with `opts` as a background job.
+pub fn check(program: &Program, opts: &CompileOptions) -> Result<(), String> {
+    let flags = [
+        (opts.tui, "--tui"),
+        (opts.serve, "--serve"),
+        (opts.stream_filter, "--stream-filter"),
+        (opts.listen_socket.is_some(), "--listen-socket"),
+        (opts.audio_input, "--input audio"),
+        (opts.image_output.is_some(), "--output image"),
+        (opts.ensemble.is_some(), "--ensemble"),
+        (!opts.hooks.is_empty(), "--hook"),
+        (!opts.filters.is_empty(), "--filter"),
+        (!opts.fallback.is_empty(), "--fallback"),
+        (opts.cache, "--cache"),
+        (opts.request_template.is_some(), "--request-template"),
+        (opts.csv_columns.is_some(), "--output csv"),
+        (opts.stdin_format != StdinFormat::Lines, "--stdin-format"),
+    ];
+    if let Some((_, flag)) = flags.iter().find(|(set, _)| *set) {
+        return Err(format!("--background submits one job and exits; it can't be combined with {}", flag));
+    }
+    if program.translate_to.is_some() {
+        return Err("--background can't translate the answer; it isn't there until --poll".to_string());
+    }
+    if program.experiment.is_some() {
+        return Err("--background can't run a variant A/B experiment".to_string());
+    }
+    if !program.loops.is_empty() || !program.branches.is_empty() || !program.parallels.is_empty() || !program.every_line.is_empty() {
+        return Err("--background runs one request; again!, if the vibe is, all at once! and for every line need more".to_string());
+    }
+    if let Some(statement) = program.statements.iter().find(|statement| statement.retries.is_some()) {
+        return Err(format!("--background can't try again: \"{}\" or try again", statement.to_line()));
+    }
+    if let Some(statement) = program.statements.iter().find(|statement| statement.write_to.is_some()) {
+        return Err(format!("--background can't write the answer to a file; it isn't there until --poll: {}", statement.to_line()));
+    }
+    let plan = opts.planner().plan(program, 0);
+    let step = match plan.as_slice() {
+        [step] => step,
+        _ => {
+            return Err(format!(
+                "--background runs one request, but this program takes {}; --explain-plan shows them",
+                plan.len()
+            ))
+        }
+    };
+    let model = match &step.route {
+        Route::Local(..) => return Err("--background asks the model, but this program's one step runs locally".to_string()),
+        Route::Cheap => opts.cheap_model.as_deref().unwrap_or(&opts.model),
+        Route::Model => &opts.model,
+        Route::Using(model) => model,
+    };
+    if model.starts_with("claude") {
+        return Err(format!("--background needs OpenAI's background responses, which {} doesn't have", model));
+    }
+    Ok(())
+}
@@ -0,0 +1,158 @@
+//! `bake`: runs a whole program (which must not have placeholders) at compile
+//! time and emits a std-only binary that prints the captured answer. No API
+//! key, no network and no runtime dependencies, so the output of a prompt can be
+//! checked in and regenerated like any other build artifact.
+
+use crate::ast::Program;
+use crate::{build_metadata, escape_rust_string, json_string, CompileOptions, SourceFiles};
+
+/// Small binaries over fast ones; all a baked program does is print a string.
+const BAKED_PROFILE: &str = r#"
+[profile.release]
+opt-level = "z"
+lto = true
+strip = true
+"#;
+
+/// Checks that every statement can be answered at compile time.
+pub fn check(program: &Program, opts: &CompileOptions) -> Result<(), String> {
+    if let Some(index) = program.required_args.first() {
+        return Err(format!("bake needs a program without placeholders, but €{} is only known at runtime", index));
+    }
+    if let Some(name) = program.named_args.first() {
+        return Err(format!("bake needs a program without placeholders, but €{{{}}} is only known at runtime", name));
+    }
+    if let Some(name) = program.secrets.first() {
+        return Err(format!("bake would write €secret({}) into the binary; refusing", name));
+    }
+    if let Some(name) = program.env_vars.first() {
+        return Err(format!("bake needs a program without placeholders, but €env.{} is only known at runtime", name));
+    }
+    if program.translate_to.is_some() {
+        return Err("bake can't translate the answer; drop the 'translate output to:' directive".into());
+    }
+    if program.experiment.is_some() {
+        return Err("bake answers once, so it can't run a variant A/B experiment; pick one of the variants".into());
+    }
+    if !program.loops.is_empty() {
+        return Err("bake answers once, so it can't go around an again! loop; write the statements out".into());
+    }
+    if !program.branches.is_empty() {
+        return Err("bake answers once, so there's no vibe to check; drop the if the vibe is".into());
+    }
+    if !program.parallels.is_empty() {
+        return Err("bake answers once, so there's nothing to ask all at once; drop the all at once! and together!".into());
+    }
+    if !program.every_line.is_empty() {
+        return Err("bake answers once, at compile time, before there are any lines; drop the for every line".into());
+    }
+    if let Some(statement) = program.statements.iter().find(|statement| statement.shell) {
+        return Err(format!("bake answers once, at compile time, so it doesn't run commands: {}", statement.to_line()));
+    }
+    if let Some(statement) = program.statements.iter().find(|statement| statement.write_to.is_some()) {
+        return Err(format!("bake only prints the answer, so it can't write it to a file: {}", statement.to_line()));
+    }
+    if crate::precompute::static_prefix(program, opts) < program.statements.len() {
+        return Err("bake can't run builtins or dynamically linked externs; use --no-builtins and --link inline".into());
+    }
+    Ok(())
+}
+
+pub fn cargo_toml(program: &Program) -> String {
+    crate::generate_cargo_toml(program, BAKED_PROFILE)
+}
+
+/// The baked program: metadata, `--help`, and the answer, all in its main.rs.
+pub fn generate_source(opts: &CompileOptions, program: &Program, answer: &str) -> SourceFiles {
+    let metadata = build_metadata(opts, program);
+    let metadata = format!(
+        "{},\"baked_at\":{}}}",
+        metadata.strip_suffix('}').unwrap_or(&metadata),
+        json_string(&utc_now())
+    );
+    let default_name = std::path::Path::new(&opts.source_name)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| opts.source_name.clone());
+
+    let main = format!(
+r###"/// How this executable was built, as JSON.
+#[used]
+static METADATA: &str = "{}";
+const NAME: &str = "{}";
+const VERSION: &str = "{}";
+const ABOUT: &str = "{}";
+
+/// The model's answer, captured when this program was baked.
+const OUTPUT: &str = "{}";
+
+fn main() {{
+    for arg in std::env::args().skip(1) {{
+        match arg.as_str() {{
+            "-h" | "--help" => {{
+                if VERSION.is_empty() {{
+                    println!("{{}}", NAME);
+                }} else {{
+                    println!("{{}} {{}}", NAME, VERSION);
+                }}
+                if !ABOUT.is_empty() {{
+                    println!("{{}}", ABOUT);
+                }}
+                println!();
+                println!("Usage: {{}}", NAME);
+                println!("Prints an answer that was baked in at compile time.");
+                println!();
+                println!("Options:");
+                println!("  -h, --help      Print this help");
+                println!("  --hihic-info    Print how this program was built, as JSON");
+                return;
+            }}
+            "--hihic-info" => {{
+                println!("{{}}", METADATA);
+                return;
+            }}
+            other => {{
+                eprintln!("Error: Unknown argument: {{}}\nRun with --help for usage.", other);
+                std::process::exit(2);
+            }}
+        }}
+    }}
+    println!("{{}}", OUTPUT);
+}}
+"###,
+        escape_rust_string(&metadata),
+        escape_rust_string(program.info.name.as_deref().unwrap_or(&default_name)),
+        escape_rust_string(program.info.version.as_deref().unwrap_or("")),
+        escape_rust_string(program.info.about.as_deref().unwrap_or("")),
+        escape_rust_string(answer),
+    );
+    vec![("main.rs", main)]
+}
+
+/// The current time as `YYYY-MM-DDTHH:MM:SSZ`.
+fn utc_now() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Days since 1970-01-01 to a civil date (Howard Hinnant's algorithm)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
@@ -0,0 +1,339 @@
+//! `matthiashihic broker [--socket <path>] [--rpm <n>] [--max-in-flight <n>] [--keys <file>]`
+//! has programs compiled with `--broker` take turns calling the model. Each
+//! call asks the broker over a Unix socket first and waits until it may go:
+//! at most `--rpm` calls a minute and `--max-in-flight` at once, however many
+//! programs make them, with the waiting programs served in turn so a busy one
+//! can't starve the others. With `--keys`, the broker also says which key to
+//! call with: of those that aren't resting after a 429, the one with the
+//! fewest calls in flight that went longest without one.
+//!
+//! The protocol is a line each way per call, on a connection of its own: the
+//! program sends `call <name> <pid>`, the broker answers `go <key>` (`go -`
+//! to use its own key) once it's its turn, and the program sends `done
+//! <status>`, or hangs up, when the call is over.
+
+#[cfg(unix)]
+use std::collections::{HashMap, VecDeque};
+#[cfg(unix)]
+use std::io::{BufRead, BufReader, Write};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::sync::{Condvar, Mutex, MutexGuard};
+#[cfg(unix)]
+use std::time::{Duration, Instant};
+
+use std::fs;
+use std::path::PathBuf;
+
+use crate::i18n::tr;
+use crate::paths;
+
+/// Every flag takes a value.
+const FLAGS: &[&str] = &["--socket", "--rpm", "--max-in-flight", "--keys"];
+/// How long a key rests after the provider answered a call with 429.
+#[cfg(unix)]
+const KEY_REST: Duration = Duration::from_secs(30);
+/// What `--rpm` counts calls over.
+#[cfg(unix)]
+const WINDOW: Duration = Duration::from_secs(60);
+
+#[cfg(unix)]
+struct Key {
+    key: String,
+    in_flight: usize,
+    last_turn: Option<Instant>,
+    resting_until: Option<Instant>,
+}
+
+/// The calls of one running program: the ones waiting for their turn, by ticket, and
+/// the ones that got it.
+#[cfg(unix)]
+#[derive(Default)]
+struct Caller {
+    waiting: VecDeque<u64>,
+    in_flight: usize,
+    last_turn: Option<Instant>,
+}
+
+#[cfg(unix)]
+#[derive(Default)]
+struct State {
+    callers: HashMap<String, Caller>,
+    keys: Vec<Key>,
+    /// When the calls of the last WINDOW got their turn, oldest first.
+    turns: VecDeque<Instant>,
+    in_flight: usize,
+    next_ticket: u64,
+}
+
+#[cfg(unix)]
+impl State {
+    /// Whose turn it is: the ticket and the key to use, if a call may go now.
+    /// Otherwise when that may change by itself; `None` if only a call
+    /// that's over can change it.
+    fn next(&mut self, broker: &Broker, now: Instant) -> Result<(u64, Option<usize>), Option<Instant>> {
+        while self.turns.front().is_some_and(|&turn| now.duration_since(turn) >= WINDOW) {
+            self.turns.pop_front();
+        }
+        if broker.max_in_flight.is_some_and(|max| self.in_flight >= max) {
+            return Err(None);
+        }
+        if let Some(rpm) = broker.rpm.filter(|&rpm| self.turns.len() >= rpm) {
+            return Err(self.turns.get(self.turns.len() - rpm).map(|&turn| turn + WINDOW));
+        }
+        let key = if self.keys.is_empty() {
+            None
+        } else {
+            let rested = |key: &Key| key.resting_until.is_none_or(|until| until <= now);
+            let available = (0..self.keys.len()).filter(|&i| rested(&self.keys[i]));
+            match available.min_by_key(|&i| (self.keys[i].in_flight, self.keys[i].last_turn)) {
+                Some(key) => Some(key),
+                None => return Err(self.keys.iter().filter_map(|key| key.resting_until).min()),
+            }
+        };
+        // The program with the fewest calls in flight goes first, then the
+        // one that waited longest since its last turn
+        let ticket = self
+            .callers
+            .values()
+            .filter_map(|caller| Some((caller.in_flight, caller.last_turn, *caller.waiting.front()?)))
+            .min()
+            .map(|(_, _, ticket)| ticket)
+            .ok_or(None)?;
+        Ok((ticket, key))
+    }
+}
+
+#[cfg(unix)]
+struct Broker {
+    rpm: Option<usize>,
+    max_in_flight: Option<usize>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[cfg(unix)]
+impl Broker {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Waits until it's the turn of a call by `name`, and takes it.
+    fn take_turn(&self, name: &str) -> Turn<'_> {
+        let mut state = self.lock();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.callers.entry(name.to_string()).or_default().waiting.push_back(ticket);
+        self.changed.notify_all();
+        loop {
+            let now = Instant::now();
+            match state.next(self, now) {
+                Ok((next, key)) if next == ticket => {
+                    let caller = state.callers.entry(name.to_string()).or_default();
+                    caller.waiting.pop_front();
+                    caller.in_flight += 1;
+                    caller.last_turn = Some(now);
+                    if let Some(key) = key {
+                        state.keys[key].in_flight += 1;
+                        state.keys[key].last_turn = Some(now);
+                    }
+                    state.in_flight += 1;
+                    state.turns.push_back(now);
+                    // The next in line may be able to go, too
+                    self.changed.notify_all();
+                    return Turn { broker: self, name: name.to_string(), key, status: None };
+                }
+                Err(Some(until)) => {
+                    state = self.changed.wait_timeout(state, until.saturating_duration_since(now)).unwrap_or_else(|e| e.into_inner()).0;
+                }
+                Ok(_) | Err(None) => state = self.changed.wait(state).unwrap_or_else(|e| e.into_inner()),
+            }
+        }
+    }
+}
+
+/// A call that got its turn; dropping it ends the call.
+#[cfg(unix)]
+struct Turn<'a> {
+    broker: &'a Broker,
+    name: String,
+    key: Option<usize>,
+    /// What the program said the call ended with; `None` if it hung up.
+    status: Option<String>,
+}
+
+#[cfg(unix)]
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        let mut state = self.broker.lock();
+        if let Some(caller) = state.callers.get_mut(&self.name) {
+            caller.in_flight -= 1;
+            if caller.in_flight == 0 && caller.waiting.is_empty() {
+                state.callers.remove(&self.name);
+            }
+        }
+        if let Some(key) = self.key {
+            let key = &mut state.keys[key];
+            key.in_flight -= 1;
+            if self.status.as_deref() == Some("429") {
+                key.resting_until = Some(Instant::now() + KEY_REST);
+            }
+        }
+        state.in_flight -= 1;
+        self.broker.changed.notify_all();
+    }
+}
+
+/// Runs the subcommand and returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let mut socket = None;
+    let mut rpm = None;
+    let mut max_in_flight = None;
+    let mut keys_file = None;
+    let mut i = 0;
+    while i < args.len() {
+        let flag = args[i].as_str();
+        if matches!(flag, "-h" | "--help") {
+            println!("{}", tr!("broker.usage"));
+            return 0;
+        }
+        let Some(value) = args.get(i + 1) else {
+            if FLAGS.contains(&flag) {
+                eprintln!("{} requires a value", flag);
+            } else if flag.starts_with('-') {
+                eprintln!("{}", tr!("broker.unknown-flag", flag = flag));
+            } else {
+                eprintln!("{}", tr!("broker.usage"));
+            }
+            return 2;
+        };
+        let parsed = match flag {
+            "--socket" => Some(value).map(|v| socket = Some(PathBuf::from(v))),
+            "--rpm" => value.parse().ok().filter(|&n: &usize| n > 0).map(|n| rpm = Some(n)),
+            "--max-in-flight" => value.parse().ok().filter(|&n: &usize| n > 0).map(|n| max_in_flight = Some(n)),
+            "--keys" => Some(value).map(|v| keys_file = Some(v.clone())),
+            s if s.starts_with('-') => {
+                eprintln!("{}", tr!("broker.unknown-flag", flag = s));
+                return 2;
+            }
+            _ => {
+                eprintln!("{}", tr!("broker.usage"));
+                return 2;
+            }
+        };
+        if parsed.is_none() {
+            eprintln!("Invalid value for {}: '{}'", flag, value);
+            return 2;
+        }
+        i += 2;
+    }
+    let Some(socket) = socket.or_else(paths::broker_socket) else {
+        eprintln!("No home directory to put the socket in; pass --socket");
+        return 2;
+    };
+    let keys = match keys_file.as_deref().map(read_keys).transpose() {
+        Ok(keys) => keys.unwrap_or_default(),
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    serve(socket, rpm, max_in_flight, keys)
+}
+
+/// One key per line; blank lines and lines starting with # don't count.
+fn read_keys(path: &str) -> Result<Vec<String>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| tr!("cli.read-failed", file = path, error = e))?;
+    let keys: Vec<String> = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    if keys.is_empty() {
+        return Err(format!("{} has no keys in it", path));
+    }
+    Ok(keys)
+}
+
+#[cfg(not(unix))]
+fn serve(_socket: PathBuf, _rpm: Option<usize>, _max_in_flight: Option<usize>, _keys: Vec<String>) -> i32 {
+    eprintln!("The broker talks over a Unix socket, which this platform doesn't have");
+    1
+}
+
+#[cfg(unix)]
+fn serve(socket: PathBuf, rpm: Option<usize>, max_in_flight: Option<usize>, keys: Vec<String>) -> i32 {
+    if UnixStream::connect(&socket).is_ok() {
+        eprintln!("A broker is running on {} already", socket.display());
+        return 1;
+    }
+    // Left over from a broker that didn't get to clean up
+    let _ = fs::remove_file(&socket);
+    if let Some(dir) = socket.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    let listener = match UnixListener::bind(&socket) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Cannot listen on {}: {}", socket.display(), e);
+            return 1;
+        }
+    };
+    let limits = [
+        rpm.map(|rpm| format!("{} call(s) a minute", rpm)),
+        max_in_flight.map(|max| format!("{} at once", max)),
+        (!keys.is_empty()).then(|| format!("{} pooled key(s)", keys.len())),
+    ];
+    let limits: Vec<String> = limits.into_iter().flatten().collect();
+    eprintln!("Broker on {}{}", socket.display(), if limits.is_empty() { String::new() } else { format!(": {}", limits.join(", ")) });
+    eprintln!("Programs compiled with --broker take their turns here");
+    if paths::broker_socket().as_ref() != Some(&socket) {
+        eprintln!("  export MATTHIASHIHIC_BROKER={}", socket.display());
+    }
+    let keys = keys.into_iter().map(|key| Key { key, in_flight: 0, last_turn: None, resting_until: None }).collect();
+    let broker: &'static Broker = Box::leak(Box::new(Broker {
+        rpm,
+        max_in_flight,
+        state: Mutex::new(State { keys, ..State::default() }),
+        changed: Condvar::new(),
+    }));
+    for stream in listener.incoming().flatten() {
+        std::thread::spawn(move || handle(stream, broker));
+    }
+    0
+}
+
+#[cfg(unix)]
+fn handle(stream: UnixStream, broker: &Broker) {
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    if reader.read_line(&mut line).is_err() {
+        return;
+    }
+    let Some(name) = line.trim().strip_prefix("call ").map(str::trim).filter(|name| !name.is_empty()) else {
+        let _ = (&stream).write_all(b"error expected: call <name> <pid>\n");
+        return;
+    };
+    let start = Instant::now();
+    let mut turn = broker.take_turn(name);
+    let key = turn.key.map(|key| broker.lock().keys[key].key.clone());
+    if (&stream).write_all(format!("go {}\n", key.as_deref().unwrap_or("-")).as_bytes()).is_err() {
+        // Gave up waiting
+        return;
+    }
+    let waited = start.elapsed();
+    line.clear();
+    if reader.read_line(&mut line).is_ok() {
+        turn.status = line.trim().strip_prefix("done ").map(str::to_string);
+    }
+    eprintln!(
+        "{} {} {} waited {}ms, took {}ms",
+        turn.name,
+        turn.key.map(|key| format!("key {}", key + 1)).unwrap_or_else(|| "own key".into()),
+        turn.status.as_deref().unwrap_or("hung up"),
+        waited.as_millis(),
+        (start.elapsed() - waited).as_millis()
+    );
+}
@@ -0,0 +1,106 @@
+//! Builtin statements: trivially computable transforms that the compiled
+//! program does itself instead of paying the model to do them.
+//!
+//! The planner routes a recognized statement to a local `builtin:<op>` step,
+//! which the runtime's `run_builtin` executes.
+
+use crate::ast::{Segment, Statement};
+
+/// Recognized statement shapes, as words with `€` for a placeholder and `N`
+/// for a whole number, and the operation they lower to.
+const PATTERNS: &[(&str, &str)] = &[
+    ("uppercase €", "uppercase"),
+    ("lowercase €", "lowercase"),
+    ("trim €", "trim"),
+    ("reverse €", "reverse"),
+    ("count lines of €", "count_lines"),
+    ("count words of €", "count_words"),
+    ("count characters of €", "count_characters"),
+    ("length of €", "count_characters"),
+    ("today", "today"),
+    ("today plus N days", "today_plus"),
+    ("today minus N days", "today_minus"),
+    ("€ plus N days", "date_plus"),
+    ("€ minus N days", "date_minus"),
+    ("days between € and €", "days_between"),
+];
+
+enum Token {
+    Word(String),
+    Arg(Segment),
+}
+
+/// The builtin operation and its arguments, if the statement is one.
+pub fn recognize(statement: &Statement) -> Option<(&'static str, Vec<Vec<Segment>>)> {
+    let tokens = tokenize(&statement.segments)?;
+    'patterns: for (pattern, op) in PATTERNS {
+        let words: Vec<&str> = pattern.split(' ').collect();
+        if words.len() != tokens.len() {
+            continue;
+        }
+        let mut args = Vec::new();
+        for (word, token) in words.iter().zip(&tokens) {
+            match (*word, token) {
+                ("€", Token::Arg(segment)) => args.push(vec![segment.clone()]),
+                ("N", Token::Word(number)) if number.parse::<u32>().is_ok() => {
+                    args.push(vec![Segment::Text(number.clone())])
+                }
+                (expected, Token::Word(actual)) if expected == actual => {}
+                _ => continue 'patterns,
+            }
+        }
+        return Some((op, args));
+    }
+    None
+}
+
+/// Splits a statement into lowercase words and placeholders (`€n`, `€*`, `€name`); a trailing `.` or
+/// `!` is ignored. Statements with secrets or calls are never builtins.
+fn tokenize(segments: &[Segment]) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    for (i, segment) in segments.iter().enumerate() {
+        match segment {
+            Segment::Text(text) => {
+                let text = if i + 1 == segments.len() {
+                    text.trim_end().trim_end_matches(['.', '!'])
+                } else {
+                    text
+                };
+                tokens.extend(text.split_whitespace().map(|word| Token::Word(word.to_lowercase())));
+            }
+            Segment::Placeholder { .. } | Segment::AllArgs | Segment::Named(_) | Segment::Var(_) | Segment::Prev => tokens.push(Token::Arg(segment.clone())),
+            _ => return None,
+        }
+    }
+    Some(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::recognize;
+    use crate::ast::Segment;
+    use crate::parser::parse_matthiashihic;
+
+    /// The builtin the only statement of `source` lowers to.
+    fn builtin(statement: &str) -> Option<(&'static str, Vec<Vec<Segment>>)> {
+        let source = format!("hihi!\n\"{}\"\neat that java!\n", statement);
+        let (program, _) = parse_matthiashihic(&source).unwrap_or_else(|e| panic!("doesn't parse: {}\n{}", e, source));
+        recognize(&program.statements[0])
+    }
+
+    #[test]
+    fn all_args() {
+        assert_eq!(builtin("count lines of €*"), Some(("count_lines", vec![vec![Segment::AllArgs]])));
+        assert_eq!(builtin("Uppercase €*."), Some(("uppercase", vec![vec![Segment::AllArgs]])));
+        // Escaped, it's just text
+        assert_eq!(builtin("count lines of €€*"), None);
+    }
+
+    #[test]
+    fn placeholders_and_numbers() {
+        let arg = Segment::Placeholder { index: 1, ty: None, default: None };
+        assert_eq!(builtin("uppercase €1"), Some(("uppercase", vec![vec![arg.clone()]])));
+        assert_eq!(builtin("€1 plus 3 days"), Some(("date_plus", vec![vec![arg], vec![Segment::Text("3".into())]])));
+        assert_eq!(builtin("uppercase €1, politely"), None);
+    }
+}
@@ -0,0 +1,67 @@
+//! `--serve-keys keys.csv` and `--serve-auth <command>`: who may call a
+//! `--serve` program, and whose API key pays for their answers, so the
+//! provider's usage page says which consumer spent what.
+//!
+//! Each row of the file is `name,token` or `name,token,KEY_VAR`: callers send
+//! the token as `Authorization: Bearer <token>`, and their answers are paid
+//! with the key in the environment variable KEY_VAR, or the program's own key
+//! without one. The tokens are compiled in, masked like `--api-key`.
+
+use std::fs;
+
+use crate::glossary::split_row;
+use crate::i18n::tr;
+
+/// One row of the file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Caller {
+    pub name: String,
+    pub token: String,
+    /// The environment variable with the key their answers are paid with.
+    pub key_var: Option<String>,
+}
+
+/// Reads a key map. Fields may be quoted like in any other CSV; blank lines
+/// and lines starting with `#` are skipped.
+pub fn read(path: &str) -> Result<Vec<Caller>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| tr!("cli.read-failed", file = path, error = e))?;
+    let mut callers: Vec<Caller> = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let at = |message: String| format!("{}:{}: {}", path, idx + 1, message);
+        let fields = split_row(line).map_err(at)?;
+        let (name, token, key_var) = match fields.as_slice() {
+            [name, token] => (name, token, None),
+            [name, token, key_var] => (name, token, Some(key_var.clone())),
+            _ => return Err(at(format!("expected 'name,token' or 'name,token,KEY_VAR', got {} columns", fields.len()))),
+        };
+        if name.is_empty() {
+            return Err(at("empty name".into()));
+        }
+        if token.is_empty() || token.chars().any(char::is_whitespace) {
+            return Err(at(format!("{}'s token has to be there, without spaces", name)));
+        }
+        if let Some(var) = key_var.as_deref().filter(|var| !is_env_name(var)) {
+            return Err(at(format!("'{}' isn't an environment variable name; use letters, digits and _", var)));
+        }
+        if callers.iter().any(|caller| caller.name == *name) {
+            return Err(at(format!("'{}' is in the file twice", name)));
+        }
+        if callers.iter().any(|caller| caller.token == *token) {
+            return Err(at(format!("{} has the same token as someone above", name)));
+        }
+        callers.push(Caller { name: name.clone(), token: token.clone(), key_var });
+    }
+    if callers.is_empty() {
+        return Err(format!("{} has no callers in it", path));
+    }
+    Ok(callers)
+}
+
+/// Letters, digits and _, not starting with a digit.
+fn is_env_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
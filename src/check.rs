@@ -0,0 +1,96 @@
+//! `matthiashihic check [--fix] <source>...` parses programs without building
+//! them, showing each error with its source line and, where there's an obvious
+//! one, the fix. `--fix` writes those fixes back.
+
+use std::fs;
+use std::path::Path;
+
+use crate::diagnostics::{self, LintLevels, ParseError};
+use crate::i18n::tr;
+use crate::parser::{parse_file, parse_matthiashihic};
+
+/// More fixes than any file needs; only a guard against a fix that doesn't help.
+const MAX_FIXES: usize = 20;
+
+/// Runs the subcommand and returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let mut fix = false;
+    let mut files = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--fix" => fix = true,
+            s if s.starts_with('-') => {
+                eprintln!("{}", tr!("check.unknown-flag", flag = s));
+                return 2;
+            }
+            s => files.push(s),
+        }
+    }
+    if files.is_empty() {
+        eprintln!("{}", tr!("check.usage"));
+        return 2;
+    }
+
+    let mut status = 0;
+    for file in files {
+        let mut contents = match fs::read_to_string(file) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("{}", tr!("cli.read-failed", file = file, error = e));
+                status = 1;
+                continue;
+            }
+        };
+        if fix {
+            let (fixed, applied) = apply_fixes(&contents, Some(Path::new(file)));
+            if !applied.is_empty() {
+                if let Err(e) = fs::write(file, &fixed) {
+                    eprintln!("{}", tr!("cli.write-failed", file = file, error = e));
+                    status = 1;
+                    continue;
+                }
+                for help in applied {
+                    println!("{}", tr!("check.fixed", file = file, help = help));
+                }
+                contents = fixed;
+            }
+        }
+        match parse_file(&contents, Path::new(file)) {
+            Ok((_, warnings)) => {
+                if diagnostics::report(&warnings, &LintLevels::default()) > 0 {
+                    status = 1;
+                }
+            }
+            Err(e) => {
+                eprint!("{}", diagnostics::render_parse_error(file, &contents, &e));
+                if e.fix.is_some() && !fix {
+                    eprintln!("{}", tr!("cli.run-fix", command = "matthiashihic", file = file));
+                }
+                status = 1;
+            }
+        }
+    }
+    status
+}
+
+/// Applies parse error fixes until the source parses or there is no obvious fix
+/// left, and returns it with the `help` of every fix applied. `path` is where
+/// the source came from, for its `nom nom` lines.
+pub fn apply_fixes(source: &str, path: Option<&Path>) -> (String, Vec<String>) {
+    let mut source = source.to_string();
+    let mut applied = Vec::new();
+    for _ in 0..MAX_FIXES {
+        let parsed = match path {
+            Some(path) => parse_file(&source, path),
+            None => parse_matthiashihic(&source),
+        };
+        match parsed {
+            Err(ParseError { fix: Some(fix), .. }) => {
+                source = fix.apply(&source);
+                applied.push(fix.help);
+            }
+            _ => break,
+        }
+    }
+    (source, applied)
+}
@@ -0,0 +1,249 @@
+//! `matthiashihic clean [--cache] [--temp] [--history] [--older-than <time>] [--dry-run]`
+//! reports what matthiashihic keeps on disk and removes it: the cache
+//! directory, Cargo projects that builds left in the temp directory, and the
+//! run history (see `paths`). Without a selection it cleans all three.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::front_matter::parse_json_object;
+use crate::i18n::tr;
+use crate::load::parse_duration;
+use crate::paths;
+
+/// Temp projects touched more recently than this may belong to a build that's
+/// still running, and are left alone.
+const BUILD_GRACE: Duration = Duration::from_secs(60 * 60);
+
+/// What one area had and what went.
+#[derive(Default)]
+struct Cleaned {
+    removed: usize,
+    kept: usize,
+    bytes: u64,
+}
+
+/// Runs the subcommand and returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let (mut cache, mut temp, mut history) = (false, false, false);
+    let mut older_than = None;
+    let mut dry_run = false;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--cache" => cache = true,
+            "--temp" => temp = true,
+            "--history" => history = true,
+            "--dry-run" => dry_run = true,
+            "--older-than" => match args.get(i + 1).and_then(|v| parse_duration(v)) {
+                Some(age) => {
+                    older_than = Some(age);
+                    i += 1;
+                }
+                None => {
+                    eprintln!("--older-than needs an age like 30d, 12h or 90m");
+                    return 2;
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", tr!("clean.usage"));
+                return 0;
+            }
+            flag => {
+                eprintln!("{}", tr!("clean.unknown-flag", flag = flag));
+                return 2;
+            }
+        }
+        i += 1;
+    }
+    if !cache && !temp && !history {
+        (cache, temp, history) = (true, true, true);
+    }
+    let now = SystemTime::now();
+    let cutoff = older_than.map(|age| now.checked_sub(age).unwrap_or(UNIX_EPOCH));
+    let verb = if dry_run { "would remove" } else { "removed" };
+
+    let mut status = 0;
+    let mut reclaimed = 0;
+    let mut report = |area: &str, location: Option<&Path>, result: io::Result<Cleaned>, unit: &str| {
+        let location = location.map(|path| path.display().to_string()).unwrap_or_else(|| "-".into());
+        match result {
+            Ok(cleaned) => {
+                reclaimed += cleaned.bytes;
+                println!(
+                    "{:<8} {}: {} {} {} ({}), kept {}",
+                    area,
+                    location,
+                    verb,
+                    cleaned.removed,
+                    unit,
+                    format_bytes(cleaned.bytes),
+                    cleaned.kept
+                );
+            }
+            Err(e) => {
+                eprintln!("{:<8} {}: {}", area, location, e);
+                status = 1;
+            }
+        }
+    };
+    if cache {
+        let dir = paths::cache_dir();
+        let result = match &dir {
+            Some(dir) => clean_dir(dir, cutoff, dry_run),
+            None => Err(no_home()),
+        };
+        report("cache", dir.as_deref(), result, "file(s)");
+    }
+    if temp {
+        let dir = paths::temp_dir();
+        let result = clean_temp(&dir, now, cutoff, dry_run);
+        report("temp", Some(&dir), result, "project(s)");
+    }
+    if history {
+        let file = paths::history_file();
+        let result = match &file {
+            Some(file) => clean_history(file, cutoff, dry_run),
+            None => Err(no_home()),
+        };
+        report("history", file.as_deref(), result, "run(s)");
+    }
+    println!("{} {}", if dry_run { "Would reclaim" } else { "Reclaimed" }, format_bytes(reclaimed));
+    status
+}
+
+fn no_home() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "no home directory to look in")
+}
+
+/// Removes the files in `dir` last modified before `cutoff` (all of them
+/// without one), and the directories that leaves empty.
+fn clean_dir(dir: &Path, cutoff: Option<SystemTime>, dry_run: bool) -> io::Result<Cleaned> {
+    let mut cleaned = Cleaned::default();
+    match clean_files(dir, cutoff, dry_run, &mut cleaned) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(cleaned),
+        result => result.map(|_| cleaned),
+    }
+}
+
+fn clean_files(dir: &Path, cutoff: Option<SystemTime>, dry_run: bool, cleaned: &mut Cleaned) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            clean_files(&path, cutoff, dry_run, cleaned)?;
+            if !dry_run && fs::read_dir(&path)?.next().is_none() {
+                fs::remove_dir(&path)?;
+            }
+        } else if cutoff.is_none_or(|cutoff| metadata.modified().is_ok_and(|modified| modified < cutoff)) {
+            if !dry_run {
+                fs::remove_file(&path)?;
+            }
+            cleaned.removed += 1;
+            cleaned.bytes += metadata.len();
+        } else {
+            cleaned.kept += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the `matthiashihic-*` Cargo projects in the temp directory, except
+/// those a build may still be using.
+fn clean_temp(dir: &Path, now: SystemTime, cutoff: Option<SystemTime>, dry_run: bool) -> io::Result<Cleaned> {
+    let mut cleaned = Cleaned::default();
+    let busy = now.checked_sub(BUILD_GRACE).unwrap_or(UNIX_EPOCH);
+    let cutoff = cutoff.map_or(busy, |cutoff| cutoff.min(busy));
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(cleaned),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().starts_with("matthiashihic-") || !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let (bytes, newest) = usage(&entry.path())?;
+        if newest >= cutoff {
+            cleaned.kept += 1;
+            continue;
+        }
+        if !dry_run {
+            fs::remove_dir_all(entry.path())?;
+        }
+        cleaned.removed += 1;
+        cleaned.bytes += bytes;
+    }
+    Ok(cleaned)
+}
+
+/// Total size of the files below `dir` and when the newest was modified.
+fn usage(dir: &Path) -> io::Result<(u64, SystemTime)> {
+    let mut bytes = 0;
+    let mut newest = fs::metadata(dir)?.modified()?;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let (size, modified) = if metadata.is_dir() { usage(&entry.path())? } else { (metadata.len(), metadata.modified()?) };
+        bytes += size;
+        newest = newest.max(modified);
+    }
+    Ok((bytes, newest))
+}
+
+/// Removes the history, or only the runs recorded before `cutoff`. Lines
+/// without a time are kept.
+fn clean_history(file: &Path, cutoff: Option<SystemTime>, dry_run: bool) -> io::Result<Cleaned> {
+    let mut cleaned = Cleaned::default();
+    let contents = match fs::read_to_string(file) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(cleaned),
+        Err(e) => return Err(e),
+    };
+    let Some(cutoff) = cutoff else {
+        if !dry_run {
+            fs::remove_file(file)?;
+        }
+        cleaned.removed = contents.lines().filter(|line| !line.trim().is_empty()).count();
+        cleaned.bytes = contents.len() as u64;
+        return Ok(cleaned);
+    };
+    let cutoff = cutoff.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut kept = String::new();
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let time = parse_json_object(line)
+            .ok()
+            .and_then(|record| record.into_iter().find(|(key, _)| key == "time"))
+            .and_then(|(_, time)| time.parse::<u64>().ok());
+        if time.is_some_and(|time| time < cutoff) {
+            cleaned.removed += 1;
+        } else {
+            cleaned.kept += 1;
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+    cleaned.bytes = (contents.len() - kept.len().min(contents.len())) as u64;
+    if !dry_run && cleaned.removed > 0 {
+        fs::write(file, kept)?;
+    }
+    Ok(cleaned)
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
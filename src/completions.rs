@@ -0,0 +1,138 @@
+//! Shell completions for a compiled program, which the packages of `--emit
+//! deb`, `rpm` and `brew-formula` install: bash, zsh and fish, made from the
+//! flags its man page lists.
+
+use crate::ast::Program;
+use crate::manpage;
+use crate::CompileOptions;
+
+/// A flag as the completions know it.
+struct Flag {
+    /// Every name it goes by, like `-h` and `--help`.
+    names: Vec<String>,
+    /// Its description's first sentence.
+    description: String,
+    value: Value,
+}
+
+/// What comes after a flag.
+enum Value {
+    None,
+    /// Something we can't guess, like an address.
+    Any,
+    File,
+    OneOf(Vec<String>),
+}
+
+/// The completions of `command`, as (bash, zsh, fish) scripts.
+pub fn scripts(program: &Program, opts: &CompileOptions, command: &str) -> (String, String, String) {
+    let mut terms = manpage::flags(program, opts);
+    // A socket's clients send the €{name}s; everywhere else they're flags
+    if opts.listen_socket.is_none() {
+        for name in &program.named_args {
+            terms.push((format!("--{} <{}>", name, name), format!("The value of €{{{}}}.", name)));
+        }
+    }
+    let flags: Vec<Flag> = terms.iter().map(|(term, description)| parse_term(term, description)).collect();
+    (bash(&flags, command), zsh(&flags, command, opts.audio_input), fish(&flags, command))
+}
+
+/// Splits a man page term like `-h, --help` or `--arm <A|B>` into its names
+/// and what it takes.
+fn parse_term(term: &str, description: &str) -> Flag {
+    let (names, value) = match term.rfind(", ") {
+        Some(comma) => match term[comma + 2..].split_once(' ') {
+            Some((last, value)) => (format!("{}, {}", &term[..comma], last), Some(value)),
+            None => (term.to_string(), None),
+        },
+        None => match term.split_once(' ') {
+            Some((name, value)) => (name.to_string(), Some(value)),
+            None => (term.to_string(), None),
+        },
+    };
+    let value = match value.map(|value| value.strip_prefix('<').and_then(|value| value.strip_suffix('>')).ok_or(value)) {
+        None => Value::None,
+        Some(Ok("file" | "path")) => Value::File,
+        Some(Ok(value)) if value.contains('|') => Value::OneOf(value.split('|').map(str::to_string).collect()),
+        Some(Ok(_)) => Value::Any,
+        Some(Err(literal)) => Value::OneOf(vec![literal.to_string()]),
+    };
+    let description = description.split(". ").next().unwrap_or_default().trim_end_matches('.').to_string();
+    Flag { names: names.split(", ").map(str::to_string).collect(), description, value }
+}
+
+fn bash(flags: &[Flag], command: &str) -> String {
+    let function = format!("_{}", command.replace(|c: char| !c.is_ascii_alphanumeric(), "_"));
+    let mut out = format!("# bash completion for {}, generated by matthiashihic\n\n{}() {{\n", command, function);
+    out.push_str("    local cur=\"${COMP_WORDS[COMP_CWORD]}\" prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n");
+    out.push_str("    case \"$prev\" in\n");
+    for flag in flags {
+        let reply = match &flag.value {
+            Value::None => continue,
+            Value::Any => "return ;;".to_string(),
+            Value::File => "COMPREPLY=($(compgen -f -- \"$cur\")); return ;;".to_string(),
+            Value::OneOf(values) => format!("COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return ;;", values.join(" ")),
+        };
+        out.push_str(&format!("        {}) {}\n", flag.names.join("|"), reply));
+    }
+    out.push_str("    esac\n");
+    let names: Vec<&str> = flags.iter().flat_map(|flag| &flag.names).map(String::as_str).collect();
+    out.push_str(&format!("    COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n}}\n", names.join(" ")));
+    out.push_str(&format!("complete -o default -F {} {}\n", function, command));
+    out
+}
+
+fn zsh(flags: &[Flag], command: &str, audio_input: bool) -> String {
+    let mut specs = Vec::new();
+    for flag in flags {
+        let description = flag.description.replace('\\', "\\\\").replace('[', "\\[").replace(']', "\\]").replace(':', "\\:");
+        let value = match &flag.value {
+            Value::None => String::new(),
+            Value::Any => ":value:".to_string(),
+            Value::File => ":file:_files".to_string(),
+            Value::OneOf(values) => format!(":value:({})", values.join(" ")),
+        };
+        specs.push(match flag.names.as_slice() {
+            [name] => shell_quote(&format!("{}[{}]{}", name, description, value)),
+            // Either name, but not both
+            names => format!(
+                "'({})'{{{}}}{}",
+                names.join(" "),
+                names.join(","),
+                shell_quote(&format!("[{}]{}", description, value))
+            ),
+        });
+    }
+    if audio_input {
+        specs.push(shell_quote("1:audio file:_files"));
+    }
+    format!("#compdef {}\n# generated by matthiashihic\n\n_arguments \\\n  {}\n", command, specs.join(" \\\n  "))
+}
+
+fn fish(flags: &[Flag], command: &str) -> String {
+    let mut out = format!("# fish completion for {}, generated by matthiashihic\n\n", command);
+    for flag in flags {
+        let mut line = format!("complete -c {}", command);
+        for name in &flag.names {
+            match name.strip_prefix("--") {
+                Some(long) => line.push_str(&format!(" -l {}", long)),
+                None => line.push_str(&format!(" -s {}", name.trim_start_matches('-'))),
+            }
+        }
+        match &flag.value {
+            Value::None => {}
+            Value::Any => line.push_str(" -x"),
+            Value::File => line.push_str(" -r -F"),
+            Value::OneOf(values) => line.push_str(&format!(" -x -a {}", shell_quote(&values.join(" ")))),
+        }
+        line.push_str(&format!(" -d {}\n", shell_quote(&flag.description)));
+        out.push_str(&line);
+    }
+    out
+}
+
+/// Single quotes for all three shells; a quote inside ends them, adds an
+/// escaped one and starts them again.
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
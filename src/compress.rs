@@ -0,0 +1,132 @@
+//! `--compress <ratio>`: a compile-time pass that shrinks long statements before
+//! they are embedded, in the spirit of LLMLingua but without a model of its own.
+//!
+//! Runs of whitespace and repeated sentences always go. After that, words are
+//! dropped in rounds, cheapest loss first, until the estimated token count
+//! reaches `ratio` of the original: filler words, then articles, then short
+//! function words. Placeholders are never touched, and statements shorter than
+//! [`MIN_TOKENS`] are left alone.
+
+use crate::ast::{Program, Segment};
+
+/// Statements below this many (estimated) tokens aren't worth compressing.
+const MIN_TOKENS: usize = 32;
+
+/// Words dropped per round, from "nobody will miss it" to "grammar suffers".
+const DROP_ROUNDS: &[&[&str]] = &[
+    &["please", "kindly", "basically", "actually", "really", "very", "just", "quite", "simply", "literally", "totally"],
+    &["the", "a", "an"],
+    &["that", "which", "is", "are", "was", "were", "be", "been", "of", "to", "and"],
+];
+
+/// Rough token count: about four characters per token, at least one per word.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.split_whitespace()
+        .map(|word| word.chars().count().div_ceil(4).max(1))
+        .sum()
+}
+
+/// Compresses every long statement towards `ratio` (0 < ratio <= 1) of its
+/// estimated tokens. Returns the program's estimated tokens before and after.
+pub fn compress(program: &mut Program, ratio: f64) -> (usize, usize) {
+    let mut before = 0;
+    let mut after = 0;
+    let variant = program.experiment.as_mut().map(|experiment| &mut experiment.b);
+    for statement in program.statements.iter_mut().chain(variant) {
+        let tokens = text_tokens(&statement.segments);
+        before += tokens;
+        if tokens >= MIN_TOKENS {
+            let target = (tokens as f64 * ratio).ceil() as usize;
+            for_each_text(&mut statement.segments, collapse_whitespace);
+            for_each_text(&mut statement.segments, drop_repeated_sentences);
+            for words in DROP_ROUNDS {
+                if text_tokens(&statement.segments) <= target {
+                    break;
+                }
+                for_each_text(&mut statement.segments, |text| drop_words(text, words));
+            }
+            statement
+                .segments
+                .retain(|segment| !matches!(segment, Segment::Text(text) if text.is_empty()));
+        }
+        after += text_tokens(&statement.segments);
+    }
+    (before, after)
+}
+
+fn text_tokens(segments: &[Segment]) -> usize {
+    segments
+        .iter()
+        .map(|segment| match segment {
+            Segment::Text(text) | Segment::Const { value: text, .. } => estimate_tokens(text),
+            // A substituted value costs something, but not what we can save
+            _ => 1,
+        })
+        .sum()
+}
+
+fn for_each_text(segments: &mut [Segment], f: impl Fn(&str) -> String) {
+    for segment in segments {
+        if let Segment::Text(text) = segment {
+            *text = f(text);
+        }
+    }
+}
+
+/// Collapses runs of spaces and tabs into one space; line breaks are kept.
+fn collapse_whitespace(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut in_run = false;
+    for ch in text.chars() {
+        if ch == ' ' || ch == '\t' {
+            if !in_run {
+                out.push(' ');
+            }
+            in_run = true;
+        } else {
+            out.push(ch);
+            in_run = false;
+        }
+    }
+    out
+}
+
+/// Drops sentences that already appeared earlier in the same text.
+fn drop_repeated_sentences(text: &str) -> String {
+    let mut seen = Vec::<String>::new();
+    let mut out = String::with_capacity(text.len());
+    for sentence in text.split_inclusive(['.', '!', '?']) {
+        let key = sentence.trim().to_lowercase();
+        if key.len() > 1 && seen.contains(&key) {
+            continue;
+        }
+        seen.push(key);
+        out.push_str(sentence);
+    }
+    out
+}
+
+/// Removes the given words (case-insensitively) where they stand alone, i.e.
+/// without punctuation attached, together with the space after them.
+fn drop_words(text: &str, words: &[&str]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        let word_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let word = &rest[..word_end];
+        let space_end = rest[word_end..]
+            .find(|c: char| !c.is_whitespace())
+            .map(|i| word_end + i)
+            .unwrap_or(rest.len());
+        let droppable = words.iter().any(|w| w.eq_ignore_ascii_case(word));
+        // Keep the last word's trailing whitespace; a placeholder may follow
+        if droppable && space_end > word_end {
+            out.extend(rest[word_end..space_end].chars().filter(|&c| c == '\n'));
+            rest = &rest[space_end..];
+            continue;
+        }
+        out.push_str(&rest[..space_end]);
+        rest = &rest[space_end..];
+    }
+    out
+}
@@ -0,0 +1,225 @@
+//! Compiler diagnostics: warnings that don't stop compilation, and the lint
+//! levels (`-A`, `-W`, `-D`) that decide how each warning is reported.
+
+use crate::i18n::tr;
+
+/// Every lint the compiler knows, with a short description for `--help`.
+pub const LINTS: &[(&str, &str)] = &[
+    ("suspicious-escape", "unknown backslash escape that is taken literally"),
+    ("empty-statement", "statement with no text"),
+    ("placeholder-gap", "stdin line that is required but never used by any placeholder"),
+    ("no-statements", "program without any statements"),
+    ("unbound-variable", "€name that no earlier statement binds with -> name, sent as written"),
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+/// A warning raised while compiling, before lint levels are applied.
+pub struct Diagnostic {
+    pub lint: &'static str,
+    /// 1-based source line, if the warning points at one.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(lint: &'static str, line: Option<usize>, message: impl Into<String>) -> Diagnostic {
+        debug_assert!(LINTS.iter().any(|(name, _)| *name == lint), "unregistered lint {}", lint);
+        Diagnostic {
+            lint,
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+/// Lint levels from the command line. Later flags override earlier ones, like rustc.
+#[derive(Default)]
+pub struct LintLevels {
+    overrides: Vec<(String, Level)>,
+}
+
+impl LintLevels {
+    /// Records `-A/-W/-D <name>`, where name is a lint or `warnings` for all of them.
+    pub fn set(&mut self, name: &str, level: Level) -> Result<(), String> {
+        if name != "warnings" && !LINTS.iter().any(|(lint, _)| *lint == name) {
+            let known = LINTS.iter().map(|(lint, _)| *lint).collect::<Vec<_>>().join(", ");
+            return Err(format!("Unknown lint '{}' (known: warnings, {})", name, known));
+        }
+        self.overrides.push((name.to_string(), level));
+        Ok(())
+    }
+
+    pub fn level(&self, lint: &str) -> Level {
+        self.overrides
+            .iter()
+            .rev()
+            .find(|(name, _)| name == lint || name == "warnings")
+            .map(|(_, level)| *level)
+            .unwrap_or(Level::Warn)
+    }
+}
+
+/// Prints the diagnostics to stderr according to `levels` and returns how many
+/// were denied (and therefore have to fail the build).
+pub fn report(diagnostics: &[Diagnostic], levels: &LintLevels) -> usize {
+    let mut denied = 0;
+    for diagnostic in diagnostics {
+        let label = match levels.level(diagnostic.lint) {
+            Level::Allow => continue,
+            Level::Warn => tr!("label.warning"),
+            Level::Deny => {
+                denied += 1;
+                tr!("label.error")
+            }
+        };
+        match diagnostic.line {
+            Some(line) => eprintln!("{}[{}]: {} {}", label, diagnostic.lint, diagnostic.message, tr!("diag.at-line", line = line)),
+            None => eprintln!("{}[{}]: {}", label, diagnostic.lint, diagnostic.message),
+        }
+    }
+    denied
+}
+
+/// A mechanical fix for a parse error: replace `start..end` (byte offsets into
+/// the source as read, byte order mark included) with `replacement`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fix {
+    /// What the fix does, shown as `help:`.
+    pub help: String,
+    pub start: usize,
+    pub end: usize,
+    pub replacement: String,
+}
+
+impl Fix {
+    pub fn insert(help: impl Into<String>, at: usize, text: impl Into<String>) -> Fix {
+        Fix { help: help.into(), start: at, end: at, replacement: text.into() }
+    }
+
+    pub fn apply(&self, source: &str) -> String {
+        format!("{}{}{}", &source[..self.start], self.replacement, &source[self.end..])
+    }
+}
+
+/// An error that stops parsing, with the line it's about and a fix if there is
+/// an obvious one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    /// 1-based source line.
+    pub line: Option<usize>,
+    pub fix: Option<Fix>,
+    /// The included file the error is in, and its contents, when it isn't in
+    /// the one being parsed. `line` counts in that file.
+    pub file: Option<Box<(String, String)>>,
+}
+
+impl ParseError {
+    pub fn at(mut self, line: usize) -> ParseError {
+        self.line = Some(line);
+        self
+    }
+
+    pub fn with_fix(mut self, fix: Fix) -> ParseError {
+        self.fix = Some(fix);
+        self
+    }
+
+    /// The error as found in the included `file`. Its fix, if any, would go
+    /// there, not into the file being parsed, so it's dropped.
+    pub fn in_file(mut self, file: String, contents: String) -> ParseError {
+        self.fix = None;
+        self.file.get_or_insert_with(|| Box::new((file, contents)));
+        self
+    }
+}
+
+impl From<String> for ParseError {
+    fn from(message: String) -> ParseError {
+        ParseError { message, line: None, fix: None, file: None }
+    }
+}
+
+impl From<&str> for ParseError {
+    fn from(message: &str) -> ParseError {
+        message.to_string().into()
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Whether stderr gets ANSI colors: only on a terminal, and never with NO_COLOR set.
+fn use_color() -> bool {
+    use std::io::IsTerminal;
+    std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+}
+
+/// `text` in the given SGR color (e.g. "1;31" for bold red) if colors are on.
+fn paint(text: &str, color: &str, colored: bool) -> String {
+    if colored {
+        format!("\x1b[{}m{}\x1b[0m", color, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// A parse error the way rustc would show it: the message, the source line it's
+/// about with a caret where the fix goes, and the fix as `help:`. Errors in an
+/// included file show that file instead of `file`.
+pub fn render_parse_error(file: &str, source: &str, error: &ParseError) -> String {
+    let (file, source) = match error.file.as_deref() {
+        Some((included, contents)) => (included.as_str(), contents.as_str()),
+        None => (file, source),
+    };
+    let colored = use_color();
+    let mut out = format!("{}: {}\n", paint(&tr!("label.error"), "1;31", colored), paint(&error.message, "1", colored));
+    let Some(line) = error.line else {
+        out.push_str(&format!("  --> {}\n", file));
+        if let Some(fix) = &error.fix {
+            out.push_str(&format!("  = {}: {}\n", paint(&tr!("label.help"), "1;36", colored), fix.help));
+        }
+        return out;
+    };
+    let gutter = " ".repeat(line.to_string().len());
+    let bar = paint("|", "1;34", colored);
+    out.push_str(&format!("{}{} {}:{}\n", gutter, paint("-->", "1;34", colored), file, line));
+    out.push_str(&format!("{} {}\n", gutter, bar));
+    // Lines are counted the way the parser counts them
+    let text = source.lines().nth(line - 1).unwrap_or("");
+    out.push_str(&format!("{} {} {}\n", paint(&line.to_string(), "1;34", colored), bar, text));
+    match &error.fix {
+        Some(fix) => {
+            let line_start = line_offset(source, line);
+            let column = match fix.start.checked_sub(line_start) {
+                Some(column) if column <= text.len() => text[..column].chars().count(),
+                _ => text.chars().count(),
+            };
+            let marker = format!("{}^ {}", " ".repeat(column), fix.help);
+            out.push_str(&format!("{} {} {}\n", gutter, bar, paint(&marker, "1;36", colored)));
+        }
+        None => out.push_str(&format!("{} {}\n", gutter, bar)),
+    }
+    out
+}
+
+/// Byte offset where the 1-based `line` starts; the end of the source if it has fewer lines.
+pub fn line_offset(source: &str, line: usize) -> usize {
+    if line <= 1 {
+        return 0;
+    }
+    source
+        .match_indices('\n')
+        .nth(line - 2)
+        .map(|(pos, _)| pos + 1)
+        .unwrap_or(source.len())
+}
@@ -0,0 +1,345 @@
+//! `matthiashihic explain <source> [<compile flag>...]`: what a program does,
+//! for whoever has to look after it without reading every prompt. It takes
+//! the same flags as a compile, since half of what a program does is in
+//! them, and prints where its inputs come from, what runs where and how, and
+//! roughly what a run costs.
+
+use crate::ast::{self, ArgType, Program, Segment, Statement};
+use crate::compress::estimate_tokens;
+use crate::planner::{self, Route};
+use crate::{CompileOptions, StdinFormat};
+
+/// USD per million input and output tokens, by model name prefix. The
+/// longest prefix that matches wins; a model that matches none has no price.
+const PRICES: &[(&str, f64, f64)] = &[
+    ("gpt-3.5-turbo", 0.50, 1.50),
+    ("gpt-4", 30.00, 60.00),
+    ("gpt-4-turbo", 10.00, 30.00),
+    ("gpt-4.1", 2.00, 8.00),
+    ("gpt-4.1-mini", 0.40, 1.60),
+    ("gpt-4.1-nano", 0.10, 0.40),
+    ("gpt-4o", 2.50, 10.00),
+    ("gpt-4o-mini", 0.15, 0.60),
+    ("o1", 15.00, 60.00),
+    ("o1-mini", 1.10, 4.40),
+    ("o3-mini", 1.10, 4.40),
+    ("claude-3-haiku", 0.25, 1.25),
+    ("claude-3-5-haiku", 0.80, 4.00),
+    ("claude-3-5-sonnet", 3.00, 15.00),
+    ("claude-3-7-sonnet", 3.00, 15.00),
+    ("claude-sonnet-4", 3.00, 15.00),
+    ("claude-3-opus", 15.00, 75.00),
+    ("claude-opus-4", 15.00, 75.00),
+];
+
+/// Tokens an answer is guessed to have, unless `max_tokens` allows fewer.
+const ANSWER_TOKENS: usize = 200;
+
+/// The explanation of `program` as compiled with `opts`, with its first
+/// `precomputed` statements answered at compile time.
+pub fn explain(program: &Program, opts: &CompileOptions, precomputed: usize) -> String {
+    let mut out = String::new();
+    let version = program.info.version.as_ref().map(|version| format!(" {}", version)).unwrap_or_default();
+    out.push_str(&format!("{}{} ({})\n", crate::program_name(opts, program), version, opts.source_name));
+    if let Some(about) = &program.info.about {
+        out.push_str(&format!("{}\n", about));
+    }
+
+    out.push_str(&format!("\nRuns {}\n", runs(program, opts)));
+    let planner = opts.planner();
+    let steps = planner.plan(program, precomputed);
+    let mut models = vec![opts.model.as_str()];
+    if steps.iter().any(|step| matches!(step.route, Route::Cheap)) || program.translate_to.is_some() {
+        models.extend(opts.cheap_model.as_deref());
+    }
+    for step in &steps {
+        if let Route::Using(model) = &step.route {
+            models.push(model);
+        }
+    }
+    if let Some((ensemble, judge)) = &opts.ensemble {
+        models.extend(ensemble.iter().map(String::as_str));
+        models.push(judge);
+    }
+    let mut seen = Vec::new();
+    for model in models {
+        if !seen.contains(&model) {
+            seen.push(model);
+            out.push_str(&format!("Model {} ({})\n", model, provider(model)));
+        }
+    }
+    if let Some(language) = &program.speak {
+        out.push_str(&format!("Answers are written in {}\n", language));
+    }
+    if let Some(language) = &program.translate_to {
+        out.push_str(&format!("Answers are translated to {} by {}\n", language, opts.cheap_model.as_deref().unwrap_or(&opts.model)));
+    }
+
+    let inputs = inputs(program, opts);
+    if !inputs.is_empty() {
+        out.push_str("\nInputs\n");
+        let width = inputs.iter().map(|(key, _)| key.chars().count()).max().unwrap_or(0);
+        for (key, source) in &inputs {
+            out.push_str(&format!("  {:<width$}  {}\n", key, source, width = width));
+        }
+    }
+
+    out.push_str("\nStatements\n");
+    out.push_str(&format!("{:>4}  {:<8}  {:>6}  {:<24}  {}\n", "", "strategy", "tokens", "runs on", "statement"));
+    for (index, statement) in program.statements.iter().enumerate().take(precomputed) {
+        out.push_str(&format!("{:>4}  {:<8}  {:>6}  {:<24}  {}\n", index + 1, "-", "-", "precomputed", statement.to_line()));
+    }
+    for step in &steps {
+        let target = planner.target(&step.route, &opts.model);
+        for &index in &step.statements {
+            for (kind, line) in planner::blocks_opening(program, index) {
+                out.push_str(&format!("{:>4}  {:<8}  {:>6}  {:<24}  {}\n", "", "", "", kind, line));
+            }
+            let statement = &program.statements[index];
+            let strategy = strategy(program, opts, &step.route, index);
+            let tokens = format!("~{}", statement_tokens(statement));
+            out.push_str(&format!("{:>4}  {:<8}  {:>6}  {:<24}  {}\n", index + 1, strategy, tokens, target, statement.to_line()));
+            for (kind, line) in planner::blocks_closing(program, index) {
+                out.push_str(&format!("{:>4}  {:<8}  {:>6}  {:<24}  {}\n", "", "", "", kind, line));
+            }
+        }
+    }
+    out.push_str("serial: asked after the statements before it; parallel: asked at the same time as others;\n");
+    out.push_str("pipeline: waits for an earlier answer, which goes into its prompt\n");
+
+    out.push_str(&format!("\nEstimate\n{}", estimate(program, opts, precomputed)));
+    out
+}
+
+/// How the program is run, e.g. `once per csv row on stdin`.
+fn runs(program: &Program, opts: &CompileOptions) -> String {
+    let how = if opts.tui {
+        "as a chat window (--tui), once per message".to_string()
+    } else if opts.serve {
+        "as an OpenAI-compatible server (--serve), once per chat completion".to_string()
+    } else if let Some(path) = &opts.listen_socket {
+        format!("on the socket {} (--listen-socket), once per request", path)
+    } else if opts.stream_filter {
+        "once per stdin line, as the lines come in (--stream-filter)".to_string()
+    } else if opts.audio_input {
+        "once per audio file or recording (--input audio)".to_string()
+    } else if !program.every_line.is_empty() {
+        "once, with the for every line block once per stdin line".to_string()
+    } else if opts.stdin_format != StdinFormat::Lines {
+        let rows = format!("once per {} row on stdin", opts.stdin_format.as_str());
+        if program.remember {
+            format!("{}, each row continuing the conversation of the ones before it", rows)
+        } else {
+            rows
+        }
+    } else {
+        "once".to_string()
+    };
+    if opts.background {
+        format!("{}, as a job submitted in the background (--background)", how)
+    } else {
+        how
+    }
+}
+
+/// Who answers `model`'s requests.
+fn provider(model: &str) -> &'static str {
+    if model.starts_with("claude") {
+        "Anthropic"
+    } else {
+        "OpenAI, or OPENAI_BASE_URL"
+    }
+}
+
+/// Every placeholder and variable the statements use, in the order they're
+/// listed, with where its value comes from.
+fn inputs(program: &Program, opts: &CompileOptions) -> Vec<(String, String)> {
+    let mut segments = Vec::new();
+    for statement in program.all_statements() {
+        collect(&statement.segments, &mut segments);
+    }
+    let mut inputs: Vec<(String, String)> = Vec::new();
+    let mut add = |key: String, source: String| {
+        if !inputs.iter().any(|(known, _)| *known == key) {
+            inputs.push((key, source));
+        }
+    };
+    for &index in &program.required_args {
+        let mut source = if opts.tui {
+            "each message".to_string()
+        } else if opts.serve {
+            "the last user message".to_string()
+        } else if opts.listen_socket.is_some() {
+            format!("args[{}] of each request", index - 1)
+        } else if opts.stream_filter {
+            "each stdin line".to_string()
+        } else if opts.audio_input {
+            "the transcript".to_string()
+        } else if opts.stdin_format == StdinFormat::Lines {
+            format!("stdin line {}", index)
+        } else {
+            let column = opts.column_map.iter().find(|(_, placeholder)| *placeholder == index).map_or(index, |(column, _)| *column);
+            format!("column {} of each row", column)
+        };
+        if let Some((_, ty)) = program.arg_types.iter().find(|(arg, _)| *arg == index) {
+            let what = match ty {
+                ArgType::Int => "an integer",
+                ArgType::Number => "a number",
+                ArgType::Path => "a path that exists",
+                ArgType::Json => "JSON",
+            };
+            source.push_str(&format!(", which has to be {}", what));
+        }
+        if let Some((_, default)) = program.arg_defaults.iter().find(|(arg, _)| *arg == index) {
+            source.push_str(&format!(", or \"{}\" without one", default));
+        }
+        add(format!("€{}", index), source);
+    }
+    for name in &program.named_args {
+        let source = if opts.listen_socket.is_some() {
+            format!("named.{} of each request", name)
+        } else if opts.stdin_format == StdinFormat::Lines && program.every_line.is_empty() {
+            format!("--{} <value>, or a {}=<value> line on stdin", name, name)
+        } else if opts.stdin_format != StdinFormat::Lines && opts.skip_header {
+            format!("--{} <value>, or the column named {} in the header row", name, name)
+        } else {
+            format!("--{} <value>", name)
+        };
+        add(format!("€{{{}}}", name), source);
+    }
+    for segment in segments {
+        let key = ast::segments_to_source(std::slice::from_ref(segment));
+        let source = match segment {
+            Segment::Secret(name) => format!("the environment variable {}, never shown", name),
+            Segment::Env(name) => format!("the environment variable {}", name),
+            Segment::File { index, .. } => format!("the file whose path is €{}", index),
+            Segment::Dir { index, .. } => format!("the files in the directory whose path is €{}", index),
+            Segment::AllArgs => "every line of stdin".to_string(),
+            Segment::GitDiff => "the staged changes of the repository it runs in".to_string(),
+            Segment::GitLog(count) => format!("the last {} commits of the repository it runs in", count),
+            Segment::Const { value, .. } => format!("\"{}\" from stuff:", value),
+            Segment::Prev => "the answer of the statement that ran right before".to_string(),
+            Segment::Var(name) if name == "loop" => "the round of the loop it's in".to_string(),
+            Segment::Var(name) if name == "line" => "the stdin line the for every line block is on".to_string(),
+            Segment::Var(name) if name == "shell" => "the output of the last shell statement".to_string(),
+            Segment::Var(name) => match program.statements.iter().position(|statement| statement.binding.as_ref() == Some(name)) {
+                Some(index) => format!("the answer of statement {}", index + 1),
+                None => "sent as written, nothing binds it".to_string(),
+            },
+            _ => continue,
+        };
+        add(key, source);
+    }
+    inputs
+}
+
+/// The segments of `segments` and of the arguments in them, in order.
+fn collect<'a>(segments: &'a [Segment], out: &mut Vec<&'a Segment>) {
+    for segment in segments {
+        match segment {
+            Segment::Call { args, .. } | Segment::Recipe { args, .. } => {
+                for arg in args {
+                    collect(arg, out);
+                }
+            }
+            segment => out.push(segment),
+        }
+    }
+}
+
+/// How the statement at `index` is asked: `serial`, `parallel` or `pipeline`.
+fn strategy(program: &Program, opts: &CompileOptions, route: &Route, index: usize) -> &'static str {
+    let statement = &program.statements[index];
+    let mut bound = program.statements[..index].iter().filter_map(|earlier| earlier.binding.as_deref());
+    if statement.uses_prev() || bound.any(|name| statement.uses_var(name)) {
+        "pipeline"
+    } else if program.parallel_of(index).is_some() || (opts.ensemble.is_some() && matches!(route, Route::Model)) {
+        "parallel"
+    } else {
+        "serial"
+    }
+}
+
+/// The statement's estimated tokens, with its placeholders as written.
+fn statement_tokens(statement: &Statement) -> usize {
+    estimate_tokens(&ast::segments_to_source(&statement.segments))
+}
+
+/// Model calls, tokens and cost of a run, each step once.
+fn estimate(program: &Program, opts: &CompileOptions, precomputed: usize) -> String {
+    let answer = opts.max_tokens.map_or(ANSWER_TOKENS, |max| ANSWER_TOKENS.min(max as usize));
+    let system = estimate_tokens(&crate::system_prompt(opts, program));
+    // The precomputed statements are asked and answered before the run starts
+    let mut history: usize = program.statements[..precomputed].iter().map(|statement| statement_tokens(statement) + answer).sum();
+    let (mut calls, mut input, mut output) = (0, 0, 0);
+    let mut cost = 0.0;
+    let mut unpriced: Vec<String> = Vec::new();
+    let mut call = |model: &str, tokens_in: usize, tokens_out: usize| {
+        calls += 1;
+        input += tokens_in;
+        output += tokens_out;
+        match price(model) {
+            Some((per_in, per_out)) => cost += (tokens_in as f64 * per_in + tokens_out as f64 * per_out) / 1_000_000.0,
+            None if !unpriced.iter().any(|known| known == model) => unpriced.push(model.to_string()),
+            None => {}
+        }
+    };
+    for step in opts.planner().plan(program, precomputed) {
+        let prompt: usize = step.statements.iter().map(|&index| statement_tokens(&program.statements[index])).sum();
+        let asked = system + history + prompt;
+        match (&step.route, &opts.ensemble) {
+            (Route::Local(..), _) => {}
+            (Route::Model, Some((models, judge))) => {
+                for model in models {
+                    call(model, asked, answer);
+                }
+                call(judge, asked + models.len() * answer, answer);
+            }
+            (Route::Cheap, _) => call(opts.cheap_model.as_deref().unwrap_or(&opts.model), asked, answer),
+            (Route::Using(model), _) => call(model, asked, answer),
+            (Route::Model, None) => call(&opts.model, asked, answer),
+        }
+        history += prompt + answer;
+    }
+    if program.translate_to.is_some() {
+        let answers = history - program.statements.iter().map(statement_tokens).sum::<usize>();
+        call(opts.cheap_model.as_deref().unwrap_or(&opts.model), answers, answers);
+    }
+
+    let mut out = format!("  {} model call(s), ~{} tokens in and ~{} out", calls, input, output);
+    match (calls, unpriced.is_empty()) {
+        (0, _) => {}
+        (_, true) => out.push_str(&format!(", ~${:.4} per run\n", cost)),
+        (_, false) => out.push_str(&format!(", ~${:.4} per run without {}, which has no known price\n", cost, unpriced.join(", "))),
+    }
+    if calls == 0 {
+        out.push('\n');
+    }
+    let mut once = Vec::new();
+    if !program.loops.is_empty() {
+        once.push("a loop for one round");
+    }
+    if !program.every_line.is_empty() {
+        once.push("the for every line block for one line");
+    }
+    if !program.branches.is_empty() {
+        once.push("both sides of an if");
+    }
+    if !once.is_empty() {
+        out.push_str(&format!("  Counting {}.\n", once.join(", ")));
+    }
+    out.push_str(&format!(
+        "  Answers are guessed at ~{} tokens, and placeholders count as written, not what they bring in.\n",
+        answer
+    ));
+    out
+}
+
+/// USD per million input and output tokens of `model`, if it's known.
+fn price(model: &str) -> Option<(f64, f64)> {
+    PRICES
+        .iter()
+        .filter(|(prefix, _, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _, _)| prefix.len())
+        .map(|&(_, per_in, per_out)| (per_in, per_out))
+}
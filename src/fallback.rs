@@ -0,0 +1,44 @@
+//! `--fallback pack.jsonl`: answers baked into the program for when the model
+//! can't be reached or won't take the key, so a demo or a kiosk still answers
+//! the inputs it knows. Each line is `{"prompt": "...", "answer": "..."}`, the
+//! way the compiled programs' `--record-fallback` writes them, or
+//! `{"prompt_hash": "<16 hex digits>", "answer": "..."}` for packs that
+//! shouldn't contain the inputs. Only the hashes end up in the program.
+
+use std::fs;
+
+use crate::front_matter::parse_json_object;
+use crate::fnv1a;
+use crate::i18n::tr;
+
+/// Reads a pack into (prompt hash, answer) pairs. A prompt recorded twice keeps
+/// the later answer.
+pub fn read(path: &str) -> Result<Vec<(u64, String)>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| tr!("cli.read-failed", file = path, error = e))?;
+    let mut answers: Vec<(u64, String)> = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let at = |message: String| format!("{}:{}: {}", path, idx + 1, message);
+        let record = parse_json_object(line).map_err(at)?;
+        let field = |key: &str| record.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+        let hash = match (field("prompt"), field("prompt_hash")) {
+            (Some(prompt), None) => fnv1a(prompt),
+            (None, Some(hex)) => u64::from_str_radix(hex, 16)
+                .ok()
+                .filter(|_| hex.len() == 16)
+                .ok_or_else(|| at(format!("prompt_hash '{}' isn't 16 hex digits", hex)))?,
+            _ => return Err(at("expected either \"prompt\" or \"prompt_hash\"".into())),
+        };
+        let Some(answer) = field("answer") else {
+            return Err(at("missing \"answer\"".into()));
+        };
+        answers.retain(|(h, _)| *h != hash);
+        answers.push((hash, answer.to_string()));
+    }
+    if answers.is_empty() {
+        return Err(format!("{} has no answers in it", path));
+    }
+    Ok(answers)
+}
@@ -0,0 +1,82 @@
+//! `matthiashihic fmt [--check] [--fix] <source>...` rewrites source files in
+//! the canonical form produced by `Program::to_source`. With `--fix`, obvious
+//! parse errors are fixed first (see `check`).
+
+use std::fs;
+use std::path::Path;
+
+use crate::check::apply_fixes;
+use crate::diagnostics;
+use crate::i18n::tr;
+use crate::parser::{format_program, parse_file};
+
+/// Runs the subcommand and returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let mut check = false;
+    let mut fix = false;
+    let mut files = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--fix" => fix = true,
+            s if s.starts_with('-') => {
+                eprintln!("{}", tr!("fmt.unknown-flag", flag = s));
+                return 2;
+            }
+            s => files.push(s),
+        }
+    }
+    if files.is_empty() {
+        eprintln!("{}", tr!("fmt.usage"));
+        return 2;
+    }
+
+    let mut status = 0;
+    for file in files {
+        let contents = match fs::read_to_string(file) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("{}", tr!("cli.read-failed", file = file, error = e));
+                status = 1;
+                continue;
+            }
+        };
+        let (fixed, applied) = if fix { apply_fixes(&contents, Some(Path::new(file))) } else { (contents.clone(), Vec::new()) };
+        let program = match parse_file(&fixed, Path::new(file)) {
+            Ok((program, _)) => program,
+            Err(e) => {
+                eprint!("{}", diagnostics::render_parse_error(file, &fixed, &e));
+                status = 1;
+                continue;
+            }
+        };
+        for help in &applied {
+            if check {
+                println!("{}", tr!("fmt.would-fix", file = file, help = help));
+            } else {
+                println!("{}", tr!("check.fixed", file = file, help = help));
+            }
+        }
+
+        // Never write something that means a different program
+        let Ok(formatted) = format_program(&program, Some(Path::new(file))) else {
+            eprintln!("{}", tr!("fmt.unsafe", file = file));
+            status = 1;
+            continue;
+        };
+
+        if formatted == contents {
+            continue;
+        }
+        if check {
+            println!("{}", tr!("fmt.would-reformat", file = file));
+            status = 1;
+        } else if let Err(e) = fs::write(file, &formatted) {
+            eprintln!("{}", tr!("cli.write-failed", file = file, error = e));
+            status = 1;
+        } else {
+            println!("{}", tr!("fmt.formatted", file = file));
+        }
+    }
+    status
+}
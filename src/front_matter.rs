@@ -0,0 +1,212 @@
+//! Front-matter: an optional block between `hihi!` and the first statement
+//! that carries per-program defaults.
+//!
+//!   hihi!
+//!   ---
+//!   model: gpt-4o
+//!   temperature: 0.2
+//!   ---
+//!   "statements..."
+//!
+//! The block is either flat YAML (`key: value` lines, `#` comments) or a flat
+//! JSON object. Values are scalars only; nesting is not supported.
+
+/// Program options declared in the front-matter.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct FrontMatter {
+    pub model: Option<String>,
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<u32>,
+    /// Same as the `name:`, `about:` and `version:` directives.
+    pub name: Option<String>,
+    pub about: Option<String>,
+    pub version: Option<String>,
+    /// Every key/value pair as written, in order, for the embedded metadata.
+    pub entries: Vec<(String, String)>,
+    /// The raw block, so formatting keeps comments and layout.
+    pub lines: Vec<String>,
+}
+
+impl FrontMatter {
+    /// The block's lines (without the `---` fences) as written, comments included.
+    pub fn to_source(&self) -> String {
+        self.lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+}
+
+/// Parses the lines between the `---` markers. `first_line` is the 1-based
+/// source line of the first content line, used in error messages.
+pub fn parse(lines: &[&str], first_line: usize) -> Result<FrontMatter, String> {
+    let content = lines.join("\n");
+    let entries = if content.trim_start().starts_with('{') {
+        parse_json_object(content.trim())
+            .map_err(|e| format!("Invalid JSON front-matter starting at line {}: {}", first_line, e))?
+    } else {
+        parse_yaml(lines, first_line)?
+    };
+
+    let mut front_matter = FrontMatter::default();
+    for (key, value) in &entries {
+        match key.replace('-', "_").as_str() {
+            "model" => {
+                if value.is_empty() {
+                    return Err("Front-matter 'model' must not be empty".into());
+                }
+                front_matter.model = Some(value.clone());
+            }
+            "temperature" => {
+                let temperature: f64 = value
+                    .parse()
+                    .map_err(|_| format!("Front-matter 'temperature' must be a number, got '{}'", value))?;
+                if !(0.0..=2.0).contains(&temperature) {
+                    return Err(format!("Front-matter 'temperature' must be between 0 and 2, got {}", value));
+                }
+                front_matter.temperature = Some(temperature);
+            }
+            "max_tokens" => {
+                let max_tokens: u32 = value
+                    .parse()
+                    .map_err(|_| format!("Front-matter 'max_tokens' must be a positive integer, got '{}'", value))?;
+                front_matter.max_tokens = Some(max_tokens);
+            }
+            "name" => front_matter.name = Some(value.clone()),
+            "about" => front_matter.about = Some(value.clone()),
+            "version" => front_matter.version = Some(value.clone()),
+            other => {
+                return Err(format!(
+                    "Unknown front-matter key '{}' (supported: model, temperature, max_tokens, name, about, version)",
+                    other
+                ))
+            }
+        }
+    }
+    front_matter.entries = entries;
+    front_matter.lines = lines.iter().map(|line| line.trim_end().to_string()).collect();
+    Ok(front_matter)
+}
+
+fn parse_yaml(lines: &[&str], first_line: usize) -> Result<Vec<(String, String)>, String> {
+    let mut entries = Vec::new();
+    for (offset, line) in lines.iter().enumerate() {
+        let t = line.trim();
+        if t.is_empty() || t.starts_with('#') {
+            continue;
+        }
+        let (key, value) = t.split_once(':').ok_or_else(|| {
+            format!(
+                "Front-matter lines must look like 'key: value'. Error at line {}: {}",
+                first_line + offset,
+                line
+            )
+        })?;
+        let key = key.trim();
+        if key.is_empty() {
+            return Err(format!("Missing front-matter key at line {}: {}", first_line + offset, line));
+        }
+        if entries.iter().any(|(k, _): &(String, String)| k == key) {
+            return Err(format!("Duplicate front-matter key '{}' at line {}", key, first_line + offset));
+        }
+        entries.push((key.to_string(), unquote(value.trim()).to_string()));
+    }
+    Ok(entries)
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if value.len() >= 2 && value.starts_with(quote) && value.ends_with(quote) {
+            return &value[1..value.len() - 1];
+        }
+    }
+    value
+}
+
+/// Parses `{"key": scalar, ...}` where scalars are strings, numbers, booleans or null.
+pub fn parse_json_object(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut chars = text.chars().peekable();
+    let mut entries = Vec::new();
+
+    skip_whitespace(&mut chars);
+    if chars.next() != Some('{') {
+        return Err("expected '{'".into());
+    }
+    skip_whitespace(&mut chars);
+    if chars.peek() == Some(&'}') {
+        chars.next();
+    } else {
+        loop {
+            skip_whitespace(&mut chars);
+            let key = parse_json_string(&mut chars)?;
+            skip_whitespace(&mut chars);
+            if chars.next() != Some(':') {
+                return Err(format!("expected ':' after key \"{}\"", key));
+            }
+            skip_whitespace(&mut chars);
+            let value = if chars.peek() == Some(&'"') {
+                parse_json_string(&mut chars)?
+            } else {
+                let mut literal = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c == ',' || c == '}' || c.is_whitespace() {
+                        break;
+                    }
+                    literal.push(c);
+                    chars.next();
+                }
+                if literal.is_empty() || literal.starts_with('{') || literal.starts_with('[') {
+                    return Err(format!("value of \"{}\" must be a string, number or boolean", key));
+                }
+                literal
+            };
+            if entries.iter().any(|(k, _): &(String, String)| *k == key) {
+                return Err(format!("duplicate key \"{}\"", key));
+            }
+            entries.push((key, value));
+            skip_whitespace(&mut chars);
+            match chars.next() {
+                Some(',') => continue,
+                Some('}') => break,
+                _ => return Err("expected ',' or '}'".into()),
+            }
+        }
+    }
+    skip_whitespace(&mut chars);
+    if chars.next().is_some() {
+        return Err("unexpected characters after closing '}'".into());
+    }
+    Ok(entries)
+}
+
+pub fn skip_whitespace(chars: &mut std::iter::Peekable<std::str::Chars>) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+pub fn parse_json_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<String, String> {
+    if chars.next() != Some('"') {
+        return Err("expected a quoted string".into());
+    }
+    let mut s = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Ok(s),
+            '\\' => match chars.next() {
+                Some('n') => s.push('\n'),
+                Some('t') => s.push('\t'),
+                Some('r') => s.push('\r'),
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    let ch = u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| format!("invalid unicode escape \\u{}", hex))?;
+                    s.push(ch);
+                }
+                Some(other) => s.push(other),
+                None => break,
+            },
+            other => s.push(other),
+        }
+    }
+    Err("unterminated string".into())
+}
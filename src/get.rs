@@ -0,0 +1,301 @@
+//! `matthiashihic get <github:user/repo/path[@rev] | name> [--index <index>]
+//! [--sha256 <hex>] [--require-signature] [<install flag>...]` fetches a program
+//! someone shared and installs it the way `install` does.
+//!
+//! A `github:` source is fetched with git at the revision after `@` (the
+//! default branch without one) and installed from a checkout of that commit in
+//! src/ of `paths::install_dir`, so its `nom nom` includes and externs come
+//! along and `upgrade` compiles the same code again. The registry records the
+//! commit, which pins it until the program is fetched again.
+//!
+//! A plain name is looked up in an index: JSON Lines with a `name`, a `source`
+//! and the `sha256` of the program for each program, and an optional `about`.
+//! The sha256 of a program covers every file it's built from, the ones its
+//! `nom nom`s and externs pull in too; see program_sha256. The index is a
+//! file, or a `github:` source itself, given with `--index` or
+//! MATTHIASHIHIC_INDEX.
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+use crate::front_matter::parse_json_object;
+use crate::i18n::tr;
+use crate::install;
+use crate::package::sha256_hex;
+use crate::diagnostics::ParseError;
+use crate::parser::{included_files, parse_file};
+use crate::paths;
+
+/// `github:user/repo/path`, at `rev` if it says `@rev`.
+struct Source {
+    owner: String,
+    repo: String,
+    path: String,
+    rev: Option<String>,
+}
+
+impl Source {
+    fn parse(text: &str) -> Result<Source, String> {
+        let invalid = || format!("'{}' isn't a source like github:user/repo/path/to/program.matthiashihic[@revision]", text);
+        let rest = text.strip_prefix("github:").ok_or_else(invalid)?;
+        let (rest, rev) = match rest.rsplit_once('@') {
+            Some((rest, rev)) => (rest, Some(rev)),
+            None => (rest, None),
+        };
+        let mut parts = rest.splitn(3, '/');
+        let (Some(owner), Some(repo), Some(path)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        let name_like = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !name_like(owner) || !name_like(repo) || path.is_empty() {
+            return Err(invalid());
+        }
+        // Only down into the checkout, never out of it
+        if !Path::new(path).components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(format!("'{}' has to be a path inside the repository", path));
+        }
+        // A revision starting with '-' would be an option to git
+        if let Some(rev) = rev.filter(|rev| rev.is_empty() || rev.starts_with('-') || !rev.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))) {
+            return Err(format!("'{}' isn't a revision; use a branch, a tag or a commit", rev));
+        }
+        Ok(Source {
+            owner: owner.to_string(),
+            repo: repo.trim_end_matches(".git").to_string(),
+            path: path.to_string(),
+            rev: rev.map(str::to_string),
+        })
+    }
+
+    fn url(&self) -> String {
+        format!("https://github.com/{}/{}.git", self.owner, self.repo)
+    }
+}
+
+/// Runs the subcommand and returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let target = match args.first() {
+        Some(arg) if arg == "-h" || arg == "--help" => {
+            println!("{}", tr!("get.usage"));
+            return 0;
+        }
+        Some(arg) if !arg.starts_with('-') => arg.clone(),
+        _ => {
+            eprintln!("{}", tr!("get.usage"));
+            return 2;
+        }
+    };
+    let mut index = std::env::var("MATTHIASHIHIC_INDEX").ok().filter(|index| !index.is_empty());
+    let mut sha256 = None;
+    let mut require_signature = false;
+    // Everything else is install's
+    let mut forwarded = Vec::new();
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            flag @ ("--index" | "--sha256") => match args.get(i + 1) {
+                Some(value) if flag == "--index" => {
+                    index = Some(value.clone());
+                    i += 1;
+                }
+                Some(value) => {
+                    sha256 = Some(value.to_ascii_lowercase());
+                    i += 1;
+                }
+                None => {
+                    eprintln!("{} needs a value", flag);
+                    return 2;
+                }
+            },
+            "--require-signature" => require_signature = true,
+            arg => forwarded.push(arg.to_string()),
+        }
+        i += 1;
+    }
+    let Some(home) = paths::install_dir() else {
+        eprintln!("No home directory to install into; set MATTHIASHIHIC_HOME");
+        return 1;
+    };
+    match get(&home, &target, index.as_deref(), sha256, require_signature) {
+        Ok((file, origin)) => install::install_source(&file.to_string_lossy(), &forwarded, Some(origin)),
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+/// Fetches `target`, checks it and returns the program file in its checkout
+/// and where it came from, pinned to the commit.
+fn get(home: &Path, target: &str, index: Option<&str>, sha256: Option<String>, require_signature: bool) -> Result<(PathBuf, String), String> {
+    let (source, expected) = if target.starts_with("github:") {
+        (Source::parse(target)?, sha256)
+    } else {
+        let index = index.ok_or_else(|| format!("'{}' isn't a github: source, and there's no --index or MATTHIASHIHIC_INDEX to look it up in", target))?;
+        let (source, listed) = lookup(index, target)?;
+        if sha256.as_ref().is_some_and(|sha256| *sha256 != listed) {
+            return Err(format!("--sha256 and the index disagree about {}", target));
+        }
+        (Source::parse(&source)?, Some(listed))
+    };
+    let (checkout, commit) = checkout(home, &source, require_signature, expected.as_deref())?;
+    let origin = format!("github:{}/{}/{}@{}", source.owner, source.repo, source.path, commit);
+    Ok((checkout.join(&source.path), origin))
+}
+
+/// Checks the program in the checkout `dir` against `expected`, before it's
+/// moved into place.
+fn check_sha256(dir: &Path, source: &Source, commit: &str, expected: Option<&str>) -> Result<(), String> {
+    if !dir.join(&source.path).is_file() {
+        return Err(format!("{} has no {} at {}", source.url(), source.path, commit));
+    }
+    let (actual, files) = program_sha256(dir, &source.path)?;
+    let what = match files {
+        1 => source.path.clone(),
+        files => format!("{} and the {} file(s) it pulls in", source.path, files - 1),
+    };
+    match expected {
+        Some(expected) if expected != actual => Err(format!(
+            "{} at {} has the sha256 {}, not {}; it isn't what you were told it is",
+            what, commit, actual, expected
+        )),
+        Some(_) => {
+            println!("Checked the sha256 of {}", what);
+            Ok(())
+        }
+        None => {
+            println!("{} has the sha256 {}; pass --sha256 to check it next time", what, actual);
+            Ok(())
+        }
+    }
+}
+
+/// The sha256 of the program `path` in `dir`, and how many files it's built
+/// from. For a program of one file it's that file's; every file its `nom
+/// nom`s and externs pull in, theirs included, adds `\0<path>\0<length>\0`
+/// and its contents to what's hashed, in the order they're found.
+fn program_sha256(dir: &Path, path: &str) -> Result<(String, usize), String> {
+    let root = dir.canonicalize().map_err(|e| format!("Can't read {}: {}", dir.display(), e))?;
+    let mut files: Vec<PathBuf> = Vec::new();
+    let mut hashed = Vec::new();
+    // Adds a file to what's hashed, unless it's in there already
+    let mut add = |file: &Path, hashed: &mut Vec<u8>| -> Result<Option<Vec<u8>>, String> {
+        let file = file.canonicalize().map_err(|e| format!("Can't read {}: {}", file.display(), e))?;
+        let relative = file
+            .strip_prefix(&root)
+            .map_err(|_| format!("{} pulls in {}, which isn't in the repository", path, file.display()))?
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        if files.contains(&file) {
+            return Ok(None);
+        }
+        let contents = fs::read(&file).map_err(|e| tr!("cli.read-failed", file = file.display(), error = e))?;
+        if !files.is_empty() {
+            hashed.extend_from_slice(format!("\0{}\0{}\0", relative, contents.len()).as_bytes());
+        }
+        hashed.extend_from_slice(&contents);
+        files.push(file);
+        Ok(Some(contents))
+    };
+    // Programs: the entry and its externs. Their nom noms are read with them
+    let mut pending = vec![root.join(path)];
+    while let Some(file) = pending.pop() {
+        let Some(contents) = add(&file, &mut hashed)? else {
+            continue;
+        };
+        let source = String::from_utf8_lossy(&contents);
+        let cant_tell = |e: ParseError| format!("Can't tell which files {} pulls in: {}", file.display(), e);
+        for included in included_files(&source, &file).map_err(cant_tell)? {
+            add(&included, &mut hashed)?;
+        }
+        let (program, _) = parse_file(&source, &file).map_err(cant_tell)?;
+        let here = file.parent().unwrap_or(&root);
+        // Reversed, so they're hashed in the order they're written
+        pending.extend(program.externs.iter().rev().map(|(_, path)| here.join(path)));
+    }
+    Ok((sha256_hex(&hashed), files.len()))
+}
+
+/// The `source` and `sha256` of `name` in the index at `index`.
+fn lookup(index: &str, name: &str) -> Result<(String, String), String> {
+    let contents = if index.starts_with("github:") {
+        let source = Source::parse(index)?;
+        let dir = paths::temp_path("matthiashihic-index", "");
+        fs::create_dir_all(&dir).map_err(|e| format!("Can't create {}: {}", dir.display(), e))?;
+        let contents = fetch(&dir, &source, false).and_then(|_| git(&dir, &["show", &format!("FETCH_HEAD:{}", source.path)]));
+        let _ = fs::remove_dir_all(&dir);
+        String::from_utf8_lossy(&contents?).into_owned()
+    } else {
+        fs::read_to_string(index).map_err(|e| tr!("cli.read-failed", file = index, error = e))?
+    };
+    for (number, line) in contents.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let record = parse_json_object(line).map_err(|e| format!("{}:{}: {}", index, number + 1, e))?;
+        let field = |key: &str| record.iter().find(|(k, _)| k == key).map(|(_, value)| value.clone());
+        if field("name").as_deref() != Some(name) {
+            continue;
+        }
+        let source = field("source").ok_or_else(|| format!("{}:{}: {} has no \"source\"", index, number + 1, name))?;
+        let sha256 = field("sha256").ok_or_else(|| format!("{}:{}: {} has no \"sha256\" to check it with", index, number + 1, name))?;
+        return Ok((source, sha256.to_ascii_lowercase()));
+    }
+    Err(format!("{} isn't in the index {}", name, index))
+}
+
+/// Checks out `source`'s commit in src/, unless it's there already, and
+/// returns the checkout and the commit.
+fn checkout(home: &Path, source: &Source, require_signature: bool, expected: Option<&str>) -> Result<(PathBuf, String), String> {
+    let src = home.join("src");
+    // In src/ so it can be renamed into place; the dot keeps `uninstall` off it
+    let fetching = src.join(format!(".fetch-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&fetching);
+    fs::create_dir_all(&fetching).map_err(|e| format!("Can't create {}: {}", fetching.display(), e))?;
+    let result = fetch(&fetching, source, require_signature).and_then(|commit| {
+        let checkout = src.join(format!("{}-{}-{}", source.owner, source.repo, &commit[..12.min(commit.len())]));
+        if checkout.exists() {
+            check_sha256(&checkout, source, &commit, expected)?;
+        } else {
+            git(&fetching, &["-c", "advice.detachedHead=false", "checkout", "--quiet", "FETCH_HEAD"])?;
+            // Checked where nothing runs it yet; a mismatch is removed below
+            check_sha256(&fetching, source, &commit, expected)?;
+            fs::remove_dir_all(fetching.join(".git")).map_err(|e| format!("Can't clean up {}: {}", fetching.display(), e))?;
+            fs::rename(&fetching, &checkout).map_err(|e| format!("Can't move {} to {}: {}", fetching.display(), checkout.display(), e))?;
+        }
+        Ok((checkout, commit))
+    });
+    let _ = fs::remove_dir_all(&fetching);
+    result
+}
+
+/// Fetches `source`'s revision into the repository `dir` makes, as FETCH_HEAD,
+/// and returns its commit.
+fn fetch(dir: &Path, source: &Source, require_signature: bool) -> Result<String, String> {
+    let rev = source.rev.as_deref().unwrap_or("HEAD");
+    git(dir, &["init", "--quiet"])?;
+    println!("Fetching {} from {}", rev, source.url());
+    git(dir, &["fetch", "--quiet", "--depth", "1", &source.url(), rev])?;
+    if require_signature {
+        git(dir, &["verify-commit", "FETCH_HEAD"])
+            .map_err(|e| format!("The commit of {} isn't signed by a key git trusts: {}", rev, e))?;
+    }
+    let commit = git(dir, &["rev-parse", "FETCH_HEAD"])?;
+    Ok(String::from_utf8_lossy(&commit).trim().to_string())
+}
+
+/// Runs git in `dir` and returns what it printed.
+fn git(dir: &Path, args: &[&str]) -> Result<Vec<u8>, String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("get needs git, which didn't run: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(match stderr.trim() {
+            "" => format!("git {} failed", args[0]),
+            stderr => format!("git {} failed: {}", args[0], stderr),
+        });
+    }
+    Ok(output.stdout)
+}
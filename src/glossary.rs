@@ -0,0 +1,104 @@
+//! `--glossary terms.csv`: terms the answers have to get right. Each row is a
+//! term and, optionally, what the answer has to say for it (a translation, the
+//! brand's spelling); a term alone has to stay exactly as it is. The generated
+//! program tells the model with the system prompt and checks every answer.
+
+use std::fs;
+
+use crate::i18n::tr;
+
+/// One row: `term` or `term,use instead`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Term {
+    pub term: String,
+    /// What the answer has to contain when the prompt mentions `term`.
+    pub required: String,
+}
+
+/// Reads a glossary. Fields may be quoted like in any other CSV; blank lines and
+/// lines starting with `#` are skipped.
+pub fn read(path: &str) -> Result<Vec<Term>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| tr!("cli.read-failed", file = path, error = e))?;
+    let mut terms = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let fields = split_row(line).map_err(|e| format!("{}:{}: {}", path, idx + 1, e))?;
+        let (term, required) = match fields.as_slice() {
+            [term] => (term.clone(), term.clone()),
+            [term, required] => (term.clone(), required.clone()),
+            _ => return Err(format!("{}:{}: expected 'term' or 'term,use instead', got {} columns", path, idx + 1, fields.len())),
+        };
+        if term.is_empty() || required.is_empty() {
+            return Err(format!("{}:{}: empty term", path, idx + 1));
+        }
+        if terms.iter().any(|t: &Term| t.term.eq_ignore_ascii_case(&term)) {
+            return Err(format!("{}:{}: '{}' is in the glossary twice", path, idx + 1, term));
+        }
+        terms.push(Term { term, required });
+    }
+    if terms.is_empty() {
+        return Err(format!("{} has no terms in it", path));
+    }
+    Ok(terms)
+}
+
+/// Splits a CSV row, trimming unquoted fields; `""` inside quotes is a quote.
+pub fn split_row(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| *c == ' ' || *c == '\t') {
+            chars.next();
+        }
+        let mut field = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    Some('"') => break,
+                    Some(c) => field.push(c),
+                    None => return Err("missing closing quote".into()),
+                }
+            }
+            while chars.peek().is_some_and(|c| *c != ',') {
+                if !chars.next().is_some_and(char::is_whitespace) {
+                    return Err("text after a closing quote".into());
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ',' {
+                    break;
+                }
+                field.push(c);
+                chars.next();
+            }
+            field = field.trim_end().to_string();
+        }
+        fields.push(field);
+        if chars.next().is_none() {
+            return Ok(fields);
+        }
+    }
+}
+
+/// The instructions added to the system prompt.
+pub fn prompt(terms: &[Term]) -> String {
+    let mut out = String::from(" Use this terminology exactly as given:");
+    for term in terms {
+        if term.term == term.required {
+            out.push_str(&format!(" keep \"{}\" as it is, never translate or rephrase it;", term.term));
+        } else {
+            out.push_str(&format!(" where the input says \"{}\", write \"{}\";", term.term, term.required));
+        }
+    }
+    out.pop();
+    out.push('.');
+    out
+}
@@ -0,0 +1,105 @@
+//! Compiler messages by key, from catalogs in `locales/`. The catalogs are
+//! embedded; `--locale` (or LC_ALL/LC_MESSAGES/LANG) picks one, and a file in
+//! MATTHIASHIHIC_CATALOG overrides single entries. A key missing from the
+//! chosen catalog falls back to English.
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::sync::OnceLock;
+
+/// Embedded catalogs; the first one is the fallback.
+const CATALOGS: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en.catalog")),
+    ("de", include_str!("../locales/de.catalog")),
+    ("fr", include_str!("../locales/fr.catalog")),
+];
+
+static ACTIVE: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+/// `message(key, &[(name, &value), ...])` with `name = value` pairs.
+#[macro_export]
+#[doc(hidden)]
+macro_rules! tr {
+    ($key:expr $(, $name:ident = $value:expr)* $(,)?) => {
+        $crate::i18n::message($key, &[$((stringify!($name), &$value as &dyn std::fmt::Display)),*])
+    };
+}
+pub use crate::tr;
+
+/// Names of the embedded catalogs, as `en|de|fr`.
+pub fn locales() -> String {
+    CATALOGS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join("|")
+}
+
+/// Picks the catalog: `locale` if given, otherwise the environment's. Without
+/// a call, messages are English.
+pub fn init(locale: Option<&str>) -> Result<(), String> {
+    let override_path = std::env::var("MATTHIASHIHIC_CATALOG").ok().filter(|p| !p.is_empty());
+    let chosen = match locale {
+        Some(name) => match CATALOGS.iter().find(|(n, _)| *n == name) {
+            Some((_, text)) => Some(*text),
+            // A locale only the override file speaks
+            None if override_path.is_some() => None,
+            None => {
+                return Err(message("cli.unknown-locale", &[("locale", &name), ("locales", &locales())]));
+            }
+        },
+        None => env_locale().and_then(|name| CATALOGS.iter().find(|(n, _)| *n == name)).map(|(_, text)| *text),
+    };
+
+    let mut entries = parse(CATALOGS[0].1);
+    if let Some(text) = chosen {
+        entries.extend(parse(text));
+    }
+    if let Some(path) = override_path {
+        let text = fs::read_to_string(&path).map_err(|e| tr!("cli.read-failed", file = path, error = e))?;
+        entries.extend(parse(&text));
+    }
+    let _ = ACTIVE.set(entries);
+    Ok(())
+}
+
+/// Language from LC_ALL, LC_MESSAGES or LANG, e.g. `de` for `de_DE.UTF-8`.
+fn env_locale() -> Option<String> {
+    let value = ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|value| !value.is_empty())?;
+    let language = value.split(['_', '.', '@']).next().unwrap_or("").to_ascii_lowercase();
+    (!language.is_empty() && language != "c" && language != "posix").then_some(language)
+}
+
+/// Entries of a catalog: `@key` lines, each followed by its text.
+fn parse(text: &str) -> HashMap<String, String> {
+    let mut entries: Vec<(String, Vec<&str>)> = Vec::new();
+    for line in text.lines() {
+        if let Some(key) = line.strip_prefix('@') {
+            entries.push((key.trim().to_string(), Vec::new()));
+        } else if line.starts_with('#') {
+            continue;
+        } else if let Some((_, lines)) = entries.last_mut() {
+            lines.push(line);
+        }
+    }
+    entries
+        .into_iter()
+        .map(|(key, mut lines)| {
+            while lines.last().is_some_and(|line| line.trim().is_empty()) {
+                lines.pop();
+            }
+            (key, lines.join("\n"))
+        })
+        .collect()
+}
+
+/// The text of `key` in the active catalog, with every `{name}` replaced.
+/// An unknown key comes back as itself, so a typo is visible but not fatal.
+pub fn message(key: &str, values: &[(&str, &dyn Display)]) -> String {
+    let entries = ACTIVE.get_or_init(|| parse(CATALOGS[0].1));
+    let mut text = entries.get(key).cloned().unwrap_or_else(|| key.to_string());
+    for (name, value) in values {
+        text = text.replace(&format!("{{{}}}", name), &value.to_string());
+    }
+    text
+}
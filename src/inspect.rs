@@ -0,0 +1,194 @@
+//! `matthiashihic inspect <program> [--profile <name>]` prints the metadata a
+//! compiled program carries, what its `--hihic-info` prints, read out of the
+//! binary instead of running it. With `--profile`, it checks that the program
+//! was compiled with that profile and that nothing in the metadata says
+//! otherwise.
+
+use std::fs;
+
+use crate::i18n::tr;
+use crate::profile::Profile;
+
+/// How the metadata `build_metadata` writes starts.
+const METADATA_START: &[u8] = b"{\"compiler\":\"matthiashihic ";
+
+/// Metadata keys that give away something each restriction rules out, with
+/// the value that does (`None` for any).
+const GIVEAWAYS: &[(&str, &str, Option<&str>)] = &[
+    ("no-shell", "hooks", None),
+    ("no-shell", "notify", None),
+    ("no-network", "cache_backend", None),
+    ("no-network", "broker", None),
+    ("no-network", "interface", Some("\"serve\"")),
+    ("no-network", "interface", Some("\"socket\"")),
+    ("no-env", "secrets", None),
+    ("no-env", "env", None),
+    ("no-files", "input", Some("\"audio\"")),
+    ("no-files", "output", Some("\"image\"")),
+    ("no-files", "cache", None),
+    ("no-files", "banner", None),
+    ("no-files", "experiment", None),
+];
+
+/// Runs the subcommand and returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let mut binary = None;
+    let mut profile = None;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--profile" => match args.get(i + 1).map(|name| Profile::parse(name)) {
+                Some(Ok(p)) => {
+                    profile = Some(p);
+                    i += 1;
+                }
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    return 2;
+                }
+                None => {
+                    eprintln!("--profile requires a name (locked-down)");
+                    return 2;
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", tr!("inspect.usage"));
+                return 0;
+            }
+            flag if flag.starts_with('-') => {
+                eprintln!("{}", tr!("inspect.unknown-flag", flag = flag));
+                return 2;
+            }
+            path if binary.is_none() => binary = Some(path.to_string()),
+            _ => {
+                eprintln!("{}", tr!("inspect.usage"));
+                return 2;
+            }
+        }
+        i += 1;
+    }
+    let Some(binary) = binary else {
+        eprintln!("{}", tr!("inspect.usage"));
+        return 2;
+    };
+    let bytes = match fs::read(&binary) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("{}", tr!("cli.read-failed", file = binary, error = e));
+            return 1;
+        }
+    };
+    let Some(metadata) = find_metadata(&bytes) else {
+        eprintln!("{} has no matthiashihic metadata; it wasn't compiled by matthiashihic", binary);
+        return 1;
+    };
+    let Some(profile) = profile else {
+        println!("{}", metadata);
+        return 0;
+    };
+    let problems = check(&fields(metadata), profile);
+    if problems.is_empty() {
+        println!("{}: {} ({})", binary, profile.name(), profile.restrictions().join(", "));
+        return 0;
+    }
+    eprintln!("{} isn't {}:", binary, profile.name());
+    for problem in problems {
+        eprintln!("  {}", problem);
+    }
+    1
+}
+
+/// The metadata JSON in a compiled program, the first copy that's whole.
+fn find_metadata(bytes: &[u8]) -> Option<&str> {
+    let mut from = 0;
+    while let Some(offset) = bytes[from..].windows(METADATA_START.len()).position(|window| window == METADATA_START) {
+        let start = from + offset;
+        if let Some(len) = object_len(&bytes[start..]) {
+            if let Ok(metadata) = std::str::from_utf8(&bytes[start..start + len]) {
+                return Some(metadata);
+            }
+        }
+        from = start + 1;
+    }
+    None
+}
+
+/// The length of the JSON object `bytes` starts with, if it ends.
+fn object_len(bytes: &[u8]) -> Option<usize> {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, &byte) in bytes.iter().enumerate() {
+        match byte {
+            // A string in the binary that only starts like the metadata
+            0 => return None,
+            _ if escaped => escaped = false,
+            b'\\' if in_string => escaped = true,
+            b'"' => in_string = !in_string,
+            _ if in_string => {}
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The top-level keys of a JSON object, each with its value as written.
+fn fields(object: &str) -> Vec<(String, String)> {
+    let inner = &object[1..object.len() - 1];
+    let mut fields = Vec::new();
+    let (mut depth, mut in_string, mut escaped) = (0, false, false);
+    let mut start = 0;
+    for (i, c) in inner.char_indices().chain(std::iter::once((inner.len(), ','))) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            _ if in_string => {}
+            '{' | '[' => depth += 1,
+            '}' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                // Keys are plain names, so the first `":` ends the key
+                if let Some((key, value)) = inner[start..i].split_once("\":") {
+                    fields.push((key.trim_start_matches('"').to_string(), value.to_string()));
+                }
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    fields
+}
+
+/// Why metadata with `fields` doesn't show a program compiled with `profile`.
+fn check(fields: &[(String, String)], profile: Profile) -> Vec<String> {
+    let value = |key: &str| fields.iter().find(|(k, _)| k == key).map(|(_, value)| value.as_str());
+    let mut problems = Vec::new();
+    match value("profile") {
+        Some(name) if name == crate::json_string(profile.name()) => {}
+        Some(name) => problems.push(format!("it was compiled with --profile {}", name.trim_matches('"'))),
+        None => problems.push(format!("it wasn't compiled with --profile {}", profile.name())),
+    }
+    if value("profile").is_some() && value("restrictions") != Some(profile.restrictions_json().as_str()) {
+        problems.push(format!(
+            "its restrictions are {}, not {}; a different matthiashihic compiled it",
+            value("restrictions").unwrap_or("missing"),
+            profile.restrictions_json()
+        ));
+    }
+    for (key, found) in fields {
+        let giveaway = GIVEAWAYS
+            .iter()
+            .find(|(restriction, giveaway, expected)| *giveaway == key && expected.is_none_or(|expected| expected == found) && profile.restrictions().contains(restriction));
+        if let Some((restriction, ..)) = giveaway {
+            problems.push(format!("{}: its metadata says \"{}\":{}", restriction, key, found));
+        }
+    }
+    problems
+}
@@ -0,0 +1,494 @@
+//! `matthiashihic install <source> [--name <name>] [--force] [<compile flag>...]`
+//! compiles a program into the bin/ of `paths::install_dir` and records it in
+//! registry.jsonl next to it: where its source is, the flags it was compiled
+//! with and by which compiler. `list` shows what's installed, `uninstall`
+//! removes programs and `upgrade` compiles them again from their sources, e.g.
+//! with `--all` after the compiler was upgraded.
+//!
+//! `get` installs through here too, with the `origin` it fetched from.
+//!
+//! The compiling is done by running this compiler again, so an installed
+//! program is built exactly like one compiled by hand.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::front_matter::parse_json_object;
+use crate::i18n::tr;
+use crate::parser::parse_file;
+use crate::{json_string, paths};
+
+const COMPILER: &str = concat!("matthiashihic ", env!("CARGO_PKG_VERSION"));
+
+/// One installed program, a line of registry.jsonl.
+struct Entry {
+    name: String,
+    /// The source, absolute.
+    source: PathBuf,
+    /// Where it was installed from; relative paths in `args` are relative to it.
+    dir: PathBuf,
+    /// The compile flags, without the source and `-o`.
+    args: Vec<String>,
+    /// `github:user/repo/path@commit` for a program `get` fetched.
+    origin: Option<String>,
+    /// The compiler that built it, like "matthiashihic 0.1.0".
+    compiler: String,
+    version: Option<String>,
+    about: Option<String>,
+    /// When it was last compiled, in seconds since the epoch.
+    installed: u64,
+}
+
+/// `matthiashihic install`.
+pub fn install(args: &[String]) -> i32 {
+    match args.first() {
+        Some(arg) if arg == "-h" || arg == "--help" => {
+            println!("{}", tr!("install.usage"));
+            0
+        }
+        Some(source) if !source.starts_with('-') => install_source(source, &args[1..], None),
+        _ => {
+            eprintln!("{}", tr!("install.usage"));
+            2
+        }
+    }
+}
+
+/// Installs the program at `source` with install's flags in `args`. `origin`
+/// is where `get` fetched it from.
+pub(crate) fn install_source(source: &str, args: &[String], origin: Option<String>) -> i32 {
+    let mut name = None;
+    let mut force = false;
+    let mut flags = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--name" => match args.get(i + 1) {
+                Some(value) => {
+                    name = Some(value.clone());
+                    i += 1;
+                }
+                None => {
+                    eprintln!("--name needs the name to install the program as");
+                    return 2;
+                }
+            },
+            "--force" => force = true,
+            "-o" => {
+                eprintln!("install picks the output itself; use --name to change what it's called");
+                return 2;
+            }
+            "--api-key" => {
+                // The registry is plain text, and upgrades would need the key again
+                eprintln!("install doesn't embed API keys; the program reads OPENAI_API_KEY when it runs");
+                return 2;
+            }
+            "bake" => {
+                eprintln!("A baked program has nothing to upgrade; bake it with -o instead");
+                return 2;
+            }
+            arg => {
+                if arg.contains('\n') {
+                    eprintln!("Compile flags for install can't contain line breaks");
+                    return 2;
+                }
+                flags.push(arg.to_string());
+            }
+        }
+        i += 1;
+    }
+    let Some(home) = paths::install_dir() else {
+        eprintln!("No home directory to install into; set MATTHIASHIHIC_HOME");
+        return 1;
+    };
+    let (source, dir) = match (fs::canonicalize(source), std::env::current_dir()) {
+        (Ok(source), Ok(dir)) => (source, dir),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{}", tr!("cli.read-failed", file = source, error = e));
+            return 1;
+        }
+    };
+    let mut entry = match describe(&source) {
+        Ok(entry) => entry,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    if let Some(name) = name {
+        entry.name = name;
+    }
+    if !is_command_name(&entry.name) {
+        eprintln!(
+            "'{}' can't be a command name; use --name with letters, digits, '-', '_' and '.' only",
+            entry.name
+        );
+        return 2;
+    }
+    entry.dir = dir;
+    entry.args = flags;
+    entry.origin = origin;
+
+    let mut registry = match read_registry(&home) {
+        Ok(registry) => registry,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    if let Some(other) = registry.iter().find(|other| other.name == entry.name) {
+        // The same program at another commit is an update, not another program
+        let unpinned = |origin: &str| origin.rsplit_once('@').map_or(origin.to_string(), |(unpinned, _)| unpinned.to_string());
+        let same = other.source == entry.source
+            || other.origin.as_deref().zip(entry.origin.as_deref()).is_some_and(|(a, b)| unpinned(a) == unpinned(b));
+        if !same && !force {
+            eprintln!(
+                "{} is installed from {} already; uninstall it, or use --force to replace it",
+                entry.name,
+                other.source.display()
+            );
+            return 1;
+        }
+    }
+    if let Err(e) = compile(&home, &mut entry) {
+        eprintln!("{}", e);
+        return 1;
+    }
+    registry.retain(|other| other.name != entry.name);
+    println!("Installed {} as {}", entry.source.display(), binary(&home, &entry.name).display());
+    registry.push(entry);
+    if let Err(e) = write_registry(&home, &registry) {
+        eprintln!("{}", e);
+        return 1;
+    }
+    // A program fetched again may come from another revision now
+    prune_fetched(&home, &registry);
+    let bin = home.join("bin");
+    let on_path = std::env::var_os("PATH").is_some_and(|path| std::env::split_paths(&path).any(|dir| dir == bin));
+    if !on_path {
+        println!("{} isn't on your PATH yet; add it to run programs by their name", bin.display());
+    }
+    0
+}
+
+/// `matthiashihic list`.
+pub fn list(args: &[String]) -> i32 {
+    if let Some(arg) = args.first() {
+        if arg == "-h" || arg == "--help" {
+            println!("{}", tr!("list.usage"));
+            return 0;
+        }
+        eprintln!("{}", tr!("list.unknown-flag", flag = arg));
+        return 2;
+    }
+    let Some(home) = paths::install_dir() else {
+        eprintln!("No home directory to look in; set MATTHIASHIHIC_HOME");
+        return 1;
+    };
+    let registry = match read_registry(&home) {
+        Ok(registry) => registry,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    if registry.is_empty() {
+        println!("Nothing installed yet; try 'matthiashihic install <source.matthiashihic>'");
+        return 0;
+    }
+    let width = registry.iter().map(|entry| entry.name.len()).max().unwrap_or(0);
+    let mut outdated = 0;
+    for entry in &registry {
+        let mut notes = Vec::new();
+        if entry.compiler != COMPILER {
+            notes.push(format!("built by {}", entry.compiler));
+            outdated += 1;
+        }
+        if !entry.source.exists() {
+            notes.push("source is gone".to_string());
+        }
+        let notes = if notes.is_empty() { String::new() } else { format!(" ({})", notes.join(", ")) };
+        println!(
+            "{:<width$}  {:<8}  {}{}",
+            entry.name,
+            entry.version.as_deref().unwrap_or("-"),
+            entry.origin.clone().unwrap_or_else(|| entry.source.display().to_string()),
+            notes,
+            width = width
+        );
+        if let Some(about) = &entry.about {
+            println!("{:<width$}  {}", "", about, width = width);
+        }
+    }
+    if outdated > 0 {
+        println!("{} program(s) were built by another compiler; 'matthiashihic upgrade --all' rebuilds them", outdated);
+    }
+    0
+}
+
+/// `matthiashihic uninstall`.
+pub fn uninstall(args: &[String]) -> i32 {
+    let mut names = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", tr!("uninstall.usage"));
+                return 0;
+            }
+            flag if flag.starts_with('-') => {
+                eprintln!("{}", tr!("uninstall.unknown-flag", flag = flag));
+                return 2;
+            }
+            name => names.push(name),
+        }
+    }
+    if names.is_empty() {
+        eprintln!("{}", tr!("uninstall.usage"));
+        return 2;
+    }
+    let Some(home) = paths::install_dir() else {
+        eprintln!("No home directory to look in; set MATTHIASHIHIC_HOME");
+        return 1;
+    };
+    let mut registry = match read_registry(&home) {
+        Ok(registry) => registry,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let mut status = 0;
+    for name in names {
+        if !registry.iter().any(|entry| entry.name == name) {
+            eprintln!("{} isn't installed", name);
+            status = 1;
+            continue;
+        }
+        let path = binary(&home, name);
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                eprintln!("Can't remove {}: {}", path.display(), e);
+                status = 1;
+                continue;
+            }
+        }
+        registry.retain(|entry| entry.name != name);
+        println!("Uninstalled {}", name);
+    }
+    if let Err(e) = write_registry(&home, &registry) {
+        eprintln!("{}", e);
+        return 1;
+    }
+    prune_fetched(&home, &registry);
+    status
+}
+
+/// `matthiashihic upgrade`.
+pub fn upgrade(args: &[String]) -> i32 {
+    let mut all = false;
+    let mut names = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--all" => all = true,
+            "-h" | "--help" => {
+                println!("{}", tr!("upgrade.usage"));
+                return 0;
+            }
+            flag if flag.starts_with('-') => {
+                eprintln!("{}", tr!("upgrade.unknown-flag", flag = flag));
+                return 2;
+            }
+            name => names.push(name.to_string()),
+        }
+    }
+    // Either --all or names, not both
+    if all != names.is_empty() {
+        eprintln!("{}", tr!("upgrade.usage"));
+        return 2;
+    }
+    let Some(home) = paths::install_dir() else {
+        eprintln!("No home directory to look in; set MATTHIASHIHIC_HOME");
+        return 1;
+    };
+    let mut registry = match read_registry(&home) {
+        Ok(registry) => registry,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let mut status = 0;
+    if let Some(name) = names.iter().find(|name| !registry.iter().any(|entry| &entry.name == *name)) {
+        eprintln!("{} isn't installed", name);
+        return 1;
+    }
+    let mut upgraded = 0;
+    for entry in registry.iter_mut().filter(|entry| all || names.contains(&entry.name)) {
+        // The source may have a new version or description by now
+        let (version, about) = match describe(&entry.source) {
+            Ok(described) => (described.version, described.about),
+            Err(e) => {
+                eprintln!("Can't upgrade {}: {}", entry.name, e);
+                status = 1;
+                continue;
+            }
+        };
+        entry.version = version;
+        entry.about = about;
+        match compile(&home, entry) {
+            Ok(()) => {
+                println!("Upgraded {}", entry.name);
+                upgraded += 1;
+            }
+            Err(e) => {
+                eprintln!("Can't upgrade {}: {}", entry.name, e);
+                status = 1;
+            }
+        }
+    }
+    if let Err(e) = write_registry(&home, &registry) {
+        eprintln!("{}", e);
+        return 1;
+    }
+    if all && registry.is_empty() {
+        println!("Nothing installed yet");
+    } else if all {
+        println!("Upgraded {} of {} program(s)", upgraded, registry.len());
+    }
+    status
+}
+
+/// An entry for the program at `source`, named by its `name:` or its file.
+fn describe(source: &Path) -> Result<Entry, String> {
+    let contents =
+        fs::read_to_string(source).map_err(|e| tr!("cli.read-failed", file = source.display(), error = e))?;
+    let (program, _) = parse_file(&contents, source).map_err(|e| format!("{}: {}", source.display(), e))?;
+    let stem = source.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    Ok(Entry {
+        name: program.info.name.filter(|name| is_command_name(name)).unwrap_or(stem),
+        source: source.to_path_buf(),
+        dir: PathBuf::new(),
+        args: Vec::new(),
+        origin: None,
+        compiler: COMPILER.to_string(),
+        version: program.info.version,
+        about: program.info.about,
+        installed: 0,
+    })
+}
+
+/// Compiles `entry` into bin/ with this compiler, then records that it did.
+fn compile(home: &Path, entry: &mut Entry) -> Result<(), String> {
+    let bin = home.join("bin");
+    fs::create_dir_all(&bin).map_err(|e| format!("Can't create {}: {}", bin.display(), e))?;
+    let compiler = std::env::current_exe().map_err(|e| format!("Can't find the compiler to run: {}", e))?;
+    let status = Command::new(compiler)
+        .arg(&entry.source)
+        .args(&entry.args)
+        .arg("-o")
+        .arg(binary(home, &entry.name))
+        .current_dir(&entry.dir)
+        .status()
+        .map_err(|e| format!("Can't run the compiler in {}: {}", entry.dir.display(), e))?;
+    if !status.success() {
+        return Err(format!("Compiling {} failed", entry.source.display()));
+    }
+    entry.compiler = COMPILER.to_string();
+    entry.installed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    Ok(())
+}
+
+/// Removes the checkouts in src/ that `get` made and no installed program
+/// comes from anymore. Ones still being fetched start with a dot.
+fn prune_fetched(home: &Path, registry: &[Entry]) {
+    let Ok(src) = fs::canonicalize(home.join("src")) else {
+        return;
+    };
+    let Ok(checkouts) = fs::read_dir(&src) else {
+        return;
+    };
+    for checkout in checkouts.flatten() {
+        let path = checkout.path();
+        let fetching = checkout.file_name().to_string_lossy().starts_with('.');
+        if !fetching && !registry.iter().any(|entry| entry.source.starts_with(&path)) {
+            let _ = fs::remove_dir_all(&path);
+        }
+    }
+}
+
+fn binary(home: &Path, name: &str) -> PathBuf {
+    home.join("bin").join(format!("{}{}", name, std::env::consts::EXE_SUFFIX))
+}
+
+/// Whether `name` works as a file name in bin/ and as a command.
+fn is_command_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(['.', '-'])
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+fn read_registry(home: &Path) -> Result<Vec<Entry>, String> {
+    let file = home.join("registry.jsonl");
+    let contents = match fs::read_to_string(&file) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(tr!("cli.read-failed", file = file.display(), error = e)),
+    };
+    let mut entries = Vec::new();
+    for (number, line) in contents.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let at = |e: String| format!("{}:{}: {}", file.display(), number + 1, e);
+        let record = parse_json_object(line).map_err(at)?;
+        let field = |key: &str| record.iter().find(|(k, _)| k == key).map(|(_, value)| value.clone());
+        let required = |key: &str| field(key).ok_or_else(|| at(format!("missing \"{}\"", key)));
+        entries.push(Entry {
+            name: required("name")?,
+            source: PathBuf::from(required("source")?),
+            dir: PathBuf::from(required("dir")?),
+            // One flag per line; install doesn't take flags with line breaks
+            args: field("args").map(|args| args.lines().map(str::to_string).collect()).unwrap_or_default(),
+            origin: field("origin"),
+            compiler: field("compiler").unwrap_or_default(),
+            version: field("version"),
+            about: field("about"),
+            installed: field("installed").and_then(|time| time.parse().ok()).unwrap_or(0),
+        });
+    }
+    Ok(entries)
+}
+
+/// Replaces registry.jsonl with `entries`, by way of a temp file so a failed
+/// write can't lose what was installed.
+fn write_registry(home: &Path, entries: &[Entry]) -> Result<(), String> {
+    let mut contents = String::new();
+    for entry in entries {
+        let mut fields = vec![
+            format!("\"name\":{}", json_string(&entry.name)),
+            format!("\"source\":{}", json_string(&entry.source.to_string_lossy())),
+            format!("\"dir\":{}", json_string(&entry.dir.to_string_lossy())),
+            format!("\"args\":{}", json_string(&entry.args.join("\n"))),
+            format!("\"compiler\":{}", json_string(&entry.compiler)),
+        ];
+        if let Some(origin) = &entry.origin {
+            fields.push(format!("\"origin\":{}", json_string(origin)));
+        }
+        if let Some(version) = &entry.version {
+            fields.push(format!("\"version\":{}", json_string(version)));
+        }
+        if let Some(about) = &entry.about {
+            fields.push(format!("\"about\":{}", json_string(about)));
+        }
+        fields.push(format!("\"installed\":{}", entry.installed));
+        contents.push_str(&format!("{{{}}}\n", fields.join(",")));
+    }
+    let file = home.join("registry.jsonl");
+    let temp = home.join("registry.jsonl.tmp");
+    fs::create_dir_all(home)
+        .and_then(|()| fs::write(&temp, contents))
+        .and_then(|()| fs::rename(&temp, &file))
+        .map_err(|e| tr!("cli.write-failed", file = file.display(), error = e))
+}
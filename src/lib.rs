@@ -0,0 +1,44 @@
+//! The compiler's front end: the parser, the AST and how it's written back as
+//! source, and the diagnostics with their fixes. The `matthiashihic` binary is
+//! built on it, and so is the fuzz target in `fuzz/`.
+
+pub mod ast;
+pub mod check;
+pub mod diagnostics;
+pub mod front_matter;
+pub mod i18n;
+pub mod parser;
+
+/// `s` as a JSON string, quotes and all.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Fuzzing entry point: parses arbitrary bytes and returns the program, if
+/// they are one. It must never panic, whatever the input, and a program has
+/// to parse the same after `to_source`.
+#[cfg(feature = "fuzz")]
+pub fn fuzz_parse(bytes: &[u8]) -> Option<ast::Program> {
+    let contents = std::str::from_utf8(bytes).ok()?;
+    // Fixes point into the source, wherever the error is
+    let _ = check::apply_fixes(contents, None);
+    let (program, _) = parser::parse_matthiashihic(contents).ok()?;
+    // Formatting must never change what a program means
+    if let Err(e) = parser::format_program(&program, None) {
+        panic!("{}", e);
+    }
+    Some(program)
+}
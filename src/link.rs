@@ -0,0 +1,400 @@
+//! Linking `extern` programs.
+//!
+//!   extern summarize = "summarize.matthiashihic"
+//!   "summarize(€1)"
+//!
+//! Inline linking (the default) replaces each call with the callee's statements,
+//! its placeholders filled by the call's arguments and its `-> name` bindings
+//! renamed to `extern.name`, so they can't clash with the caller's. A binding
+//! of the call itself moves to the last of them. Dynamic linking keeps the
+//! call; the generated binary runs the callee's compiled binary, which has to
+//! sit next to it, and uses its output as the call's answer.
+//!
+//! Uses of a `recipe` are always inlined the same way, as if the recipe were
+//! an extern of its own.
+
+use std::path::{Path, PathBuf};
+
+use crate::ast::{self, ArgType, Program, Recipe, Segment, Statement};
+use crate::parser::parse_file;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LinkMode {
+    Inline,
+    Dynamic,
+}
+
+impl LinkMode {
+    pub fn parse(s: &str) -> Result<LinkMode, String> {
+        match s {
+            "inline" => Ok(LinkMode::Inline),
+            "dynamic" => Ok(LinkMode::Dynamic),
+            other => Err(format!("Unknown link mode '{}' (supported: inline, dynamic)", other)),
+        }
+    }
+}
+
+/// Resolves the extern calls of `program`, read from `source`. Inline calls are
+/// replaced in place; dynamic ones are checked and left for the generated binary.
+pub fn link(program: &mut Program, source: &Path, mode: LinkMode) -> Result<(), String> {
+    let mut stack = vec![canonical(source)];
+    link_program(program, source, mode, &mut stack)?;
+    // Known only now, since a recipe or an extern may come first
+    let arm_b = program.experiment.as_ref().filter(|experiment| experiment.index == 0).map(|experiment| &experiment.b);
+    if let Some(first) = program.statements.first().into_iter().chain(arm_b).find(|first| first.uses_prev()) {
+        return Err(format!("€prev in the first statement has no statement before it to pipe in: {}", first.to_line()));
+    }
+    Ok(())
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn link_program(program: &mut Program, source: &Path, mode: LinkMode, stack: &mut Vec<PathBuf>) -> Result<(), String> {
+    expand_macros(program);
+    inline_recipes(program)?;
+    let dir = source.parent().unwrap_or(Path::new("."));
+    let mut statements = Vec::new();
+    let mut starts = Vec::new();
+    for statement in std::mem::take(&mut program.statements) {
+        starts.push(statements.len());
+        let Some(Segment::Call { name, args }) = statement.segments.first() else {
+            statements.push(statement);
+            continue;
+        };
+        let path = program
+            .externs
+            .iter()
+            .find(|(declared, _)| declared == name)
+            .map(|(_, path)| dir.join(path))
+            .ok_or_else(|| format!("Call to undeclared extern '{}'", name))?;
+        let mut callee = load(&path, name, args.len())?;
+
+        match mode {
+            LinkMode::Dynamic => statements.push(statement),
+            LinkMode::Inline => {
+                let key = canonical(&path);
+                if stack.contains(&key) {
+                    return Err(format!("extern '{}' ends up calling itself ({})", name, path.display()));
+                }
+                if callee.experiment.is_some() {
+                    return Err(format!("extern '{}' runs a variant A/B experiment, which only the main program can", name));
+                }
+                if !callee.loops.is_empty() {
+                    return Err(format!("extern '{}' has an again! loop, which only the main program can; use --link dynamic", name));
+                }
+                if !callee.branches.is_empty() {
+                    return Err(format!("extern '{}' has an if the vibe is, which only the main program can; use --link dynamic", name));
+                }
+                if !callee.parallels.is_empty() {
+                    return Err(format!("extern '{}' has an all at once! block, which only the main program can; use --link dynamic", name));
+                }
+                if !callee.every_line.is_empty() {
+                    return Err(format!("extern '{}' has a for every line block, which only the main program can; use --link dynamic", name));
+                }
+                stack.push(key);
+                link_program(&mut callee, &path, mode, stack)?;
+                stack.pop();
+
+                carry_arg_types(&mut program.arg_types, &callee.arg_types, name, args)?;
+                for secret in &callee.secrets {
+                    if !program.secrets.contains(secret) {
+                        program.secrets.push(secret.clone());
+                    }
+                }
+                for name in &callee.env_vars {
+                    if !program.env_vars.contains(name) {
+                        program.env_vars.push(name.clone());
+                    }
+                }
+                inline(&mut statements, name, callee.statements, args, &statement)?;
+            }
+        }
+    }
+    program.statements = statements;
+    renumber(program, &starts);
+    program.arg_types.sort_by_key(|(index, _)| *index);
+    program.secrets.sort();
+    program.env_vars.sort();
+    Ok(())
+}
+
+/// Inlining moves every statement after a call: `starts` has the new position
+/// of each old statement. Moves the experiment, the loops, the branches, the
+/// `all at once!` blocks, the includes and the comments along.
+fn renumber(program: &mut Program, starts: &[usize]) {
+    let end = program.statements.len();
+    let start = |index: usize| starts.get(index).copied().unwrap_or(end);
+    if let Some(experiment) = &mut program.experiment {
+        experiment.index = start(experiment.index);
+    }
+    for looped in &mut program.loops {
+        let first = start(looped.first);
+        looped.len = start(looped.first + looped.len) - first;
+        looped.first = first;
+    }
+    for branch in &mut program.branches {
+        let first = start(branch.first);
+        let otherwise = start(branch.first + branch.then_len);
+        branch.else_len = start(branch.end()) - otherwise;
+        branch.then_len = otherwise - first;
+        branch.first = first;
+    }
+    for parallel in &mut program.parallels {
+        let first = start(parallel.first);
+        parallel.len = start(parallel.first + parallel.len) - first;
+        parallel.first = first;
+    }
+    for every_line in &mut program.every_line {
+        let first = start(every_line.first);
+        every_line.len = start(every_line.first + every_line.len) - first;
+        every_line.first = first;
+    }
+    for include in &mut program.includes {
+        let first = start(include.first);
+        include.len = start(include.first + include.len) - first;
+        include.first = first;
+    }
+    for comment in &mut program.comments {
+        comment.before = comment.before.map(start);
+    }
+}
+
+/// Puts the expansion of every macro use in its place.
+fn expand_macros(program: &mut Program) {
+    let recipes = program.recipes.iter_mut().flat_map(|recipe| recipe.statements.iter_mut());
+    let arm_b = program.experiment.iter_mut().map(|experiment| &mut experiment.b);
+    for statement in program.statements.iter_mut().chain(recipes).chain(arm_b) {
+        statement.segments = ast::expand_macros(std::mem::take(&mut statement.segments));
+    }
+    for looped in &mut program.loops {
+        looped.count = ast::expand_macros(std::mem::take(&mut looped.count));
+    }
+}
+
+/// Replaces every use of a recipe with its statements.
+fn inline_recipes(program: &mut Program) -> Result<(), String> {
+    if program.recipes.is_empty() {
+        return Ok(());
+    }
+    let mut statements = Vec::new();
+    let mut starts = Vec::new();
+    for statement in std::mem::take(&mut program.statements) {
+        starts.push(statements.len());
+        use_recipe(&program.recipes, statement, &mut statements, &mut program.arg_types)?;
+    }
+    program.statements = statements;
+    renumber(program, &starts);
+    program.arg_types.sort_by_key(|(index, _)| *index);
+    Ok(())
+}
+
+/// Appends `statement` to `statements`, or the statements of the recipe it
+/// uses. `arg_types` gets the types the recipe puts on its arguments.
+fn use_recipe(
+    recipes: &[Recipe],
+    statement: Statement,
+    statements: &mut Vec<Statement>,
+    arg_types: &mut Vec<(usize, ArgType)>,
+) -> Result<(), String> {
+    let Some(Segment::Recipe { name, args }) = statement.segments.first() else {
+        statements.push(statement);
+        return Ok(());
+    };
+    let recipe = recipes
+        .iter()
+        .find(|recipe| recipe.name == *name)
+        .ok_or_else(|| format!("Use of undeclared recipe '{}'", name))?;
+    // Recipes may use the ones declared before them
+    let mut body = Vec::new();
+    let mut types = Vec::new();
+    for inner in &recipe.statements {
+        use_recipe(recipes, inner.clone(), &mut body, &mut types)?;
+    }
+    for segment in body.iter().flat_map(|statement| &statement.segments) {
+        if let Segment::Placeholder { index, ty: Some(ty), .. } = segment {
+            if !types.contains(&(*index, *ty)) {
+                types.push((*index, *ty));
+            }
+        }
+    }
+    carry_arg_types(arg_types, &types, name, args)?;
+    inline(statements, name, body, args, &statement)
+}
+
+/// A type on the callee's €n carries over to the caller's placeholder if the
+/// argument is a plain one.
+fn carry_arg_types(
+    arg_types: &mut Vec<(usize, ArgType)>,
+    callee_types: &[(usize, ArgType)],
+    name: &str,
+    args: &[Vec<Segment>],
+) -> Result<(), String> {
+    for (index, ty) in callee_types {
+        if let [Segment::Placeholder { index: arg, .. }] = args[index - 1].as_slice() {
+            match arg_types.iter().find(|(existing, _)| existing == arg) {
+                Some((_, existing)) if existing != ty => {
+                    return Err(format!(
+                        "€{} is passed to '{}' as :{} but annotated as :{}",
+                        arg,
+                        name,
+                        ty.name(),
+                        existing.name()
+                    ))
+                }
+                Some(_) => {}
+                None => arg_types.push((*arg, *ty)),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Appends the callee's statements, filled in with `args`; a binding,
+/// `as json`, `write it to` or `better be` of the call goes to the last of them, and its `using`,
+/// temperature and retries to every one that doesn't say its own.
+fn inline(
+    statements: &mut Vec<Statement>,
+    name: &str,
+    callee: Vec<Statement>,
+    args: &[Vec<Segment>],
+    call: &Statement,
+) -> Result<(), String> {
+    let first = statements.len();
+    for inlined in callee {
+        let inlined = substitute(inlined, name, args).map_err(|e| format!("{} (in '{}')", e, name))?;
+        // An extern's expectations are about its own input; its own --check checks them
+        statements.push(Statement { expect: None, ..inlined });
+    }
+    if let Some(model) = &call.model {
+        for inlined in &mut statements[first..] {
+            inlined.model.get_or_insert_with(|| model.clone());
+        }
+    }
+    if let Some(temperature) = call.temperature {
+        for inlined in &mut statements[first..] {
+            inlined.temperature.get_or_insert(temperature);
+        }
+    }
+    if let Some(retries) = call.retries {
+        for inlined in &mut statements[first..] {
+            inlined.retries.get_or_insert(retries);
+        }
+    }
+    if let Some(schema) = &call.schema {
+        match statements[first..].last_mut() {
+            Some(last) if last.schema.is_none() => last.schema = Some(schema.clone()),
+            Some(_) => {
+                return Err(format!(
+                    "'{}' says 'as json' for its last statement itself, so the call can't say it again",
+                    name
+                ))
+            }
+            None => return Err(format!("'{}' has no statements whose answer could be json", name)),
+        }
+    }
+    if let Some(path) = &call.write_to {
+        match statements[first..].last_mut() {
+            Some(last) if last.write_to.is_none() => last.write_to = Some(path.clone()),
+            Some(_) => {
+                return Err(format!(
+                    "'{}' writes the answer of its last statement to a file itself, so the call can't say 'write it to' again",
+                    name
+                ))
+            }
+            None => return Err(format!("'{}' has no statements whose answer could be written to \"{}\"", name, path)),
+        }
+    }
+    if let Some(expected) = &call.expect {
+        if let Some(last) = statements[first..].last_mut() {
+            last.expect = Some(expected.clone());
+        }
+    }
+    if let Some(binding) = &call.binding {
+        match statements[first..].last_mut() {
+            Some(last) if last.binding.is_none() => last.binding = Some(binding.clone()),
+            Some(_) => {
+                return Err(format!(
+                    "'{}' binds the answer of its last statement itself, so '-> {}' can't bind the call; bind it in '{}' instead",
+                    name, binding, name
+                ))
+            }
+            None => return Err(format!("'{}' has no statements whose answer '-> {}' could bind", name, binding)),
+        }
+    }
+    Ok(())
+}
+
+/// File name of an extern's compiled binary: its source file name without the extension.
+pub fn binary_name(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string())
+}
+
+/// Reads and parses a callee and checks that it takes `arity` stdin lines.
+fn load(path: &Path, name: &str, arity: usize) -> Result<Program, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read extern '{}' from {}: {}", name, path.display(), e))?;
+    // The callee's warnings are its own business; it gets compiled on its own, too
+    let (callee, _) = parse_file(&contents, path).map_err(|e| format!("Parse error in {}: {}", path.display(), e))?;
+    if let Some(named) = callee.named_args.first() {
+        return Err(format!("'{}' uses €{{{}}}, but a called program only gets its arguments as €1, €2, ...", name, named));
+    }
+    let expected = callee.required_args.last().copied().unwrap_or(0);
+    if expected != arity {
+        return Err(format!(
+            "'{}' takes {} argument(s), but is called with {}",
+            name, expected, arity
+        ));
+    }
+    Ok(callee)
+}
+
+/// Fills the callee's placeholders with the caller's argument segments and
+/// moves its bindings into the `callee.` namespace.
+fn substitute(statement: Statement, callee: &str, args: &[Vec<Segment>]) -> Result<Statement, String> {
+    let segments = substitute_segments(statement.segments, callee, args)?;
+    let binding = statement.binding.map(|binding| format!("{}.{}", callee, binding));
+    Ok(Statement { segments, binding, ..statement })
+}
+
+fn substitute_segments(statement: Vec<Segment>, callee: &str, args: &[Vec<Segment>]) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    for segment in statement {
+        match segment {
+            Segment::Placeholder { index, .. } => segments.extend(args[index - 1].iter().cloned()),
+            // Every argument, one per line, like the callee's stdin would have them
+            Segment::AllArgs => {
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        segments.push(Segment::Text("\n".to_string()));
+                    }
+                    segments.extend(arg.iter().cloned());
+                }
+            }
+            // The path has to come from the caller's stdin, too
+            Segment::File { index, pages } => match args[index - 1].as_slice() {
+                [Segment::Placeholder { index: arg, .. }] => segments.push(Segment::File { index: *arg, pages }),
+                _ => return Err(format!("€file({}) has to be passed a plain placeholder like €1", index)),
+            },
+            Segment::Dir { index, glob, max_bytes } => match args[index - 1].as_slice() {
+                [Segment::Placeholder { index: arg, .. }] => segments.push(Segment::Dir { index: *arg, glob, max_bytes }),
+                _ => return Err(format!("€dir({}) has to be passed a plain placeholder like €1", index)),
+            },
+            Segment::Var(var) => segments.push(Segment::Var(format!("{}.{}", callee, var))),
+            // A recipe passes its own arguments on to the externs it calls
+            Segment::Call { name, args: call_args } => {
+                let call_args = call_args
+                    .into_iter()
+                    .map(|arg| substitute_segments(arg, callee, args))
+                    .collect::<Result<Vec<_>, _>>()?;
+                segments.push(Segment::Call { name, args: call_args });
+            }
+            other => segments.push(other),
+        }
+    }
+    Ok(segments)
+}
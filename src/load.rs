@@ -0,0 +1,397 @@
+//! `matthiashihic load <url> [--rps <n>] [--duration <time>] [--args-file <inputs.jsonl>]`
+//! sends chat completion requests to a `--serve`d program (or anything else
+//! that speaks the same API) at a fixed rate and reports latency percentiles
+//! and error rates. Plain HTTP only; served programs listen on localhost.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::i18n::tr;
+
+/// Requests per second unless `--rps` says otherwise.
+const DEFAULT_RPS: f64 = 10.0;
+const DEFAULT_DURATION: Duration = Duration::from_secs(10);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+/// Requests that may wait for an answer at once; a tick beyond that is counted
+/// as dropped instead of piling up threads.
+const DEFAULT_MAX_IN_FLIGHT: usize = 256;
+/// Sent when there's no `--args-file`.
+const DEFAULT_MESSAGE: &str = "\"hihi!\"";
+
+/// Where the requests go.
+struct Target {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Target {
+    /// `http://host[:port][/path]`. A path that doesn't already end in
+    /// `/chat/completions` gets it appended, so the URL `--serve` prints works.
+    fn parse(url: &str) -> Result<Target, String> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("load only speaks plain HTTP, got '{}'", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| format!("Invalid port in '{}'", url))?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("No host in '{}'", url));
+        }
+        let path = if path.ends_with("/chat/completions") {
+            path.to_string()
+        } else if path.ends_with("/v1") {
+            format!("{}/chat/completions", path)
+        } else {
+            format!("{}/v1/chat/completions", path)
+        };
+        Ok(Target { host: host.to_string(), port, path })
+    }
+}
+
+/// How one request went.
+enum Outcome {
+    /// Total time, and with `--stream` the time to the first piece of content.
+    Ok { total: Duration, first_token: Option<Duration> },
+    Failed(String),
+    /// Not sent, because `max_in_flight` requests were still waiting.
+    Dropped,
+}
+
+/// Runs the subcommand and returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let mut url = None;
+    let mut rps = DEFAULT_RPS;
+    let mut duration = DEFAULT_DURATION;
+    let mut timeout = DEFAULT_TIMEOUT;
+    let mut max_in_flight = DEFAULT_MAX_IN_FLIGHT;
+    let mut args_file = None;
+    let mut stream = false;
+    let mut i = 0;
+    while i < args.len() {
+        let value = args.get(i + 1).map(|s| s.as_str());
+        let parsed = match (args[i].as_str(), value) {
+            ("--stream", _) => {
+                stream = true;
+                i += 1;
+                continue;
+            }
+            ("--rps", Some(v)) => v.parse().ok().filter(|n: &f64| *n > 0.0 && n.is_finite()).map(|n| rps = n),
+            ("--duration", Some(v)) => parse_duration(v).map(|d| duration = d),
+            ("--timeout", Some(v)) => parse_duration(v).map(|d| timeout = d),
+            ("--max-in-flight", Some(v)) => v.parse().ok().filter(|n| *n > 0).map(|n| max_in_flight = n),
+            ("--args-file", Some(v)) => Some(v).map(|v| args_file = Some(v.to_string())),
+            (flag @ ("--rps" | "--duration" | "--timeout" | "--max-in-flight" | "--args-file"), None) => {
+                eprintln!("{} requires a value", flag);
+                return 2;
+            }
+            (s, _) if s.starts_with('-') => {
+                eprintln!("{}", tr!("load.unknown-flag", flag = s));
+                return 2;
+            }
+            (s, _) if url.is_none() => {
+                url = Some(s.to_string());
+                i += 1;
+                continue;
+            }
+            _ => {
+                eprintln!("{}", tr!("load.usage"));
+                return 2;
+            }
+        };
+        if parsed.is_none() {
+            eprintln!("Invalid value for {}: '{}'", args[i], value.unwrap_or_default());
+            return 2;
+        }
+        i += 2;
+    }
+    let Some(url) = url else {
+        eprintln!("{}", tr!("load.usage"));
+        return 2;
+    };
+    let target = match Target::parse(&url) {
+        Ok(target) => target,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 2;
+        }
+    };
+    let messages = match args_file {
+        Some(path) => match read_args_file(&path) {
+            Ok(messages) => messages,
+            Err(e) => {
+                eprintln!("{}", e);
+                return 2;
+            }
+        },
+        None => vec![DEFAULT_MESSAGE.to_string()],
+    };
+    let bodies: Vec<String> = messages.iter().map(|message| request_body(message, stream)).collect();
+
+    eprintln!(
+        "Loading http://{}:{}{} with {} request(s)/s for {:.1}s ...",
+        target.host,
+        target.port,
+        target.path,
+        rps,
+        duration.as_secs_f64()
+    );
+    let report = Report::new(fire(Arc::new(target), &bodies, rps, duration, timeout, max_in_flight));
+    report.print(stream, duration);
+    if report.failed.is_empty() && report.dropped == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+/// Sends `bodies` round-robin, one request every 1/`rps` seconds, each on its
+/// own thread, and collects how every one of them went.
+fn fire(
+    target: Arc<Target>,
+    bodies: &[String],
+    rps: f64,
+    duration: Duration,
+    timeout: Duration,
+    max_in_flight: usize,
+) -> Vec<Outcome> {
+    let (tx, rx) = mpsc::channel();
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let interval = Duration::from_secs_f64(1.0 / rps);
+    let start = Instant::now();
+    let count = (duration.as_secs_f64() * rps).round().max(1.0) as u32;
+    for sent in 0..count {
+        // Open loop: the schedule doesn't wait for slow answers
+        if let Some(wait) = (start + interval * sent).checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+        let body = bodies[sent as usize % bodies.len()].clone();
+        if in_flight.load(Ordering::SeqCst) >= max_in_flight {
+            let _ = tx.send(Outcome::Dropped);
+            continue;
+        }
+        in_flight.fetch_add(1, Ordering::SeqCst);
+        let (tx, target, in_flight) = (tx.clone(), Arc::clone(&target), Arc::clone(&in_flight));
+        thread::spawn(move || {
+            let outcome = send(&target, &body, timeout);
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            let _ = tx.send(outcome);
+        });
+    }
+    drop(tx);
+    rx.into_iter().collect()
+}
+
+/// One request with a fresh connection, timed from connecting to the last byte.
+fn send(target: &Target, body: &str, timeout: Duration) -> Outcome {
+    let start = Instant::now();
+    let addr = match (target.host.as_str(), target.port).to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) {
+        Some(addr) => addr,
+        None => return Outcome::Failed(format!("cannot resolve {}", target.host)),
+    };
+    let mut stream = match TcpStream::connect_timeout(&addr, timeout) {
+        Ok(stream) => stream,
+        Err(e) => return Outcome::Failed(format!("connect: {}", e.kind())),
+    };
+    let _ = stream.set_read_timeout(Some(timeout));
+    let _ = stream.set_write_timeout(Some(timeout));
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        target.path,
+        target.host,
+        target.port,
+        body.len(),
+        body
+    );
+    if let Err(e) = stream.write_all(request.as_bytes()) {
+        return Outcome::Failed(format!("send: {}", e.kind()));
+    }
+
+    let mut response = Vec::new();
+    let mut first_token = None;
+    let mut buf = [0u8; 8192];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                response.extend_from_slice(&buf[..n]);
+                if first_token.is_none() && has_content(&response) {
+                    first_token = Some(start.elapsed());
+                }
+            }
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                return Outcome::Failed("timeout".into());
+            }
+            Err(e) => return Outcome::Failed(format!("receive: {}", e.kind())),
+        }
+    }
+    let total = start.elapsed();
+
+    let status = response
+        .split(|b| *b == b'\r')
+        .next()
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok());
+    match status {
+        Some(200) if contains(&response, b"data: {\"error\"") => Outcome::Failed("error event in stream".into()),
+        Some(200) if contains(&response, b"text/event-stream") && !contains(&response, b"data: [DONE]") => {
+            Outcome::Failed("stream ended without [DONE]".into())
+        }
+        Some(200) => Outcome::Ok { total, first_token },
+        Some(code) => Outcome::Failed(format!("HTTP {}", code)),
+        None => Outcome::Failed("not an HTTP response".into()),
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+/// Whether any non-empty content has arrived; the first chunk of a stream
+/// often only carries the role and `"content":""`.
+fn has_content(response: &[u8]) -> bool {
+    let needle = b"\"content\":\"";
+    response
+        .windows(needle.len() + 1)
+        .any(|window| window.starts_with(needle) && window[needle.len()] != b'"')
+}
+
+/// The request for one line of the args file: a JSON string is sent as the
+/// user's message, a JSON object as the whole request body.
+fn request_body(message: &str, stream: bool) -> String {
+    if message.starts_with('{') {
+        return message.to_string();
+    }
+    format!(
+        "{{\"messages\":[{{\"role\":\"user\",\"content\":{}}}],\"stream\":{}}}",
+        message, stream
+    )
+}
+
+/// The non-empty lines of a JSONL file; each has to be a JSON string or object.
+fn read_args_file(path: &str) -> Result<Vec<String>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| tr!("cli.read-failed", file = path, error = e))?;
+    let mut messages = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let quoted = line.len() >= 2 && line.starts_with('"') && line.ends_with('"');
+        let object = line.starts_with('{') && line.ends_with('}');
+        if !quoted && !object {
+            return Err(format!(
+                "{}:{}: each line has to be a JSON string (the message) or object (the request body)",
+                path,
+                idx + 1
+            ));
+        }
+        messages.push(line.to_string());
+    }
+    if messages.is_empty() {
+        return Err(format!("{} has no requests in it", path));
+    }
+    Ok(messages)
+}
+
+/// `30s`, `2m`, `500ms`, `12h`, `30d` or plain seconds.
+pub(crate) fn parse_duration(text: &str) -> Option<Duration> {
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(i) => text.split_at(i),
+        None => (text, "s"),
+    };
+    let number: f64 = number.parse().ok().filter(|n: &f64| *n > 0.0 && n.is_finite())?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        "d" => number * 86400.0,
+        _ => return None,
+    };
+    Some(Duration::from_secs_f64(seconds))
+}
+
+/// What the outcomes add up to.
+struct Report {
+    latencies: Vec<Duration>,
+    first_tokens: Vec<Duration>,
+    /// Error kinds with how often each happened, most frequent first.
+    failed: Vec<(String, usize)>,
+    dropped: usize,
+    total: usize,
+}
+
+impl Report {
+    fn new(outcomes: Vec<Outcome>) -> Report {
+        let total = outcomes.len();
+        let mut latencies = Vec::new();
+        let mut first_tokens = Vec::new();
+        let mut failed: Vec<(String, usize)> = Vec::new();
+        let mut dropped = 0;
+        for outcome in outcomes {
+            match outcome {
+                Outcome::Ok { total, first_token } => {
+                    latencies.push(total);
+                    first_tokens.extend(first_token);
+                }
+                Outcome::Failed(kind) => match failed.iter_mut().find(|(k, _)| *k == kind) {
+                    Some((_, count)) => *count += 1,
+                    None => failed.push((kind, 1)),
+                },
+                Outcome::Dropped => dropped += 1,
+            }
+        }
+        latencies.sort();
+        first_tokens.sort();
+        failed.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        Report { latencies, first_tokens, failed, dropped, total }
+    }
+
+    fn print(&self, stream: bool, duration: Duration) {
+        let errors: usize = self.failed.iter().map(|(_, count)| count).sum();
+        let percent = |count: usize| 100.0 * count as f64 / self.total.max(1) as f64;
+        println!("requests     {} ({:.1}/s)", self.total, self.total as f64 / duration.as_secs_f64());
+        println!("ok           {} ({:.1}%)", self.latencies.len(), percent(self.latencies.len()));
+        println!("errors       {} ({:.1}%)", errors, percent(errors));
+        for (kind, count) in &self.failed {
+            println!("  {:>6}     {}", count, kind);
+        }
+        if self.dropped > 0 {
+            println!("dropped      {} ({:.1}%, too many requests in flight)", self.dropped, percent(self.dropped));
+        }
+        print_percentiles("latency", &self.latencies);
+        if stream {
+            print_percentiles("first token", &self.first_tokens);
+        }
+    }
+}
+
+fn print_percentiles(label: &str, sorted: &[Duration]) {
+    if sorted.is_empty() {
+        return;
+    }
+    // Nearest rank
+    let at = |p: f64| sorted[((p * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len()) - 1];
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    println!(
+        "{:<13}p50 {:.0}ms  p90 {:.0}ms  p95 {:.0}ms  p99 {:.0}ms  max {:.0}ms",
+        label,
+        ms(at(0.50)),
+        ms(at(0.90)),
+        ms(at(0.95)),
+        ms(at(0.99)),
+        ms(sorted[sorted.len() - 1])
+    );
+}
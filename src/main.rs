@@ -1,45 +1,136 @@
 //! matthiashihic - compiler for *.matthiashihic that uses OpenAI to execute pseudocode
 //!
 //! Usage:
-//!   ./matthiashihic program.matthiashihic --api-key <OPENAI_API_KEY> [--model <MODEL_NAME>]
+//!   ./matthiashihic program.matthiashihic --api-key <OPENAI_API_KEY> [--model <MODEL_NAME>] [--sink <KIND:URL>]
 //!
 //! Specification:
-//!   hihi!                     -- required program header (first non-empty line)
+//!   hihi! (or hihi! v2)       -- required program header (first non-empty line); v2 allows speak and macro
+//!   ---  ...  ---             -- optional front-matter with per-program defaults
+//!   name: / about: / version: -- optional program metadata, before the first statement
+//!   persona "..."             -- optional character the model plays, before the first statement
+//!   speak <language>!         -- optional language every answer is written in, before the first statement
+//!   remember!                 -- optional; each stdin record continues the conversation before it
+//!   extern name = "file"      -- optional other program, called as "name(arg, ...)"
+//!   stuff: name = "value"     -- optional constants, used as €name in statements
+//!   macro name(x) = "... €x ..." -- optional text put in place of €name(arg) by the compiler
+//!   recipe name: ... serve it! -- optional statements used as "do name with a, b"
 //!   "text"                    -- only allowed statement; pseudocode to execute
+//!   "text" -> name            -- the same, its answer available as €name afterwards
+//!   "... €prev ..."           -- the answer of the statement before, piped in
+//!   "text" using gpt-4o-mini  -- the same, sent to that model instead of --model
+//!   "text" (chill), (wild)    -- the same, at temperature 0.2 or 1.2; or say temperature 0.7
+//!   "text" as json {k: [string]} -- the same, its answer JSON of that shape, checked
+//!   "text" or try again 3 times -- the same, asked up to 3 more times if it fails
+//!   "text" write it to "a.txt" -- the same, its answer written to that file instead of stdout
+//!   shell "git log -5"        -- a command run with sh; its output is €shell afterwards
+//!   better be "42"            -- after a statement: its answer, checked when run with --check
+//!   """ ... """               -- a statement spanning lines, sent as written
+//!   r"..." or r#"..."#        -- a statement without escapes, for regexes and paths
+//!   again! N times ... enough! -- statements run N times, the round as €loop
+//!   if the vibe is "yes" ... otherwise ... done! -- statements run if the answer before is "yes"
+//!   all at once! ... together! -- statements asked at the same time, answers in order
+//!   for every line ... that's all! -- statements run for each stdin line, the line as €line
+//!   nom nom "file"            -- another file's statements, in place of this line
+//!   hm, ...                   -- a comment line, skipped wherever it is
+//!   €1, €{name}               -- inside "text": a stdin line, or a --name / name=value input
+//!   €*                        -- inside "text": every stdin line, one per line
+//!   €1 or \"World\"           -- inside "text": the same, "World" if stdin has no line for it
 //!   eat that java!            -- required terminator; stop parsing here
 //!   anything after terminator -- ignored (comments)
 //!
 //! The compiler reads the pseudocode and sends it to OpenAI API for execution,
 //! streaming the response back to stdout.
 
+mod background;
+mod bake;
+mod broker;
+mod builtins;
+mod callers;
+mod clean;
+mod completions;
+mod compress;
+mod explain;
+mod fallback;
+mod fmt;
+mod get;
+mod glossary;
+mod inspect;
+mod install;
+mod link;
+mod load;
+mod manpage;
+mod mock_server;
+mod package;
+mod paths;
+mod planner;
+mod precompute;
+mod profile;
+mod request_template;
+mod route;
+mod stats;
+mod trace;
+mod types;
+mod watermark;
+
 use std::env;
 use std::fs;
 
+// The front end is the library's, for the fuzz target in fuzz/ to link with
+use matthiashihic::{ast, check, diagnostics, front_matter, i18n, json_string, parser};
+
+use ast::{Program, Segment, Statement};
+use planner::Route;
+use diagnostics::{Level, LintLevels};
+use i18n::tr;
+use parser::parse_file;
+
+/// What every model call is told the program is.
+const SYSTEM_PROMPT: &str = "You are an assistant that acts as if it were a program written in a language called 'matthiashihic'. This language allows every string to become a new string. Don't take it too literally, and ignore everything that doesn't make sense. If the user asks you to 'say' or 'make' something, for instance, just print it. Answer the code statement as if you had computed them. Do not reply with anything but the result.";
+
+/// `--output csv`: what the system prompt adds, so answers come as rows of
+/// `columns`.
+fn csv_prompt(columns: &[String], header: bool) -> String {
+    format!(
+        " Reply with CSV only, no code fences and no other text: {}one line per row with exactly these {} comma-separated fields: {}. Wrap a field in double quotes if it has a comma, a double quote or a line break in it, and double the double quotes inside it.",
+        if header { format!("the header line {}, then ", columns.join(",")) } else { String::new() },
+        columns.len(),
+        columns.join(", ")
+    )
+}
+
+/// The compiler's usage text, with `program` as the command.
+fn usage(program: &str) -> String {
+    let lints = diagnostics::LINTS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ");
+    tr!(
+        "usage",
+        p = program,
+        cheap = planner::DEFAULT_CHEAP_MODEL,
+        buffer = DEFAULT_OUTPUT_BUFFER,
+        lints = lints,
+        locales = i18n::locales()
+    )
+}
+
 fn usage_and_exit(program: &str) -> ! {
-    let msg = format!(
-        "Usage:
-  {p} <source.matthiashihic> [--api-key <OPENAI_API_KEY>] [--model <MODEL_NAME>] [-o <output>]
-
-Example:
-  {p} hello.matthiashihic --api-key sk-... -o hello
-  {p} hello.matthiashihic --model gpt-4o -o hello
-  {p} hello.matthiashihic -o hello  # Uses OPENAI_API_KEY env var at runtime
-
-Default model: gpt-4
-API key priority: 1) OPENAI_API_KEY env var at runtime, 2) embedded key from --api-key
-",
-        p = program
-    );
-    eprint!("{}", msg);
+    eprintln!("{}", usage(program));
     std::process::exit(2);
 }
 
 fn escape_rust_string(s: &str) -> String {
-    s.replace('\\', "\\\\")
-        .replace('"', "\\\"")
-        .replace('\n', "\\n")
-        .replace('\r', "\\r")
-        .replace('\t', "\\t")
+    let mut out = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            // NUL and other control characters would end up raw in the generated source
+            c if c.is_control() => out.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
 }
 
 fn xor_encrypt(data: &str, key: &[u8]) -> Vec<u8> {
@@ -59,63 +150,743 @@ fn generate_xor_key() -> Vec<u8> {
     format!("matthiashihic-{}", nanos).into_bytes()
 }
 
-fn process_placeholders(s: &str, required_args: &mut std::collections::HashSet<usize>) -> Result<String, String> {
-    let mut result = String::new();
-    let mut chars = s.chars().peekable();
-    
-    while let Some(ch) = chars.next() {
-        if ch == '€' {
-            if let Some(&next_ch) = chars.peek() {
-                if next_ch == '€' {
-                    // €€index -> €index (escape)
-                    chars.next(); // consume the second €
-                    result.push('€');
-                } else if next_ch.is_ascii_digit() {
-                    // €index -> placeholder
-                    let mut num_str = String::new();
-                    while let Some(&digit_ch) = chars.peek() {
-                        if digit_ch.is_ascii_digit() {
-                            num_str.push(digit_ch);
-                            chars.next();
-                        } else {
-                            break;
-                        }
-                    }
-                    if let Ok(index) = num_str.parse::<usize>() {
-                        if index == 0 {
-                            return Err("Placeholder indices must start at 1 (found €0)".into());
-                        }
-                        required_args.insert(index);
-                        result.push_str(&format!("{{ARG_{}}}", index));
-                    } else {
-                        return Err(format!("Invalid placeholder number: €{}", num_str));
-                    }
-                } else {
-                    result.push(ch);
+/// Checks a `--key-expires` date, YYYY-MM-DD, which has to be today or later.
+/// The key works through the end of that day, UTC.
+fn parse_key_expiry(date: &str) -> Result<String, String> {
+    let invalid = || format!("--key-expires needs a date like 2025-12-31, got '{}'", date);
+    let parts: Vec<i64> = date.splitn(3, '-').map(|part| part.parse().map_err(|_| invalid())).collect::<Result<_, _>>()?;
+    let [year, month, day] = parts[..] else {
+        return Err(invalid());
+    };
+    let days_in_month = match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return Err(invalid()),
+    };
+    if date.len() != 10 || !(1..=days_in_month).contains(&day) {
+        return Err(invalid());
+    }
+    let today = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86400)
+        .unwrap_or(0);
+    // Days since 1970-01-01 (Howard Hinnant's algorithm)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let days = era * 146097 + yoe * 365 + yoe / 4 - yoe / 100 + doy - 719468;
+    if days < today as i64 {
+        return Err(format!("--key-expires {} is over already; the key would never work", date));
+    }
+    Ok(date.to_string())
+}
+
+/// Where the final answer goes besides (or instead of) stdout.
+enum Sink {
+    Slack(String),
+    Webhook(String),
+    Smtp(String),
+}
+
+impl Sink {
+    fn parse(spec: &str) -> Result<Sink, String> {
+        if spec.starts_with("smtp://") || spec.starts_with("smtps://") {
+            return Self::parse_smtp(spec);
+        }
+        let (kind, url) = spec
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid sink '{}', expected <kind>:<url>", spec))?;
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            return Err(format!("Sink URL must start with http:// or https://: {}", url));
+        }
+        match kind {
+            "slack" => Ok(Sink::Slack(url.to_string())),
+            "webhook" => Ok(Sink::Webhook(url.to_string())),
+            other => Err(format!("Unknown sink kind '{}' (supported: slack, webhook, smtp)", other)),
+        }
+    }
+
+    fn parse_smtp(spec: &str) -> Result<Sink, String> {
+        let rest = spec.split_once("://").map(|(_, rest)| rest).unwrap_or("");
+        let (authority, query) = rest.split_once('?').unwrap_or((rest, ""));
+        if authority.contains('@') {
+            return Err("SMTP credentials must not be part of the sink URL; set SMTP_USERNAME and SMTP_PASSWORD at runtime".into());
+        }
+        if authority.trim_end_matches('/').is_empty() {
+            return Err(format!("SMTP sink is missing a host: {}", spec));
+        }
+        if !query.split('&').any(|pair| pair.starts_with("to=") && pair.len() > 3) {
+            return Err(format!("SMTP sink needs at least one recipient (?to=...): {}", spec));
+        }
+        Ok(Sink::Smtp(spec.to_string()))
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Sink::Slack(_) => "slack",
+            Sink::Webhook(_) => "webhook",
+            Sink::Smtp(_) => "smtp",
+        }
+    }
+
+    fn url(&self) -> &str {
+        match self {
+            Sink::Slack(url) | Sink::Webhook(url) | Sink::Smtp(url) => url,
+        }
+    }
+}
+
+/// Checks a `--cache-backend`: `redis://host[:port][/db]` or
+/// `s3://bucket[/prefix]`. Credentials come from the environment at runtime.
+fn parse_cache_backend(spec: &str) -> Result<String, String> {
+    if let Some(rest) = spec.strip_prefix("redis://") {
+        let (authority, db) = rest.split_once('/').unwrap_or((rest, ""));
+        if authority.contains('@') {
+            return Err("Redis credentials must not be part of the cache backend; set REDIS_USERNAME and REDIS_PASSWORD at runtime".into());
+        }
+        if authority.split(':').next().unwrap_or("").is_empty() {
+            return Err(format!("Redis cache backend is missing a host: {}", spec));
+        }
+        if !db.is_empty() && db.parse::<u32>().is_err() {
+            return Err(format!("Redis database must be a number, got '{}'", db));
+        }
+        return Ok(spec.to_string());
+    }
+    if let Some(rest) = spec.strip_prefix("s3://") {
+        let bucket = rest.split('/').next().unwrap_or("");
+        if bucket.is_empty() || !bucket.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.') {
+            return Err(format!("S3 cache backend needs a bucket of lowercase letters, digits, - and .: {}", spec));
+        }
+        return Ok(spec.trim_end_matches('/').to_string());
+    }
+    Err(format!("Unknown cache backend '{}' (supported: redis://host[:port][/db], s3://bucket[/prefix])", spec))
+}
+
+/// How the generated executable reads placeholder values from stdin.
+#[derive(Clone, Copy, PartialEq)]
+enum StdinFormat {
+    /// One line per placeholder, read once.
+    Lines,
+    /// One run per comma-separated row, columns feed placeholders.
+    Csv,
+    /// One run per tab-separated row, columns feed placeholders.
+    Tsv,
+}
+
+impl StdinFormat {
+    fn parse(s: &str) -> Result<StdinFormat, String> {
+        match s {
+            "lines" => Ok(StdinFormat::Lines),
+            "csv" => Ok(StdinFormat::Csv),
+            "tsv" => Ok(StdinFormat::Tsv),
+            other => Err(format!("Unknown stdin format '{}' (supported: lines, csv, tsv)", other)),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            StdinFormat::Lines => "lines",
+            StdinFormat::Csv => "csv",
+            StdinFormat::Tsv => "tsv",
+        }
+    }
+}
+
+/// Parses `--map-columns 1=2,3=1` into (column, placeholder) pairs, both 1-based.
+fn parse_column_map(spec: &str) -> Result<Vec<(usize, usize)>, String> {
+    let mut map = Vec::new();
+    for pair in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (column, placeholder) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid column mapping '{}', expected <column>=<placeholder>", pair))?;
+        let column: usize = column
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid column number in '{}'", pair))?;
+        let placeholder: usize = placeholder
+            .trim()
+            .trim_start_matches('€')
+            .parse()
+            .map_err(|_| format!("Invalid placeholder number in '{}'", pair))?;
+        if column == 0 || placeholder == 0 {
+            return Err(format!("Columns and placeholders start at 1 (found '{}')", pair));
+        }
+        if map.iter().any(|&(_, p)| p == placeholder) {
+            return Err(format!("Placeholder €{} is mapped more than once", placeholder));
+        }
+        map.push((column, placeholder));
+    }
+    Ok(map)
+}
+
+/// Everything from the command line that influences the generated executable.
+struct CompileOptions {
+    /// File name of the source, recorded in the embedded metadata.
+    source_name: String,
+    api_key: Option<String>,
+    model: String,
+    temperature: Option<f64>,
+    max_tokens: Option<u32>,
+    sinks: Vec<Sink>,
+    sink_template: Option<String>,
+    sink_only: bool,
+    stdin_format: StdinFormat,
+    column_map: Vec<(usize, usize)>,
+    skip_header: bool,
+    /// Leading statements already answered by `--precompute`.
+    precomputed: Option<precompute::Precomputed>,
+    builtins: bool,
+    /// Model for trivial statements; `None` sends them to `model` as well.
+    cheap_model: Option<String>,
+    /// `--input audio`: €1 is the transcript of an audio file or recording.
+    audio_input: bool,
+    /// `--output image <file>`: the answer is an image prompt; the PNG goes here.
+    image_output: Option<String>,
+    /// `--tui`: a terminal chat window instead of stdin/stdout.
+    tui: bool,
+    /// `--notify`: a desktop notification when the program finishes or fails.
+    notify: bool,
+    /// `--ensemble`/`--judge`: models asked for every model step, and the one
+    /// that merges their answers.
+    ensemble: Option<(Vec<String>, String)>,
+    /// `--serve`: an OpenAI-compatible HTTP endpoint instead of stdin/stdout.
+    serve: bool,
+    /// `--serve-keys`: who may call `--serve`, and whose key pays for it.
+    serve_keys: Vec<callers::Caller>,
+    /// `--serve-auth`: a command that checks a caller's token.
+    serve_auth: Option<String>,
+    /// `--stream-filter`: run once per stdin line, as the lines come in.
+    stream_filter: bool,
+    /// `--listen-socket`: a Unix socket (named pipe on Windows) other programs
+    /// send placeholder values to, instead of stdin/stdout.
+    listen_socket: Option<String>,
+    /// `--output-buffer`: streamed pieces queued for a slow stdout.
+    output_buffer: usize,
+    /// `--resume`: times a stream that dies mid-answer is asked to continue.
+    resume: u32,
+    /// `--repair`: times a json or CSV answer that doesn't check out is sent
+    /// back with what's wrong with it.
+    repair: u32,
+    /// `--session-tokens`: tokens a `--tui` or `--serve` chat session may use.
+    session_tokens: Option<u64>,
+    /// `--hook <point>=<command>`, in the order given.
+    hooks: Vec<(String, String)>,
+    /// `--glossary`: terms the answers have to get right.
+    glossary: Vec<glossary::Term>,
+    /// `--filter <filter>=<policy>`, one per filter.
+    filters: Vec<(String, String)>,
+    /// `--banner`: text shown on stderr the first time a user runs the program.
+    banner: Option<String>,
+    /// `--fallback`: (prompt hash, answer) for when the model is unavailable.
+    fallback: Vec<(u64, String)>,
+    /// `--fail-on-refusal`: exit with REFUSAL_EXIT_CODE instead of printing a
+    /// refusal as the answer.
+    fail_on_refusal: bool,
+    /// `--cache`: reuse the answer to a model step asked exactly like this before.
+    cache: bool,
+    /// `--cache-similarity`: also reuse it for requests at least this similar.
+    cache_similarity: Option<f64>,
+    /// `--cache-backend`: where answers are shared besides the local cache.
+    cache_backend: Option<String>,
+    /// `--cache-ttl`: seconds a cached answer counts.
+    cache_ttl: Option<u64>,
+    /// `--broker`: every model call waits for its turn from `matthiashihic broker`.
+    broker: bool,
+    /// `--profile`: restrictions the program was checked against, stamped
+    /// into the metadata.
+    profile: Option<profile::Profile>,
+    /// `--key-expires`: the last day (UTC, YYYY-MM-DD) the embedded key is used.
+    key_expires: Option<String>,
+    /// `--watermark`: how every answer says where it came from.
+    watermark: Option<watermark::Watermark>,
+    /// `--background`: the one step is submitted as a job, `--poll` gets it.
+    background: bool,
+    /// `--request-template`: the JSON body of every chat completions request.
+    request_template: Option<String>,
+    /// `--emit types`: json answers are deserialized into the emitted types
+    /// before they're shown.
+    types: bool,
+    /// `--output csv --columns`: the answer is asked for and printed as CSV
+    /// with these columns.
+    csv_columns: Option<Vec<String>>,
+    /// Whether the CSV starts with a header line (`--no-header` says no).
+    csv_header: bool,
+}
+
+impl CompileOptions {
+    fn planner(&self) -> planner::Planner<'_> {
+        planner::Planner {
+            builtins: self.builtins,
+            cheap_model: self.cheap_model.as_deref().filter(|cheap| *cheap != self.model),
+            ensemble: self.ensemble.as_ref().map(|(models, judge)| (models.as_slice(), judge.as_str())),
+        }
+    }
+
+    /// Cargo features of the generated runtime this program needs.
+    fn runtime_features(&self, program: &Program) -> Vec<&'static str> {
+        let mut features = Vec::new();
+        if !program_files(program).is_empty() {
+            features.push("documents");
+        }
+        if self.sinks.iter().any(|sink| matches!(sink, Sink::Smtp(_))) {
+            features.push("smtp");
+        }
+        if self.audio_input {
+            features.push("audio");
+        }
+        if self.image_output.is_some() {
+            features.push("image");
+        }
+        if self.tui {
+            features.push("tui");
+        }
+        if self.serve {
+            features.push("serve");
+        }
+        if self.cache_backend.as_deref().is_some_and(|backend| backend.starts_with("s3://")) {
+            features.push("s3");
+        }
+        if self.types {
+            features.push("types");
+        }
+        features
+    }
+}
+
+/// FNV-1a, the same as `fnv1a` in the generated runtime.
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3))
+}
+
+/// The program's `name:`, or its source file's name without the extension.
+fn program_name(opts: &CompileOptions, program: &Program) -> String {
+    let default_name = std::path::Path::new(&opts.source_name)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| opts.source_name.clone());
+    program.info.name.clone().unwrap_or(default_name)
+}
+
+/// The system prompt of every model call: the default one or the persona's,
+/// and what `--output csv` asks for.
+fn system_prompt(opts: &CompileOptions, program: &Program) -> String {
+    let mut system_prompt = match &program.persona {
+        Some(persona) if persona.only => persona.text.clone(),
+        Some(persona) => format!("{} Answer as this persona: {}", SYSTEM_PROMPT, persona.text),
+        None => SYSTEM_PROMPT.to_string(),
+    };
+    if let Some(language) = &program.speak {
+        system_prompt.push_str(&format!(" Always answer in {}, whatever language the statement is written in.", language));
+    }
+    if let Some(columns) = &opts.csv_columns {
+        system_prompt.push_str(&csv_prompt(columns, opts.csv_header));
+    }
+    system_prompt
+}
+
+/// JSON document embedded into every executable describing how it was built.
+fn build_metadata(opts: &CompileOptions, program: &Program) -> String {
+    let mut fields = vec![
+        format!("\"compiler\":{}", json_string(concat!("matthiashihic ", env!("CARGO_PKG_VERSION")))),
+        format!("\"source\":{}", json_string(&opts.source_name)),
+        format!("\"model\":{}", json_string(&opts.model)),
+    ];
+    for (key, value) in [
+        ("name", &program.info.name),
+        ("about", &program.info.about),
+        ("version", &program.info.version),
+    ] {
+        if let Some(value) = value {
+            fields.push(format!("{}:{}", json_string(key), json_string(value)));
+        }
+    }
+    if !program.secrets.is_empty() {
+        // Names only; the values never reach the compiler
+        let names = program.secrets.iter().map(|name| json_string(name)).collect::<Vec<_>>();
+        fields.push(format!("\"secrets\":[{}]", names.join(",")));
+    }
+    if !program.env_vars.is_empty() {
+        let names = program.env_vars.iter().map(|name| json_string(name)).collect::<Vec<_>>();
+        fields.push(format!("\"env\":[{}]", names.join(",")));
+    }
+    if let Some(persona) = &program.persona {
+        fields.push(format!("\"persona\":{},\"persona_only\":{}", json_string(&persona.text), persona.only));
+    }
+    if let Some(language) = &program.speak {
+        fields.push(format!("\"speak\":{}", json_string(language)));
+    }
+    if program.remember {
+        fields.push("\"remember\":true".to_string());
+    }
+    if opts.audio_input {
+        fields.push("\"input\":\"audio\"".to_string());
+    }
+    if opts.image_output.is_some() {
+        fields.push("\"output\":\"image\"".to_string());
+    }
+    if let Some(columns) = &opts.csv_columns {
+        let names = columns.iter().map(|name| json_string(name)).collect::<Vec<_>>();
+        fields.push(format!("\"output\":\"csv\",\"columns\":[{}]", names.join(",")));
+    }
+    if opts.tui {
+        fields.push("\"interface\":\"tui\"".to_string());
+    }
+    if opts.notify {
+        fields.push("\"notify\":true".to_string());
+    }
+    if opts.fail_on_refusal {
+        fields.push("\"fail_on_refusal\":true".to_string());
+    }
+    if opts.cache {
+        fields.push("\"cache\":true".to_string());
+    }
+    if let Some(similarity) = opts.cache_similarity {
+        fields.push(format!("\"cache_similarity\":{}", similarity));
+    }
+    if let Some(backend) = &opts.cache_backend {
+        fields.push(format!("\"cache_backend\":{}", json_string(backend)));
+    }
+    if opts.broker {
+        fields.push("\"broker\":true".to_string());
+    }
+    if !opts.hooks.is_empty() {
+        let hooks = opts.hooks.iter().map(|(point, command)| format!("[{},{}]", json_string(point), json_string(command)));
+        fields.push(format!("\"hooks\":[{}]", hooks.collect::<Vec<_>>().join(",")));
+    }
+    if opts.serve {
+        fields.push("\"interface\":\"serve\",\"compat\":\"openai\"".to_string());
+    }
+    if opts.stream_filter {
+        fields.push("\"interface\":\"stream-filter\"".to_string());
+    }
+    if opts.listen_socket.is_some() {
+        fields.push("\"interface\":\"socket\"".to_string());
+    }
+    if let Some((models, judge)) = &opts.ensemble {
+        let models = models.iter().map(|model| json_string(model)).collect::<Vec<_>>();
+        fields.push(format!("\"ensemble\":[{}],\"judge\":{}", models.join(","), json_string(judge)));
+    }
+    if let Some(language) = &program.translate_to {
+        fields.push(format!("\"translate_to\":{}", json_string(language)));
+    }
+    if !opts.fallback.is_empty() {
+        fields.push(format!("\"fallback_answers\":{}", opts.fallback.len()));
+    }
+    if opts.banner.is_some() {
+        fields.push("\"banner\":true".to_string());
+    }
+    if !opts.filters.is_empty() {
+        let filters = opts.filters.iter().map(|(filter, policy)| format!("{}:{}", json_string(filter), json_string(policy)));
+        fields.push(format!("\"filters\":{{{}}}", filters.collect::<Vec<_>>().join(",")));
+    }
+    if !opts.glossary.is_empty() {
+        fields.push(format!("\"glossary_terms\":{}", opts.glossary.len()));
+    }
+    if let Some(experiment) = &program.experiment {
+        fields.push(format!(
+            "\"experiment\":{{\"statement\":{},\"split\":[{},{}]}}",
+            experiment.index + 1,
+            experiment.split,
+            100 - experiment.split
+        ));
+    }
+    if let Some(precomputed) = &opts.precomputed {
+        fields.push(format!("\"precomputed_statements\":{}", precomputed.statements));
+    }
+    let expectations = program.statements.iter().filter(|statement| statement.expect.is_some()).count();
+    if expectations > 0 {
+        fields.push(format!("\"expectations\":{}", expectations));
+    }
+    if let Some(date) = &opts.key_expires {
+        fields.push(format!("\"key_expires\":{}", json_string(date)));
+    }
+    if let Some(style) = opts.watermark {
+        fields.push(format!("\"watermark\":{}", json_string(style.name())));
+    }
+    if opts.background {
+        fields.push("\"background\":true".to_string());
+    }
+    if opts.request_template.is_some() {
+        fields.push("\"request_template\":true".to_string());
+    }
+    if opts.types {
+        fields.push("\"types\":true".to_string());
+    }
+    if let Some(profile) = opts.profile {
+        fields.push(format!("\"profile\":{},\"restrictions\":{}", json_string(profile.name()), profile.restrictions_json()));
+    }
+    if !program.front_matter.entries.is_empty() {
+        let entries = program.front_matter.entries.iter()
+            .map(|(k, v)| format!("{}:{}", json_string(k), json_string(v)))
+            .collect::<Vec<_>>()
+            .join(",");
+        fields.push(format!("\"front_matter\":{{{}}}", entries));
+    }
+    format!("{{{}}}", fields.join(","))
+}
+
+fn option_literal<T: std::fmt::Display>(value: Option<T>) -> String {
+    match value {
+        Some(v) => format!("Some({})", v),
+        None => "None".to_string(),
+    }
+}
+
+/// The distinct `€file(...)` references of a program, in order of appearance.
+fn program_files(program: &Program) -> Vec<(usize, Option<(u32, u32)>)> {
+    let mut files = Vec::new();
+    for statement in program.all_statements() {
+        for segment in &statement.segments {
+            if let Segment::File { index, pages } = segment {
+                if !files.contains(&(*index, *pages)) {
+                    files.push((*index, *pages));
                 }
-            } else {
-                result.push(ch);
             }
-        } else {
-            result.push(ch);
         }
     }
-    
-    Ok(result)
+    files
+}
+
+/// The distinct `€gitdiff` and `€gitlog(n)` segments of a program.
+fn program_git(program: &Program) -> Vec<Segment> {
+    let mut git = Vec::new();
+    for statement in program.all_statements() {
+        for segment in &statement.segments {
+            if matches!(segment, Segment::GitDiff | Segment::GitLog(_)) && !git.contains(segment) {
+                git.push(segment.clone());
+            }
+        }
+    }
+    git
 }
 
-fn generate_executable_source(api_key: Option<&str>, model: &str, pseudocode: &str, required_args: &[usize]) -> String {
-    let escaped_model = escape_rust_string(model);
-    let escaped_code = escape_rust_string(pseudocode);
+/// Where `--hook` commands can run: before each model request, on every
+/// streamed piece of an answer, and on the whole answer.
+const HOOK_POINTS: &[&str] = &["pre-request", "per-delta", "post-response"];
+
+/// What `--filter` looks for in answers, and what it can do about it.
+const OUTPUT_FILTERS: &[&str] = &["profanity", "pii"];
+const FILTER_POLICIES: &[&str] = &["block", "mask", "warn"];
+
+/// Streamed pieces (a few characters each) that may queue up for a slow
+/// stdout unless `--output-buffer` says otherwise.
+const DEFAULT_OUTPUT_BUFFER: usize = 256;
+
+/// How often a json or CSV answer that doesn't check out is sent back to be
+/// fixed unless `--repair` says otherwise.
+const DEFAULT_REPAIR_ATTEMPTS: u32 = 1;
+
+/// What `€dir(n)` reads without options: every file, up to about 200 KB.
+const DEFAULT_DIR_GLOB: &str = "**/*";
+const DEFAULT_DIR_MAX_BYTES: usize = 200 * 1024;
+
+/// The distinct `€dir(...)` references of a program, in order of appearance.
+fn program_dirs(program: &Program) -> Vec<(usize, Option<String>, Option<usize>)> {
+    let mut dirs = Vec::new();
+    for statement in program.all_statements() {
+        for segment in &statement.segments {
+            if let Segment::Dir { index, glob, max_bytes } = segment {
+                let dir = (*index, glob.clone(), *max_bytes);
+                if !dirs.contains(&dir) {
+                    dirs.push(dir);
+                }
+            }
+        }
+    }
+    dirs
+}
+
+/// A generated program's `src` directory, as (file name, contents).
+type SourceFiles = Vec<(&'static str, String)>;
+
+/// The runtime every compiled program is made of, split by concern. Only
+/// `config.rs` is generated per program; it holds every constant the compile
+/// options and the program decide.
+const RUNTIME_MODULES: &[(&str, &str)] = &[
+    ("main.rs", include_str!("runtime/main.rs")),
+    ("access.rs", include_str!("runtime/access.rs")),
+    ("auth.rs", include_str!("runtime/auth.rs")),
+    ("background.rs", include_str!("runtime/background.rs")),
+    ("broker.rs", include_str!("runtime/broker.rs")),
+    ("cache.rs", include_str!("runtime/cache.rs")),
+    ("provider.rs", include_str!("runtime/provider.rs")),
+    ("socket.rs", include_str!("runtime/socket.rs")),
+    ("stream.rs", include_str!("runtime/stream.rs")),
+    ("trace.rs", include_str!("runtime/trace.rs")),
+    ("io.rs", include_str!("runtime/io.rs")),
+    // Shared with the compiler
+    ("paths.rs", include_str!("paths.rs")),
+];
+
+/// The sources of the compiled program: the runtime modules and its config.
+fn generate_executable_source(opts: &CompileOptions, program: &Program) -> SourceFiles {
+    let required_args = &program.required_args;
+    let api_key = opts.api_key.as_deref();
+    let escaped_model = escape_rust_string(&opts.model);
+    let precomputed_statements = opts.precomputed.as_ref().map(|p| p.statements).unwrap_or(0);
+    let precomputed_str = match &opts.precomputed {
+        Some(precomputed) => format!(
+            "Some((\"{}\", \"{}\"))",
+            escape_rust_string(&ast::pseudocode_of(&program.statements[..precomputed.statements])),
+            escape_rust_string(&precomputed.answer)
+        ),
+        None => "None".to_string(),
+    };
+    let plan = |program: &Program| {
+        let steps = opts.planner().plan(program, precomputed_statements);
+        steps.iter().map(|step| {
+            let statements: Vec<Statement> = step.statements.iter().map(|&i| program.statements[i].clone()).collect();
+            let pseudocode = escape_rust_string(&ast::pseudocode_of(&statements));
+            // A bound statement is a step of its own
+            let binding = option_literal(statements[0].binding.as_ref().map(|name| format!("\"{}\"", escape_rust_string(name))));
+            // Every statement of a step asks for the same temperature
+            let temperature = option_literal(statements[0].temperature.map(|temperature| format!("{:?}", temperature.value())));
+            // And one whose answer is json, which is checked on its own
+            let schema = option_literal(statements[0].schema.as_ref().map(|schema| format!("\"{}\"", escape_rust_string(&schema.to_json_schema()))));
+            // So is the first one of a loop, which says how often the loop's steps run
+            let repeat = option_literal(program.loops.iter().find(|looped| looped.first == step.statements[0]).map(|looped| {
+                let len = steps.iter().filter(|other| looped.contains(other.statements[0])).count();
+                format!("(\"{}\", {})", escape_rust_string(&ast::segments_pseudocode(&looped.count)), len)
+            }));
+            // And the first one of an if, which says how many steps each side has
+            let branch = option_literal(program.branches.iter().find(|branch| branch.first == step.statements[0]).map(|branch| {
+                let otherwise = branch.first + branch.then_len;
+                let then_len = steps.iter().filter(|other| (branch.first..otherwise).contains(&other.statements[0])).count();
+                let else_len = steps.iter().filter(|other| (otherwise..branch.end()).contains(&other.statements[0])).count();
+                format!("(\"{}\", {}, {})", escape_rust_string(&branch.vibe), then_len, else_len)
+            }));
+            // And one that's tried again, so the others aren't
+            let retries = statements[0].retries.unwrap_or(0);
+            // And one written to a file, so only its answer goes there
+            let write_to = option_literal(statements[0].write_to.as_ref().map(|path| format!("\"{}\"", escape_rust_string(path))));
+            // And one with an expectation, so it's checked against its own answer
+            let expect = option_literal(statements[0].expect.as_ref().map(|expected| format!("\"{}\"", escape_rust_string(expected))));
+            // And the first one of an all at once! block, which says how many steps it has
+            let together = option_literal(program.parallels.iter().find(|parallel| parallel.first == step.statements[0]).map(|parallel| {
+                steps.iter().filter(|other| parallel.contains(other.statements[0])).count().to_string()
+            }));
+            // And the first one of a for every line block, which says how many steps run per line
+            let every_line = option_literal(program.every_line.iter().find(|every_line| every_line.first == step.statements[0]).map(|every_line| {
+                steps.iter().filter(|other| every_line.contains(other.statements[0])).count().to_string()
+            }));
+            match &step.route {
+                Route::Local(target, args) => {
+                    let args = args.iter()
+                        .map(|arg| format!("\"{}\"", escape_rust_string(&ast::segments_pseudocode(arg))))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    format!(
+                        "Step {{ model: \"\", pseudocode: \"{}\", local: Some((\"{}\", &[{}])), ensemble: false, temperature: None, binding: {}, schema: {}, repeat: {}, branch: {}, together: {}, every_line: {}, retries: {}, write_to: {}, expect: {} }}",
+                        pseudocode,
+                        escape_rust_string(target),
+                        args,
+                        binding,
+                        schema,
+                        repeat,
+                        branch,
+                        together,
+                        every_line,
+                        retries,
+                        write_to,
+                        expect
+                    )
+                }
+                Route::Cheap | Route::Model | Route::Using(_) => {
+                    let model = match &step.route {
+                        Route::Cheap => opts.cheap_model.as_deref().unwrap_or(&opts.model),
+                        Route::Using(model) => model,
+                        _ => &opts.model,
+                    };
+                    format!(
+                        "Step {{ model: \"{}\", pseudocode: \"{}\", local: None, ensemble: {}, temperature: {}, binding: {}, schema: {}, repeat: {}, branch: {}, together: {}, every_line: {}, retries: {}, write_to: {}, expect: {} }}",
+                        escape_rust_string(model),
+                        pseudocode,
+                        opts.ensemble.is_some() && matches!(step.route, Route::Model),
+                        temperature,
+                        binding,
+                        schema,
+                        repeat,
+                        branch,
+                        together,
+                        every_line,
+                        retries,
+                        write_to,
+                        expect
+                    )
+                }
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",\n    ")
+    };
+    let steps_str = plan(program);
+    let experiment_str = option_literal(program.experiment.as_ref().zip(program.arm_b()).map(|(experiment, arm_b)| {
+        format!("({}, &[\n    {}\n])", experiment.split, plan(&arm_b))
+    }));
+    let translate_str = option_literal(program.translate_to.as_ref().map(|language| {
+        // Translating is the kind of job the cheap model is for
+        let model = opts.cheap_model.as_deref().unwrap_or(&opts.model);
+        format!("(\"{}\", \"{}\")", escape_rust_string(language), escape_rust_string(model))
+    }));
+    let glossary_str = opts.glossary.iter()
+        .map(|term| format!("(\"{}\", \"{}\")", escape_rust_string(&term.term), escape_rust_string(&term.required)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let fallback_str = opts.fallback.iter()
+        .map(|(hash, answer)| format!("(0x{:016x}, \"{}\")", hash, escape_rust_string(answer)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let banner_str = option_literal(opts.banner.as_ref().map(|banner| format!("\"{}\"", escape_rust_string(banner))));
+    let filters_str = opts.filters.iter()
+        .map(|(filter, policy)| format!("(\"{}\", \"{}\")", filter, policy))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let glossary_prompt_str = if opts.glossary.is_empty() {
+        String::new()
+    } else {
+        escape_rust_string(&glossary::prompt(&opts.glossary))
+    };
+    let hooks_str = opts.hooks.iter()
+        .map(|(point, command)| format!("(\"{}\", \"{}\")", point, escape_rust_string(command)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let ensemble_str = option_literal(opts.ensemble.as_ref().map(|(models, judge)| {
+        let models = models.iter().map(|model| format!("\"{}\"", escape_rust_string(model))).collect::<Vec<_>>();
+        format!("(&[{}], \"{}\")", models.join(", "), escape_rust_string(judge))
+    }));
+    let listen_socket_str = option_literal(opts.listen_socket.as_ref().map(|path| format!("\"{}\"", escape_rust_string(path))));
+    let image_output_str = option_literal(opts.image_output.as_ref().map(|path| format!("\"{}\"", escape_rust_string(path))));
+    let escaped_metadata = escape_rust_string(&build_metadata(opts, program));
+    // Keep a decimal point so the literal is an f64 even for whole numbers
+    let temperature_str = option_literal(opts.temperature.map(|t| format!("{:?}", t)));
+    let cache_similarity_str = option_literal(opts.cache_similarity.map(|s| format!("{:?}", s)));
+    let cache_backend_str = option_literal(opts.cache_backend.as_ref().map(|b| format!("\"{}\"", escape_rust_string(b))));
+    let cache_ttl_str = option_literal(opts.cache_ttl);
+    let cache_namespace = fnv1a(&format!("{}\0{}", opts.model, ast::pseudocode_of(&program.statements)));
+    let max_tokens_str = option_literal(opts.max_tokens);
+    let escaped_name = escape_rust_string(&program_name(opts, program));
+    let escaped_version = escape_rust_string(program.info.version.as_deref().unwrap_or(""));
+    let escaped_about = escape_rust_string(program.info.about.as_deref().unwrap_or(""));
     
-    // Generate encrypted key and XOR key if API key is provided
-    let (encrypted_key_bytes, xor_key_bytes) = if let Some(key) = api_key {
-        let xor_key = generate_xor_key();
-        let encrypted = xor_encrypt(key, &xor_key);
-        (encrypted, xor_key)
+    // Generate encrypted key and XOR key if API key is provided; the
+    // --serve-keys tokens are masked with the same one
+    let xor_key_bytes = if api_key.is_some() || !opts.serve_keys.is_empty() {
+        generate_xor_key()
     } else {
-        (Vec::new(), Vec::new())
+        Vec::new()
     };
+    let encrypted_key_bytes = api_key.map(|key| xor_encrypt(key, &xor_key_bytes)).unwrap_or_default();
+    let serve_keys_str = opts.serve_keys.iter()
+        .map(|caller| {
+            let token = xor_encrypt(&caller.token, &xor_key_bytes).iter().map(|b| b.to_string()).collect::<Vec<_>>().join(", ");
+            format!("(\"{}\", &[{}], \"{}\")", escape_rust_string(&caller.name), token, caller.key_var.as_deref().unwrap_or(""))
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let serve_auth_str = option_literal(opts.serve_auth.as_ref().map(|command| format!("\"{}\"", escape_rust_string(command))));
     
     let encrypted_key_str = encrypted_key_bytes.iter()
         .map(|b| format!("{}", b))
@@ -128,335 +899,1157 @@ fn generate_executable_source(api_key: Option<&str>, model: &str, pseudocode: &s
         .join(", ");
     
     let has_embedded_key = api_key.is_some();
+    let key_expires_str = option_literal(opts.key_expires.as_ref().map(|date| format!("\"{}\"", date)));
+    let watermark_str = option_literal(opts.watermark.map(|style| format!("\"{}\"", style.name())));
     
-    let max_arg = required_args.iter().max().copied().unwrap_or(0);
-    let arg_reading_code = if max_arg > 0 {
-        let substitutions = required_args.iter().map(|&i| {
-            format!("    pseudocode = pseudocode.replace(\"{{ARG_{}}}\", &lines[{}]);", i, i - 1)
-        }).collect::<Vec<_>>().join("\n");
-        
-        format!(r#"
-    // Check if stdin is available
-    use std::io::{{IsTerminal, BufRead}};
-    if io::stdin().is_terminal() {{
-        eprintln!("Error: This program expects {} line(s) from stdin.\nUsage: echo 'value' | €0 or cat file | €0");
-        std::process::exit(2);
-    }}
-    
-    // Read arguments from stdin
-    let stdin = io::stdin();
-    let mut lines: Vec<String> = Vec::new();
-    for line in stdin.lock().lines() {{
-        lines.push(line.expect("Failed to read line from stdin"));
-        if lines.len() >= {} {{
-            break;
-        }}
-    }}
-    
-    if lines.len() < {} {{
-        eprintln!("Error: Expected {} arguments from stdin, got {{}}\nUsage: Pipe {} lines into this program, one per line.", lines.len());
-        std::process::exit(2);
-    }}
-    
-    // Substitute placeholders in pseudocode
-    let mut pseudocode = pseudocode.to_string();
-{}
-"#, max_arg, max_arg, max_arg, max_arg, max_arg, substitutions)
-    } else {
-        String::new()
+    let required_args_str = required_args.iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let arg_types_str = program.arg_types.iter()
+        .map(|(index, ty)| format!("({}, \"{}\")", index, ty.name()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let arg_defaults_str = program.arg_defaults.iter()
+        .map(|(index, value)| format!("({}, \"{}\")", index, escape_rust_string(value)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let named_args_str = program.named_args.iter()
+        .map(|name| format!("\"{}\"", name))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let secrets_str = program.secrets.iter()
+        .map(|name| format!("\"{}\"", name))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let env_vars_str = program.env_vars.iter()
+        .map(|name| format!("\"{}\"", name))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let files_str = program_files(program).iter()
+        .map(|(index, pages)| format!("({}, {})", index, option_literal(pages.map(|(first, last)| format!("({}, {})", first, last)))))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let dirs_str = program_dirs(program).iter()
+        .map(|(index, glob, max_bytes)| {
+            format!(
+                "(\"{}\", {}, \"{}\", {})",
+                ast::dir_key(*index, glob.as_deref(), *max_bytes),
+                index,
+                escape_rust_string(glob.as_deref().unwrap_or(DEFAULT_DIR_GLOB)),
+                max_bytes.unwrap_or(DEFAULT_DIR_MAX_BYTES)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let git_str = program_git(program).iter()
+        .map(|segment| match segment {
+            Segment::GitLog(count) => format!("(\"GIT_LOG_{}\", {})", count, count),
+            _ => "(\"GIT_DIFF\", 0)".to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let column_map_str = opts.column_map.iter()
+        .map(|(column, placeholder)| format!("({}, {})", column, placeholder))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let sinks_str = opts.sinks.iter()
+        .map(|sink| format!("(\"{}\", \"{}\")", sink.kind(), escape_rust_string(sink.url())))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let sink_template_str = match &opts.sink_template {
+        Some(t) => format!("Some(\"{}\")", escape_rust_string(t)),
+        None => "None".to_string(),
     };
+    let csv_columns_str = option_literal(opts.csv_columns.as_ref().map(|columns| {
+        let names = columns.iter().map(|name| format!("\"{}\"", escape_rust_string(name))).collect::<Vec<_>>();
+        format!("&[{}]", names.join(", "))
+    }));
+    let system_prompt = system_prompt(opts, program);
+    let request_template_str = option_literal(opts.request_template.as_ref().map(|t| format!("\"{}\"", escape_rust_string(t))));
     
-    let pseudocode_var = if max_arg > 0 { "&pseudocode" } else { "pseudocode" };
-    
-    let code = format!(
-r###"use std::io::{{self, Write}};
-
-#[tokio::main]
-async fn main() {{
-    // Try environment variable first, then fall back to embedded key
-    let api_key = if let Ok(env_key) = std::env::var("OPENAI_API_KEY") {{
-        env_key
-    }} else if {} {{
-        // Decrypt embedded key using XOR
-        let encrypted: Vec<u8> = vec![{}];
-        let xor_key: Vec<u8> = vec![{}];
-        let decrypted: Vec<u8> = encrypted
-            .iter()
-            .enumerate()
-            .map(|(i, &b)| b ^ xor_key[i % xor_key.len()])
-            .collect();
-        String::from_utf8(decrypted).expect("Invalid API key")
-    }} else {{
-        eprintln!("Error: No API key found. Set OPENAI_API_KEY environment variable.");
-        std::process::exit(1);
-    }};
-    
-    let model = "{}";
-    let pseudocode = "{}";{}
-    
-    if let Err(e) = run_openai_stream(&api_key, model, {}).await {{
-        eprintln!("Error: {{}}", e);
-        std::process::exit(1);
-    }}
-}}
+    let config = format!(
+r###"//! What the compiler put into this program: how it was compiled, what it
+//! reads and its execution plan. The compiler writes this file for every
+//! program; the other modules are the same for all of them.
 
-async fn run_openai_stream(api_key: &str, model: &str, pseudocode: &str) -> Result<(), Box<dyn std::error::Error>> {{
-    let prompt = "You are an assistant that acts as if it were a program written in a language called 'matthiashihic'. This language allows every string to become a new string. Don't take it too literally, and ignore everything that doesn't make sense. If the user asks you to 'say' or 'make' something, for instance, just print it. Answer the code statement as if you had computed them. Do not reply with anything but the result.";
-    
-    let client = reqwest::Client::new();
-    let request_body = serde_json::json!({{
-        "model": model,
-        "messages": [
-            {{
-                "role": "system",
-                "content": prompt
-            }},
-            {{
-                "role": "user",
-                "content": pseudocode
-            }}
-        ],
-        "stream": true
-    }});
-    
-    let response = client
-        .post("https://api.openai.com/v1/chat/completions")
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {{}}", api_key))
-        .json(&request_body)
-        .send()
-        .await?;
-    
-    if !response.status().is_success() {{
-        let status = response.status();
-        let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
-        return Err(format!("OpenAI API error ({{}}): {{}}", status, error_text).into());
-    }}
-    
-    use futures_util::StreamExt;
-    let mut stream = response.bytes_stream();
-    let mut buffer = String::new();
-    
-    while let Some(chunk_result) = stream.next().await {{
-        let chunk = chunk_result?;
-        let text = String::from_utf8_lossy(&chunk);
-        buffer.push_str(&text);
-        
-        while let Some(newline_pos) = buffer.find('\n') {{
-            let line = buffer[..newline_pos].to_string();
-            buffer = buffer[newline_pos + 1..].to_string();
-            
-            if line.starts_with("data: ") {{
-                let data = &line[6..];
-                if data.trim() == "[DONE]" {{
-                    break;
-                }}
-                
-                if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(data) {{
-                    if let Some(choices) = parsed["choices"].as_array() {{
-                        if let Some(choice) = choices.first() {{
-                            if let Some(content) = choice["delta"]["content"].as_str() {{
-                                if !content.is_empty() {{
-                                    print!("{{}}", content);
-                                    io::stdout().flush()?;
-                                }}
-                            }}
-                        }}
-                    }}
-                }}
-            }}
-        }}
-    }}
-    
-    println!();
-    Ok(())
+/// How this executable was built, as JSON.
+#[used]
+pub(crate) static METADATA: &str = "{}";
+pub(crate) const NAME: &str = "{}";
+pub(crate) const VERSION: &str = "{}";
+pub(crate) const ABOUT: &str = "{}";
+
+pub(crate) const TEMPERATURE: Option<f64> = {};
+pub(crate) const MAX_TOKENS: Option<u32> = {};
+
+/// Destinations the final answer is POSTed to, as (kind, url).
+pub(crate) const SINKS: &[(&str, &str)] = &[{}];
+pub(crate) const SINK_TEMPLATE: Option<&str> = {};
+pub(crate) const ECHO_STDOUT: bool = {};
+/// `--output-buffer`: pieces of answers that may wait for a slow stdout before
+/// reading the model's stream pauses.
+pub(crate) const OUTPUT_BUFFER: usize = {};
+/// `--resume`: how often an answer whose stream died halfway is asked to
+/// continue before the program gives up and says it's incomplete.
+pub(crate) const RESUME_ATTEMPTS: u32 = {};
+/// `--repair`: how often a json or CSV answer that doesn't check out is sent
+/// back to the model with what's wrong, before the program gives up.
+pub(crate) const REPAIR_ATTEMPTS: u32 = {};
+/// `--session-tokens`: tokens (prompt and completion) a chat session may use
+/// before it gets no more answers. It's warned at 80%.
+pub(crate) const SESSION_TOKENS: Option<u64> = {:?};
+/// Sent after an answer that doesn't check out, with what's wrong with it.
+pub(crate) const REPAIR_PROMPT: &str = "Reply with your whole answer again, fixed, and nothing else.";
+/// Sent after the part of an answer that made it, to get the rest.
+pub(crate) const CONTINUE_PROMPT: &str = "Your answer was cut off. Continue exactly where it stopped, without repeating anything and without commenting on it. It ended with:";
+
+/// Placeholder indices (1-based) used by the program.
+pub(crate) const REQUIRED_ARGS: &[usize] = &[{}];
+/// Type annotations (`€1:int`) checked before substitution, as (placeholder, type).
+pub(crate) const ARG_TYPES: &[(usize, &str)] = &[{}];
+/// `€1 or "..."` defaults, as (placeholder, value): what a placeholder gets
+/// when stdin runs out before its line.
+pub(crate) const ARG_DEFAULTS: &[(usize, &str)] = &[{}];
+/// `€{{name}}` placeholders in the order they first appear. Their values follow
+/// the numbered ones, from `--name <value>` or a `name=value` stdin line.
+pub(crate) const NAMED_ARGS: &[&str] = &[{}];
+/// Environment variables behind `€secret(NAME)`. Their values are only ever
+/// put into the prompt and are scrubbed from everything the program prints.
+pub(crate) const SECRETS: &[&str] = &[{}];
+/// Environment variables behind `€env.NAME`, read when the program starts.
+pub(crate) const ENV_VARS: &[&str] = &[{}];
+/// `€file(n)` references as (placeholder, page range): stdin line n names a
+/// file whose text goes into the prompt.
+pub(crate) const FILES: &[(usize, Option<(u32, u32)>)] = &[{}];
+/// `€dir(...)` references as (marker key, placeholder, glob, max bytes).
+pub(crate) const DIRS: &[(&str, usize, &str, usize)] = &[{}];
+/// `€gitdiff` and `€gitlog(n)` as (marker key, commits); 0 commits is the diff.
+pub(crate) const GIT: &[(&str, usize)] = &[{}];
+/// Git output beyond this many bytes is cut off.
+pub(crate) const GIT_MAX_BYTES: usize = 100 * 1024;
+/// How placeholder values arrive on stdin: "lines", "csv" or "tsv".
+pub(crate) const STDIN_FORMAT: &str = "{}";
+pub(crate) const SKIP_HEADER: bool = {};
+/// Which (1-based) column feeds which placeholder, as (column, placeholder).
+/// Placeholders not listed here read the column with their own index.
+pub(crate) const COLUMN_MAP: &[(usize, usize)] = &[{}];
+/// `--input audio`: €1 is the transcript of the audio file given as argument,
+/// or of a `--record`ing.
+pub(crate) const AUDIO_INPUT: bool = {};
+#[cfg(feature = "audio")]
+pub(crate) const TRANSCRIPTION_MODEL: &str = "whisper-1";
+/// `--output image`: the answer is used as an image prompt and the generated
+/// PNG is written to this file.
+pub(crate) const IMAGE_OUTPUT: Option<&str> = {};
+#[cfg(feature = "image")]
+pub(crate) const IMAGE_MODEL: &str = "dall-e-3";
+/// `--tui`: a chat window; every message is €1 and each session remembers
+/// its earlier answers.
+pub(crate) const TUI: bool = {};
+/// `--notify`: a desktop notification when the program finishes or fails.
+pub(crate) const NOTIFY: bool = {};
+/// `--serve`: answer OpenAI-style chat completion requests over HTTP instead
+/// of reading stdin. The last user message is €1.
+pub(crate) const SERVE: bool = {};
+pub(crate) const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+/// `--serve-keys`: who may call, as (name, bearer token XOR KEY_MASK, the
+/// environment variable with the key their answers are paid with, or "" for
+/// the program's own).
+pub(crate) const SERVE_KEYS: &[(&str, &[u8], &str)] = &[{}];
+/// `--serve-auth`: a command that gets a caller's token on stdin, and lets
+/// them in by exiting with 0 and printing name[,KEY_VAR].
+pub(crate) const SERVE_AUTH: Option<&str> = {};
+/// `--listen-socket`: answer length-prefixed JSON requests on this Unix socket
+/// (named pipe on Windows) instead of reading stdin.
+pub(crate) const LISTEN_SOCKET: Option<&str> = {};
+/// `--stream-filter`: run once per stdin line as the lines arrive, printing
+/// each answer as soon as it's in.
+pub(crate) const STREAM_FILTER: bool = {};
+/// Lines a stream filter or a `for every line` block works on at once unless
+/// `--parallel` says otherwise.
+pub(crate) const DEFAULT_PARALLEL: usize = 4;
+/// `--hook`: (point, command) pairs run around every model call, in order.
+/// Points are pre-request, per-delta and post-response.
+pub(crate) const HOOKS: &[(&str, &str)] = &[{}];
+
+/// Statements answered at compile time by --precompute, and their answer.
+/// The answer is replayed as the model's earlier reply before the rest runs.
+pub(crate) const PRECOMPUTED: Option<(&str, &str)> = {};
+
+/// One unit of work: statements sent to `model`, or a single statement run
+/// here. `local` is a `builtin:` operation, `shell:` or the name of an extern program's
+/// binary (which has to be next to this one), with its argument templates.
+pub(crate) struct Step {{
+    pub(crate) model: &'static str,
+    pub(crate) pseudocode: &'static str,
+    pub(crate) local: Option<(&'static str, &'static [&'static str])>,
+    /// Asked of every ensemble model instead of `model`.
+    pub(crate) ensemble: bool,
+    /// `(chill)`, `(wild)` or `temperature 0.7`: sent instead of TEMPERATURE.
+    pub(crate) temperature: Option<f64>,
+    /// `-> name`: later steps get the answer wherever they say `{{VAR_name}}`.
+    pub(crate) binding: Option<&'static str>,
+    /// `as json {{...}}`: the JSON Schema the answer is asked for in, and
+    /// checked against before it's shown.
+    pub(crate) schema: Option<&'static str>,
+    /// `again! N times` starting here: N (which may be a placeholder), and how
+    /// many steps from this one on go around, with the round as `{{VAR_loop}}`.
+    pub(crate) repeat: Option<(&'static str, usize)>,
+    /// `if the vibe is` starting here: the vibe, and how many steps from this
+    /// one on run if the last answer has it, and how many after those if not.
+    pub(crate) branch: Option<(&'static str, usize, usize)>,
+    /// `all at once!` starting here: how many steps from this one on are
+    /// asked at the same time, each seeing only the history before them.
+    pub(crate) together: Option<usize>,
+    /// `for every line` starting here: how many steps from this one on run
+    /// again for each line of stdin, with the line as `{{VAR_line}}`.
+    pub(crate) every_line: Option<usize>,
+    /// `or try again N times`: how often the step is asked again if it fails.
+    pub(crate) retries: u32,
+    /// `write it to "..."`: the file the answer goes into instead of stdout.
+    pub(crate) write_to: Option<&'static str>,
+    /// `better be "..."`: what `--check` expects the answer to be.
+    pub(crate) expect: Option<&'static str>,
 }}
-"###, has_embedded_key, encrypted_key_str, xor_key_str, escaped_model, escaped_code, arg_reading_code, pseudocode_var);
-    code
-}
 
-fn make_temp_project_dir(prefix: &str) -> std::path::PathBuf {
-    let mut p = env::temp_dir();
-    let nanos = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .map(|d| d.as_nanos())
-        .unwrap_or(0);
-    let pid = std::process::id();
-    p.push(format!("{}-{}-{}", prefix, pid, nanos));
-    p
+/// The execution plan, in order. Each step sees the earlier ones as history.
+pub(crate) const STEPS: &[Step] = &[
+    {}
+];
+
+/// `variant A: "...", variant B: "..."`: the share of runs (in percent) that
+/// get STEPS, and the plan the other runs get instead.
+pub(crate) const EXPERIMENT: Option<(u32, &[Step])> = {};
+
+/// `translate output to:` as (language, model). The answer is translated by a
+/// call of its own, so the steps above never see the target language.
+pub(crate) const TRANSLATE_TO: Option<(&str, &str)> = {};
+
+/// `remember!`: every stdin record continues the conversation of the ones
+/// before it.
+pub(crate) const REMEMBER: bool = {};
+
+/// `for every line`: stdin is the lines the block runs for, not the
+/// program's arguments.
+pub(crate) const EVERY_LINE: bool = {};
+
+/// `€*`: the program reads all of stdin, and every line of it comes after
+/// the other arguments, one per line.
+pub(crate) const ALL_ARGS: bool = {};
+
+/// `--ensemble`: (models, judge). Ensemble steps go to all models at once; the
+/// judge sees their answers and replies with the one to keep.
+pub(crate) const ENSEMBLE: Option<(&[&str], &str)> = {};
+/// `--glossary`: (term, what an answer has to say for it). Every answer to a
+/// prompt that mentions a term is checked for it.
+pub(crate) const GLOSSARY: &[(&str, &str)] = &[{}];
+/// The glossary as told to the model, after every system prompt.
+pub(crate) const GLOSSARY_PROMPT: &str = "{}";
+/// `--filter <profanity|pii>=<block|mask|warn>`: what happens to answers that
+/// contain either.
+pub(crate) const FILTERS: &[(&str, &str)] = &[{}];
+/// `--banner`: shown on stderr the first time a user runs this program.
+pub(crate) const BANNER: Option<&str> = {};
+/// `--fallback`: (FNV-1a of `fallback_prompt`, answer), for when the model is
+/// unavailable.
+pub(crate) const FALLBACK: &[(u64, &str)] = &[{}];
+/// `--fail-on-refusal`: when the model declines, print nothing and exit with
+/// REFUSAL_EXIT_CODE instead of passing the apology on as the answer.
+pub(crate) const FAIL_ON_REFUSAL: bool = {};
+pub(crate) const REFUSAL_EXIT_CODE: i32 = 3;
+/// How the error a refusal ends the program with starts.
+pub(crate) const REFUSED: &str = "The model refused";
+/// `--cache`: model steps look for their answer in the cache before asking.
+pub(crate) const CACHE: bool = {};
+/// `--cache-similarity`: how similar (0 to 1) a cached request has to be to
+/// count; `None` counts exact ones only.
+pub(crate) const CACHE_SIMILARITY: Option<f64> = {};
+/// `--cache-backend`: `redis://...` or `s3://...`, shared with every machine
+/// that runs this program.
+pub(crate) const CACHE_BACKEND: Option<&str> = {};
+/// `--cache-ttl`: seconds a cached answer counts.
+pub(crate) const CACHE_TTL: Option<u64> = {};
+/// The program's hash, which its answers are kept under in the cache backend.
+pub(crate) const CACHE_NAMESPACE: &str = "{:016x}";
+/// `--broker`: model calls wait for their turn, and maybe a key, from
+/// `matthiashihic broker`.
+pub(crate) const BROKER: bool = {};
+/// `--profile locked-down`: the program may not write files, so
+/// `--record-fallback` isn't there.
+pub(crate) const LOCKED_DOWN: bool = {};
+/// `--watermark`: "line" or "invisible"; every answer ends with where it came
+/// from, written that way.
+pub(crate) const WATERMARK: Option<&str> = {};
+/// `--background`: the one step is submitted as a job, and `--poll <id>`
+/// gets its answer.
+pub(crate) const BACKGROUND: bool = {};
+/// `--request-template`: the JSON body of every chat completions request, its
+/// "€name" strings filled in from the body the program would send otherwise.
+pub(crate) const REQUEST_TEMPLATE: Option<&str> = {};
+/// `--output csv --columns`: the answer's columns, which it's checked for and
+/// printed with, quoted where it has to be.
+pub(crate) const CSV_COLUMNS: Option<&[&str]> = {};
+/// Whether the CSV starts with a header line of CSV_COLUMNS.
+pub(crate) const CSV_HEADER: bool = {};
+pub(crate) const JUDGE_PROMPT: &str = "You are given a request and several candidate answers to it from different assistants. Pick the best answer, or merge them into a better one if each gets something right. Reply with the final answer only, exactly as it should be shown, without mentioning the candidates.";
+
+pub(crate) const SYSTEM_PROMPT: &str = "{}";
+
+/// Whether a key was compiled in, as EMBEDDED_KEY XOR KEY_MASK.
+pub(crate) const HAS_EMBEDDED_KEY: bool = {};
+pub(crate) const EMBEDDED_KEY: &[u8] = &[{}];
+pub(crate) const KEY_MASK: &[u8] = &[{}];
+/// `--key-expires`: the last day (UTC) the embedded key is used.
+pub(crate) const KEY_EXPIRES: Option<&str> = {};
+
+/// `--model`: where the steps go unless they say otherwise.
+pub(crate) const MODEL: &str = "{}";
+"###, escaped_metadata, escaped_name, escaped_version, escaped_about, temperature_str, max_tokens_str, sinks_str, sink_template_str, !opts.sink_only, opts.output_buffer, opts.resume, opts.repair, opts.session_tokens, required_args_str, arg_types_str, arg_defaults_str, named_args_str, secrets_str, env_vars_str, files_str, dirs_str, git_str, opts.stdin_format.as_str(), opts.skip_header, column_map_str, opts.audio_input, image_output_str, opts.tui, opts.notify, opts.serve, serve_keys_str, serve_auth_str, listen_socket_str, opts.stream_filter, hooks_str, precomputed_str, steps_str, experiment_str, translate_str, program.remember, !program.every_line.is_empty(), program.all_statements().any(Statement::uses_all_args), ensemble_str,
+    glossary_str, glossary_prompt_str, filters_str, banner_str, fallback_str, opts.fail_on_refusal, opts.cache, cache_similarity_str, cache_backend_str, cache_ttl_str, cache_namespace, opts.broker, opts.profile == Some(profile::Profile::LockedDown), watermark_str, opts.background, request_template_str, csv_columns_str, opts.csv_header, escape_rust_string(&system_prompt), has_embedded_key, encrypted_key_str, xor_key_str, key_expires_str, escaped_model);
+    let mut files = vec![("config.rs", config)];
+    if opts.types {
+        files.push(("types.rs", types::runtime(program)));
+    }
+    files.extend(RUNTIME_MODULES.iter().map(|(name, source)| (*name, source.to_string())));
+    files
 }
 
-fn create_cargo_project(project_dir: &std::path::Path, rust_source: &str) -> Result<(), Box<dyn std::error::Error>> {
-    // Create project structure
-    fs::create_dir_all(project_dir)?;
-    let src_dir = project_dir.join("src");
-    fs::create_dir_all(&src_dir)?;
-    
-    // Write main.rs
-    fs::write(src_dir.join("main.rs"), rust_source)?;
-    
-    // Write Cargo.toml
-    let cargo_toml = r#"[package]
-name = "matthiashihic_exec"
-version = "0.1.0"
-edition = "2021"
 
+/// The temp project's Cargo.toml. The package always builds a `matthiashihic_exec`
+/// binary; version and description come from the program's directives, and
+/// `rest` (dependencies, profiles) is appended as is.
+fn generate_cargo_toml(program: &Program, rest: &str) -> String {
+    let version = program.info.version.as_deref().unwrap_or("0.1.0");
+    let mut package = format!(
+        "[package]\nname = \"matthiashihic_exec\"\nversion = {}\nedition = \"2021\"\n",
+        json_string(version)
+    );
+    if let Some(about) = &program.info.about {
+        package.push_str(&format!("description = {}\n", json_string(about)));
+    }
+    if let Some(name) = &program.info.name {
+        package.push_str(&format!("\n[package.metadata.matthiashihic]\nname = {}\n", json_string(name)));
+    }
+    package + rest
+}
+
+const RUNTIME_DEPENDENCIES: &str = r#"
 [dependencies]
 reqwest = { version = "0.12", features = ["json", "stream"] }
 serde_json = "1.0"
 tokio = { version = "1", features = ["full"] }
 futures-util = "0.3"
+lettre = { version = "0.11", default-features = false, features = ["builder", "smtp-transport", "tokio1", "tokio1-native-tls"], optional = true }
+base64 = { version = "0.22", optional = true }
+ratatui = { version = "0.30", optional = true }
+axum = { version = "0.8", optional = true }
+pdf-extract = { version = "0.12", optional = true }
+zip = { version = "9", default-features = false, features = ["deflate-flate2-zlib-rs"], optional = true }
+hmac-sha256 = { version = "1.1", optional = true }
+serde = { version = "1", features = ["derive"], optional = true }
+
+[features]
+smtp = ["dep:lettre"]
+audio = ["reqwest/multipart"]
+image = ["dep:base64"]
+tui = ["dep:ratatui"]
+serve = ["dep:axum"]
+documents = ["dep:pdf-extract", "dep:zip"]
+s3 = ["dep:hmac-sha256"]
+types = ["dep:serde"]
 "#;
-    fs::write(project_dir.join("Cargo.toml"), cargo_toml)?;
-    
+
+fn create_cargo_project(project_dir: &std::path::Path, sources: &SourceFiles, cargo_toml: &str) -> Result<(), Box<dyn std::error::Error>> {
+    // Create project structure
+    fs::create_dir_all(project_dir)?;
+    let src_dir = project_dir.join("src");
+    fs::create_dir_all(&src_dir)?;
+
+    // Files that didn't change are left alone, so a project that's built again
+    // (like after --precompute) only sees what's new
+    for (name, contents) in sources {
+        write_if_changed(&src_dir.join(name), contents)?;
+    }
+    write_if_changed(&project_dir.join("Cargo.toml"), cargo_toml)?;
+
     Ok(())
 }
 
+fn write_if_changed(path: &std::path::Path, contents: &str) -> std::io::Result<()> {
+    if fs::read_to_string(path).is_ok_and(|existing| existing == contents) {
+        return Ok(());
+    }
+    fs::write(path, contents)
+}
 
+/// Builds the generated project in release mode and returns the path of the binary.
+fn cargo_build(project_dir: &std::path::Path, features: &[&str]) -> Result<std::path::PathBuf, String> {
+    let mut cargo = std::process::Command::new("cargo");
+    cargo
+        .arg("build")
+        .arg("--release")
+        .arg("--manifest-path")
+        .arg(project_dir.join("Cargo.toml"));
+    if !features.is_empty() {
+        cargo.arg("--features").arg(features.join(","));
+    }
+    let status = cargo
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::inherit())
+        .status();
+    match status {
+        Ok(s) if s.success() => Ok(project_dir.join("target").join("release").join("matthiashihic_exec")),
+        Ok(s) => Err(format!("Compiler exited with status: {}", s)),
+        Err(e) => Err(format!("Failed to spawn cargo: {}", e)),
+    }
+}
 
-fn parse_matthiashihic(contents: &str) -> Result<(String, Vec<usize>), String> {
-    // Split into lines but preserve order.
-    let lines: Vec<&str> = contents.lines().collect();
-    let mut required_args = std::collections::HashSet::<usize>::new();
-
-    // Find first non-empty line
-    let mut idx = 0usize;
-    while idx < lines.len() && lines[idx].trim().is_empty() {
-        idx += 1;
-    }
-    if idx >= lines.len() {
-        return Err("Empty file; expected 'hihi!' header".into());
-    }
-    if lines[idx].trim() != "hihi!" {
-        return Err("First non-empty line must be exactly: hihi!".into());
-    }
-    idx += 1;
-
-    let mut code_lines = Vec::<String>::new();
-    let mut terminator_found = false;
-    while idx < lines.len() {
-        let line = lines[idx];
-        let t = line.trim();
-        if t.is_empty() {
-            idx += 1;
-            continue;
-        }
-        if t == "eat that java!" {
-            terminator_found = true;
-            break;
-        }
-        // Parse a quoted string line: must start with " and end with "
-        let trimmed = line.trim_start();
-        if !trimmed.starts_with('\"') {
-            return Err(format!(
-                "Only quoted string statements allowed. Error at line {}: {}",
-                idx + 1,
-                line
-            ));
-        }
-        // parse contents until unescaped closing quote
-        let mut inner = String::new();
-        let mut escaped = false;
-        let mut found_closing_quote = false;
-        let mut char_indices = trimmed.char_indices().skip(1); // skip opening quote
-        
-        while let Some((pos, ch)) = char_indices.next() {
-            if escaped {
-                // simple escapes: \n, \t, \r, \\, \"
-                let mapped = match ch {
-                    'n' => '\n',
-                    't' => '\t',
-                    'r' => '\r',
-                    '\\' => '\\',
-                    '"' => '"',
-                    other => other, // unknown escape -> take literally
-                };
-                inner.push(mapped);
-                escaped = false;
-                continue;
-            }
-            if ch == '\\' {
-                escaped = true;
-                continue;
-            }
-            if ch == '"' {
-                // done; ensure the rest are whitespace
-                let rest = &trimmed[pos + ch.len_utf8()..];
-                if rest.trim().is_empty() {
-                    found_closing_quote = true;
-                    // Process the string for €index placeholders and €€index escaping
-                    let processed = process_placeholders(&inner, &mut required_args)?;
-                    code_lines.push(processed);
-                    break;
-                } else {
-                    return Err(format!(
-                        "Trailing characters after closing quote at line {}: {}",
-                        idx + 1,
-                        rest
-                    ));
-                }
-            } else {
-                inner.push(ch);
-            }
-        }
-        // If the inner string didn't get closed (we exited loop), try to detect that:
-        if !found_closing_quote {
-            // It means we didn't find a closing quote properly
-            return Err(format!(
-                "Missing closing quote for string starting at line {}: {}",
-                idx + 1,
-                line
-            ));
-        }
-        idx += 1;
-    }
-
-    if !terminator_found {
-        return Err("Missing terminator line: eat that java!".into());
-    }
-
-    let mut args_vec: Vec<usize> = required_args.into_iter().collect();
-    args_vec.sort();
-    Ok((code_lines.join("\n"), args_vec))
+/// `--fuzz-stdin`: the library's `fuzz_parse`, and code generated for whatever
+/// parses. It must never panic either.
+#[cfg(feature = "fuzz")]
+fn fuzz_compile(bytes: &[u8]) {
+    if let Some(program) = matthiashihic::fuzz_parse(bytes) {
+        let opts = CompileOptions {
+            source_name: "fuzz.matthiashihic".to_string(),
+            api_key: None,
+            model: program.front_matter.model.clone().unwrap_or_else(|| "gpt-4".to_string()),
+            temperature: program.front_matter.temperature,
+            max_tokens: program.front_matter.max_tokens,
+            sinks: Vec::new(),
+            sink_template: None,
+            sink_only: false,
+            stdin_format: StdinFormat::Lines,
+            column_map: Vec::new(),
+            skip_header: false,
+            precomputed: None,
+            builtins: true,
+            cheap_model: Some(planner::DEFAULT_CHEAP_MODEL.to_string()),
+            audio_input: false,
+            image_output: None,
+            tui: false,
+            notify: false,
+            ensemble: None,
+            serve: false,
+            serve_keys: Vec::new(),
+            serve_auth: None,
+            stream_filter: false,
+            listen_socket: None,
+            output_buffer: DEFAULT_OUTPUT_BUFFER,
+            resume: 0,
+            repair: DEFAULT_REPAIR_ATTEMPTS,
+            session_tokens: None,
+            hooks: Vec::new(),
+            glossary: Vec::new(),
+            filters: Vec::new(),
+            banner: None,
+            fallback: Vec::new(),
+            fail_on_refusal: false,
+            cache: false,
+            cache_similarity: None,
+            cache_backend: None,
+            cache_ttl: None,
+            broker: false,
+            profile: None,
+            key_expires: None,
+            watermark: None,
+            background: false,
+            request_template: None,
+            types: false,
+            csv_columns: None,
+            csv_header: true,
+        };
+        let _ = generate_executable_source(&opts, &program);
+        let _ = generate_cargo_toml(&program, RUNTIME_DEPENDENCIES);
+    }
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let prog = args.get(0).map(|s| s.as_str()).unwrap_or("matthiashihic");
+    let mut args: Vec<String> = env::args().collect();
+    // `--locale` goes first so even fmt and check speak it
+    let locale = match args.iter().position(|a| a == "--locale") {
+        Some(i) if i + 1 < args.len() => Some(args.drain(i..i + 2).nth(1).unwrap_or_default()),
+        Some(_) => {
+            eprintln!("--locale requires an argument");
+            std::process::exit(2);
+        }
+        None => None,
+    };
+    if let Err(e) = i18n::init(locale.as_deref()) {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    }
+    // AFL/honggfuzz-style harness: one input per run on stdin
+    #[cfg(feature = "fuzz")]
+    if args.get(1).map(|a| a.as_str()) == Some("--fuzz-stdin") {
+        use std::io::Read;
+        let mut bytes = Vec::new();
+        let _ = std::io::stdin().read_to_end(&mut bytes);
+        fuzz_compile(&bytes);
+        return;
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("fmt") {
+        std::process::exit(fmt::run(&args[2..]));
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("check") {
+        std::process::exit(check::run(&args[2..]));
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("load") {
+        std::process::exit(load::run(&args[2..]));
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("mock-server") {
+        std::process::exit(mock_server::run(&args[2..]));
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("route") {
+        std::process::exit(route::run(&args[2..]));
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("stats") {
+        std::process::exit(stats::run(&args[2..]));
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("trace") {
+        std::process::exit(trace::run(&args[2..]));
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("clean") {
+        std::process::exit(clean::run(&args[2..]));
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("emit-manpage") {
+        std::process::exit(manpage::run(&args[2..]));
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("broker") {
+        std::process::exit(broker::run(&args[2..]));
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("inspect") {
+        std::process::exit(inspect::run(&args[2..]));
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("provenance") {
+        std::process::exit(watermark::run(&args[2..]));
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("install") {
+        std::process::exit(install::install(&args[2..]));
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("get") {
+        std::process::exit(get::run(&args[2..]));
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("list") {
+        std::process::exit(install::list(&args[2..]));
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("uninstall") {
+        std::process::exit(install::uninstall(&args[2..]));
+    }
+    if args.get(1).map(|a| a.as_str()) == Some("upgrade") {
+        std::process::exit(install::upgrade(&args[2..]));
+    }
+    let prog = args.first().map(|s| s.as_str()).unwrap_or("matthiashihic");
     if args.len() < 2 {
         usage_and_exit(prog);
     }
+    let bake = args[1] == "bake";
+    let explain = args[1] == "explain";
 
     let mut src_path: Option<String> = None;
     let mut api_key: Option<String> = None;
-    let mut model: String = "gpt-4".to_string();
+    let mut model: Option<String> = None;
     let mut out_path: Option<std::path::PathBuf> = None;
+    let mut sinks: Vec<Sink> = Vec::new();
+    let mut sink_template_path: Option<String> = None;
+    let mut request_template_path: Option<String> = None;
+    let mut sink_only = false;
+    let mut stdin_format = StdinFormat::Lines;
+    let mut column_map: Vec<(usize, usize)> = Vec::new();
+    let mut skip_header = false;
+    let mut precompute = false;
+    let mut compress_ratio: Option<f64> = None;
+    let mut link_mode = link::LinkMode::Inline;
+    let mut builtins = true;
+    let mut no_shell = false;
+    let mut cheap_model = Some(planner::DEFAULT_CHEAP_MODEL.to_string());
+    let mut explain_plan = false;
+    let mut audio_input = false;
+    let mut image_output: Option<String> = None;
+    let mut csv_output = false;
+    let mut csv_columns: Option<Vec<String>> = None;
+    let mut csv_header = true;
+    let mut tui = false;
+    let mut notify = false;
+    let mut fail_on_refusal = false;
+    let mut cache = false;
+    let mut cache_similarity: Option<f64> = None;
+    let mut cache_backend: Option<String> = None;
+    let mut cache_ttl: Option<u64> = None;
+    let mut broker = false;
+    let mut profile: Option<profile::Profile> = None;
+    let mut key_expires: Option<String> = None;
+    let mut watermark: Option<watermark::Watermark> = None;
+    let mut background = false;
+    let mut serve = false;
+    let mut stream_filter = false;
+    let mut listen_socket: Option<String> = None;
+    let mut emit_manpage = false;
+    let mut emit_types = false;
+    let mut emit_typescript = false;
+    let mut packages: Vec<package::Package> = Vec::new();
+    let mut output_buffer: Option<usize> = None;
+    let mut resume = 0;
+    let mut repair = DEFAULT_REPAIR_ATTEMPTS;
+    let mut session_tokens: Option<u64> = None;
+    let mut hooks: Vec<(String, String)> = Vec::new();
+    let mut glossary = Vec::new();
+    let mut fallback = Vec::new();
+    let mut filters: Vec<(String, String)> = Vec::new();
+    let mut banner_path: Option<String> = None;
+    // `--compat openai`; the only flavour there is so far
+    let mut compat = false;
+    let mut serve_keys = Vec::new();
+    let mut serve_auth: Option<String> = None;
+    let mut ensemble: Option<Vec<String>> = None;
+    let mut judge: Option<String> = None;
+    let mut lint_levels = LintLevels::default();
 
-    let mut i = 1;
+    let mut i = if bake || explain { 2 } else { 1 };
     while i < args.len() {
         match args[i].as_str() {
             "--api-key" => {
                 if i + 1 >= args.len() {
-                    eprintln!("--api-key requires an argument");
+                    eprintln!("--api-key requires an argument");
+                    usage_and_exit(prog);
+                }
+                api_key = Some(args[i + 1].clone());
+                i += 2;
+            }
+            "--model" => {
+                if i + 1 >= args.len() {
+                    eprintln!("--model requires an argument");
+                    usage_and_exit(prog);
+                }
+                model = Some(args[i + 1].clone());
+                i += 2;
+            }
+            "-o" => {
+                if i + 1 >= args.len() {
+                    eprintln!("-o requires an argument");
+                    usage_and_exit(prog);
+                }
+                out_path = Some(std::path::PathBuf::from(args[i + 1].clone()));
+                i += 2;
+            }
+            "--sink" => {
+                if i + 1 >= args.len() {
+                    eprintln!("--sink requires an argument");
+                    usage_and_exit(prog);
+                }
+                match Sink::parse(&args[i + 1]) {
+                    Ok(sink) => sinks.push(sink),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        usage_and_exit(prog);
+                    }
+                }
+                i += 2;
+            }
+            "--sink-template" => {
+                if i + 1 >= args.len() {
+                    eprintln!("--sink-template requires an argument");
+                    usage_and_exit(prog);
+                }
+                sink_template_path = Some(args[i + 1].clone());
+                i += 2;
+            }
+            "--request-template" => {
+                if i + 1 >= args.len() {
+                    eprintln!("--request-template requires a file");
+                    usage_and_exit(prog);
+                }
+                request_template_path = Some(args[i + 1].clone());
+                i += 2;
+            }
+            "--sink-only" => {
+                sink_only = true;
+                i += 1;
+            }
+            "--stdin-format" => {
+                if i + 1 >= args.len() {
+                    eprintln!("--stdin-format requires an argument");
+                    usage_and_exit(prog);
+                }
+                match StdinFormat::parse(&args[i + 1]) {
+                    Ok(format) => stdin_format = format,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        usage_and_exit(prog);
+                    }
+                }
+                i += 2;
+            }
+            "--map-columns" => {
+                if i + 1 >= args.len() {
+                    eprintln!("--map-columns requires an argument");
+                    usage_and_exit(prog);
+                }
+                match parse_column_map(&args[i + 1]) {
+                    Ok(map) => column_map = map,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        usage_and_exit(prog);
+                    }
+                }
+                i += 2;
+            }
+            "--skip-header" => {
+                skip_header = true;
+                i += 1;
+            }
+            "--columns" => {
+                let columns: Option<Vec<String>> = args.get(i + 1).map(|list| list.split(',').map(|name| name.trim().to_string()).collect());
+                match columns {
+                    Some(columns) if columns.iter().all(|name| !name.is_empty()) => {
+                        if let Some(name) = columns.iter().enumerate().find(|(n, name)| columns[..*n].contains(name)).map(|(_, name)| name) {
+                            eprintln!("--columns has '{}' twice", name);
+                            usage_and_exit(prog);
+                        }
+                        csv_columns = Some(columns);
+                    }
+                    _ => {
+                        eprintln!("--columns requires the CSV's column names, e.g. --columns name,score");
+                        usage_and_exit(prog);
+                    }
+                }
+                i += 2;
+            }
+            "--no-header" => {
+                csv_header = false;
+                i += 1;
+            }
+            "--precompute" => {
+                precompute = true;
+                i += 1;
+            }
+            "--cheap-model" => {
+                if i + 1 >= args.len() {
+                    eprintln!("--cheap-model requires a model name (or 'none')");
+                    usage_and_exit(prog);
+                }
+                cheap_model = match args[i + 1].as_str() {
+                    "none" => None,
+                    name => Some(name.to_string()),
+                };
+                i += 2;
+            }
+            "--input" => {
+                if i + 1 >= args.len() {
+                    eprintln!("--input requires text or audio");
+                    usage_and_exit(prog);
+                }
+                audio_input = match args[i + 1].as_str() {
+                    "text" => false,
+                    "audio" => true,
+                    other => {
+                        eprintln!("Unknown input mode '{}' (supported: text, audio)", other);
+                        usage_and_exit(prog);
+                    }
+                };
+                i += 2;
+            }
+            "--output" => {
+                match args.get(i + 1).map(|s| s.as_str()) {
+                    Some("text") => {
+                        image_output = None;
+                        csv_output = false;
+                        i += 2;
+                    }
+                    Some("csv") => {
+                        image_output = None;
+                        csv_output = true;
+                        i += 2;
+                    }
+                    Some("image") => {
+                        if i + 2 >= args.len() {
+                            eprintln!("--output image requires the PNG file to write, e.g. --output image out.png");
+                            usage_and_exit(prog);
+                        }
+                        image_output = Some(args[i + 2].clone());
+                        csv_output = false;
+                        i += 3;
+                    }
+                    _ => {
+                        eprintln!("--output requires text, csv or image <file>");
+                        usage_and_exit(prog);
+                    }
+                }
+            }
+            "--tui" => {
+                tui = true;
+                i += 1;
+            }
+            "--notify" => {
+                notify = true;
+                i += 1;
+            }
+            "--fail-on-refusal" => {
+                fail_on_refusal = true;
+                i += 1;
+            }
+            "--cache" => {
+                cache = true;
+                i += 1;
+            }
+            "--cache-similarity" => {
+                match args.get(i + 1).and_then(|s| s.parse::<f64>().ok()) {
+                    Some(s) if s > 0.0 && s <= 1.0 => cache_similarity = Some(s),
+                    _ => {
+                        eprintln!("--cache-similarity requires a similarity above 0 and up to 1, e.g. 0.95");
+                        usage_and_exit(prog);
+                    }
+                }
+                // Similar hits are cache hits too
+                cache = true;
+                i += 2;
+            }
+            "--cache-backend" => {
+                let Some(spec) = args.get(i + 1) else {
+                    eprintln!("--cache-backend requires redis://host[:port][/db] or s3://bucket[/prefix]");
+                    usage_and_exit(prog);
+                };
+                match parse_cache_backend(spec) {
+                    Ok(backend) => cache_backend = Some(backend),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        usage_and_exit(prog);
+                    }
+                }
+                cache = true;
+                i += 2;
+            }
+            "--key-expires" => {
+                match args.get(i + 1).map(|date| parse_key_expiry(date)) {
+                    Some(Ok(date)) => key_expires = Some(date),
+                    Some(Err(e)) => {
+                        eprintln!("{}", e);
+                        usage_and_exit(prog);
+                    }
+                    None => {
+                        eprintln!("--key-expires requires a date like 2025-12-31");
+                        usage_and_exit(prog);
+                    }
+                }
+                i += 2;
+            }
+            "--cache-ttl" => {
+                match args.get(i + 1).and_then(|v| load::parse_duration(v)) {
+                    Some(ttl) if ttl.as_secs() > 0 => cache_ttl = Some(ttl.as_secs()),
+                    _ => {
+                        eprintln!("--cache-ttl requires an age like 30d, 12h or 90m");
+                        usage_and_exit(prog);
+                    }
+                }
+                i += 2;
+            }
+            "--broker" => {
+                broker = true;
+                i += 1;
+            }
+            "--profile" => {
+                if i + 1 >= args.len() {
+                    eprintln!("--profile requires a name (locked-down)");
+                    usage_and_exit(prog);
+                }
+                match profile::Profile::parse(&args[i + 1]) {
+                    Ok(p) => profile = Some(p),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        usage_and_exit(prog);
+                    }
+                }
+                i += 2;
+            }
+            "--watermark" => {
+                match args.get(i + 1).map(|style| watermark::Watermark::parse(style)) {
+                    Some(Ok(style)) => watermark = Some(style),
+                    Some(Err(e)) => {
+                        eprintln!("{}", e);
+                        usage_and_exit(prog);
+                    }
+                    None => {
+                        eprintln!("--watermark requires a style (line, invisible)");
+                        usage_and_exit(prog);
+                    }
+                }
+                i += 2;
+            }
+            "--background" => {
+                background = true;
+                i += 1;
+            }
+            "--serve" => {
+                serve = true;
+                i += 1;
+            }
+            "--stream-filter" => {
+                stream_filter = true;
+                i += 1;
+            }
+            "--emit" => {
+                let kind = args.get(i + 1).map(|kind| kind.as_str());
+                match (kind, kind.and_then(package::Package::parse)) {
+                    (Some("manpage"), _) => emit_manpage = true,
+                    (Some("types"), _) => emit_types = true,
+                    (Some("typescript"), _) => emit_typescript = true,
+                    (_, Some(package)) => {
+                        if !packages.contains(&package) {
+                            packages.push(package);
+                        }
+                    }
+                    _ => {
+                        eprintln!("--emit takes what to write next to the program: manpage, types, typescript, deb, rpm or brew-formula");
+                        usage_and_exit(prog);
+                    }
+                }
+                i += 2;
+            }
+            "--listen-socket" => {
+                match args.get(i + 1) {
+                    Some(path) if !path.starts_with('-') => listen_socket = Some(path.clone()),
+                    _ => {
+                        eprintln!("--listen-socket requires a path like /tmp/program.sock (or \\\\.\\pipe\\program on Windows)");
+                        usage_and_exit(prog);
+                    }
+                }
+                i += 2;
+            }
+            "--output-buffer" => {
+                match args.get(i + 1).and_then(|n| n.parse::<usize>().ok()) {
+                    Some(n) if n > 0 => output_buffer = Some(n),
+                    _ => {
+                        eprintln!("--output-buffer requires a number of pieces, at least 1");
+                        usage_and_exit(prog);
+                    }
+                }
+                i += 2;
+            }
+            "--resume" => {
+                match args.get(i + 1).and_then(|n| n.parse::<u32>().ok()) {
+                    Some(n) => resume = n,
+                    None => {
+                        eprintln!("--resume requires a number of attempts");
+                        usage_and_exit(prog);
+                    }
+                }
+                i += 2;
+            }
+            "--repair" => {
+                match args.get(i + 1).and_then(|n| n.parse::<u32>().ok()) {
+                    Some(n) => repair = n,
+                    None => {
+                        eprintln!("--repair requires a number of attempts, or 0 to fail right away");
+                        usage_and_exit(prog);
+                    }
+                }
+                i += 2;
+            }
+            "--session-tokens" => {
+                match args.get(i + 1).and_then(|n| n.parse::<u64>().ok()) {
+                    Some(n) if n > 0 => session_tokens = Some(n),
+                    _ => {
+                        eprintln!("--session-tokens requires a number of tokens, at least 1");
+                        usage_and_exit(prog);
+                    }
+                }
+                i += 2;
+            }
+            "--hook" => {
+                let Some((point, command)) = args.get(i + 1).and_then(|hook| hook.split_once('=')) else {
+                    eprintln!("--hook requires <point>=<command>, e.g. --hook post-response=./log.sh");
+                    usage_and_exit(prog);
+                };
+                if !HOOK_POINTS.contains(&point) {
+                    eprintln!("Unknown hook point '{}' (supported: {})", point, HOOK_POINTS.join(", "));
+                    usage_and_exit(prog);
+                }
+                if command.trim().is_empty() {
+                    eprintln!("--hook {} needs a command", point);
+                    usage_and_exit(prog);
+                }
+                hooks.push((point.to_string(), command.to_string()));
+                i += 2;
+            }
+            "--filter" => {
+                let Some((filter, policy)) = args.get(i + 1).and_then(|filter| filter.split_once('=')) else {
+                    eprintln!("--filter requires <filter>=<policy>, e.g. --filter pii=mask");
+                    usage_and_exit(prog);
+                };
+                if !OUTPUT_FILTERS.contains(&filter) {
+                    eprintln!("Unknown filter '{}' (supported: {})", filter, OUTPUT_FILTERS.join(", "));
+                    usage_and_exit(prog);
+                }
+                if !FILTER_POLICIES.contains(&policy) {
+                    eprintln!("Unknown policy '{}' for --filter {} (supported: {})", policy, filter, FILTER_POLICIES.join(", "));
+                    usage_and_exit(prog);
+                }
+                if filters.iter().any(|(existing, _)| existing == filter) {
+                    eprintln!("--filter {} is given more than once", filter);
+                    usage_and_exit(prog);
+                }
+                filters.push((filter.to_string(), policy.to_string()));
+                i += 2;
+            }
+            "--banner" => {
+                if i + 1 >= args.len() {
+                    eprintln!("--banner requires a file");
+                    usage_and_exit(prog);
+                }
+                banner_path = Some(args[i + 1].clone());
+                i += 2;
+            }
+            "--glossary" => {
+                let Some(path) = args.get(i + 1) else {
+                    eprintln!("--glossary requires a CSV file");
+                    usage_and_exit(prog);
+                };
+                match glossary::read(path) {
+                    Ok(terms) => glossary = terms,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(2);
+                    }
+                }
+                i += 2;
+            }
+            "--fallback" => {
+                let Some(path) = args.get(i + 1) else {
+                    eprintln!("--fallback requires a JSONL file");
+                    usage_and_exit(prog);
+                };
+                match fallback::read(path) {
+                    Ok(answers) => fallback = answers,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(2);
+                    }
+                }
+                i += 2;
+            }
+            "--compat" => {
+                match args.get(i + 1).map(|s| s.as_str()) {
+                    Some("openai") => {}
+                    Some(other) => {
+                        eprintln!("Unknown API flavour '{}' (supported: openai)", other);
+                        usage_and_exit(prog);
+                    }
+                    None => {
+                        eprintln!("--compat requires an API flavour (openai)");
+                        usage_and_exit(prog);
+                    }
+                }
+                compat = true;
+                i += 2;
+            }
+            "--serve-keys" => {
+                let Some(path) = args.get(i + 1) else {
+                    eprintln!("--serve-keys requires a CSV file of name,token[,KEY_VAR] rows");
+                    usage_and_exit(prog);
+                };
+                match callers::read(path) {
+                    Ok(callers) => serve_keys = callers,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(2);
+                    }
+                }
+                i += 2;
+            }
+            "--serve-auth" => {
+                match args.get(i + 1) {
+                    Some(command) if !command.trim().is_empty() => serve_auth = Some(command.clone()),
+                    _ => {
+                        eprintln!("--serve-auth requires a command that checks a token");
+                        usage_and_exit(prog);
+                    }
+                }
+                i += 2;
+            }
+            "--ensemble" => {
+                if i + 1 >= args.len() {
+                    eprintln!("--ensemble requires a comma-separated list of models");
                     usage_and_exit(prog);
                 }
-                api_key = Some(args[i + 1].clone());
+                let mut models: Vec<String> = Vec::new();
+                for model in args[i + 1].split(',').map(str::trim) {
+                    if model.is_empty() || models.iter().any(|m| m == model) {
+                        eprintln!("--ensemble needs distinct, non-empty model names, got '{}'", args[i + 1]);
+                        usage_and_exit(prog);
+                    }
+                    models.push(model.to_string());
+                }
+                if models.len() < 2 {
+                    eprintln!("--ensemble needs at least two models; use --model for one");
+                    usage_and_exit(prog);
+                }
+                ensemble = Some(models);
                 i += 2;
             }
-            "--model" => {
+            "--judge" => {
                 if i + 1 >= args.len() {
-                    eprintln!("--model requires an argument");
+                    eprintln!("--judge requires a model name");
                     usage_and_exit(prog);
                 }
-                model = args[i + 1].clone();
+                judge = Some(args[i + 1].clone());
                 i += 2;
             }
-            "-o" => {
+            "--explain-plan" => {
+                explain_plan = true;
+                i += 1;
+            }
+            "--no-builtins" => {
+                builtins = false;
+                i += 1;
+            }
+            "--no-shell" => {
+                no_shell = true;
+                i += 1;
+            }
+            "--link" => {
                 if i + 1 >= args.len() {
-                    eprintln!("-o requires an argument");
+                    eprintln!("--link requires inline or dynamic");
+                    usage_and_exit(prog);
+                }
+                match link::LinkMode::parse(&args[i + 1]) {
+                    Ok(mode) => link_mode = mode,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        usage_and_exit(prog);
+                    }
+                }
+                i += 2;
+            }
+            "--compress" => {
+                if i + 1 >= args.len() {
+                    eprintln!("--compress requires a ratio, e.g. 0.7");
+                    usage_and_exit(prog);
+                }
+                match args[i + 1].parse::<f64>() {
+                    Ok(ratio) if ratio > 0.0 && ratio <= 1.0 => compress_ratio = Some(ratio),
+                    _ => {
+                        eprintln!("--compress ratio must be a number between 0 (exclusive) and 1, got '{}'", args[i + 1]);
+                        usage_and_exit(prog);
+                    }
+                }
+                i += 2;
+            }
+            flag @ ("-A" | "-W" | "-D") => {
+                if i + 1 >= args.len() {
+                    eprintln!("{} requires a lint name (or 'warnings')", flag);
+                    usage_and_exit(prog);
+                }
+                let level = match flag {
+                    "-A" => Level::Allow,
+                    "-W" => Level::Warn,
+                    _ => Level::Deny,
+                };
+                if let Err(e) = lint_levels.set(&args[i + 1], level) {
+                    eprintln!("{}", e);
                     usage_and_exit(prog);
                 }
-                out_path = Some(std::path::PathBuf::from(args[i + 1].clone()));
                 i += 2;
             }
             s if s.starts_with('-') => {
@@ -477,16 +2070,158 @@ fn main() {
     let src_path = match src_path {
         Some(p) => p,
         None => {
-            eprintln!("No source file specified");
+            eprintln!("{}", tr!("cli.no-source"));
             usage_and_exit(prog);
         }
     };
     
+    if bake && (!sinks.is_empty() || sink_template_path.is_some() || stdin_format != StdinFormat::Lines || precompute || audio_input || image_output.is_some() || tui || notify || ensemble.is_some() || serve || stream_filter || output_buffer.is_some() || !hooks.is_empty() || !filters.is_empty() || banner_path.is_some() || !fallback.is_empty() || fail_on_refusal || cache || broker || listen_socket.is_some() || emit_manpage || emit_types || emit_typescript || !packages.is_empty() || watermark.is_some() || background || request_template_path.is_some() || csv_output) {
+        eprintln!("bake only takes --api-key, --model, -o and lint flags; the baked binary just prints");
+        usage_and_exit(prog);
+    }
+
+    if key_expires.is_some() && api_key.is_none() {
+        eprintln!("--key-expires only makes sense with --api-key; there's no key to expire");
+        usage_and_exit(prog);
+    }
+
     // API key is now optional - can be provided at compile time or runtime via env var
-    if api_key.is_none() {
-        eprintln!("Note: No --api-key provided. Compiled program will require OPENAI_API_KEY environment variable.");
+    if api_key.is_none() && !bake && !explain_plan && !explain {
+        eprintln!("{}", tr!("cli.no-api-key"));
+    }
+
+    if sink_only && sinks.is_empty() {
+        eprintln!("--sink-only requires at least one --sink");
+        usage_and_exit(prog);
     }
 
+    if stdin_format == StdinFormat::Lines && (!column_map.is_empty() || skip_header) {
+        eprintln!("--map-columns and --skip-header require --stdin-format csv or tsv");
+        usage_and_exit(prog);
+    }
+
+    if background && precompute {
+        eprintln!("--background submits the program's one request; there's nothing to --precompute");
+        usage_and_exit(prog);
+    }
+
+    if audio_input && stdin_format != StdinFormat::Lines {
+        eprintln!("--input audio reads the audio instead of stdin; drop --stdin-format");
+        usage_and_exit(prog);
+    }
+
+    if (!serve_keys.is_empty() || serve_auth.is_some()) && !serve {
+        eprintln!("--serve-keys and --serve-auth check who calls --serve, so they need it");
+        usage_and_exit(prog);
+    }
+    if compat && !serve {
+        eprintln!("--compat only makes sense with --serve");
+        usage_and_exit(prog);
+    }
+
+    if serve && (tui || audio_input || image_output.is_some() || !sinks.is_empty() || stdin_format != StdinFormat::Lines || notify) {
+        eprintln!("--serve answers over HTTP; it can't be combined with --tui, --input audio, --output image, --sink, --stdin-format or --notify");
+        usage_and_exit(prog);
+    }
+
+    if stream_filter && (tui || serve || audio_input || image_output.is_some() || !sinks.is_empty() || stdin_format != StdinFormat::Lines) {
+        eprintln!("--stream-filter reads stdin line by line and prints each answer; it can't be combined with --tui, --serve, --input audio, --output image, --sink or --stdin-format");
+        usage_and_exit(prog);
+    }
+
+    if listen_socket.is_some() && (tui || serve || stream_filter || audio_input || image_output.is_some() || !sinks.is_empty() || stdin_format != StdinFormat::Lines || notify || fail_on_refusal) {
+        eprintln!("--listen-socket answers over the socket; it can't be combined with --tui, --serve, --stream-filter, --input audio, --output image, --sink, --stdin-format, --notify or --fail-on-refusal");
+        usage_and_exit(prog);
+    }
+
+    if csv_output && csv_columns.is_none() {
+        eprintln!("--output csv requires --columns with the CSV's column names, e.g. --columns name,score");
+        usage_and_exit(prog);
+    }
+
+    if !csv_output && (csv_columns.is_some() || !csv_header) {
+        eprintln!("--columns and --no-header only make sense with --output csv");
+        usage_and_exit(prog);
+    }
+
+    if csv_output && (tui || serve || stream_filter || listen_socket.is_some() || precompute || watermark.is_some()) {
+        eprintln!("--output csv prints the answer as CSV rows; it can't be combined with --tui, --serve, --stream-filter, --listen-socket, --precompute or --watermark");
+        usage_and_exit(prog);
+    }
+
+    if broker && !cfg!(unix) {
+        eprintln!("--broker talks to the broker over a Unix socket, which this platform doesn't have");
+        usage_and_exit(prog);
+    }
+
+    if cache_ttl.is_some() && !cache {
+        eprintln!("--cache-ttl only makes sense with --cache");
+        usage_and_exit(prog);
+    }
+
+    if judge.is_some() && ensemble.is_none() {
+        eprintln!("--judge only makes sense with --ensemble");
+        usage_and_exit(prog);
+    }
+
+    if fail_on_refusal && (tui || serve || stream_filter) {
+        eprintln!("--fail-on-refusal ends the program with an exit code; it can't be combined with --tui, --serve or --stream-filter");
+        usage_and_exit(prog);
+    }
+
+    if session_tokens.is_some() && !tui && !serve {
+        eprintln!("--session-tokens limits chat sessions, so it needs --tui or --serve");
+        usage_and_exit(prog);
+    }
+
+    if tui && (audio_input || image_output.is_some() || !sinks.is_empty() || stdin_format != StdinFormat::Lines || notify) {
+        eprintln!("--tui shows the answers in its window; it can't be combined with --input audio, --output image, --sink, --stdin-format or --notify");
+        usage_and_exit(prog);
+    }
+
+    let sink_template = match sink_template_path {
+        Some(path) => match fs::read_to_string(&path) {
+            Ok(t) => Some(t),
+            Err(e) => {
+                eprintln!("Failed to read sink template {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let request_template = match request_template_path {
+        Some(path) => match fs::read_to_string(&path) {
+            Ok(template) => {
+                if let Err(e) = request_template::check(&template) {
+                    eprintln!("Request template {} {}", path, e);
+                    std::process::exit(1);
+                }
+                Some(template)
+            }
+            Err(e) => {
+                eprintln!("Failed to read request template {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let banner = match banner_path {
+        Some(path) => match fs::read_to_string(&path) {
+            Ok(text) if text.trim().is_empty() => {
+                eprintln!("Banner {} is empty", path);
+                std::process::exit(1);
+            }
+            Ok(text) => Some(text.trim_end().to_string()),
+            Err(e) => {
+                eprintln!("Failed to read banner {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
     let src_path_buf = std::path::PathBuf::from(&src_path);
     if !src_path_buf.exists() {
         eprintln!("Source file does not exist: {}", src_path);
@@ -508,50 +2243,312 @@ fn main() {
     let src_contents = match fs::read_to_string(&src_path) {
         Ok(s) => s,
         Err(e) => {
-            eprintln!("Failed to read {}: {}", src_path, e);
+            eprintln!("{}", tr!("cli.read-failed", file = src_path, error = e));
             std::process::exit(1);
         }
     };
 
-    let (pseudocode, required_args) = match parse_matthiashihic(&src_contents) {
-        Ok(v) => v,
+    let (mut program, warnings) = match parse_file(&src_contents, &src_path_buf) {
+        Ok(parsed) => parsed,
         Err(e) => {
-            eprintln!("Parse error: {}", e);
+            eprint!("{}", diagnostics::render_parse_error(&src_path, &src_contents, &e));
+            if e.fix.is_some() {
+                eprintln!("{}", tr!("cli.run-fix", command = prog, file = src_path));
+            }
             std::process::exit(2);
         }
     };
 
-    // Generate Rust source code for the executable
-    let rust_src = generate_executable_source(api_key.as_deref(), &model, &pseudocode, &required_args);
+    let denied = diagnostics::report(&warnings, &lint_levels);
+    if denied > 0 {
+        eprintln!("{}", tr!("cli.denied", count = denied));
+        std::process::exit(2);
+    }
+
+    // Every other way to run a program answers one input at a time, or reads
+    // all of stdin in one run, so there'd be nothing to remember
+    if program.remember && !program.every_line.is_empty() {
+        eprintln!("{}", tr!("cli.remember-every-line"));
+        std::process::exit(2);
+    }
+    if program.remember && stdin_format == StdinFormat::Lines {
+        eprintln!("{}", tr!("cli.remember-one-record"));
+        std::process::exit(2);
+    }
+
+    if !program.every_line.is_empty() {
+        if let Some(index) = program.required_args.first() {
+            eprintln!("{}", tr!("cli.every-line-placeholder", index = index));
+            std::process::exit(2);
+        }
+        let flags = [
+            (stdin_format != StdinFormat::Lines, "--stdin-format"),
+            (audio_input, "--input audio"),
+            (tui, "--tui"),
+            (serve, "--serve"),
+            (stream_filter, "--stream-filter"),
+            (listen_socket.is_some(), "--listen-socket"),
+        ];
+        if let Some((_, flag)) = flags.iter().find(|(set, _)| *set) {
+            eprintln!("{}", tr!("cli.every-line-flag", flag = flag));
+            std::process::exit(2);
+        }
+    }
+
+    if program.all_statements().any(Statement::uses_all_args) {
+        let flags = [
+            (stdin_format != StdinFormat::Lines, "--stdin-format"),
+            (!program.every_line.is_empty(), "for every line"),
+            (audio_input, "--input audio"),
+            (tui, "--tui"),
+            (serve, "--serve"),
+            (stream_filter, "--stream-filter"),
+            (listen_socket.is_some(), "--listen-socket"),
+        ];
+        if let Some((_, flag)) = flags.iter().find(|(set, _)| *set) {
+            eprintln!("{}", tr!("cli.all-args-flag", flag = flag));
+            std::process::exit(2);
+        }
+    }
+
+    if stdin_format != StdinFormat::Lines {
+        if program.required_args.is_empty() && program.named_args.is_empty() {
+            eprintln!("{}", tr!("cli.stdin-format-placeholders", format = stdin_format.as_str()));
+            std::process::exit(2);
+        }
+        if let Some(&(_, placeholder)) = column_map.iter().find(|(_, p)| !program.required_args.contains(p)) {
+            eprintln!("{}", tr!("cli.map-columns-unused", placeholder = placeholder));
+            std::process::exit(2);
+        }
+    }
+
+    if audio_input && (program.required_args != [1] || !program.named_args.is_empty()) {
+        eprintln!("{}", tr!("cli.only-first-placeholder", flag = "--input audio"));
+        std::process::exit(2);
+    }
+
+    if tui && (program.required_args != [1] || !program.named_args.is_empty()) {
+        eprintln!("{}", tr!("cli.only-first-placeholder", flag = "--tui"));
+        std::process::exit(2);
+    }
+
+    if serve && (program.required_args != [1] || !program.named_args.is_empty()) {
+        eprintln!("{}", tr!("cli.only-first-placeholder", flag = "--serve"));
+        std::process::exit(2);
+    }
+
+    if stream_filter && (program.required_args != [1] || !program.named_args.is_empty()) {
+        eprintln!("{}", tr!("cli.only-first-placeholder", flag = "--stream-filter"));
+        std::process::exit(2);
+    }
+
+    if precompute && program.experiment.is_some() {
+        eprintln!("{}", tr!("cli.precompute-experiment"));
+        std::process::exit(2);
+    }
+
+    if let Err(e) = link::link(&mut program, &src_path_buf, link_mode) {
+        eprintln!("{}", tr!("cli.link-error", error = e));
+        std::process::exit(2);
+    }
+
+    // After linking, so an extern's shell statements count, too
+    if let Some(statement) = program.statements.iter().find(|statement| statement.shell).filter(|_| no_shell) {
+        eprintln!("{}", tr!("cli.no-shell", statement = statement.to_line()));
+        std::process::exit(2);
+    }
+
+    if (emit_types || emit_typescript) && !types::has_types(&program) {
+        eprintln!("{}", tr!("cli.emit-no-types", emit = if emit_types { "types" } else { "typescript" }));
+        std::process::exit(2);
+    }
+
+    if csv_output && program.translate_to.is_some() {
+        eprintln!("{}", tr!("cli.csv-translate"));
+        std::process::exit(2);
+    }
+
+    let variant_b = program.experiment.iter().map(|experiment| &experiment.b);
+    if let Some(statement) = program.statements.iter().chain(variant_b).find(|statement| statement.schema.is_some()).filter(|_| csv_output) {
+        eprintln!("{}", tr!("cli.csv-json", statement = statement.to_line()));
+        std::process::exit(2);
+    }
+
+    if let Some(statement) = program.statements.iter().find(|statement| statement.write_to.is_some()) {
+        let flags = [(serve, "--serve"), (listen_socket.is_some(), "--listen-socket"), (stream_filter, "--stream-filter")];
+        if let Some((_, flag)) = flags.iter().find(|(set, _)| *set) {
+            eprintln!("{}", tr!("cli.write-to-sent-back", flag = flag, statement = statement.to_line()));
+            std::process::exit(2);
+        }
+        if !filters.is_empty() {
+            eprintln!("{}", tr!("cli.write-to-filter", statement = statement.to_line()));
+            std::process::exit(2);
+        }
+    }
+
+    if let Some(ratio) = compress_ratio {
+        let (before, after) = compress::compress(&mut program, ratio);
+        eprintln!("{}", tr!("cli.compressed", before = before, after = after));
+    }
+
+    // Explicit flags win over the front-matter, which wins over the defaults
+    let model = model
+        .or_else(|| program.front_matter.model.clone())
+        .unwrap_or_else(|| "gpt-4".to_string());
+
+    if request_template.is_some() && model.starts_with("claude") {
+        eprintln!("{}", tr!("cli.request-template-anthropic", model = model));
+        usage_and_exit(prog);
+    }
+
+    let mut opts = CompileOptions {
+        source_name: src_path_buf
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| src_path.clone()),
+        api_key,
+        model,
+        temperature: program.front_matter.temperature,
+        max_tokens: program.front_matter.max_tokens,
+        sinks,
+        sink_template,
+        sink_only,
+        stdin_format,
+        column_map,
+        skip_header,
+        precomputed: None,
+        builtins,
+        cheap_model,
+        audio_input,
+        image_output,
+        tui,
+        notify,
+        ensemble: None,
+        serve,
+        serve_keys,
+        serve_auth,
+        stream_filter,
+        listen_socket,
+        output_buffer: output_buffer.unwrap_or(DEFAULT_OUTPUT_BUFFER),
+        resume,
+        repair,
+        session_tokens,
+        hooks,
+        glossary,
+        filters,
+        banner,
+        fallback,
+        fail_on_refusal,
+        cache,
+        cache_similarity,
+        cache_backend,
+        cache_ttl,
+        broker,
+        profile,
+        key_expires,
+        watermark,
+        background,
+        request_template,
+        types: emit_types,
+        csv_columns,
+        csv_header,
+    };
+    // The judge defaults to the program's model
+    opts.ensemble = ensemble.map(|models| (models, judge.unwrap_or_else(|| opts.model.clone())));
+
+    if let Some(profile) = opts.profile {
+        let violations = profile::violations(profile, &program, &opts);
+        if !violations.is_empty() {
+            eprintln!("--profile {} rules out what this program does:", profile.name());
+            for (restriction, what) in violations {
+                eprintln!("  {}: {}", restriction, what);
+            }
+            std::process::exit(2);
+        }
+    }
+
+    if opts.background {
+        if let Err(e) = background::check(&program, &opts) {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    }
+
+    if explain {
+        let precomputed = if precompute { precompute::static_prefix(&program, &opts) } else { 0 };
+        print!("{}", explain::explain(&program, &opts, precomputed));
+        std::process::exit(0);
+    }
+
+    if explain_plan {
+        let precomputed = if precompute { precompute::static_prefix(&program, &opts) } else { 0 };
+        match (&program.experiment, program.arm_b()) {
+            (Some(experiment), Some(arm_b)) => {
+                println!("Variant A ({}% of runs):", experiment.split);
+                print!("{}", opts.planner().explain(&program, &opts.model, precomputed));
+                println!("Variant B ({}% of runs):", 100 - experiment.split);
+                print!("{}", opts.planner().explain(&arm_b, &opts.model, precomputed));
+            }
+            _ => print!("{}", opts.planner().explain(&program, &opts.model, precomputed)),
+        }
+        std::process::exit(0);
+    }
+
+    // Named after the command, so `man ./hello.1` and `man hello` agree, and
+    // so are the packages
+    let out_stem = out_path.to_string_lossy();
+    let out_stem = out_stem.strip_suffix(".exe").unwrap_or(&out_stem).to_string();
+    let command = std::path::Path::new(&out_stem).file_name().map_or(out_stem.clone(), |name| name.to_string_lossy().into_owned());
+    for &package in &packages {
+        if let Err(e) = package::check(package, &program, &command) {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    }
 
     // Create temporary Cargo project
-    let temp_project = make_temp_project_dir("matthiashihic");
-    if let Err(e) = create_cargo_project(&temp_project, &rust_src) {
+    let temp_project = paths::temp_path("matthiashihic", "");
+
+    if precompute {
+        match precompute::run(&opts, &program, &temp_project) {
+            Ok(Some(precomputed)) => opts.precomputed = Some(precomputed),
+            Ok(None) => eprintln!("Note: --precompute found no leading statements without placeholders"),
+            Err(e) => {
+                eprintln!("{}", e);
+                let _ = fs::remove_dir_all(&temp_project);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Generate Rust source code for the executable
+    let (rust_src, cargo_toml) = if bake {
+        if let Err(e) = bake::check(&program, &opts) {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+        let answer = match precompute::run(&opts, &program, &temp_project) {
+            Ok(precomputed) => precomputed.map(|p| p.answer).unwrap_or_default(),
+            Err(e) => {
+                eprintln!("{}", e);
+                let _ = fs::remove_dir_all(&temp_project);
+                std::process::exit(1);
+            }
+        };
+        (bake::generate_source(&opts, &program, &answer), bake::cargo_toml(&program))
+    } else {
+        (generate_executable_source(&opts, &program), generate_cargo_toml(&program, RUNTIME_DEPENDENCIES))
+    };
+    if let Err(e) = create_cargo_project(&temp_project, &rust_src, &cargo_toml) {
         eprintln!("Failed to create temporary Cargo project: {}", e);
         std::process::exit(1);
     }
 
     // Compile with cargo
     let out_str = out_path.to_string_lossy();
-    eprintln!(
-        "Compiling {} -> {} using cargo ...",
-        src_path,
-        out_str
-    );
-    let status = std::process::Command::new("cargo")
-        .arg("build")
-        .arg("--release")
-        .arg("--manifest-path")
-        .arg(temp_project.join("Cargo.toml"))
-        .stdin(std::process::Stdio::null())
-        .stdout(std::process::Stdio::inherit())
-        .stderr(std::process::Stdio::inherit())
-        .status();
-
-    let compiled_binary = temp_project.join("target").join("release").join("matthiashihic_exec");
-
-    match status {
-        Ok(s) if s.success() => {
+    eprintln!("{}", tr!("cli.compiling", source = src_path, output = out_str));
+    match cargo_build(&temp_project, &opts.runtime_features(&program)) {
+        Ok(compiled_binary) => {
             // Copy compiled binary to output location
             if let Err(e) = fs::copy(&compiled_binary, &out_path) {
                 eprintln!("Failed to copy binary to {}: {}", out_str, e);
@@ -573,16 +2570,44 @@ fn main() {
             // Clean up temp project
             let _ = fs::remove_dir_all(&temp_project);
             
-            println!("Built executable: {}", out_str);
+            println!("{}", tr!("cli.built", output = out_str));
+            if emit_manpage {
+                let page_path = format!("{}.1", out_stem);
+                if let Err(e) = fs::write(&page_path, manpage::program(&program, &opts, &command)) {
+                    eprintln!("{}", tr!("cli.write-failed", file = page_path, error = e));
+                    std::process::exit(1);
+                }
+                println!("Wrote {}", page_path);
+            }
+            let type_files = [
+                (emit_types, "rs", types::rust(&program, &opts.source_name)),
+                (emit_typescript, "ts", types::typescript(&program, &opts.source_name)),
+            ];
+            for (_, extension, contents) in type_files.iter().filter(|(emit, ..)| *emit) {
+                let types_path = format!("{}.types.{}", out_stem, extension);
+                if let Err(e) = fs::write(&types_path, contents) {
+                    eprintln!("{}", tr!("cli.write-failed", file = types_path, error = e));
+                    std::process::exit(1);
+                }
+                println!("Wrote {}", types_path);
+            }
+            for &package in &packages {
+                match package::write(package, &program, &opts, &out_path, &command) {
+                    Ok(written) => {
+                        for path in written {
+                            println!("Wrote {}", path.display());
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+            }
             std::process::exit(0);
         }
-        Ok(s) => {
-            eprintln!("Compiler exited with status: {}", s);
-            let _ = fs::remove_dir_all(&temp_project);
-            std::process::exit(1);
-        }
         Err(e) => {
-            eprintln!("Failed to spawn cargo: {}", e);
+            eprintln!("{}", e);
             let _ = fs::remove_dir_all(&temp_project);
             std::process::exit(1);
         }
@@ -0,0 +1,564 @@
+//! Man pages in roff: one for a compiled program (`--emit manpage`), written
+//! next to it, and one for the compiler (`matthiashihic emit-manpage`), made
+//! from the same usage text `--help` prints, so the two never disagree.
+
+use std::fs;
+
+use crate::ast::{ArgType, Program, Segment, Statement};
+use crate::i18n::tr;
+use crate::{CompileOptions, Sink, StdinFormat};
+
+/// A man page as it's written, one request per line.
+struct Page {
+    out: String,
+}
+
+impl Page {
+    /// `.TH`: the command, its section, and the left footer.
+    fn new(command: &str, source: &str) -> Page {
+        let mut page = Page { out: String::new() };
+        page.request(&format!(
+            ".TH \"{}\" \"1\" \"\" \"{}\" \"User Commands\"",
+            escape(&command.to_uppercase()),
+            escape(source)
+        ));
+        page
+    }
+
+    fn request(&mut self, request: &str) {
+        self.out.push_str(request);
+        self.out.push('\n');
+    }
+
+    fn section(&mut self, title: &str) {
+        self.request(&format!(".SH \"{}\"", escape(&title.to_uppercase())));
+    }
+
+    fn subsection(&mut self, title: &str) {
+        self.request(&format!(".SS \"{}\"", escape(title)));
+    }
+
+    fn paragraph(&mut self, text: &str) {
+        self.request(".PP");
+        self.text(text);
+    }
+
+    /// A term (a flag or placeholder, bold with its <arguments> in italics)
+    /// and what it means.
+    fn item(&mut self, term: &str, description: &str) {
+        self.request(".TP");
+        self.request(&term_font(term));
+        self.text(description);
+    }
+
+    /// Lines shown as they are, for synopses and examples.
+    fn preformatted<'a>(&mut self, lines: impl IntoIterator<Item = &'a str>) {
+        self.request(".nf");
+        for line in lines {
+            self.text(line);
+        }
+        self.request(".fi");
+    }
+
+    fn text(&mut self, text: &str) {
+        for line in text.lines() {
+            let line = escape(line);
+            // A line starting with . or ' would be a request
+            if line.starts_with(['.', '\'']) {
+                self.out.push_str("\\&");
+            }
+            self.out.push_str(&line);
+            self.out.push('\n');
+        }
+    }
+}
+
+/// Escapes what roff would read as its own: backslashes, and hyphens, which
+/// it would otherwise typeset as dashes nobody can paste into a terminal.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\e").replace('-', "\\-")
+}
+
+fn term_font(term: &str) -> String {
+    let mut out = String::from("\\fB");
+    for ch in escape(term).chars() {
+        match ch {
+            '<' => out.push_str("\\fI<"),
+            '>' => out.push_str(">\\fB"),
+            ch => out.push(ch),
+        }
+    }
+    out.push_str("\\fR");
+    out
+}
+
+/// The man page of a compiled program called `command`: how it's run, what
+/// its placeholders take, and the flags and variables it reads.
+pub fn program(program: &Program, opts: &CompileOptions, command: &str) -> String {
+    let name = crate::program_name(opts, program);
+    let source = match &program.info.version {
+        Some(version) => format!("{} {}", name, version),
+        None => name.clone(),
+    };
+    let mut page = Page::new(command, &source);
+    let interface = Interface::of(opts);
+    let max_arg = program.required_args.iter().max().copied().unwrap_or(0);
+
+    page.section("name");
+    let about = program.info.about.as_deref().unwrap_or("a program compiled by matthiashihic");
+    page.text(&format!("{} - {}", command, about));
+
+    page.section("synopsis");
+    let named: String = program.named_args.iter().map(|name| format!(" --{} <{}>", name, name)).collect();
+    let synopsis = match interface {
+        Interface::Tui => vec![command.to_string()],
+        Interface::Serve => vec![format!("{} [--listen <addr>] [--access-log <file>]", command)],
+        Interface::Socket => vec![format!("{} [--listen-socket <path>]", command)],
+        Interface::StreamFilter => vec![format!("<lines> | {} [--parallel <n>]", command)],
+        Interface::Audio => vec![format!("{} <audio file>", command), format!("{} --record", command)],
+        Interface::Stdin if !program.every_line.is_empty() => vec![format!("<lines> | {}{} [--parallel <n>]", command, named)],
+        Interface::Stdin if max_arg == 0 && opts.stdin_format == StdinFormat::Lines => vec![format!("{}{}", command, named)],
+        Interface::Stdin if opts.stdin_format == StdinFormat::Lines => {
+            vec![format!("<{} line(s)> | {}{}", max_arg, command, named)]
+        }
+        Interface::Stdin => vec![format!("<{} rows> | {}{}", opts.stdin_format.as_str(), command, named)],
+    };
+    page.preformatted(synopsis.iter().map(String::as_str));
+
+    page.section("description");
+    let how = match interface {
+        Interface::Tui => "Opens a chat window; every message you send is €1, and each session remembers its earlier answers.".to_string(),
+        Interface::Serve => "Serves an OpenAI-compatible /v1/chat/completions; the last user message is €1.".to_string(),
+        Interface::Socket => format!(
+            "Listens on {} for other programs. Each message, either way, is a 4-byte big-endian length, then JSON. A request is {{\"args\": [\"€1\", ...], \"named\": {{\"name\": \"value\"}}}}; the answer comes back as {{\"type\": \"text\"}} pieces while it streams in, then \"done\" or \"error\".",
+            opts.listen_socket.as_deref().unwrap_or_default()
+        ),
+        Interface::StreamFilter => "Runs once per stdin line as the lines come in, printing each answer as soon as it's in.".to_string(),
+        Interface::Audio => "Transcribes the audio and runs with the transcript as €1.".to_string(),
+        Interface::Stdin if !program.every_line.is_empty() => {
+            "Runs its for every line block once per stdin line, with the line as €line, and prints the answers in the order of the lines.".to_string()
+        }
+        Interface::Stdin if max_arg == 0 && program.named_args.is_empty() => "Runs once and prints the answer.".to_string(),
+        Interface::Stdin if opts.stdin_format == StdinFormat::Lines => "Reads its placeholders from stdin, runs once and prints the answer.".to_string(),
+        Interface::Stdin if program.remember => format!(
+            "Runs once per {} row on stdin; columns fill the placeholders, and each row continues the conversation of the rows before it.",
+            opts.stdin_format.as_str()
+        ),
+        Interface::Stdin => format!("Runs once per {} row on stdin; columns fill the placeholders.", opts.stdin_format.as_str()),
+    };
+    page.paragraph(&how);
+    page.paragraph(&format!(
+        "Compiled by matthiashihic from {}; the statements are answered by {}.",
+        opts.source_name, opts.model
+    ));
+    if opts.background {
+        page.paragraph("The question is submitted as a background job and the program prints its ID; --poll <id> prints the answer once it's in.");
+    }
+    if opts.fail_on_refusal {
+        page.paragraph("If the model refuses, nothing is printed and the exit code is 3.");
+    }
+    if !opts.serve_keys.is_empty() || opts.serve_auth.is_some() {
+        page.paragraph("Callers need a key, sent as 'Authorization: Bearer <key>'; without one that's let in, the answer is a 401. Each caller's answers may be paid with a key of their own.");
+    }
+    if let Some(limit) = opts.session_tokens {
+        let session = if opts.serve { "A conversation (or every conversation of a request's \"user\")" } else { "Each session" };
+        page.paragraph(&format!(
+            "{} may use {} tokens. It's told when it has used 80% of them, and once they're gone it gets no more answers.",
+            session, limit
+        ));
+    }
+    let commands: Vec<String> = program.statements.iter().filter(|statement| statement.shell).map(Statement::to_line).collect();
+    if !commands.is_empty() {
+        page.paragraph(&format!("It runs commands with sh: {}.", commands.join(", ")));
+    }
+    let written: Vec<&str> = program.statements.iter().filter_map(|statement| statement.write_to.as_deref()).collect();
+    if !written.is_empty() {
+        page.paragraph(&format!("Some answers are written to files instead of printed: {}.", written.join(", ")));
+    }
+    match opts.watermark {
+        Some(crate::watermark::Watermark::Line) => {
+            page.paragraph("Every answer ends with a line saying which program, model and request it came from, and when.")
+        }
+        Some(crate::watermark::Watermark::Invisible) => page.paragraph(
+            "Every answer ends with an invisible line of zero-width characters saying which program, model and request it came from, and when; 'matthiashihic provenance' reads it.",
+        ),
+        None => {}
+    }
+
+    let files: Vec<(usize, &str)> = program
+        .all_statements()
+        .flat_map(|statement| &statement.segments)
+        .filter_map(|segment| match segment {
+            Segment::File { index, .. } => Some((*index, "a path; the file's text is sent (PDF and DOCX files are extracted)")),
+            Segment::Dir { index, .. } => Some((*index, "a directory; the matching files are sent, each under its name")),
+            _ => None,
+        })
+        .collect();
+    let git: Vec<&Segment> = program
+        .all_statements()
+        .flat_map(|statement| &statement.segments)
+        .filter(|segment| matches!(segment, Segment::GitDiff | Segment::GitLog(_)))
+        .collect();
+    let all_args = program.all_statements().any(Statement::uses_all_args);
+    if max_arg > 0 || all_args || !program.named_args.is_empty() || !git.is_empty() {
+        page.section("placeholders");
+    }
+    for index in 1..=max_arg {
+        let mut description = match interface {
+            Interface::Tui => "The message.".to_string(),
+            Interface::Serve => "The last user message.".to_string(),
+            Interface::StreamFilter => "The stdin line.".to_string(),
+            Interface::Audio => "The transcript.".to_string(),
+            Interface::Socket => format!("Value {} of the request's \"args\".", index),
+            Interface::Stdin if opts.stdin_format == StdinFormat::Lines => format!("Line {} of stdin.", index),
+            Interface::Stdin => {
+                let column = opts.column_map.iter().find(|(_, p)| *p == index).map_or(index, |(c, _)| *c);
+                format!("Column {} of each row.", column)
+            }
+        };
+        if !program.required_args.contains(&index) {
+            description.push_str(" Not used, but still read.");
+        }
+        if let Some((_, ty)) = program.arg_types.iter().find(|(arg, _)| *arg == index) {
+            let expected = match ty {
+                ArgType::Int => "an integer",
+                ArgType::Number => "a number",
+                ArgType::Path => "an existing path",
+                ArgType::Json => "valid JSON",
+            };
+            description.push_str(&format!(" Must be {}.", expected));
+        }
+        if let Some((_, what)) = files.iter().find(|(arg, _)| *arg == index) {
+            description.push_str(&format!(" It's {}.", what));
+        }
+        if let Some((_, value)) = program.arg_defaults.iter().find(|(arg, _)| *arg == index) {
+            description.push_str(&format!(" Defaults to \"{}\" when it's missing.", value));
+        }
+        page.item(&format!("€{}", index), &description);
+    }
+    if all_args {
+        page.item("€*", "Every line of stdin, one per line.");
+    }
+    for name in &program.named_args {
+        let (term, description) = match interface {
+            Interface::Socket => (format!("€{{{}}}", name), format!("\"{}\" in the request's \"named\".", name)),
+            _ if opts.stdin_format == StdinFormat::Lines => {
+                (format!("--{} <{}>", name, name), format!("€{{{}}}. A {}=value line on stdin works, too.", name, name))
+            }
+            _ if opts.skip_header => {
+                (format!("--{} <{}>", name, name), format!("€{{{}}}. A header column called {} works, too.", name, name))
+            }
+            _ => (format!("--{} <{}>", name, name), format!("€{{{}}}.", name)),
+        };
+        page.item(&term, &description);
+    }
+    let mut described = Vec::new();
+    for segment in git {
+        if described.contains(&segment) {
+            continue;
+        }
+        described.push(segment);
+        match segment {
+            Segment::GitDiff => page.item("€gitdiff", "The staged changes of the git repository it runs in."),
+            Segment::GitLog(count) => page.item(
+                &format!("€gitlog({})", count),
+                &format!("The last {} commits of the git repository it runs in.", count),
+            ),
+            _ => {}
+        }
+    }
+
+    page.section("options");
+    for (term, description) in flags(program, opts) {
+        page.item(&term, &description);
+    }
+
+    page.section("environment");
+    match &opts.key_expires {
+        Some(date) => page.item(
+            "OPENAI_API_KEY",
+            &format!("API key; overrides the key embedded at compile time, which is only used until {} (UTC).", date),
+        ),
+        None => page.item("OPENAI_API_KEY", "API key; overrides any key embedded at compile time."),
+    }
+    page.item("OPENAI_BASE_URL", "API to use instead of https://api.openai.com/v1.");
+    if opts.ensemble.as_ref().is_some_and(|(models, judge)| judge.starts_with("claude") || models.iter().any(|m| m.starts_with("claude"))) {
+        page.item("ANTHROPIC_API_KEY", "API key for the claude-* models.");
+    }
+    if program.experiment.is_some() {
+        page.item("MATTHIASHIHIC_HISTORY", "File runs are recorded in for 'matthiashihic stats'.");
+    }
+    if opts.background {
+        page.item("MATTHIASHIHIC_STATE_DIR", "Where the jobs are kept, in jobs/.");
+    }
+    if opts.banner.is_some() {
+        page.item("MATTHIASHIHIC_CONFIG_DIR", "Where it's remembered that the banner was shown.");
+    }
+    if opts.broker {
+        page.item("MATTHIASHIHIC_BROKER", "Socket of the 'matthiashihic broker' every model call waits for.");
+    }
+    if opts.sinks.iter().any(|sink| matches!(sink, Sink::Smtp(_))) {
+        page.item("SMTP_USERNAME, SMTP_PASSWORD", "Credentials of the mail server the answer is sent through.");
+    }
+    match opts.cache_backend.as_deref() {
+        Some(backend) if backend.starts_with("redis://") => {
+            page.item("REDIS_USERNAME, REDIS_PASSWORD", "Credentials of the Redis server answers are cached in.")
+        }
+        Some(_) => page.item(
+            "AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY",
+            "Credentials of the bucket answers are cached in.",
+        ),
+        None => {}
+    }
+    for secret in &program.secrets {
+        page.item(secret, "Secret used by the program (required).");
+    }
+    for name in &program.env_vars {
+        page.item(name, "Read by the program (required).");
+    }
+
+    page.section("exit status");
+    page.item("0", "The program ran.");
+    page.item("1", "It failed: the model, the network, a sink or a hook.");
+    page.item("2", "The input or the command line was wrong.");
+    if opts.fail_on_refusal {
+        page.item("3", "The model refused.");
+    }
+    if program.statements.iter().any(|statement| statement.expect.is_some()) && !matches!(interface, Interface::Tui | Interface::Serve | Interface::Socket) {
+        page.item("4", "With --check: an answer wasn't what it better be.");
+    }
+    if opts.background {
+        page.item("5", "With --poll: the job is still running.");
+    }
+
+    page.section("see also");
+    page.text("matthiashihic(1)");
+    page.out
+}
+
+/// The flags a compiled program takes besides its `€{name}`s, as (term,
+/// description), e.g. `--listen <addr>`. The completions are made from these,
+/// too.
+pub fn flags(program: &Program, opts: &CompileOptions) -> Vec<(String, String)> {
+    let interface = Interface::of(opts);
+    let mut flags = Vec::new();
+    let mut flag = |term: &str, description: &str| flags.push((term.to_string(), description.to_string()));
+    flag("-h, --help", "Print the help and exit.");
+    flag("--hihic-info", "Print how this program was built, as JSON, and exit.");
+    match interface {
+        Interface::Audio => flag("--record", "Record from the default microphone until Enter (needs SoX)."),
+        Interface::Serve => {
+            flag("--listen <addr>", "Address to listen on.");
+            flag("--access-log <file>", "Log every request to this file instead of stdout. It's moved to <file>.1 at 10 MB; five old ones are kept.");
+            flag("--access-log-format <json|text>", "Log a JSON object per request (the default), or key=value pairs.");
+            flag(
+                "--access-log-redact <fields>",
+                "Blank out these comma-separated fields of the access log: request_id, caller, args_hash, error.",
+            );
+        }
+        Interface::Socket => flag("--listen-socket <path>", "Socket (or named pipe) to listen on instead."),
+        Interface::StreamFilter => flag("--parallel <n>", "Lines to work on at once."),
+        Interface::Stdin if !program.every_line.is_empty() => flag("--parallel <n>", "Lines to work on at once."),
+        _ => {}
+    }
+    if opts.ensemble.is_some() && interface != Interface::Tui {
+        flag("--show-all", "Print every ensemble model's answer to stderr, too.");
+    }
+    if opts.background {
+        flag("--poll <id>", "Print the answer of the job with this ID, or exit with 5 while it's still running.");
+        flag("--wait", "With --poll, wait for the answer instead.");
+        flag("--jobs", "List the jobs this program submitted.");
+    }
+    if opts.banner.is_some() {
+        flag("--no-banner", "Don't show the banner, e.g. in CI.");
+    }
+    if let Some(experiment) = &program.experiment {
+        flag(
+            "--arm <A|B>",
+            &format!("Run this variant instead of the one the request ID picks ({}% of runs get A).", experiment.split),
+        );
+    }
+    let checked = program.statements.iter().any(|statement| statement.expect.is_some());
+    if checked && !matches!(interface, Interface::Tui | Interface::Serve | Interface::Socket) {
+        flag("--check", "Compare the answers with their 'better be's, and exit with 4 if one isn't what it better be.");
+    }
+    if !matches!(interface, Interface::Tui | Interface::Serve | Interface::Socket) {
+        flag("--trailer json", "End the output with a ---hihic:{...} line saying whether it's complete and how many tokens it took.");
+        flag("--typewriter <n>", "Show the answer at a steady <n> characters a second, however it streams in; piped output isn't slowed down.");
+        flag("--newline <lf|crlf>", "End the output's lines with \\n (the default) or \\r\\n.");
+        flag("-n, --no-final-newline", "Leave out the newline the output would end with.");
+        flag("--encoding <name>", "Write the output in utf-8 (the default), utf-16le, utf-16be, latin1 or ascii; what it can't say becomes a ?.");
+    }
+    flag(
+        "--doctor",
+        "Check the key, the provider, the clock and the proxy settings, print the environment with the keys masked, and exit.",
+    );
+    if opts.profile.is_none() {
+        flag("--record-fallback <file>", "Append every answer to <file>, a pack for compiling with --fallback.");
+    }
+    if opts.profile.is_none() && !opts.background {
+        flag("--trace <dir>", "Save every model call, what was asked, the answer and how long it took, for 'matthiashihic trace view <dir>'.");
+        flag("--trace-redact <fields>", "Blank out some of system, prompt and answer in the trace.");
+    }
+    flags
+}
+
+/// How a compiled program gets its input, as far as its man page cares.
+#[derive(Clone, Copy, PartialEq)]
+enum Interface {
+    Stdin,
+    Audio,
+    Tui,
+    Serve,
+    Socket,
+    StreamFilter,
+}
+
+impl Interface {
+    fn of(opts: &CompileOptions) -> Interface {
+        if opts.tui {
+            Interface::Tui
+        } else if opts.serve {
+            Interface::Serve
+        } else if opts.listen_socket.is_some() {
+            Interface::Socket
+        } else if opts.stream_filter {
+            Interface::StreamFilter
+        } else if opts.audio_input {
+            Interface::Audio
+        } else {
+            Interface::Stdin
+        }
+    }
+}
+
+/// The compiler's man page, in the catalog's language. The synopsis and the
+/// examples are the usage's first two blocks; every `Heading:` block after
+/// them is a subsection of OPTIONS, its `  --flag  what it does` lines items.
+pub fn compiler() -> String {
+    let usage = crate::usage("matthiashihic");
+    let mut page = Page::new("matthiashihic", concat!("matthiashihic ", env!("CARGO_PKG_VERSION")));
+    page.section("name");
+    page.text(&format!("matthiashihic - {}", tr!("manpage.name")));
+    let blocks = usage.split("\n\n").map(|block| block.trim_end_matches('\n')).filter(|block| !block.trim().is_empty());
+    let mut options = false;
+    for (index, block) in blocks.enumerate() {
+        let mut lines = block.lines();
+        let heading = lines.next().unwrap_or_default();
+        match index {
+            0 => {
+                page.section("synopsis");
+                page.preformatted(lines.map(|line| line.strip_prefix("  ").unwrap_or(line)));
+                page.section("description");
+                page.paragraph(&tr!("manpage.description"));
+            }
+            1 => {
+                page.section(heading.trim_end_matches(':'));
+                page.preformatted(lines.map(str::trim_start));
+            }
+            _ if heading.starts_with(' ') || !heading.ends_with(':') => page.paragraph(&block.lines().map(str::trim).collect::<Vec<_>>().join("\n")),
+            _ => {
+                if !options {
+                    page.section(&tr!("manpage.options"));
+                    options = true;
+                }
+                page.subsection(heading.trim_end_matches(':'));
+                options_block(&mut page, lines);
+            }
+        }
+    }
+    page.section(&tr!("manpage.see-also"));
+    page.text("https://github.com/MatthiasKainer/matthiashihic");
+    page.out
+}
+
+/// The lines of a `Heading:` block: a line indented by two that starts with
+/// `-` is a flag, its description after the first run of spaces (or at the
+/// column of the lines below it) and on the lines indented further below it.
+/// Other lines indented by two are text.
+fn options_block<'a>(page: &mut Page, lines: impl Iterator<Item = &'a str>) {
+    // The flag's line, and the description's lines after it
+    let mut item: Option<(&str, Vec<&str>)> = None;
+    let mut text: Vec<&str> = Vec::new();
+    let flush_item = |page: &mut Page, item: &mut Option<(&str, Vec<&str>)>| {
+        let Some((line, rest)) = item.take() else {
+            return;
+        };
+        let indent = line.len() - line.trim_start().len();
+        let column = rest.first().map_or(0, |first| first.len() - first.trim_start().len());
+        let at = match line.trim_end()[indent..].find("  ") {
+            Some(at) => Some(indent + at),
+            None if column > indent && line.len() > column && line.as_bytes()[column - 1] == b' ' => Some(column),
+            None => None,
+        };
+        let (term, first) = at.map_or((line, ""), |at| line.split_at(at));
+        let description: Vec<&str> = std::iter::once(first).chain(rest).map(str::trim).filter(|line| !line.is_empty()).collect();
+        page.item(term.trim(), &description.join("\n"));
+    };
+    for line in lines {
+        let indent = line.len() - line.trim_start().len();
+        if indent <= 2 && line.trim_start().starts_with('-') {
+            flush_item(page, &mut item);
+            if !text.is_empty() {
+                page.paragraph(&std::mem::take(&mut text).join("\n"));
+            }
+            item = Some((line, Vec::new()));
+        } else if let (Some((_, rest)), true) = (&mut item, indent > 2) {
+            rest.push(line);
+        } else {
+            flush_item(page, &mut item);
+            text.push(line.trim());
+        }
+    }
+    flush_item(page, &mut item);
+    if !text.is_empty() {
+        page.paragraph(&text.join("\n"));
+    }
+}
+
+/// `matthiashihic emit-manpage [-o <file>]`: prints the compiler's man page,
+/// or writes it to a file. Returns the exit code.
+pub fn run(args: &[String]) -> i32 {
+    let mut output = None;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-o" => match args.get(i + 1) {
+                Some(path) => {
+                    output = Some(path.clone());
+                    i += 1;
+                }
+                None => {
+                    eprintln!("-o requires a file");
+                    return 2;
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", tr!("manpage.usage"));
+                return 0;
+            }
+            flag => {
+                eprintln!("{}", tr!("manpage.unknown-flag", flag = flag));
+                return 2;
+            }
+        }
+        i += 1;
+    }
+    let page = compiler();
+    match output {
+        Some(path) => match fs::write(&path, page) {
+            Ok(()) => {
+                eprintln!("Wrote {}", path);
+                0
+            }
+            Err(e) => {
+                eprintln!("{}", tr!("cli.write-failed", file = path, error = e));
+                1
+            }
+        },
+        None => {
+            print!("{}", page);
+            0
+        }
+    }
+}
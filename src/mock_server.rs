@@ -0,0 +1,652 @@
+//! `matthiashihic mock-server [--listen <addr>] [--responses <file.jsonl>] ...`
+//! answers like the OpenAI chat completions API, streaming or not, with canned
+//! responses and as much latency and as many faults as you ask for. Compiled
+//! programs use it with OPENAI_BASE_URL, so nothing needs a key or a bill.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::compress::estimate_tokens;
+use crate::front_matter::{parse_json_object, parse_json_string, skip_whitespace};
+use crate::i18n::tr;
+use crate::json_string;
+use crate::load::parse_duration;
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:8089";
+/// Answer when no `--responses` line matches.
+const DEFAULT_RESPONSE: &str = "hihi! You said: €input";
+/// Pause between streamed pieces unless `--chunk-delay` says otherwise.
+const DEFAULT_CHUNK_DELAY: Duration = Duration::from_millis(10);
+/// Requests bigger than this are refused; nobody's prompt is 16 MiB.
+const MAX_BODY: usize = 16 * 1024 * 1024;
+/// Every flag takes a value.
+const FLAGS: &[&str] = &[
+    "--listen",
+    "--responses",
+    "--latency",
+    "--jitter",
+    "--chunk-delay",
+    "--error-rate",
+    "--error-status",
+    "--inject",
+    "--seed",
+];
+
+/// One line of the responses file.
+struct Canned {
+    /// Substring of the last user message; `None` matches everything.
+    pattern: Option<String>,
+    /// Answer text, or the error message when `status` isn't 200.
+    response: String,
+    status: u16,
+    latency: Option<Duration>,
+}
+
+/// A way for a request to go wrong on purpose.
+#[derive(Clone, Copy, PartialEq)]
+enum Fault {
+    /// An error response with this status.
+    Status(u16),
+    /// Never answer; the client has to give up on its own.
+    Timeout,
+    /// Stream half the answer, then an event that isn't JSON, then hang up.
+    GarbledSse,
+    /// Stream half the answer and hang up, without `finish_reason` or `[DONE]`.
+    Disconnect,
+}
+
+impl Fault {
+    fn parse(name: &str) -> Option<Fault> {
+        match name {
+            "timeout" => Some(Fault::Timeout),
+            "garbled-sse" => Some(Fault::GarbledSse),
+            "disconnect" => Some(Fault::Disconnect),
+            status => status.parse().ok().filter(|s| (400..600).contains(s)).map(Fault::Status),
+        }
+    }
+
+    fn name(self) -> String {
+        match self {
+            Fault::Status(status) => status.to_string(),
+            Fault::Timeout => "timeout".into(),
+            Fault::GarbledSse => "garbled-sse".into(),
+            Fault::Disconnect => "disconnect".into(),
+        }
+    }
+}
+
+struct Config {
+    responses: Vec<Canned>,
+    latency: Duration,
+    jitter: Duration,
+    chunk_delay: Duration,
+    /// Faults with the share of requests each one hits, together at most 1.
+    faults: Vec<(Fault, f64)>,
+    /// xorshift state for jitter and injected faults.
+    random: AtomicU64,
+    requests: AtomicU64,
+}
+
+impl Config {
+    /// A number in [0, 1).
+    fn random(&self) -> f64 {
+        let step = |mut x: u64| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+        let x = step(self.random.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(step(x))).unwrap_or(1));
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Rolls the dice for one request.
+    fn fault(&self) -> Option<Fault> {
+        if self.faults.is_empty() {
+            return None;
+        }
+        let mut roll = self.random();
+        for (fault, rate) in &self.faults {
+            if roll < *rate {
+                return Some(*fault);
+            }
+            roll -= rate;
+        }
+        None
+    }
+}
+
+/// Runs the subcommand and returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let mut listen = DEFAULT_LISTEN.to_string();
+    let mut responses_file = None;
+    let mut latency = Duration::ZERO;
+    let mut jitter = Duration::ZERO;
+    let mut chunk_delay = DEFAULT_CHUNK_DELAY;
+    let mut faults = Vec::new();
+    let mut error_rate = 0.0;
+    let mut error_status = 500;
+    let mut seed = None;
+    let mut i = 0;
+    while i < args.len() {
+        let flag = args[i].as_str();
+        let Some(value) = args.get(i + 1).map(|s| s.as_str()) else {
+            if FLAGS.contains(&flag) {
+                eprintln!("{} requires a value", flag);
+            } else if flag.starts_with('-') {
+                eprintln!("{}", tr!("mock.unknown-flag", flag = flag));
+            } else {
+                eprintln!("{}", tr!("mock.usage"));
+            }
+            return 2;
+        };
+        let parsed = match flag {
+            "--listen" => Some(value).map(|v| listen = v.to_string()),
+            "--responses" => Some(value).map(|v| responses_file = Some(v.to_string())),
+            "--latency" => parse_time(value).map(|d| latency = d),
+            "--jitter" => parse_time(value).map(|d| jitter = d),
+            "--chunk-delay" => parse_time(value).map(|d| chunk_delay = d),
+            "--error-rate" => value.parse().ok().filter(|r| (0.0..=1.0).contains(r)).map(|r| error_rate = r),
+            "--error-status" => value.parse().ok().filter(|s| (400..600).contains(s)).map(|s| error_status = s),
+            "--inject" => parse_faults(value).map(|f| faults.extend(f)),
+            "--seed" => value.parse().ok().map(|s: u64| seed = Some(s)),
+            s if s.starts_with('-') => {
+                eprintln!("{}", tr!("mock.unknown-flag", flag = s));
+                return 2;
+            }
+            _ => {
+                eprintln!("{}", tr!("mock.usage"));
+                return 2;
+            }
+        };
+        if parsed.is_none() {
+            eprintln!("Invalid value for {}: '{}'", flag, value);
+            return 2;
+        }
+        i += 2;
+    }
+
+    if error_rate > 0.0 {
+        faults.push((Fault::Status(error_status), error_rate));
+    }
+    let total: f64 = faults.iter().map(|(_, rate)| rate).sum();
+    if total > 1.0 + f64::EPSILON {
+        eprintln!("Injected faults add up to {:.0}% of requests; at most 100% can fail", total * 100.0);
+        return 2;
+    }
+
+    let responses = match responses_file {
+        Some(path) => match read_responses(&path) {
+            Ok(responses) => responses,
+            Err(e) => {
+                eprintln!("{}", e);
+                return 2;
+            }
+        },
+        None => Vec::new(),
+    };
+    let seed = seed.unwrap_or_else(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(1)
+    });
+    let config = Arc::new(Config {
+        responses,
+        latency,
+        jitter,
+        chunk_delay,
+        faults,
+        // xorshift never leaves zero
+        random: AtomicU64::new(seed.max(1)),
+        requests: AtomicU64::new(0),
+    });
+
+    let listener = match TcpListener::bind(&listen) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Cannot listen on {}: {}", listen, e);
+            return 1;
+        }
+    };
+    let addr = listener.local_addr().map(|a| a.to_string()).unwrap_or(listen);
+    eprintln!("Mock provider on http://{}/v1", addr);
+    eprintln!("Point programs at it with:");
+    eprintln!("  export OPENAI_BASE_URL=http://{}/v1", addr);
+    for stream in listener.incoming().flatten() {
+        let config = Arc::clone(&config);
+        thread::spawn(move || handle(stream, &config));
+    }
+    0
+}
+
+/// `429:10%,timeout:5%,garbled-sse:2%`; a rate is a percentage or a fraction.
+fn parse_faults(text: &str) -> Option<Vec<(Fault, f64)>> {
+    text.split(',')
+        .map(|item| {
+            let (name, rate) = item.trim().rsplit_once(':')?;
+            let rate = match rate.strip_suffix('%') {
+                Some(percent) => percent.parse::<f64>().ok()? / 100.0,
+                None => rate.parse().ok()?,
+            };
+            (0.0..=1.0).contains(&rate).then_some(())?;
+            Some((Fault::parse(name)?, rate))
+        })
+        .collect()
+}
+
+/// Like `parse_duration`, but `0` is fine too.
+fn parse_time(text: &str) -> Option<Duration> {
+    if text.trim_end_matches(|c: char| c.is_ascii_alphabetic()).parse::<f64>() == Ok(0.0) {
+        return Some(Duration::ZERO);
+    }
+    parse_duration(text)
+}
+
+/// Lines like `{"match": "weather", "response": "Sunny, €model says", "latency": "2s"}`;
+/// `"status": 429` turns a line into an error. First match wins.
+fn read_responses(path: &str) -> Result<Vec<Canned>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| tr!("cli.read-failed", file = path, error = e))?;
+    let mut responses = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let at = |e: String| format!("{}:{}: {}", path, idx + 1, e);
+        let mut canned = Canned { pattern: None, response: String::new(), status: 200, latency: None };
+        let mut has_response = false;
+        for (key, value) in parse_json_object(line).map_err(at)? {
+            match key.as_str() {
+                "match" => canned.pattern = Some(value),
+                "response" => {
+                    canned.response = value;
+                    has_response = true;
+                }
+                "status" => {
+                    canned.status = value
+                        .parse()
+                        .ok()
+                        .filter(|s| (200..600).contains(s))
+                        .ok_or_else(|| at(format!("invalid status '{}'", value)))?
+                }
+                "latency" => {
+                    canned.latency = Some(parse_time(&value).ok_or_else(|| at(format!("invalid latency '{}'", value)))?)
+                }
+                other => return Err(at(format!("unknown key \"{}\"", other))),
+            }
+        }
+        if !has_response {
+            return Err(at("missing \"response\"".into()));
+        }
+        responses.push(canned);
+    }
+    Ok(responses)
+}
+
+fn handle(mut stream: TcpStream, config: &Config) {
+    let start = Instant::now();
+    let Some((method, path, body)) = read_request(&stream) else {
+        return;
+    };
+    let route = path.split('?').next().unwrap_or("");
+    let (status, model) = match (method.as_str(), route) {
+        ("POST", p) if p.ends_with("/chat/completions") => chat_completions(&mut stream, config, &body),
+        ("GET", p) if p.ends_with("/models") => {
+            let body = "{\"object\":\"list\",\"data\":[{\"id\":\"mock\",\"object\":\"model\",\"owned_by\":\"matthiashihic\"}]}";
+            let _ = respond(&mut stream, 200, "application/json", body);
+            ("200".to_string(), String::new())
+        }
+        _ => {
+            let _ = respond(&mut stream, 404, "application/json", &error_body("no such endpoint on the mock", "not_found"));
+            ("404".to_string(), String::new())
+        }
+    };
+    eprintln!("{} {} {} {} {}ms", method, route, model, status, start.elapsed().as_millis());
+}
+
+/// Method, path and body of a request; `None` for anything that isn't one.
+fn read_request(stream: &TcpStream) -> Option<(String, String, String)> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).ok()?;
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next()?.to_string(), parts.next()?.to_string());
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().ok()?;
+            }
+        }
+    }
+    if length > MAX_BODY {
+        return None;
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    Some((method, path, String::from_utf8_lossy(&body).into_owned()))
+}
+
+/// Answers one chat completion; returns the status (or the injected fault)
+/// and the model asked for.
+fn chat_completions(stream: &mut TcpStream, config: &Config, body: &str) -> (String, String) {
+    let request = match Json::parse(body) {
+        Ok(request) => request,
+        Err(e) => {
+            let _ = respond(stream, 400, "application/json", &error_body(&format!("invalid JSON: {}", e), "invalid_request_error"));
+            return ("400".to_string(), String::new());
+        }
+    };
+    let model = request.get("model").and_then(Json::as_str).unwrap_or("mock").to_string();
+    let streaming = matches!(request.get("stream"), Some(Json::Bool(true)));
+    let include_usage = matches!(
+        request.get("stream_options").and_then(|options| options.get("include_usage")),
+        Some(Json::Bool(true))
+    );
+    let input = last_user_message(&request);
+    let n = config.requests.fetch_add(1, Ordering::Relaxed) + 1;
+
+    let canned = config
+        .responses
+        .iter()
+        .find(|c| c.pattern.as_ref().is_none_or(|p| input.contains(p.as_str())));
+    let mut latency = canned.and_then(|c| c.latency).unwrap_or(config.latency);
+    latency += config.jitter.mul_f64(config.random());
+    thread::sleep(latency);
+
+    let template = canned.map(|c| c.response.as_str()).unwrap_or(DEFAULT_RESPONSE);
+    let text = template.replace("€input", &input).replace("€model", &model);
+    if let Some(status) = canned.map(|c| c.status).filter(|s| *s != 200) {
+        let _ = respond(stream, status, "application/json", &error_body(&text, "mock_error"));
+        return (status.to_string(), model);
+    }
+    let fault = config.fault();
+    match fault {
+        Some(Fault::Status(status)) => {
+            let _ = respond(stream, status, "application/json", &error_body("injected by mock-server", "mock_error"));
+            return (status.to_string(), model);
+        }
+        Some(Fault::Timeout) => {
+            hang(stream);
+            return ("timeout".to_string(), model);
+        }
+        _ => {}
+    }
+
+    let id = format!("chatcmpl-mock-{}", n);
+    let created = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let prompt_tokens = estimate_tokens(&input);
+    let completion_tokens = estimate_tokens(&text);
+    let usage = format!(
+        "{{\"prompt_tokens\":{},\"completion_tokens\":{},\"total_tokens\":{}}}",
+        prompt_tokens,
+        completion_tokens,
+        prompt_tokens + completion_tokens
+    );
+    if !streaming {
+        let body = format!(
+            "{{\"id\":\"{}\",\"object\":\"chat.completion\",\"created\":{},\"model\":{},\"choices\":[{{\"index\":0,\"message\":{{\"role\":\"assistant\",\"content\":{}}},\"finish_reason\":\"stop\"}}],\"usage\":{}}}",
+            id,
+            created,
+            json_string(&model),
+            json_string(&text),
+            usage
+        );
+        let body = match fault {
+            // Half a document, which no JSON parser will take
+            Some(_) => {
+                let mut cut = body.len() / 2;
+                while !body.is_char_boundary(cut) {
+                    cut -= 1;
+                }
+                body[..cut].to_string()
+            }
+            None => body,
+        };
+        let _ = respond(stream, 200, "application/json", &body);
+        return (fault.map_or("200".to_string(), Fault::name), model);
+    }
+
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+    if stream.write_all(head.as_bytes()).is_err() {
+        return ("200".to_string(), model);
+    }
+    let chunk = |delta: &str, finish: &str| {
+        format!(
+            "data: {{\"id\":\"{}\",\"object\":\"chat.completion.chunk\",\"created\":{},\"model\":{},\"choices\":[{{\"index\":0,\"delta\":{},\"finish_reason\":{}}}]}}\n\n",
+            id,
+            created,
+            json_string(&model),
+            delta,
+            finish
+        )
+    };
+    let mut events = vec![chunk("{\"role\":\"assistant\",\"content\":\"\"}", "null")];
+    events.extend(pieces(&text).map(|piece| chunk(&format!("{{\"content\":{}}}", json_string(piece)), "null")));
+    match fault {
+        Some(Fault::GarbledSse) => {
+            events.truncate(events.len() / 2 + 1);
+            // Cut off mid-object, as if a proxy lost the rest
+            events.push(format!(
+                "data: {{\"id\":\"{}\",\"object\":\"chat.completion.chunk\",\"choices\":[{{\"delta\":{{\"content\":\"gar\n\n",
+                id
+            ));
+        }
+        Some(Fault::Disconnect) => events.truncate(events.len() / 2 + 1),
+        _ => {
+            events.push(chunk("{}", "\"stop\""));
+            if include_usage {
+                events.push(format!(
+                    "data: {{\"id\":\"{}\",\"object\":\"chat.completion.chunk\",\"created\":{},\"model\":{},\"choices\":[],\"usage\":{}}}\n\n",
+                    id,
+                    created,
+                    json_string(&model),
+                    usage
+                ));
+            }
+            events.push("data: [DONE]\n\n".to_string());
+        }
+    }
+    for (i, event) in events.iter().enumerate() {
+        if i > 0 {
+            thread::sleep(config.chunk_delay);
+        }
+        // The client hung up; nothing left to do
+        if stream.write_all(event.as_bytes()).and_then(|_| stream.flush()).is_err() {
+            break;
+        }
+    }
+    (fault.map_or("200".to_string(), Fault::name), model)
+}
+
+/// Holds the connection without a word until the client gives up.
+fn hang(stream: &TcpStream) {
+    if stream.set_read_timeout(Some(Duration::from_secs(1))).is_err() {
+        return;
+    }
+    let mut buf = [0u8; 1024];
+    loop {
+        match (&*stream).read(&mut buf) {
+            Ok(0) => return,
+            Ok(_) => continue,
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => continue,
+            Err(_) => return,
+        }
+    }
+}
+
+/// The text in word-sized pieces, each with the whitespace in front of it.
+fn pieces(text: &str) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let word_start = rest.find(|c: char| !c.is_whitespace()).unwrap_or(rest.len());
+        let end = rest[word_start..].find(char::is_whitespace).map_or(rest.len(), |i| word_start + i);
+        let (piece, tail) = rest.split_at(end);
+        rest = tail;
+        Some(piece)
+    })
+}
+
+/// Content of the last `user` message; text parts of a content array are joined.
+fn last_user_message(request: &Json) -> String {
+    let Some(Json::Array(messages)) = request.get("messages") else {
+        return String::new();
+    };
+    let Some(message) = messages.iter().rev().find(|m| m.get("role").and_then(Json::as_str) == Some("user")) else {
+        return String::new();
+    };
+    match message.get("content") {
+        Some(Json::String(text)) => text.clone(),
+        Some(Json::Array(parts)) => parts.iter().filter_map(|p| p.get("text").and_then(Json::as_str)).collect::<Vec<_>>().join("\n"),
+        _ => String::new(),
+    }
+}
+
+fn error_body(message: &str, kind: &str) -> String {
+    format!("{{\"error\":{{\"message\":{},\"type\":\"{}\"}}}}", json_string(message), kind)
+}
+
+fn respond(stream: &mut TcpStream, status: u16, content_type: &str, body: &str) -> std::io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+/// Just enough JSON to read a request, or check a request template.
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Number,
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+type Chars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+impl Json {
+    pub(crate) fn parse(text: &str) -> Result<Json, String> {
+        let mut chars = text.chars().peekable();
+        let value = Json::value(&mut chars, 0)?;
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            Some(c) => Err(format!("unexpected '{}' after the value", c)),
+            None => Ok(value),
+        }
+    }
+
+    fn value(chars: &mut Chars, depth: usize) -> Result<Json, String> {
+        // Deep enough for any request, shallow enough for the stack
+        if depth > 64 {
+            return Err("nested too deeply".into());
+        }
+        skip_whitespace(chars);
+        match chars.peek() {
+            Some('"') => parse_json_string(chars).map(Json::String),
+            Some('[') => {
+                chars.next();
+                let mut items = Vec::new();
+                skip_whitespace(chars);
+                if chars.peek() == Some(&']') {
+                    chars.next();
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(Json::value(chars, depth + 1)?);
+                    skip_whitespace(chars);
+                    match chars.next() {
+                        Some(',') => continue,
+                        Some(']') => return Ok(Json::Array(items)),
+                        _ => return Err("expected ',' or ']'".into()),
+                    }
+                }
+            }
+            Some('{') => {
+                chars.next();
+                let mut entries = Vec::new();
+                skip_whitespace(chars);
+                if chars.peek() == Some(&'}') {
+                    chars.next();
+                    return Ok(Json::Object(entries));
+                }
+                loop {
+                    skip_whitespace(chars);
+                    let key = parse_json_string(chars)?;
+                    skip_whitespace(chars);
+                    if chars.next() != Some(':') {
+                        return Err(format!("expected ':' after key \"{}\"", key));
+                    }
+                    entries.push((key, Json::value(chars, depth + 1)?));
+                    skip_whitespace(chars);
+                    match chars.next() {
+                        Some(',') => continue,
+                        Some('}') => return Ok(Json::Object(entries)),
+                        _ => return Err("expected ',' or '}'".into()),
+                    }
+                }
+            }
+            _ => {
+                let mut literal = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c == ',' || c == ']' || c == '}' || c.is_whitespace() {
+                        break;
+                    }
+                    literal.push(c);
+                    chars.next();
+                }
+                match literal.as_str() {
+                    "null" => Ok(Json::Null),
+                    "true" => Ok(Json::Bool(true)),
+                    "false" => Ok(Json::Bool(false)),
+                    n if n.parse::<f64>().is_ok() => Ok(Json::Number),
+                    other => Err(format!("unexpected '{}'", other)),
+                }
+            }
+        }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
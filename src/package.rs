@@ -0,0 +1,399 @@
+//! `--emit deb`, `--emit rpm` and `--emit brew-formula`: the compiled program
+//! with its man page and completions, packaged so it installs like any other
+//! command. Debs are written here, ar and tar and all; rpms are built by
+//! `rpmbuild` from a generated spec; a formula comes with the tarball it
+//! installs from.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::ast::Program;
+use crate::i18n::tr;
+use crate::{completions, manpage, paths, CompileOptions};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Package {
+    Deb,
+    Rpm,
+    BrewFormula,
+}
+
+impl Package {
+    pub fn parse(s: &str) -> Option<Package> {
+        match s {
+            "deb" => Some(Package::Deb),
+            "rpm" => Some(Package::Rpm),
+            "brew-formula" => Some(Package::BrewFormula),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Package::Deb => "deb",
+            Package::Rpm => "rpm",
+            Package::BrewFormula => "brew-formula",
+        }
+    }
+}
+
+const RPMBUILD_MISSING: &str = "--emit rpm needs rpmbuild, which isn't installed (it's in rpm-build, or rpm on Debian)";
+
+/// A file of the package: its path under the prefix, contents and mode.
+type Entry = (String, Vec<u8>, u32);
+
+/// Whether `command` can be packaged as `kind`, checked before anything is
+/// compiled. Package managers want a version, and names they can type.
+pub fn check(kind: Package, program: &Program, command: &str) -> Result<(), String> {
+    if program.info.version.is_none() {
+        return Err(format!("--emit {} needs the program's version; add 'version: 1.0.0' before the first statement", kind.as_str()));
+    }
+    let valid = command.len() >= 2
+        && command.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && command.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "+-.".contains(c));
+    if !valid {
+        return Err(format!(
+            "The package is named after the program, '{}', which needs to be lowercase letters, digits, '+', '-' and '.'; pick another -o",
+            command
+        ));
+    }
+    match kind {
+        Package::BrewFormula if command.starts_with(|c: char| c.is_ascii_digit()) => {
+            Err(format!("Homebrew formulas can't start with a digit, and '{}' does; pick another -o", command))
+        }
+        Package::Deb if deb_arch().is_none() => {
+            Err(format!("Debian has no name for this machine's architecture, {}", std::env::consts::ARCH))
+        }
+        Package::Deb | Package::Rpm if std::env::consts::OS != "linux" => Err(format!(
+            "{} packages are for Linux, and the program is built for {}",
+            kind.as_str(),
+            std::env::consts::OS
+        )),
+        Package::Rpm if Command::new("rpmbuild").arg("--version").stdout(Stdio::null()).stderr(Stdio::null()).status().is_err() => {
+            Err(RPMBUILD_MISSING.to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Packages the program compiled to `binary` as `command`, next to it.
+/// Returns the files written.
+pub fn write(kind: Package, program: &Program, opts: &CompileOptions, binary: &Path, command: &str) -> Result<Vec<PathBuf>, String> {
+    // A pre-release sorts before its release with ~, and a - would be taken for
+    // Debian's revision, or rejected by rpm
+    let version = program.info.version.as_deref().unwrap_or_default().replace('-', "~");
+    let version = version.as_str();
+    let dir = binary.parent().unwrap_or(Path::new(""));
+    let binary = fs::read(binary).map_err(|e| format!("Can't read {}: {}", binary.display(), e))?;
+    // Debian's zsh doesn't look in site-functions
+    let zsh_dir = if kind == Package::Deb { "vendor-completions" } else { "site-functions" };
+    let (bash, zsh, fish) = completions::scripts(program, opts, command);
+    let entries: Vec<Entry> = vec![
+        (format!("bin/{}", command), binary, 0o755),
+        (format!("share/man/man1/{}.1", command), manpage::program(program, opts, command).into_bytes(), 0o644),
+        (format!("share/bash-completion/completions/{}", command), bash.into_bytes(), 0o644),
+        (format!("share/zsh/{}/_{}", zsh_dir, command), zsh.into_bytes(), 0o644),
+        (format!("share/fish/vendor_completions.d/{}.fish", command), fish.into_bytes(), 0o644),
+    ];
+    let about = program.info.about.as_deref().unwrap_or("A program compiled by matthiashihic");
+    match kind {
+        Package::Deb => {
+            let arch = deb_arch().unwrap_or_default();
+            let path = dir.join(format!("{}_{}_{}.deb", command, version, arch));
+            write_file(&path, &deb(&entries, command, version, arch, about))?;
+            Ok(vec![path])
+        }
+        Package::Rpm => rpm(&entries, command, version, about, dir).map(|path| vec![path]),
+        Package::BrewFormula => {
+            let tarball = dir.join(format!("{}-{}.tar", command, version));
+            let top = format!("{}-{}", command, version);
+            let entries: Vec<Entry> = entries.into_iter().map(|(path, data, mode)| (format!("{}/{}", top, path), data, mode)).collect();
+            let tar = tar(&entries);
+            write_file(&tarball, &tar)?;
+            let formula = dir.join(format!("{}.rb", command));
+            let url = tarball.canonicalize().unwrap_or(tarball.clone());
+            write_file(&formula, brew_formula(&entries, command, version, about, &url, &sha256_hex(&tar)).as_bytes())?;
+            Ok(vec![tarball, formula])
+        }
+    }
+}
+
+fn write_file(path: &Path, contents: &[u8]) -> Result<(), String> {
+    fs::write(path, contents).map_err(|e| tr!("cli.write-failed", file = path.display(), error = e))
+}
+
+/// What Debian calls the architecture the compiler runs on, and builds for.
+fn deb_arch() -> Option<&'static str> {
+    match std::env::consts::ARCH {
+        "x86_64" => Some("amd64"),
+        "aarch64" => Some("arm64"),
+        "x86" => Some("i386"),
+        "arm" => Some("armhf"),
+        "riscv64" => Some("riscv64"),
+        "s390x" => Some("s390x"),
+        _ => None,
+    }
+}
+
+/// A deb: an ar archive of the format version, the control file and the
+/// files under /usr, both as uncompressed tars, which dpkg takes since 1.17.6.
+fn deb(entries: &[Entry], command: &str, version: &str, arch: &str, about: &str) -> Vec<u8> {
+    let installed_size: usize = entries.iter().map(|(_, data, _)| data.len().div_ceil(1024)).sum();
+    let mut control = format!(
+        "Package: {}\nVersion: {}\nArchitecture: {}\nSection: utils\nPriority: optional\nInstalled-Size: {}\n",
+        command, version, arch, installed_size
+    );
+    // Who to blame, the way Debian's own tools ask; dpkg complains without anyone
+    let email = std::env::var("DEBEMAIL").or_else(|_| std::env::var("EMAIL")).unwrap_or_else(|_| "unknown@localhost".into());
+    let name = std::env::var("DEBFULLNAME").unwrap_or_else(|_| "Unknown".into());
+    control.push_str(&format!("Maintainer: {} <{}>\n", name, email));
+    control.push_str(&format!("Description: {}\n", about.replace('\n', " ")));
+    let control = tar(&[("control".to_string(), control.into_bytes(), 0o644)]);
+    let data: Vec<Entry> = entries.iter().map(|(path, data, mode)| (format!("usr/{}", path), data.clone(), *mode)).collect();
+    let data = tar(&data);
+
+    let mut out = b"!<arch>\n".to_vec();
+    for (name, member) in [("debian-binary", &b"2.0\n"[..]), ("control.tar", &control), ("data.tar", &data)] {
+        out.extend_from_slice(format!("{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n", name, now(), 0, 0, "100644", member.len()).as_bytes());
+        out.extend_from_slice(member);
+        if member.len() % 2 == 1 {
+            out.push(b'\n');
+        }
+    }
+    out
+}
+
+/// Builds an rpm with `rpmbuild`, from a spec whose %install copies the files
+/// in, and moves it to `dir`.
+fn rpm(entries: &[Entry], command: &str, version: &str, about: &str, dir: &Path) -> Result<PathBuf, String> {
+    let top = paths::temp_path("matthiashihic-rpm", "");
+    let result = build_rpm(entries, command, version, about, dir, &top);
+    let _ = fs::remove_dir_all(&top);
+    result
+}
+
+fn build_rpm(entries: &[Entry], command: &str, version: &str, about: &str, dir: &Path, top: &Path) -> Result<PathBuf, String> {
+    let stage = top.join("stage");
+    for (path, data, mode) in entries {
+        let path = stage.join("usr").join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("Can't create {}: {}", parent.display(), e))?;
+        }
+        write_file(&path, data)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let _ = fs::set_permissions(&path, fs::Permissions::from_mode(*mode));
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+    }
+    let files: String = entries.iter().map(|(path, _, _)| format!("/usr/{}\n", path)).collect();
+    let spec = format!(
+        r#"# Generated by matthiashihic
+Name: {command}
+Version: {version}
+Release: 1
+Summary: {summary}
+License: Unspecified
+AutoReqProv: no
+%global debug_package %{{nil}}
+%global __os_install_post %{{nil}}
+
+%description
+{summary}
+
+%install
+mkdir -p %{{buildroot}}
+cp -a '{stage}/.' %{{buildroot}}/
+
+%files
+{files}"#,
+        command = command,
+        version = version,
+        summary = about.replace('\n', " ").replace('%', "%%"),
+        stage = stage.display(),
+        files = files,
+    );
+    let spec_path = top.join(format!("{}.spec", command));
+    write_file(&spec_path, spec.as_bytes())?;
+    let output = Command::new("rpmbuild")
+        .arg("-bb")
+        .arg("--define")
+        .arg(format!("_topdir {}", top.display()))
+        .arg(&spec_path)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => RPMBUILD_MISSING.to_string(),
+            _ => format!("Failed to run rpmbuild: {}", e),
+        })?;
+    if !output.status.success() {
+        return Err(format!("rpmbuild failed ({}):\n{}", output.status, String::from_utf8_lossy(&output.stderr).trim_end()));
+    }
+    // RPMS/<arch>/<name>-<version>-1.<arch>.rpm, whatever rpmbuild thinks the arch is
+    let built = fs::read_dir(top.join("RPMS"))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .flat_map(|arch| fs::read_dir(arch.path()).into_iter().flatten().flatten())
+        .map(|entry| entry.path())
+        .find(|path| path.extension().is_some_and(|extension| extension == "rpm"))
+        .ok_or("rpmbuild said it worked, but left no .rpm")?;
+    let path = dir.join(built.file_name().unwrap_or_default());
+    fs::copy(&built, &path).map_err(|e| format!("Failed to copy the rpm to {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// A formula installing from the tarball at `url`; upload the tarball and
+/// change the url to share it.
+fn brew_formula(entries: &[Entry], command: &str, version: &str, about: &str, url: &Path, sha256: &str) -> String {
+    let class: String = command
+        .split(['-', '.', '+'])
+        .map(|part| {
+            let mut chars = part.chars();
+            chars.next().map(|first| first.to_ascii_uppercase().to_string() + chars.as_str()).unwrap_or_default()
+        })
+        .collect();
+    let ruby = |text: &str| format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\"").replace('#', "\\#"));
+    let installs = ["bin", "man1", "bash_completion", "zsh_completion", "fish_completion"];
+    let mut out = format!(
+        "# Generated by matthiashihic; upload the tarball and point url at it to share\nclass {} < Formula\n  desc {}\n  homepage \"https://github.com/MatthiasKainer/matthiashihic\"\n  url {}\n  sha256 \"{}\"\n  version {}\n\n  def install\n",
+        class,
+        ruby(&about.replace('\n', " ")),
+        ruby(&format!("file://{}", url.display())),
+        sha256,
+        ruby(version)
+    );
+    let top = format!("{}-{}/", command, version);
+    for (install, (path, _, _)) in installs.iter().zip(entries) {
+        let path = path.strip_prefix(&top).unwrap_or(path);
+        match *install {
+            // Homebrew names the bash one after the command, too
+            "bash_completion" => out.push_str(&format!("    {}.install {} => {}\n", install, ruby(path), ruby(command))),
+            _ => out.push_str(&format!("    {}.install {}\n", install, ruby(path))),
+        }
+    }
+    out.push_str(&format!(
+        "  end\n\n  test do\n    assert_match {}, shell_output(\"#{{bin}}/{} --help\")\n  end\nend\n",
+        ruby(command),
+        command
+    ));
+    out
+}
+
+/// A ustar archive of `entries` and the directories they're in, owned by root.
+fn tar(entries: &[Entry]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut dirs: Vec<String> = Vec::new();
+    for (path, data, mode) in entries {
+        let mut parent = String::new();
+        for part in path.split('/').rev().skip(1).collect::<Vec<_>>().into_iter().rev() {
+            parent.push_str(part);
+            parent.push('/');
+            if !dirs.contains(&parent) {
+                out.extend_from_slice(&tar_header(&format!("./{}", parent), 0, 0o755, b'5'));
+                dirs.push(parent.clone());
+            }
+        }
+        out.extend_from_slice(&tar_header(&format!("./{}", path), data.len(), *mode, b'0'));
+        out.extend_from_slice(data);
+        out.resize(out.len().div_ceil(512) * 512, 0);
+    }
+    // Two empty blocks end it
+    out.resize(out.len() + 1024, 0);
+    out
+}
+
+fn tar_header(path: &str, size: usize, mode: u32, kind: u8) -> [u8; 512] {
+    let mut header = [0u8; 512];
+    let mut field = |at: usize, len: usize, value: &[u8]| header[at..at + value.len().min(len)].copy_from_slice(&value[..value.len().min(len)]);
+    // Longer paths go in the prefix, split at a slash
+    match path.len() {
+        0..=100 => field(0, 100, path.as_bytes()),
+        _ => {
+            let split = path[..path.len().min(156)].rfind('/').unwrap_or(0);
+            field(345, 155, &path.as_bytes()[..split]);
+            field(0, 100, &path.as_bytes()[split + 1..]);
+        }
+    }
+    field(100, 8, format!("{:07o}\0", mode).as_bytes());
+    field(108, 8, b"0000000\0");
+    field(116, 8, b"0000000\0");
+    field(124, 12, format!("{:011o}\0", size).as_bytes());
+    field(136, 12, format!("{:011o}\0", now()).as_bytes());
+    field(156, 1, &[kind]);
+    field(257, 8, b"ustar\x0000");
+    field(265, 32, b"root");
+    field(297, 32, b"root");
+    // The checksum counts itself as spaces
+    field(148, 8, b"        ");
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header
+}
+
+/// Seconds since the epoch, for the files' times; `SOURCE_DATE_EPOCH` makes
+/// packages built twice the same.
+fn now() -> u64 {
+    std::env::var("SOURCE_DATE_EPOCH").ok().and_then(|epoch| epoch.parse().ok()).unwrap_or_else(|| {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    })
+}
+
+/// SHA-256 of `data` in hex, for the formula and `get`'s checksums.
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5, 0xd807aa98,
+        0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786,
+        0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8,
+        0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+        0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819,
+        0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a,
+        0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+    let mut h: [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+    h.iter().map(|word| format!("{:08x}", word)).collect()
+}
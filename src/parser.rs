@@ -0,0 +1,2098 @@
+//! Parser for *.matthiashihic source files.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::ast::{expand_macros, ArgType, Branch, Comment, Edition, EveryLine, Experiment, Include, Loop, Macro, Parallel, Persona, Program, ProgramInfo, Recipe, Schema, Segment, Statement, Temperature};
+use crate::diagnostics::{line_offset, Diagnostic, Fix, ParseError};
+use crate::front_matter::{self, FrontMatter};
+use crate::i18n::tr;
+
+const SECRET_PREFIX: [char; 7] = ['s', 'e', 'c', 'r', 'e', 't', '('];
+const ENV_PREFIX: [char; 4] = ['e', 'n', 'v', '.'];
+const FILE_PREFIX: [char; 5] = ['f', 'i', 'l', 'e', '('];
+const DIR_PREFIX: [char; 4] = ['d', 'i', 'r', '('];
+const GITDIFF: [char; 7] = ['g', 'i', 't', 'd', 'i', 'f', 'f'];
+const GITLOG_PREFIX: [char; 7] = ['g', 'i', 't', 'l', 'o', 'g', '('];
+/// `€n or "..."`: the value €n gets when its stdin line is missing.
+const DEFAULT_PREFIX: [char; 5] = [' ', 'o', 'r', ' ', '"'];
+/// Most commits `€gitlog(n)` may ask for.
+const MAX_GITLOG: usize = 1000;
+/// Most macro uses inside each other's expansions or arguments, which is how
+/// deep a macro that uses itself gets before it's an error.
+const MAX_MACRO_DEPTH: usize = 16;
+/// Most bytes the macros of a program may expand to, all uses together.
+const MAX_MACRO_BYTES: usize = 1024 * 1024;
+
+/// Opens and closes a statement that may span lines.
+const TRIPLE_QUOTE: &str = "\"\"\"";
+/// Sources larger than this are rejected before parsing.
+const MAX_SOURCE_BYTES: usize = 1024 * 1024;
+/// Most statements `nom nom` may bring into a program, however many files deep.
+const MAX_INCLUDED_STATEMENTS: usize = 10_000;
+/// Highest placeholder index, which is also the most stdin lines a program may require.
+pub const MAX_PLACEHOLDER: usize = 1000;
+/// Flags of the compiled programs that `€{name}`'s `--name` would shadow.
+const RESERVED_NAMES: &[&str] = &["help", "record", "listen", "parallel", "arm", "trailer", "newline", "encoding"];
+/// `€loop`: the round of the `again!` loop a statement is in.
+const LOOP_COUNTER: &str = "loop";
+/// `€line`: the stdin line a `for every line` block is on.
+const LINE: &str = "line";
+/// `€prev`: the answer of the statement that ran right before.
+const PREV: &str = "prev";
+/// `€shell`: the output of the last `shell "..."` statement.
+const SHELL: &str = "shell";
+/// Most rounds `again! N times` may run, written down or read at runtime.
+pub const MAX_LOOP_ROUNDS: usize = 1000;
+/// Most times `or try again N times` may ask a failed statement again.
+const MAX_RETRIES: u32 = 10;
+
+/// Placeholders collected while parsing statements.
+#[derive(Default)]
+struct Placeholders {
+    args: BTreeSet<usize>,
+    /// `€{name}`s in the order they first appear, since that's the order the
+    /// program asks for them in.
+    named: Vec<String>,
+    arg_types: Vec<(usize, ArgType)>,
+    /// `€n or "..."` defaults, as (index, value).
+    arg_defaults: Vec<(usize, String)>,
+    secrets: Vec<String>,
+    env_vars: Vec<String>,
+    /// Names bound with `-> name` so far; `€name` only means one of these.
+    bindings: Vec<String>,
+    /// `€name`s of the current statement that aren't bound, taken literally.
+    unbound: Vec<String>,
+    /// The `stuff:` constants, as (name, value).
+    constants: Vec<(String, String)>,
+    /// The `macro` definitions.
+    macros: Vec<Macro>,
+    /// The arguments of the macro being expanded, by parameter name, with
+    /// the macros in them expanded already.
+    macro_args: Vec<(String, Vec<Segment>)>,
+    /// The macros being expanded, at their arguments or their bodies,
+    /// outermost first.
+    expanding: Vec<String>,
+    /// How many of `expanding` are at their bodies.
+    bodies: usize,
+    /// Bytes the macros have expanded to so far.
+    macro_bytes: usize,
+    /// The files `nom nom` has read, in the order it read them.
+    read: Vec<PathBuf>,
+}
+
+/// Splits statement text into literal text, `€index[:type][ or "default"]` placeholders,
+/// `€{name}` placeholders, `€secret(NAME)`, `€env.NAME`, `€file(index[, pages=a-b])`, `€dir(index[, glob=..., max_bytes=...])`,
+/// `€gitdiff`, `€gitlog(n)`, `€prev`, `€name(arg, ...)` of a macro, `€name` of
+/// a constant and `€name` of an earlier `-> name`, resolving `€€` to a literal `€`.
+fn process_placeholders(s: &str, found: &mut Placeholders) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    let mut text = String::new();
+    let chars: Vec<char> = s.chars().collect();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let ch = chars[i];
+        i += 1;
+        if ch != '€' {
+            text.push(ch);
+            continue;
+        }
+        match chars.get(i) {
+            Some('€') => {
+                // €€index -> €index (escape)
+                i += 1; // consume the second €
+                text.push('€');
+            }
+            Some('s') if chars[i..].starts_with(&SECRET_PREFIX) => {
+                // €secret(NAME) -> read from the environment at runtime
+                let name_start = i + SECRET_PREFIX.len();
+                let close = (name_start..chars.len())
+                    .find(|&j| chars[j] == ')')
+                    .ok_or("Missing ')' in €secret(NAME)")?;
+                let name: String = chars[name_start..close].iter().collect();
+                if !is_identifier(&name) {
+                    return Err(format!("Invalid secret name '{}'; use letters, digits and _ like an environment variable", name));
+                }
+                if !found.secrets.contains(&name) {
+                    found.secrets.push(name.clone());
+                }
+                if !text.is_empty() {
+                    segments.push(Segment::Text(std::mem::take(&mut text)));
+                }
+                segments.push(Segment::Secret(name));
+                i = close + 1;
+            }
+            Some('e') if chars[i..].starts_with(&ENV_PREFIX) => {
+                // €env.NAME -> read from the environment when the program starts
+                let name_start = i + ENV_PREFIX.len();
+                let name_end = (name_start..chars.len())
+                    .find(|&j| !(chars[j].is_ascii_alphanumeric() || chars[j] == '_'))
+                    .unwrap_or(chars.len());
+                let name: String = chars[name_start..name_end].iter().collect();
+                if !is_identifier(&name) {
+                    return Err("€env. needs the name of an environment variable, e.g. €env.USER".to_string());
+                }
+                if !found.env_vars.contains(&name) {
+                    found.env_vars.push(name.clone());
+                }
+                if !text.is_empty() {
+                    segments.push(Segment::Text(std::mem::take(&mut text)));
+                }
+                segments.push(Segment::Env(name));
+                i = name_end;
+            }
+            Some('f') if chars[i..].starts_with(&FILE_PREFIX) => {
+                // €file(index, pages=a-b) -> the text of the file named by stdin line `index`
+                let args_start = i + FILE_PREFIX.len();
+                let close = (args_start..chars.len())
+                    .find(|&j| chars[j] == ')')
+                    .ok_or("Missing ')' in €file(index)")?;
+                let args: String = chars[args_start..close].iter().collect();
+                let (index, pages) = parse_file_args(&args)?;
+                found.args.insert(index);
+                if !text.is_empty() {
+                    segments.push(Segment::Text(std::mem::take(&mut text)));
+                }
+                segments.push(Segment::File { index, pages });
+                i = close + 1;
+            }
+            Some('d') if chars[i..].starts_with(&DIR_PREFIX) => {
+                // €dir(index, glob="...", max_bytes=...) -> the matching files below stdin line `index`
+                let args_start = i + DIR_PREFIX.len();
+                // The glob is quoted and may contain a ')'
+                let mut quoted = false;
+                let close = (args_start..chars.len())
+                    .find(|&j| {
+                        if chars[j] == '"' {
+                            quoted = !quoted;
+                        }
+                        chars[j] == ')' && !quoted
+                    })
+                    .ok_or("Missing ')' in €dir(index)")?;
+                let args: String = chars[args_start..close].iter().collect();
+                let (index, glob, max_bytes) = parse_dir_args(&args)?;
+                found.args.insert(index);
+                if !text.is_empty() {
+                    segments.push(Segment::Text(std::mem::take(&mut text)));
+                }
+                segments.push(Segment::Dir { index, glob, max_bytes });
+                i = close + 1;
+            }
+            Some('g') if chars[i..].starts_with(&GITDIFF) => {
+                // €gitdiff -> the staged diff, read by the program when it runs
+                if !text.is_empty() {
+                    segments.push(Segment::Text(std::mem::take(&mut text)));
+                }
+                segments.push(Segment::GitDiff);
+                i += GITDIFF.len();
+            }
+            Some('g') if chars[i..].starts_with(&GITLOG_PREFIX) => {
+                // €gitlog(n) -> the last n commits
+                let count_start = i + GITLOG_PREFIX.len();
+                let close = (count_start..chars.len())
+                    .find(|&j| chars[j] == ')')
+                    .ok_or("Missing ')' in €gitlog(n)")?;
+                let count: String = chars[count_start..close].iter().collect();
+                let count = count
+                    .trim()
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|n| (1..=MAX_GITLOG).contains(n))
+                    .ok_or_else(|| format!("€gitlog(n) needs a number of commits between 1 and {}, got '{}'", MAX_GITLOG, count))?;
+                if !text.is_empty() {
+                    segments.push(Segment::Text(std::mem::take(&mut text)));
+                }
+                segments.push(Segment::GitLog(count));
+                i = close + 1;
+            }
+            Some(c) if c.is_ascii_digit() => {
+                // €index -> placeholder
+                let digits_start = i;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+                let num_str: String = chars[digits_start..i].iter().collect();
+                let index = num_str
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid placeholder number: €{}", num_str))?;
+                if index == 0 {
+                    return Err("Placeholder indices must start at 1 (found €0)".into());
+                }
+                if index > MAX_PLACEHOLDER {
+                    return Err(format!("Placeholder €{} is too large; at most €{} lines can be read from stdin", index, MAX_PLACEHOLDER));
+                }
+                found.args.insert(index);
+
+                // Optional `:type` annotation, e.g. €1:int
+                let mut ty = None;
+                if chars.get(i) == Some(&':') {
+                    let name_end = (i + 1..chars.len())
+                        .find(|&j| !chars[j].is_ascii_alphanumeric())
+                        .unwrap_or(chars.len());
+                    let name: String = chars[i + 1..name_end].iter().collect();
+                    if let Some(parsed) = ArgType::parse(&name) {
+                        match found.arg_types.iter().find(|(arg, _)| *arg == index) {
+                            Some((_, existing)) if *existing != parsed => {
+                                return Err(format!(
+                                    "€{} is annotated as both :{} and :{}",
+                                    index,
+                                    existing.name(),
+                                    parsed.name()
+                                ));
+                            }
+                            Some(_) => {}
+                            None => found.arg_types.push((index, parsed)),
+                        }
+                        ty = Some(parsed);
+                        i = name_end;
+                    }
+                }
+
+                // Optional default for a missing stdin line, e.g. €1 or "World"
+                let mut default = None;
+                if chars[i..].starts_with(&DEFAULT_PREFIX) {
+                    let value_start = i + DEFAULT_PREFIX.len();
+                    let close = (value_start..chars.len())
+                        .find(|&j| chars[j] == '"')
+                        .ok_or_else(|| format!("Missing closing quote in €{} or \"...\"", index))?;
+                    let value: String = chars[value_start..close].iter().collect();
+                    if value.contains('€') {
+                        return Err(format!("The default of €{} is taken literally, so it can't hold placeholders: \"{}\"", index, value));
+                    }
+                    match found.arg_defaults.iter().find(|(arg, _)| *arg == index) {
+                        Some((_, existing)) if *existing != value => {
+                            return Err(format!("€{} defaults to both \"{}\" and \"{}\"", index, existing, value));
+                        }
+                        Some(_) => {}
+                        None => found.arg_defaults.push((index, value.clone())),
+                    }
+                    default = Some(value);
+                    i = close + 1;
+                }
+
+                if !text.is_empty() {
+                    segments.push(Segment::Text(std::mem::take(&mut text)));
+                }
+                segments.push(Segment::Placeholder { index, ty, default });
+            }
+            Some('*') => {
+                // €* -> every stdin line, read by the program when it runs
+                if !text.is_empty() {
+                    segments.push(Segment::Text(std::mem::take(&mut text)));
+                }
+                segments.push(Segment::AllArgs);
+                i += 1;
+            }
+            Some('{') => {
+                // €{name} -> the value labelled name when the program runs
+                let name_start = i + 1;
+                let close = (name_start..chars.len())
+                    .find(|&j| chars[j] == '}')
+                    .ok_or("Missing '}' in €{name}")?;
+                let name: String = chars[name_start..close].iter().collect();
+                if !is_identifier(&name) {
+                    return Err(format!("Invalid placeholder name '{}'; use letters, digits and _", name));
+                }
+                if RESERVED_NAMES.contains(&name.as_str()) {
+                    return Err(format!("€{{{}}} would clash with the program's own --{} flag; pick another name", name, name));
+                }
+                if !found.named.contains(&name) {
+                    found.named.push(name.clone());
+                }
+                if !text.is_empty() {
+                    segments.push(Segment::Text(std::mem::take(&mut text)));
+                }
+                segments.push(Segment::Named(name));
+                i = close + 1;
+            }
+            Some(c) if c.is_ascii_alphabetic() || *c == '_' => {
+                // €name -> the answer bound to name, if a statement before was
+                let name_end = (i..chars.len())
+                    .find(|&j| !(chars[j].is_ascii_alphanumeric() || chars[j] == '_'))
+                    .unwrap_or(chars.len());
+                let name: String = chars[i..name_end].iter().collect();
+                i = name_end;
+                if name == PREV {
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Prev);
+                    continue;
+                }
+                if let Some((_, arg)) = found.macro_args.iter().find(|(param, _)| *param == name) {
+                    // A parameter, inside the macro's body
+                    let arg = arg.clone();
+                    count_macro_bytes(found, expanded_len(&arg))?;
+                    for segment in arg {
+                        match segment {
+                            Segment::Text(arg) => text.push_str(&arg),
+                            segment => {
+                                if !text.is_empty() {
+                                    segments.push(Segment::Text(std::mem::take(&mut text)));
+                                }
+                                segments.push(segment);
+                            }
+                        }
+                    }
+                    continue;
+                }
+                if let Some(definition) = found.macros.iter().find(|definition| definition.name == name && chars.get(i) == Some(&'(')) {
+                    let definition = definition.clone();
+                    let close = closing_paren(&chars, i).ok_or_else(|| format!("Missing ')' in €{}(...)", name))?;
+                    let inner: String = chars[i + 1..close].iter().collect();
+                    i = close + 1;
+                    let (args, expansion) = expand_macro(&definition, &inner, found)?;
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    if found.bodies == 0 {
+                        segments.push(Segment::Macro { name, args, expansion });
+                    } else {
+                        // Inside another macro's body, only its expansion is kept
+                        segments.extend(expansion);
+                    }
+                    continue;
+                }
+                if let Some((_, value)) = found.constants.iter().find(|(constant, _)| *constant == name) {
+                    let value = value.clone();
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Const { name, value });
+                    continue;
+                }
+                if !found.bindings.contains(&name) {
+                    text.push('€');
+                    text.push_str(&name);
+                    found.unbound.push(name);
+                    continue;
+                }
+                if !text.is_empty() {
+                    segments.push(Segment::Text(std::mem::take(&mut text)));
+                }
+                segments.push(Segment::Var(name));
+            }
+            _ => text.push(ch),
+        }
+    }
+
+    if !text.is_empty() {
+        segments.push(Segment::Text(text));
+    }
+    Ok(segments)
+}
+
+/// Where the `)` that closes the `(` at `open` is, if it's closed.
+fn closing_paren(chars: &[char], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (j, &c) in chars.iter().enumerate().skip(open) {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 1 => return Some(j),
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The arguments of a use of `definition`, `inner` being what's between its
+/// parentheses, and what it expands to. Arguments are split on the commas
+/// outside of parentheses and trimmed, and may use whatever the statement
+/// may; the body gets them for its parameters.
+fn expand_macro(definition: &Macro, inner: &str, found: &mut Placeholders) -> Result<(Vec<Vec<Segment>>, Vec<Segment>), String> {
+    let mut args = Vec::new();
+    if !inner.trim().is_empty() {
+        let mut depth = 0;
+        let mut arg = String::new();
+        for c in inner.chars() {
+            match c {
+                ',' if depth == 0 => args.push(std::mem::take(&mut arg)),
+                c => {
+                    depth += match c {
+                        '(' => 1,
+                        ')' => -1,
+                        _ => 0,
+                    };
+                    arg.push(c);
+                }
+            }
+        }
+        args.push(arg);
+    }
+    if args.len() != definition.params.len() {
+        return Err(format!(
+            "macro {} takes {} argument(s), got {}: €{}({})",
+            definition.name,
+            definition.params.len(),
+            args.len(),
+            definition.name,
+            inner
+        ));
+    }
+    if found.expanding.len() == MAX_MACRO_DEPTH {
+        let chain = found.expanding.iter().chain([&definition.name]).cloned().collect::<Vec<_>>();
+        return Err(format!("Macros expand more than {} deep, does one use itself? {}", MAX_MACRO_DEPTH, chain.join(" -> ")));
+    }
+    // Macros in the arguments are as deep as those in the body
+    found.expanding.push(definition.name.clone());
+    let expanded = expand_arguments_and_body(definition, &args, found);
+    found.expanding.pop();
+    expanded
+}
+
+/// The part of expand_macro that runs with `definition` on `found.expanding`.
+fn expand_arguments_and_body(
+    definition: &Macro,
+    args: &[String],
+    found: &mut Placeholders,
+) -> Result<(Vec<Vec<Segment>>, Vec<Segment>), String> {
+    let args = args.iter().map(|arg| process_placeholders(arg.trim(), found)).collect::<Result<Vec<_>, _>>()?;
+    count_macro_bytes(found, definition.body.len())?;
+    // The body gets what the arguments expand to, not the macro uses in them
+    let params = definition.params.iter().cloned().zip(args.iter().cloned().map(expand_macros)).collect();
+    let outer = std::mem::replace(&mut found.macro_args, params);
+    found.bodies += 1;
+    let expansion = process_placeholders(&definition.body, found);
+    found.bodies -= 1;
+    found.macro_args = outer;
+    Ok((args, expansion?))
+}
+
+/// Adds `bytes` to what the macros have expanded to, which is an error past
+/// MAX_MACRO_BYTES.
+fn count_macro_bytes(found: &mut Placeholders, bytes: usize) -> Result<(), String> {
+    found.macro_bytes += bytes;
+    if found.macro_bytes > MAX_MACRO_BYTES {
+        let chain = found.expanding.join(" -> ");
+        return Err(format!("Macros expand to more than {} bytes here: {}", MAX_MACRO_BYTES, chain));
+    }
+    Ok(())
+}
+
+/// Roughly how many bytes `segments` take: their text, and a byte for
+/// everything else.
+fn expanded_len(segments: &[Segment]) -> usize {
+    segments
+        .iter()
+        .map(|segment| match segment {
+            Segment::Text(text) => text.len(),
+            _ => 1,
+        })
+        .sum()
+}
+
+/// `macro name(a, b) = "..."`, after `macro`. Every parameter has to be used
+/// in the body, or its argument would go nowhere.
+fn parse_macro(text: &str, line: usize, warnings: &mut Vec<Diagnostic>) -> Result<Macro, String> {
+    let usage = || format!("macros look like: macro shout(x) = \"say €x in capitals\", got: macro {}", text);
+    let (head, value) = text.split_once('=').ok_or_else(usage)?;
+    let (name, params) = head.trim().strip_suffix(')').and_then(|head| head.split_once('(')).ok_or_else(usage)?;
+    let name = name.trim();
+    if !is_identifier(name) {
+        return Err(format!("Invalid macro name '{}'; use letters, digits and _", name));
+    }
+    if is_reserved(name) || name == "env" {
+        return Err(format!("'{}' can't be a macro, €{} means something else already", name, name));
+    }
+    let params: Vec<String> = if params.trim().is_empty() {
+        Vec::new()
+    } else {
+        params.split(',').map(|param| param.trim().to_string()).collect()
+    };
+    for (index, param) in params.iter().enumerate() {
+        if !is_identifier(param) || is_reserved(param) {
+            return Err(format!("'{}' can't be a parameter of macro {}; use letters, digits and _", param, name));
+        }
+        if params[..index].contains(param) {
+            return Err(format!("macro {} has two parameters called {}", name, param));
+        }
+    }
+    let value = value.trim();
+    if !value.starts_with('"') {
+        return Err(usage());
+    }
+    let (body, rest) = read_quoted(value, line, warnings).ok_or_else(|| format!("The body of macro {} is missing its closing quote", name))?;
+    if !rest.trim().is_empty() {
+        return Err(format!("Unexpected text after the body of macro {}: {}", name, rest.trim()));
+    }
+    let used = |param: &str| {
+        body.match_indices(&format!("€{}", param)).any(|(at, used)| {
+            // €€x is text, and €xy is something else
+            let escaped = body[..at].chars().rev().take_while(|&c| c == '€').count() % 2 == 1;
+            let next = body[at + used.len()..].chars().next();
+            !escaped && !next.is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+        })
+    };
+    if let Some(param) = params.iter().find(|param| !used(param)) {
+        return Err(format!("macro {} doesn't use its parameter {}; say €{} in the body", name, param, param));
+    }
+    Ok(Macro { name: name.to_string(), params, body })
+}
+
+/// The stdin line a `€file(...)` or `€dir(...)` reads its path from.
+fn parse_path_index(kind: &str, index: &str) -> Result<usize, String> {
+    let index = index
+        .trim()
+        .parse::<usize>()
+        .map_err(|_| format!("€{}(...) needs a placeholder number, e.g. €{}(1), got '{}'", kind, kind, index.trim()))?;
+    if index == 0 || index > MAX_PLACEHOLDER {
+        return Err(format!("€{}({}) must name a stdin line between 1 and {}", kind, index, MAX_PLACEHOLDER));
+    }
+    Ok(index)
+}
+
+/// Parses the inside of `€file(1)` or `€file(1, pages=2-5)`; `pages=3` is page 3 only.
+fn parse_file_args(args: &str) -> Result<(usize, Option<(u32, u32)>), String> {
+    let (index, pages) = match args.split_once(',') {
+        Some((index, pages)) => (index, Some(pages.trim())),
+        None => (args, None),
+    };
+    let index = parse_path_index("file", index)?;
+    let Some(pages) = pages else {
+        return Ok((index, None));
+    };
+    let range = pages
+        .strip_prefix("pages=")
+        .ok_or_else(|| format!("€file({}, ...) only takes pages=<first>-<last>, got '{}'", index, pages))?;
+    let (first, last) = range.split_once('-').unwrap_or((range, range));
+    let (first, last) = match (first.trim().parse::<u32>(), last.trim().parse::<u32>()) {
+        (Ok(first), Ok(last)) if first >= 1 && first <= last => (first, last),
+        _ => return Err(format!("Invalid page range '{}'; use e.g. pages=1-5", range)),
+    };
+    Ok((index, Some((first, last))))
+}
+
+/// Parses the inside of `€dir(1, glob="**/*.rs", max_bytes=200k)`; both
+/// options are optional, and the glob may be left unquoted.
+fn parse_dir_args(args: &str) -> Result<(usize, Option<String>, Option<usize>), String> {
+    let (index, mut options) = args.split_once(',').unwrap_or((args, ""));
+    let index = parse_path_index("dir", index)?;
+    let mut glob = None;
+    let mut max_bytes = None;
+    while !options.trim().is_empty() {
+        let (key, rest) = options
+            .split_once('=')
+            .ok_or_else(|| format!("€dir({}, ...) options look like glob=\"**/*.rs\" or max_bytes=200k", index))?;
+        let rest = rest.trim_start();
+        let (value, next) = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').ok_or("Missing closing quote in €dir(...) option")?;
+                let next = quoted[end + 1..].trim_start();
+                if !next.is_empty() && !next.starts_with(',') {
+                    return Err(format!("Unexpected '{}' after a quoted €dir(...) option", next));
+                }
+                (&quoted[..end], next.strip_prefix(',').unwrap_or(next))
+            }
+            None => rest.split_once(',').unwrap_or((rest, "")),
+        };
+        match key.trim() {
+            "glob" if glob.is_none() => {
+                if value.is_empty() || value.contains(['\\', '"']) || value.chars().any(char::is_control) {
+                    return Err(format!("Invalid glob '{}' in €dir({}, ...)", value, index));
+                }
+                glob = Some(value.to_string());
+            }
+            "max_bytes" if max_bytes.is_none() => max_bytes = Some(parse_size(value.trim())?),
+            "glob" | "max_bytes" => return Err(format!("€dir({}, ...) sets {} twice", index, key.trim())),
+            other => return Err(format!("Unknown €dir option '{}' (supported: glob, max_bytes)", other)),
+        }
+        options = next;
+    }
+    Ok((index, glob, max_bytes))
+}
+
+/// `200k`, `1m` or a plain number of bytes.
+fn parse_size(value: &str) -> Result<usize, String> {
+    let lower = value.to_ascii_lowercase();
+    let (digits, unit) = match lower.strip_suffix('k') {
+        Some(digits) => (digits, 1024),
+        None => match lower.strip_suffix('m') {
+            Some(digits) => (digits, 1024 * 1024),
+            None => (lower.as_str(), 1),
+        },
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .filter(|&n| n > 0)
+        .ok_or_else(|| format!("Invalid max_bytes '{}'; use e.g. 200k", value))
+}
+
+/// `-> name` after a statement's closing quote, if it's there. The name can't
+/// be one that `€name` would read as something else.
+fn parse_binding(rest: &str) -> Result<Option<String>, String> {
+    let Some(name) = rest.trim().strip_prefix("->") else {
+        return Ok(None);
+    };
+    let name = name.trim();
+    if !is_identifier(name) {
+        return Err(format!("Invalid name '{}' after '->'; use letters, digits and _", name));
+    }
+    if is_reserved(name) {
+        return Err(format!("'{}' can't be bound, €{} means something else already", name, name));
+    }
+    Ok(Some(name.to_string()))
+}
+
+/// `using <model>` after a statement's closing quote, if it's there, and what
+/// follows it.
+fn parse_model_suffix(rest: &str) -> Result<(Option<String>, &str), String> {
+    let Some(text) = rest.trim_start().strip_prefix("using") else {
+        return Ok((None, rest));
+    };
+    let text = text.trim_start();
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    let model = &text[..end];
+    if model.is_empty() || model.starts_with("->") {
+        return Err("'using' needs the model to send the statement to, like using gpt-4o-mini".into());
+    }
+    if !model.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':' | '/')) {
+        return Err(format!("'{}' isn't a model name; use letters, digits and - _ . : /", model));
+    }
+    Ok((Some(model.to_string()), &text[end..]))
+}
+
+/// `(chill)`, `(wild)` or `temperature <0-2>` after a statement's closing
+/// quote (or its `using`), if it's there, and what follows it.
+fn parse_temperature_suffix(rest: &str) -> Result<(Option<Temperature>, &str), String> {
+    let text = rest.trim_start();
+    if let Some(rest) = text.strip_prefix("(chill)") {
+        return Ok((Some(Temperature::Chill), rest));
+    }
+    if let Some(rest) = text.strip_prefix("(wild)") {
+        return Ok((Some(Temperature::Wild), rest));
+    }
+    let Some(text) = text.strip_prefix("temperature") else {
+        return Ok((None, rest));
+    };
+    let text = text.trim_start();
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    match text[..end].parse::<f64>() {
+        Ok(value) if (0.0..=2.0).contains(&value) => Ok((Some(Temperature::Exact(value)), &text[end..])),
+        _ => Err(format!("'temperature' needs a number between 0 and 2, like temperature 0.2, got '{}'", &text[..end])),
+    }
+}
+
+/// `as json {...}` after a statement's closing quote, if it's there, and what
+/// follows it. The schema has to be an object, which is what structured
+/// outputs answer with.
+fn parse_json_suffix(rest: &str) -> Result<(Option<Schema>, &str), String> {
+    let Some(text) = rest.trim_start().strip_prefix("as json") else {
+        return Ok((None, rest));
+    };
+    if !text.trim_start().starts_with('{') {
+        return Err("'as json' needs the shape of the answer, like {colors: [string]}".into());
+    }
+    let (schema, rest) = parse_schema(text)?;
+    Ok((Some(schema), rest))
+}
+
+/// `or try again N times` after a statement's closing quote (or what comes
+/// before it), if it's there, and what follows it.
+fn parse_retry_suffix(rest: &str) -> Result<(Option<u32>, &str), String> {
+    let Some(text) = rest.trim_start().strip_prefix("or try again") else {
+        return Ok((None, rest));
+    };
+    let invalid = || format!("'or try again' needs how often, like or try again 3 times (1 to {})", MAX_RETRIES);
+    let text = text.trim_start();
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    let times = text[..end].parse::<u32>().ok().filter(|times| (1..=MAX_RETRIES).contains(times)).ok_or_else(invalid)?;
+    let text = text[end..].trim_start();
+    let unit = if times == 1 { "time" } else { "times" };
+    let rest = text.strip_prefix(unit).filter(|rest| !rest.starts_with(char::is_alphanumeric)).ok_or_else(invalid)?;
+    Ok((Some(times), rest))
+}
+
+/// `write it to "file"` after a statement's closing quote (or what comes
+/// before it), if it's there, and what follows it. The file name is plain
+/// text, the same for every run.
+fn parse_write_suffix<'a>(rest: &'a str, line: usize, warnings: &mut Vec<Diagnostic>) -> Result<(Option<String>, &'a str), String> {
+    let Some(text) = rest.trim_start().strip_prefix("write it to") else {
+        return Ok((None, rest));
+    };
+    let text = text.trim_start();
+    let no_placeholders = "write it to takes a plain file name, so it can't have placeholders; write € as €€";
+    let (path, rest) = read_plain(text, line, warnings, no_placeholders)
+        .ok_or_else(|| format!("'write it to' needs a file name in quotes, like write it to \"haiku.txt\", got: write it to {}", text))??;
+    if path.trim().is_empty() {
+        return Err("'write it to' needs a file name, like write it to \"haiku.txt\"".to_string());
+    }
+    Ok((Some(path), rest))
+}
+
+/// The expected answer of a `better be "..."` line, from what follows
+/// `better be`: plain text, compared as written.
+fn parse_expectation(text: &str, line: usize, warnings: &mut Vec<Diagnostic>) -> Result<String, String> {
+    let text = text.trim_start();
+    let no_placeholders = "better be compares the answer with plain text, so it can't have placeholders; write € as €€";
+    let (expected, rest) = read_plain(text, line, warnings, no_placeholders)
+        .ok_or_else(|| format!("expectations look like: better be \"42\", got: better be {}", text))??;
+    if !rest.trim().is_empty() {
+        return Err(format!("Unexpected text after the expectation: {}", rest.trim()));
+    }
+    Ok(expected)
+}
+
+/// The rest of a `persona` line: `"..."` or `only "..."`.
+fn parse_persona(text: &str, line: usize, warnings: &mut Vec<Diagnostic>) -> Result<Persona, String> {
+    let text = text.trim_start();
+    let (only, quoted) = match text.strip_prefix("only") {
+        Some(rest) if rest.starts_with([' ', '\t', '"']) => (true, rest.trim_start()),
+        _ => (false, text),
+    };
+    let no_placeholders = "persona is the same for every run, so it can't have placeholders; write € as €€";
+    let (persona, rest) = read_plain(quoted, line, warnings, no_placeholders)
+        .ok_or_else(|| format!("personas look like: persona \"you are a pirate\", got: persona {}", text))??;
+    if !rest.trim().is_empty() {
+        return Err(format!("Unexpected text after the persona: {}", rest.trim()));
+    }
+    if persona.trim().is_empty() {
+        return Err("persona needs someone to be, e.g. persona \"you are a pirate\"".to_string());
+    }
+    Ok(Persona { text: persona, only })
+}
+
+/// Reads the quoted plain text `text` starts with, and returns it and what
+/// follows; None if it doesn't start with one, and `no_placeholders` if it
+/// has any. `€€` is a `€`, but there's nothing to fill in.
+fn read_plain<'a>(text: &'a str, line: usize, warnings: &mut Vec<Diagnostic>, no_placeholders: &str) -> Option<Result<(String, &'a str), String>> {
+    let (quoted, rest) = Some(text).filter(|text| text.starts_with('"')).and_then(|text| read_quoted(text, line, warnings))?;
+    let plain = process_placeholders(&quoted, &mut Placeholders::default()).and_then(|segments| {
+        segments
+            .into_iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => Ok(text),
+                _ => Err(no_placeholders.to_string()),
+            })
+            .collect()
+    });
+    Some(plain.map(|plain| (plain, rest)))
+}
+
+/// One type of an `as json` schema: `string`, `number`, `integer`, `boolean`,
+/// `[type]` or `{key: type, ...}`. Returns it and the text after it.
+fn parse_schema(text: &str) -> Result<(Schema, &str), String> {
+    let text = text.trim_start();
+    if let Some(items) = text.strip_prefix('[') {
+        let (items, rest) = parse_schema(items)?;
+        let rest = rest.trim_start().strip_prefix(']').ok_or("A list type like [string] is missing its ']'")?;
+        return Ok((Schema::Array(Box::new(items)), rest));
+    }
+    if let Some(mut rest) = text.strip_prefix('{') {
+        let mut fields: Vec<(String, Schema)> = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix('}') {
+                if fields.is_empty() {
+                    return Err("An object type needs at least one field, like {name: string}".into());
+                }
+                return Ok((Schema::Object(fields), after));
+            }
+            let (key, after) = if let Some(quoted) = rest.strip_prefix('"') {
+                let end = quoted.find('"').ok_or("A quoted key in a json schema is missing its closing quote")?;
+                (quoted[..end].to_string(), &quoted[end + 1..])
+            } else {
+                let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+                (rest[..end].to_string(), &rest[end..])
+            };
+            if key.is_empty() {
+                return Err(format!("Expected a key in the json schema at: {}", rest));
+            }
+            if fields.iter().any(|(existing, _)| *existing == key) {
+                return Err(format!("The key '{}' is in the json schema twice", key));
+            }
+            let after = after.trim_start().strip_prefix(':').ok_or_else(|| format!("The key '{}' needs a ':' and a type", key))?;
+            let (schema, after) = parse_schema(after)?;
+            fields.push((key, schema));
+            rest = after.trim_start();
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with('}') {
+                return Err("Fields of a json schema are separated by ',' and end with '}'".into());
+            }
+        }
+    }
+    let end = text.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(text.len());
+    let schema = match &text[..end] {
+        "string" => Schema::String,
+        "number" => Schema::Number,
+        "integer" => Schema::Integer,
+        "boolean" => Schema::Boolean,
+        "" => return Err(format!("Expected a type in the json schema at: {}", text)),
+        other => {
+            return Err(format!(
+                "Unknown type '{}' in a json schema; use string, number, integer, boolean, [type] or {{key: type}}",
+                other
+            ))
+        }
+    };
+    Ok((schema, &text[end..]))
+}
+
+/// Whether `€name` means something of its own, so `name` can't be bound or
+/// be a constant.
+fn is_reserved(name: &str) -> bool {
+    ["secret", "file", "dir", "gitlog", LOOP_COUNTER, LINE, PREV, SHELL].contains(&name) || name.starts_with("gitdiff")
+}
+
+/// A `name = "value"` line of the `stuff:` section, or `None` if `line` isn't
+/// one. A value is plain text; `€€` in it is a `€`, like in a statement.
+fn parse_constant(line: &str, number: usize, warnings: &mut Vec<Diagnostic>) -> Result<Option<(String, String)>, String> {
+    let Some((name, value)) = line.split_once('=') else {
+        return Ok(None);
+    };
+    let (name, value) = (name.trim(), value.trim());
+    if !is_identifier(name) || !value.starts_with('"') {
+        return Ok(None);
+    }
+    if is_reserved(name) {
+        return Err(format!("'{}' can't be a constant, €{} means something else already", name, name));
+    }
+    let (value, rest) = read_quoted(value, number, warnings).ok_or_else(|| format!("The value of '{}' is missing its closing quote", name))?;
+    if !rest.trim().is_empty() {
+        return Err(format!("Unexpected text after the value of '{}': {}", name, rest.trim()));
+    }
+    Ok(Some((name.to_string(), value.replace("€€", "€"))))
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parses `extern name = "path"`.
+fn parse_extern(line: &str) -> Result<(String, String), String> {
+    let rest = line.strip_prefix("extern").unwrap_or(line);
+    let (name, path) = rest
+        .split_once('=')
+        .ok_or_else(|| format!("extern declarations look like: extern name = \"file.matthiashihic\", got: {}", line))?;
+    let name = name.trim();
+    if !is_identifier(name) {
+        return Err(format!("Invalid extern name '{}'; use letters, digits and _", name));
+    }
+    let path = path.trim();
+    if path.len() < 2 || !path.starts_with('"') || !path.ends_with('"') {
+        return Err(format!("The path of extern '{}' must be quoted", name));
+    }
+    let path = &path[1..path.len() - 1];
+    if path.is_empty() {
+        return Err(format!("The path of extern '{}' must not be empty", name));
+    }
+    Ok((name.to_string(), path.to_string()))
+}
+
+/// Recognizes `name(arg, ...)` where `name` is a declared extern. Arguments
+/// are split on commas and trimmed.
+fn parse_call(inner: &str, externs: &[(String, String)], found: &mut Placeholders) -> Result<Option<Segment>, String> {
+    let t = inner.trim();
+    let Some((name, rest)) = t.split_once('(') else {
+        return Ok(None);
+    };
+    let Some(args) = rest.strip_suffix(')') else {
+        return Ok(None);
+    };
+    if !externs.iter().any(|(declared, _)| declared == name) {
+        return Ok(None);
+    }
+    let args = if args.trim().is_empty() {
+        Vec::new()
+    } else {
+        args.split(',')
+            .map(|arg| process_placeholders(arg.trim(), found))
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    Ok(Some(Segment::Call { name: name.to_string(), args }))
+}
+
+/// What a statement can call: the declared externs, and the recipes so far
+/// with the number of arguments each takes.
+#[derive(Clone, Copy)]
+struct Known<'a> {
+    externs: &'a [(String, String)],
+    recipes: &'a [(String, usize)],
+}
+
+/// Parses a quoted statement and its `-> name` binding. `quote_at` is where a
+/// missing closing quote goes.
+fn parse_statement(
+    line: &str,
+    number: usize,
+    quote_at: usize,
+    known: Known,
+    found: &mut Placeholders,
+    warnings: &mut Vec<Diagnostic>,
+) -> Result<Statement, ParseError> {
+    if let Some(command) = shell_command(line.trim_start()) {
+        let statement = parse_statement(command, number, quote_at, known, found, warnings)?;
+        return shell_statement(statement, number, found);
+    }
+    if starts_raw(line.trim_start()) {
+        let Some((inner, rest)) = read_raw(line.trim_start()) else {
+            let hashes = line.trim_start()[1..].chars().take_while(|c| *c == '#').count();
+            let closing = format!("\"{}", "#".repeat(hashes));
+            let error = ParseError::from(format!("The raw string on line {} isn't closed with {}", number, closing)).at(number);
+            return Err(error.with_fix(Fix::insert(tr!("fix.quote"), quote_at, closing)));
+        };
+        // Nothing in it is an escape, so fmt couldn't write it back raw
+        if inner.contains('\r') {
+            let e = format!("The raw string on line {} has a carriage return in it; use a quoted string with \\r instead", number);
+            return Err(ParseError::from(e).at(number));
+        }
+        let statement = finish_statement(inner, rest, number, known, found, warnings)?;
+        return Ok(Statement { raw: true, ..statement });
+    }
+    let Some((inner, rest)) = read_quoted(line.trim_start(), number, warnings) else {
+        // It means we didn't find a closing quote properly
+        let error = ParseError::from(tr!("parse.missing-quote", line = number, text = line)).at(number);
+        // A quote after a trailing backslash would be escaped, too
+        let backslashes = line.trim_end().chars().rev().take_while(|c| *c == '\\').count();
+        if backslashes % 2 == 1 {
+            return Err(error);
+        }
+        return Err(error.with_fix(Fix::insert(tr!("fix.quote"), quote_at, "\"")));
+    };
+    finish_statement(inner, rest, number, known, found, warnings)
+}
+
+/// Reads a `"""` statement starting on line `idx` (0-based) of `lines`, up to
+/// the line with the closing `"""`. Returns its text, whatever follows the
+/// closing quotes and the index of their line. The text is verbatim, except
+/// that quotes on lines of their own don't add an empty line.
+fn read_block<'a>(lines: &[&'a str], idx: usize) -> Result<(String, &'a str, usize), ParseError> {
+    let opening = lines[idx].trim_start().strip_prefix(TRIPLE_QUOTE).unwrap_or_default();
+    if let Some(end) = opening.find(TRIPLE_QUOTE) {
+        return Ok((opening[..end].to_string(), &opening[end + TRIPLE_QUOTE.len()..], idx));
+    }
+    let mut text: Vec<&str> = Vec::new();
+    if !opening.trim().is_empty() {
+        text.push(opening);
+    }
+    for (end, line) in lines.iter().enumerate().skip(idx + 1) {
+        let Some(pos) = line.find(TRIPLE_QUOTE) else {
+            text.push(line);
+            continue;
+        };
+        if !line[..pos].trim().is_empty() {
+            text.push(&line[..pos]);
+        }
+        return Ok((text.join("\n"), &line[pos + TRIPLE_QUOTE.len()..], end));
+    }
+    let e = format!("The \"\"\" on line {} isn't closed with another \"\"\"", idx + 1);
+    Err(ParseError::from(e).at(idx + 1))
+}
+
+/// Makes a statement of the text from between its quotes and what follows
+/// them, on line `number`.
+fn finish_statement(
+    inner: String,
+    rest: &str,
+    number: usize,
+    known: Known,
+    found: &mut Placeholders,
+    warnings: &mut Vec<Diagnostic>,
+) -> Result<Statement, ParseError> {
+    let at = |e: String| ParseError::from(format!("{} (line {})", e, number)).at(number);
+    // done; ensure the rest is whitespace, `using <model>`, `(chill)`,
+    // `as json {...}`, `or try again N times`, `write it to "file"` or a `-> name`
+    // binding, in that order
+    let (model, rest) = parse_model_suffix(rest).map_err(at)?;
+    let (temperature, rest) = parse_temperature_suffix(rest).map_err(at)?;
+    let (schema, rest) = parse_json_suffix(rest).map_err(at)?;
+    let (retries, rest) = parse_retry_suffix(rest).map_err(at)?;
+    let (write_to, rest) = parse_write_suffix(rest, number, warnings).map_err(at)?;
+    let binding = parse_binding(rest).map_err(at)?;
+    if binding.is_none() && !rest.trim().is_empty() {
+        return Err(ParseError::from(tr!("parse.trailing", line = number, text = rest)).at(number));
+    }
+    if inner.trim().is_empty() {
+        warnings.push(Diagnostic::new("empty-statement", Some(number), tr!("lint.empty-statement")));
+    }
+    // Process the string for €index placeholders and €€index escaping
+    let segments = if let Some(use_) = parse_recipe_use(&inner, known.recipes, found).map_err(at)? {
+        vec![use_]
+    } else if let Some(call) = parse_call(&inner, known.externs, found)? {
+        vec![call]
+    } else {
+        process_placeholders(&inner, found)?
+    };
+    warn_unbound(found, number, warnings);
+    if model.is_some() && matches!(segments.as_slice(), [Segment::Call { .. }]) {
+        return Err(at("A call runs another program rather than a model, so it can't say 'using'".into()));
+    }
+    if let (Some(temperature), [Segment::Call { .. }]) = (temperature, segments.as_slice()) {
+        return Err(at(format!("A call runs another program rather than a model, so it can't say '{}'", temperature.to_source())));
+    }
+    if let Some(name) = binding.as_ref().filter(|name| found.constants.iter().any(|(constant, _)| constant == *name)) {
+        return Err(at(format!("'{}' is a constant from stuff:, so it can't be bound", name)));
+    }
+    if let Some(name) = &binding {
+        found.bindings.push(name.clone());
+    }
+    Ok(Statement { segments, binding, model, temperature, schema, retries, write_to, expect: None, raw: false, shell: false })
+}
+
+/// Parses a `recipe name:` block from its first line up to `serve it!`.
+/// Returns the recipe, the number of arguments it takes and the index of
+/// its `serve it!` line.
+fn parse_recipe(
+    lines: &[&str],
+    start: usize,
+    name: &str,
+    line_start: &dyn Fn(usize) -> usize,
+    known: Known,
+    found: &mut Placeholders,
+    warnings: &mut Vec<Diagnostic>,
+) -> Result<(Recipe, usize, usize), ParseError> {
+    let at = |e: String, line: usize| ParseError::from(format!("{} (line {})", e, line)).at(line);
+    if !is_identifier(name) {
+        return Err(at(format!("Invalid recipe name '{}'; use letters, digits and _", name), start + 1));
+    }
+    if known.recipes.iter().any(|(declared, _)| declared == name) {
+        return Err(at(format!("recipe '{}' is declared more than once", name), start + 1));
+    }
+    // A recipe's €n are its arguments, and its bindings are its own
+    let mut inner = Placeholders {
+        constants: found.constants.clone(),
+        macros: found.macros.clone(),
+        macro_bytes: found.macro_bytes,
+        ..Placeholders::default()
+    };
+    let mut statements = Vec::new();
+    let mut comments = Vec::new();
+    let mut idx = start + 1;
+    loop {
+        let Some(line) = lines.get(idx).filter(|line| line.trim() != "eat that java!") else {
+            return Err(at(format!("recipe '{}' isn't closed with 'serve it!'", name), start + 1));
+        };
+        match line.trim() {
+            "" => {}
+            "serve it!" => break,
+            t if t.starts_with("hm,") => {
+                comments.push(Comment { before: Some(statements.len()), text: t["hm,".len()..].trim().to_string() });
+            }
+            t if t.starts_with("better be") => {
+                return Err(at(format!("recipe '{}' can't say 'better be'; put it after the statement that uses the recipe", name), idx + 1))
+            }
+            t if t.starts_with(TRIPLE_QUOTE) => {
+                let (text, rest, end) = read_block(lines, idx)?;
+                statements.push(finish_statement(text, rest, idx + 1, known, &mut inner, warnings)?);
+                idx = end;
+            }
+            t if t.starts_with('"') || starts_raw(t) || shell_command(t).is_some() => {
+                let quote_at = line_start(idx + 1) + line.trim_end().len();
+                statements.push(parse_statement(line, idx + 1, quote_at, known, &mut inner, warnings)?);
+            }
+            _ => {
+                return Err(at(
+                    format!("recipe '{}' holds quoted statements only, and ends with 'serve it!'", name),
+                    idx + 1,
+                ))
+            }
+        }
+        idx += 1;
+    }
+    if statements.is_empty() {
+        return Err(at(format!("recipe '{}' has no statements", name), start + 1));
+    }
+    if let Some(named) = inner.named.first() {
+        return Err(at(format!("recipe '{}' gets its arguments as €1, €2, ..., so it can't use €{{{}}}", name, named), start + 1));
+    }
+    if let Some((index, _)) = inner.arg_defaults.first() {
+        return Err(at(format!("recipe '{}' gets every argument from its 'do', so €{} can't have a default", name, index), start + 1));
+    }
+    for secret in inner.secrets {
+        if !found.secrets.contains(&secret) {
+            found.secrets.push(secret);
+        }
+    }
+    for name in inner.env_vars {
+        if !found.env_vars.contains(&name) {
+            found.env_vars.push(name);
+        }
+    }
+    found.macro_bytes = inner.macro_bytes;
+    let arity = inner.args.last().copied().unwrap_or(0);
+    Ok((Recipe { name: name.to_string(), statements, comments }, arity, idx))
+}
+
+/// Recognizes `do name` and `do name with arg, ...` where `name` is a recipe
+/// declared above. Arguments are split on commas and trimmed.
+fn parse_recipe_use(inner: &str, recipes: &[(String, usize)], found: &mut Placeholders) -> Result<Option<Segment>, String> {
+    let Some(rest) = inner.trim().strip_prefix("do ") else {
+        return Ok(None);
+    };
+    let (name, args) = rest.trim_start().split_once(' ').unwrap_or((rest.trim_start(), ""));
+    let Some((_, arity)) = recipes.iter().find(|(declared, _)| declared == name) else {
+        return Ok(None);
+    };
+    let args = match args.trim_start().strip_prefix("with ") {
+        Some(args) => args
+            .split(',')
+            .map(|arg| process_placeholders(arg.trim(), found))
+            .collect::<Result<Vec<_>, _>>()?,
+        // `do greet nicely` is something to do, not a recipe
+        None if !args.trim().is_empty() => return Ok(None),
+        None => Vec::new(),
+    };
+    if args.len() != *arity {
+        return Err(format!("recipe '{}' takes {} argument(s), but is used with {}", name, arity, args.len()));
+    }
+    Ok(Some(Segment::Recipe { name: name.to_string(), args }))
+}
+
+/// Reads the quoted string `text` starts with, resolving escapes. Returns its
+/// contents and whatever follows the closing quote, or None if there is none.
+fn read_quoted<'a>(text: &'a str, line: usize, warnings: &mut Vec<Diagnostic>) -> Option<(String, &'a str)> {
+    let mut inner = String::new();
+    let mut escaped = false;
+    for (pos, ch) in text.char_indices().skip(1) {
+        if escaped {
+            // simple escapes: \n, \t, \r, \\, \"
+            let mapped = match ch {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '\\' => '\\',
+                '"' => '"',
+                other => {
+                    // unknown escape -> take literally
+                    warnings.push(Diagnostic::new(
+                        "suspicious-escape",
+                        Some(line),
+                        tr!("lint.suspicious-escape", char = other),
+                    ));
+                    other
+                }
+            };
+            inner.push(mapped);
+            escaped = false;
+            continue;
+        }
+        match ch {
+            '\\' => escaped = true,
+            '"' => return Some((inner, &text[pos + ch.len_utf8()..])),
+            ch => inner.push(ch),
+        }
+    }
+    None
+}
+
+/// The command of a `shell "..."` line, from its opening quote on, if `text`
+/// is one.
+fn shell_command(text: &str) -> Option<&str> {
+    let command = text.strip_prefix("shell")?.strip_prefix([' ', '\t'])?.trim_start();
+    (command.starts_with('"') || starts_raw(command)).then_some(command)
+}
+
+/// Checks that a `shell "..."` statement is a command and nothing a model
+/// would answer, and makes its output `€shell` from here on.
+fn shell_statement(statement: Statement, number: usize, found: &mut Placeholders) -> Result<Statement, ParseError> {
+    let at = |e: String| ParseError::from(format!("{} (line {})", e, number)).at(number);
+    if statement.model.is_some() {
+        return Err(at("shell runs a command rather than a model, so it can't say 'using'".into()));
+    }
+    if let Some(temperature) = statement.temperature {
+        return Err(at(format!("shell runs a command rather than a model, so it can't say '{}'", temperature.to_source())));
+    }
+    if statement.schema.is_some() {
+        return Err(at("shell runs a command rather than a model, so it can't say 'as json'".into()));
+    }
+    if matches!(statement.segments.as_slice(), [Segment::Call { .. } | Segment::Recipe { .. }]) {
+        return Err(at("shell runs its text as a command, so it can't call a program or use a recipe".into()));
+    }
+    if !found.bindings.iter().any(|name| name == SHELL) {
+        found.bindings.push(SHELL.to_string());
+    }
+    Ok(Statement { shell: true, ..statement })
+}
+
+/// Whether `text` starts with a raw string, `r"` or `r#"`.
+fn starts_raw(text: &str) -> bool {
+    text.strip_prefix('r').is_some_and(|rest| rest.trim_start_matches('#').starts_with('"'))
+}
+
+/// Reads the raw string `text` starts with: `r"..."`, or `r#"..."#` with as
+/// many `#`s as it takes. Nothing in it is an escape; it ends at the first
+/// quote followed by as many `#`s as it started with. Returns its contents and
+/// whatever follows, or None if it isn't closed.
+fn read_raw(text: &str) -> Option<(String, &str)> {
+    let hashes = text.strip_prefix('r')?.chars().take_while(|c| *c == '#').count();
+    let inner = text[1 + hashes..].strip_prefix('"')?;
+    let closing = format!("\"{}", "#".repeat(hashes));
+    let end = inner.find(&closing)?;
+    Some((inner[..end].to_string(), &inner[end + closing.len()..]))
+}
+
+/// The rest of an `again! N times` line: N as a number, or as the one
+/// placeholder or variable that says how many when the program runs.
+fn parse_loop_count(text: &str, found: &mut Placeholders) -> Result<Vec<Segment>, String> {
+    let text = text.trim();
+    let count = text
+        .strip_suffix(':')
+        .unwrap_or(text)
+        .trim_end()
+        .strip_suffix("times")
+        .map(str::trim_end)
+        .filter(|count| !count.is_empty())
+        .ok_or_else(|| format!("loops look like: again! 3 times, got: again! {}", text))?;
+    if let Ok(rounds) = count.parse::<usize>() {
+        if !(1..=MAX_LOOP_ROUNDS).contains(&rounds) {
+            return Err(format!("again! runs between 1 and {} times, not {}", MAX_LOOP_ROUNDS, rounds));
+        }
+        return Ok(vec![Segment::Text(rounds.to_string())]);
+    }
+    let segments = process_placeholders(count, found)?;
+    match segments.as_slice() {
+        [Segment::Placeholder { .. } | Segment::Named(_) | Segment::Var(_) | Segment::Prev] => Ok(segments),
+        [Segment::Const { name, value }] => match value.trim().parse::<usize>() {
+            Ok(rounds) if (1..=MAX_LOOP_ROUNDS).contains(&rounds) => Ok(segments),
+            _ => Err(format!("again! €{} times needs €{} to be between 1 and {}, not '{}'", name, name, MAX_LOOP_ROUNDS, value)),
+        },
+        _ => Err(format!("again! needs a number of times, or one placeholder like €1 that says it, got '{}'", count)),
+    }
+}
+
+/// Checks that no statement of the `all at once!` block on line `line` waits
+/// for another's answer: none says €prev, or uses a binding made in the block.
+fn check_parallel(statements: &[Statement], line: usize) -> Result<(), ParseError> {
+    let at = |e: String| ParseError::from(format!("{} (line {})", e, line)).at(line);
+    if statements.is_empty() {
+        return Err(at("The all at once! block has no statements".into()));
+    }
+    if statements.iter().any(Statement::uses_prev) {
+        return Err(at("€prev in an all at once! block would wait for the statement before it; bind the answer with -> name before the block".into()));
+    }
+    for (i, statement) in statements.iter().enumerate() {
+        if let Some(name) = &statement.binding {
+            if statements[i + 1..].iter().any(|later| later.uses_var(name)) {
+                return Err(at(format!("€{} is bound in the all at once! block, so nothing else in it can wait for it; use it after 'together!'", name)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Rejects `for every line` blocks whose lines would get in each other's way.
+fn check_every_line(statements: &[Statement], line: usize) -> Result<(), ParseError> {
+    let at = |e: String| ParseError::from(format!("{} (line {})", e, line)).at(line);
+    if statements.is_empty() {
+        return Err(at("The for every line block has no statements".into()));
+    }
+    if statements.iter().any(|statement| statement.write_to.is_some()) {
+        return Err(at("'write it to' in a for every line block would have each line write over the one before".into()));
+    }
+    Ok(())
+}
+
+/// `(70%)` after `variant A` or `variant B`, if it's there, and the rest of the line.
+fn variant_share(text: &str) -> Result<(Option<u8>, &str), String> {
+    let text = text.trim_start();
+    let Some(rest) = text.strip_prefix('(') else {
+        return Ok((None, text));
+    };
+    let (share, rest) = rest.split_once(')').ok_or("missing ')' after the variant's share")?;
+    let share = share
+        .trim()
+        .strip_suffix('%')
+        .and_then(|share| share.trim().parse::<u8>().ok())
+        .filter(|share| *share <= 100)
+        .ok_or_else(|| format!("a variant's share looks like (70%), got ({})", share))?;
+    Ok((Some(share), rest))
+}
+
+/// One arm of a variant line: `[(n%)]: "..."` after `variant A` or `variant B`.
+/// Returns its statement, its share if given, and the rest of the line.
+fn parse_variant<'a>(
+    arm: &str,
+    text: &'a str,
+    line: usize,
+    warnings: &mut Vec<Diagnostic>,
+    placeholders: &mut Placeholders,
+) -> Result<(Statement, Option<u8>, &'a str), ParseError> {
+    let error = |message: String| ParseError::from(format!("{} (line {})", message, line)).at(line);
+    let (share, rest) = variant_share(text).map_err(error)?;
+    let rest = rest
+        .trim_start()
+        .strip_prefix(':')
+        .map(str::trim_start)
+        .filter(|rest| rest.starts_with('"'))
+        .ok_or_else(|| error(format!("variant {} needs a quoted statement after ':'", arm)))?;
+    let (inner, rest) = read_quoted(rest, line, warnings)
+        .ok_or_else(|| error(format!("missing closing quote in variant {}", arm)))?;
+    if inner.trim().is_empty() {
+        warnings.push(Diagnostic::new("empty-statement", Some(line), tr!("lint.empty-statement")));
+    }
+    let segments = process_placeholders(&inner, placeholders)?;
+    Ok((Statement { segments, binding: None, model: None, temperature: None, schema: None, retries: None, write_to: None, expect: None, raw: false, shell: false }, share, rest))
+}
+
+/// The rest of a `variant A [(n%)]: "...", variant B [(m%)]: "..."` line after
+/// `variant A`: both statements and arm A's share in percent (50 unless given).
+fn parse_variants(
+    text: &str,
+    line: usize,
+    warnings: &mut Vec<Diagnostic>,
+    placeholders: &mut Placeholders,
+) -> Result<(Statement, Statement, u8), ParseError> {
+    let error = |message: String| ParseError::from(format!("{} (line {})", message, line)).at(line);
+    let (a, a_share, rest) = parse_variant("A", text, line, warnings, placeholders)?;
+    let rest = rest
+        .trim_start()
+        .strip_prefix(',')
+        .and_then(|rest| rest.trim_start().strip_prefix("variant B"))
+        .ok_or_else(|| error("expected ', variant B: \"...\"' after variant A".into()))?;
+    let (b, b_share, rest) = parse_variant("B", rest, line, warnings, placeholders)?;
+    if !rest.trim().is_empty() {
+        return Err(ParseError::from(tr!("parse.trailing", line = line, text = rest)).at(line));
+    }
+    let split = match (a_share, b_share) {
+        (None, None) => 50,
+        (Some(a), None) => a,
+        (None, Some(b)) => 100 - b,
+        (Some(a), Some(b)) if u16::from(a) + u16::from(b) == 100 => a,
+        (Some(a), Some(b)) => return Err(error(format!("variant shares must add up to 100%, got {}% and {}%", a, b))),
+    };
+    if !(1..=99).contains(&split) {
+        return Err(error("each variant needs a share between 1% and 99%".into()));
+    }
+    Ok((a, b, split))
+}
+
+/// Warns about the `€name`s of the statement on `line` that weren't bound before it.
+fn warn_unbound(placeholders: &mut Placeholders, line: usize, warnings: &mut Vec<Diagnostic>) {
+    for name in placeholders.unbound.drain(..) {
+        warnings.push(Diagnostic::new("unbound-variable", Some(line), tr!("lint.unbound-variable", name = name)));
+    }
+}
+
+fn unquote_directive(value: &str) -> &str {
+    let value = value.trim();
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        &value[1..value.len() - 1]
+    } else {
+        value
+    }
+}
+
+/// True if `line` is `word` give or take case, spaces and punctuation, like
+/// `Hihi` for `hihi!`.
+fn looks_like(line: &str, word: &str) -> bool {
+    let simplify = |s: &str| {
+        s.chars()
+            .filter(|c| c.is_alphanumeric() || *c == ' ')
+            .collect::<String>()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_lowercase()
+    };
+    simplify(line) == simplify(word)
+}
+
+/// Reads the file a `nom nom "..."` line on line `number` names and appends
+/// its statements, and returns the path as written. `rest` is the line after
+/// `nom nom`; `stack` the files being parsed, the one with the line last, which
+/// paths are relative to. A file that's on it already would include itself.
+fn include(
+    rest: &str,
+    number: usize,
+    stack: &mut Vec<PathBuf>,
+    known: Known,
+    found: &mut Placeholders,
+    statements: &mut Vec<Statement>,
+    warnings: &mut Vec<Diagnostic>,
+) -> Result<String, ParseError> {
+    let at = |e: String| ParseError::from(format!("{} (line {})", e, number)).at(number);
+    let rest = rest.trim_start();
+    let (written, rest) = Some(rest)
+        .filter(|rest| rest.starts_with('"'))
+        .and_then(|rest| read_quoted(rest, number, warnings))
+        .ok_or_else(|| at(format!("nom nom lines look like: nom nom \"other.matthiashihic\", got: nom nom {}", rest)))?;
+    if !rest.trim().is_empty() {
+        return Err(ParseError::from(tr!("parse.trailing", line = number, text = rest)).at(number));
+    }
+    let Some(from) = stack.last() else {
+        return Err(at("nom nom looks for files next to the one it's in, and this source didn't come from a file".into()));
+    };
+    let path = from.parent().unwrap_or(Path::new("")).join(&written);
+    let same = |a: &Path, b: &Path| match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    };
+    if let Some(pos) = stack.iter().position(|file| same(file, &path)) {
+        let chain: Vec<String> = stack[pos..].iter().chain([&path]).map(|file| file.display().to_string()).collect();
+        return Err(at(format!("'{}' would end up eating itself: {}", written, chain.join(" -> "))));
+    }
+    let contents = fs::read_to_string(&path).map_err(|e| at(format!("Can't nom nom {}: {}", path.display(), e)))?;
+    let including = from.display().to_string();
+    found.read.push(path.clone());
+    stack.push(path);
+    let result = parse_included(&contents, stack, known, found, statements);
+    let path = stack.pop().unwrap_or_default();
+    result.map_err(|e| {
+        let mut e = e.in_file(path.display().to_string(), contents);
+        e.message = format!("{}, nom nom'd from {}:{}", e.message, including, number);
+        e
+    })?;
+    Ok(written)
+}
+
+/// Appends the statements of a file that's included: a program with nothing
+/// but statements and `nom nom` lines. Its warnings are left for when it's
+/// checked on its own.
+fn parse_included(
+    contents: &str,
+    stack: &mut Vec<PathBuf>,
+    known: Known,
+    found: &mut Placeholders,
+    statements: &mut Vec<Statement>,
+) -> Result<(), ParseError> {
+    let contents = contents.strip_prefix('\u{feff}').unwrap_or(contents);
+    let lines: Vec<&str> = contents.lines().collect();
+    let mut idx = lines.iter().position(|line| !line.trim().is_empty()).ok_or_else(|| ParseError::from(tr!("parse.empty")))?;
+    // Any edition: an included file only has the statements every edition has
+    if Edition::requested(lines[idx]).and_then(Edition::from_number).is_none() {
+        return Err(ParseError::from(tr!("parse.header")).at(idx + 1));
+    }
+    let mut warnings = Vec::new();
+    let first = statements.len();
+    while idx + 1 < lines.len() {
+        idx += 1;
+        let line = lines[idx];
+        let t = line.trim();
+        if t.is_empty() || t.starts_with("hm,") {
+            continue;
+        }
+        if t == "eat that java!" {
+            if statements.len() == first {
+                return Err(ParseError::from(format!("There's nothing to nom in here, it has no statements (line {})", idx + 1)).at(idx + 1));
+            }
+            return Ok(());
+        }
+        if let Some(rest) = t.strip_prefix("nom nom") {
+            include(rest, idx + 1, stack, known, found, statements, &mut warnings)?;
+            continue;
+        }
+        if statements.len() >= MAX_INCLUDED_STATEMENTS {
+            let e = format!("nom nom brings in more than {} statements (line {})", MAX_INCLUDED_STATEMENTS, idx + 1);
+            return Err(ParseError::from(e).at(idx + 1));
+        }
+        if t.starts_with(TRIPLE_QUOTE) {
+            let (text, rest, end) = read_block(&lines, idx)?;
+            statements.push(finish_statement(text, rest, idx + 1, known, found, &mut warnings)?);
+            idx = end;
+            continue;
+        }
+        if !t.starts_with('"') && !starts_raw(t) && shell_command(t).is_none() {
+            let e = format!("Files that get nom nom'd hold statements and nom nom lines only, got: {} (line {})", t, idx + 1);
+            return Err(ParseError::from(e).at(idx + 1));
+        }
+        statements.push(parse_statement(line, idx + 1, 0, known, found, &mut warnings)?);
+    }
+    Err(tr!("parse.missing-terminator").into())
+}
+
+/// Parses a source file into its AST plus any warnings found along the way;
+/// lint levels are applied by the caller. Without a path to the file, it can't
+/// have `nom nom` lines.
+pub fn parse_matthiashihic(original: &str) -> Result<(Program, Vec<Diagnostic>), ParseError> {
+    parse_source(original, &mut Vec::new()).map(|(program, warnings, _)| (program, warnings))
+}
+
+/// Parses the source read from `path`, which its `nom nom` lines are relative to.
+pub fn parse_file(original: &str, path: &Path) -> Result<(Program, Vec<Diagnostic>), ParseError> {
+    parse_source(original, &mut vec![path.to_path_buf()]).map(|(program, warnings, _)| (program, warnings))
+}
+
+/// The files the `nom nom` lines of the source read from `path` pull in,
+/// nested ones too, in the order they're read.
+pub fn included_files(original: &str, path: &Path) -> Result<Vec<PathBuf>, ParseError> {
+    parse_source(original, &mut vec![path.to_path_buf()]).map(|(_, _, read)| read)
+}
+
+/// `program` as canonical source, the way `fmt` writes it, once it's checked
+/// to parse back to `program`; an error says why it doesn't. `path` is where
+/// the source will be, for its `nom nom` lines.
+pub fn format_program(program: &Program, path: Option<&Path>) -> Result<String, String> {
+    let formatted = program.to_source();
+    let reparsed = match path {
+        Some(path) => parse_file(&formatted, path),
+        None => parse_matthiashihic(&formatted),
+    };
+    match reparsed {
+        Ok((reparsed, _)) if reparsed == *program => Ok(formatted),
+        Ok((reparsed, _)) => Err(format!("the formatted source parses to a different program:\n{}\n{:?}\n{:?}", formatted, program, reparsed)),
+        Err(e) => Err(format!("the formatted source doesn't parse: {}\n{}", e, formatted)),
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn parse_source(original: &str, stack: &mut Vec<PathBuf>) -> Result<(Program, Vec<Diagnostic>, Vec<PathBuf>), ParseError> {
+    if original.len() > MAX_SOURCE_BYTES {
+        return Err(tr!("parse.too-large", size = original.len(), limit = MAX_SOURCE_BYTES).into());
+    }
+    // Editors on some platforms like to start files with a byte order mark
+    let contents = original.strip_prefix('\u{feff}').unwrap_or(original);
+    // Fixes point into the source as read, mark and all
+    let bom = original.len() - contents.len();
+    let line_start = |line: usize| bom + line_offset(contents, line);
+    // Replaces the trimmed text of a 1-based line
+    let replace_line = |line: usize, text: &str, help: &str| {
+        let start = line_start(line) + (text.len() - text.trim_start().len());
+        Fix { help: help.to_string(), start, end: start + text.trim().len(), replacement: String::new() }
+    };
+
+    // Split into lines but preserve order.
+    let lines: Vec<&str> = contents.lines().collect();
+    let mut placeholders = Placeholders::default();
+
+    // Find first non-empty line
+    let mut idx = 0usize;
+    while idx < lines.len() && lines[idx].trim().is_empty() {
+        idx += 1;
+    }
+    if idx >= lines.len() {
+        let error = ParseError::from(tr!("parse.empty"));
+        return Err(error.with_fix(Fix::insert(tr!("fix.header-empty"), bom, "hihi!\n")));
+    }
+    let Some(requested) = Edition::requested(lines[idx]) else {
+        let error = ParseError::from(tr!("parse.header")).at(idx + 1);
+        let fix = if looks_like(lines[idx], "hihi!") {
+            Fix { replacement: "hihi!".into(), ..replace_line(idx + 1, lines[idx], &tr!("fix.header-spelling")) }
+        } else {
+            Fix::insert(tr!("fix.header-missing"), line_start(idx + 1), "hihi!\n")
+        };
+        return Err(error.with_fix(fix));
+    };
+    let Some(edition) = Edition::from_number(requested) else {
+        let error = tr!("parse.edition-unknown", edition = requested, latest = Edition::LATEST.header());
+        return Err(ParseError::from(error).at(idx + 1));
+    };
+    let header = idx;
+    // Syntax from a later edition than the header's, which the fix raises
+    let needs = |since: Edition, what: &str, line: usize| -> Result<(), ParseError> {
+        if edition >= since {
+            return Ok(());
+        }
+        let error = ParseError::from(tr!("parse.edition-needed", what = what, edition = since.header(), line = line)).at(line);
+        let fix = replace_line(header + 1, lines[header], &tr!("fix.edition", edition = since.header()));
+        Err(error.with_fix(Fix { replacement: since.header(), ..fix }))
+    };
+    idx += 1;
+
+    // Optional front-matter: the first non-empty line after the header is `---`
+    let mut front_matter = FrontMatter::default();
+    let mut peek = idx;
+    while peek < lines.len() && lines[peek].trim().is_empty() {
+        peek += 1;
+    }
+    if peek < lines.len() && lines[peek].trim() == "---" {
+        let start = peek + 1;
+        let end = (start..lines.len())
+            .find(|&i| lines[i].trim() == "---")
+            .ok_or_else(|| format!("Unterminated front-matter starting at line {}; expected a closing ---", peek + 1))?;
+        front_matter = front_matter::parse(&lines[start..end], start + 1)?;
+        idx = end + 1;
+    }
+
+    let mut info = ProgramInfo::default();
+    for (key, value) in [
+        ("name", &front_matter.name),
+        ("about", &front_matter.about),
+        ("version", &front_matter.version),
+    ] {
+        if let Some(value) = value {
+            info.set(key, value)?;
+        }
+    }
+
+    let mut directives = Vec::<(String, String)>::new();
+    let mut externs = Vec::<(String, String)>::new();
+    let mut recipes = Vec::<Recipe>::new();
+    let mut recipe_arities = Vec::<(String, usize)>::new();
+    let mut translate_to: Option<String> = None;
+    let mut persona: Option<Persona> = None;
+    // The language of `speak <language>!` and its line, once it's seen
+    let mut speak: Option<(String, usize)> = None;
+    // The line of `remember!`, once it's seen
+    let mut remember: Option<usize> = None;
+    let mut statements = Vec::<Statement>::new();
+    let mut warnings = Vec::<Diagnostic>::new();
+    let mut experiment: Option<Experiment> = None;
+    let mut loops = Vec::<Loop>::new();
+    // The open `again!` loop: its first statement, count and line
+    let mut open_loop: Option<(usize, Vec<Segment>, usize)> = None;
+    let mut branches = Vec::<Branch>::new();
+    // The open `if the vibe is`: its vibe, first statement, first statement
+    // after `otherwise` (once that's seen) and line
+    let mut open_branch: Option<(String, usize, Option<usize>, usize)> = None;
+    let mut parallels = Vec::<Parallel>::new();
+    // The open `all at once!` block: its first statement and line
+    let mut open_parallel: Option<(usize, usize)> = None;
+    let mut every_line = Vec::<EveryLine>::new();
+    // The open `for every line` block: its first statement, line and how
+    // many names were bound before it
+    let mut open_every_line: Option<(usize, usize, usize)> = None;
+    let mut includes = Vec::<Include>::new();
+    let mut comments = Vec::<Comment>::new();
+    // The line of `stuff:`, once it's seen
+    let mut stuff: Option<usize> = None;
+    // Whether the last line (comments aside) was a statement, which a `better be` would be about
+    let mut after_statement = false;
+    let mut terminator_found = false;
+    while idx < lines.len() {
+        let line = lines[idx];
+        let t = line.trim();
+        if t.is_empty() {
+            idx += 1;
+            continue;
+        }
+        if t == "eat that java!" {
+            if let Some((_, line, _)) = open_every_line {
+                return Err(ParseError::from(format!("The for every line block on line {} isn't closed with 'that's all!'", line)).at(line));
+            }
+            if let Some((_, line)) = open_parallel {
+                return Err(ParseError::from(format!("The all at once! block on line {} isn't closed with 'together!'", line)).at(line));
+            }
+            if let Some((_, _, _, line)) = open_branch {
+                return Err(ParseError::from(format!("The if on line {} isn't closed with 'done!'", line)).at(line));
+            }
+            if let Some((_, _, line)) = open_loop {
+                return Err(ParseError::from(format!("The loop on line {} isn't closed with 'enough!'", line)).at(line));
+            }
+            terminator_found = true;
+            break;
+        }
+        // name:/about:/version:/translate output to: directives and externs come before the first statement
+        let preamble = statements.is_empty() && open_loop.is_none() && open_branch.is_none() && open_parallel.is_none() && open_every_line.is_none();
+        // Skipped, not removed, so the lines after it keep their numbers
+        if let Some(text) = t.strip_prefix("hm,") {
+            let before = (!preamble).then_some(statements.len());
+            comments.push(Comment { before, text: text.trim().to_string() });
+            idx += 1;
+            continue;
+        }
+        let follows_statement = std::mem::take(&mut after_statement);
+        if let Some(rest) = t.strip_prefix("better be") {
+            let at = |e: String| ParseError::from(format!("{} (line {})", e, idx + 1)).at(idx + 1);
+            if !follows_statement {
+                return Err(at("'better be' goes on the line right after the statement whose answer it expects".into()));
+            }
+            let expected = parse_expectation(rest, idx + 1, &mut warnings).map_err(at)?;
+            let index = statements.len() - 1;
+            // Whichever variant a run gets, it's checked the same
+            if let Some(experiment) = experiment.as_mut().filter(|experiment| experiment.index == index) {
+                experiment.b.expect = Some(expected.clone());
+            }
+            statements[index].expect = Some(expected);
+            idx += 1;
+            continue;
+        }
+        if preamble && t == "stuff:" {
+            if let Some(line) = stuff {
+                return Err(ParseError::from(format!("stuff: is declared more than once, first on line {} (line {})", line, idx + 1)).at(idx + 1));
+            }
+            if let Some(recipe) = recipes.last() {
+                let e = format!("stuff: goes before the recipes, and this one comes after '{}' (line {})", recipe.name, idx + 1);
+                return Err(ParseError::from(e).at(idx + 1));
+            }
+            stuff = Some(idx + 1);
+            idx += 1;
+            continue;
+        }
+        if preamble && stuff.is_some() && recipes.is_empty() {
+            let at = |e: String| ParseError::from(format!("{} (line {})", e, idx + 1)).at(idx + 1);
+            if let Some((name, value)) = parse_constant(t, idx + 1, &mut warnings).map_err(at)? {
+                if placeholders.constants.iter().any(|(declared, _)| *declared == name) {
+                    return Err(at(format!("'{}' is defined more than once in stuff:", name)));
+                }
+                if placeholders.macros.iter().any(|declared| declared.name == name) {
+                    return Err(at(format!("'{}' is a macro already", name)));
+                }
+                placeholders.constants.push((name, value));
+                idx += 1;
+                continue;
+            }
+        }
+        if let Some(rest) = t.strip_prefix("macro ") {
+            let at = |e: String| ParseError::from(format!("{} (line {})", e, idx + 1)).at(idx + 1);
+            needs(Edition::V2, "macro", idx + 1)?;
+            if !preamble {
+                return Err(at("macros go before the first statement".into()));
+            }
+            if let Some(recipe) = recipes.last() {
+                return Err(at(format!("macros go before the recipes, and this one comes after '{}'", recipe.name)));
+            }
+            let definition = parse_macro(rest, idx + 1, &mut warnings).map_err(at)?;
+            if placeholders.macros.iter().any(|declared| declared.name == definition.name) {
+                return Err(at(format!("macro {} is defined more than once", definition.name)));
+            }
+            if placeholders.constants.iter().any(|(constant, _)| *constant == definition.name) {
+                return Err(at(format!("'{}' is a constant from stuff: already", definition.name)));
+            }
+            placeholders.macros.push(definition);
+            idx += 1;
+            continue;
+        }
+        if preamble && t.starts_with("extern ") {
+            if let Some(recipe) = recipes.last() {
+                let e = format!("externs go before the recipes, and this one comes after '{}' (line {})", recipe.name, idx + 1);
+                return Err(ParseError::from(e).at(idx + 1));
+            }
+            let (name, path) = parse_extern(t).map_err(|e| ParseError::from(format!("{} (line {})", e, idx + 1)).at(idx + 1))?;
+            if externs.iter().any(|(declared, _)| *declared == name) {
+                return Err(ParseError::from(format!("extern '{}' is declared more than once (line {})", name, idx + 1)).at(idx + 1));
+            }
+            externs.push((name, path));
+            idx += 1;
+            continue;
+        }
+        if preamble {
+            if let Some((key, value)) = t.split_once(':') {
+                let key = key.trim();
+                if info.slot(key).is_some() {
+                    let value = unquote_directive(value);
+                    info.set(key, value)
+                        .map_err(|e| ParseError::from(format!("{} (line {})", e, idx + 1)).at(idx + 1))?;
+                    directives.push((key.to_string(), value.trim().to_string()));
+                    idx += 1;
+                    continue;
+                }
+                if key == "translate output to" {
+                    let value = unquote_directive(value).trim();
+                    if translate_to.is_some() {
+                        return Err(ParseError::from(format!("'{}' is declared more than once (line {})", key, idx + 1)).at(idx + 1));
+                    }
+                    if value.is_empty() {
+                        return Err(ParseError::from(format!("'{}' needs a language, e.g. 'fr' (line {})", key, idx + 1)).at(idx + 1));
+                    }
+                    translate_to = Some(value.to_string());
+                    directives.push((key.to_string(), value.to_string()));
+                    idx += 1;
+                    continue;
+                }
+            }
+        }
+        if let Some(rest) = t.strip_prefix("persona").filter(|rest| rest.starts_with([' ', '\t', '"'])) {
+            let at = |e: String| ParseError::from(format!("{} (line {})", e, idx + 1)).at(idx + 1);
+            if !preamble {
+                return Err(at("persona goes before the first statement".into()));
+            }
+            if persona.is_some() {
+                return Err(at("persona is declared more than once".into()));
+            }
+            persona = Some(parse_persona(rest, idx + 1, &mut warnings).map_err(at)?);
+            idx += 1;
+            continue;
+        }
+        if let Some(language) = t.strip_prefix("speak ").and_then(|rest| rest.strip_suffix('!')) {
+            let at = |e: String| ParseError::from(format!("{} (line {})", e, idx + 1)).at(idx + 1);
+            needs(Edition::V2, "speak", idx + 1)?;
+            if !preamble {
+                return Err(at("speak goes before the first statement".into()));
+            }
+            if let Some((_, line)) = &speak {
+                return Err(at(format!("speak is there already, on line {}", line)));
+            }
+            let language = language.trim();
+            if language.is_empty() || language.contains(['"', '€']) {
+                return Err(at(format!("speak takes a plain language name, e.g. speak german!, got: {}", t)));
+            }
+            speak = Some((language.to_string(), idx + 1));
+            idx += 1;
+            continue;
+        }
+        if t == "remember!" {
+            let at = |e: String| ParseError::from(format!("{} (line {})", e, idx + 1)).at(idx + 1);
+            if !preamble {
+                return Err(at("remember! goes before the first statement".into()));
+            }
+            if let Some(line) = remember {
+                return Err(at(format!("remember! is there already, on line {}", line)));
+            }
+            remember = Some(idx + 1);
+            idx += 1;
+            continue;
+        }
+        if let Some(name) = t.strip_prefix("recipe ").and_then(|rest| rest.strip_suffix(':')) {
+            if !preamble {
+                return Err(ParseError::from(format!("Recipes go before the first statement (line {})", idx + 1)).at(idx + 1));
+            }
+            let known = Known { externs: &externs, recipes: &recipe_arities };
+            let (recipe, arity, end) = parse_recipe(&lines, idx, name.trim(), &line_start, known, &mut placeholders, &mut warnings)?;
+            recipe_arities.push((recipe.name.clone(), arity));
+            recipes.push(recipe);
+            idx = end + 1;
+            continue;
+        }
+        if let Some(count) = t.strip_prefix("again!") {
+            let at = |e: String| ParseError::from(format!("{} (line {})", e, idx + 1)).at(idx + 1);
+            if let Some((_, _, line)) = open_loop {
+                return Err(at(format!("Loops don't nest; close the one from line {} with 'enough!' first", line)));
+            }
+            if let Some((_, _, _, line)) = open_branch {
+                return Err(at(format!("Loops can't go in an if; close the one from line {} with 'done!' first", line)));
+            }
+            if let Some((_, line)) = open_parallel {
+                return Err(at(format!("Loops can't go in an all at once! block; close the one from line {} with 'together!' first", line)));
+            }
+            if let Some((_, line, _)) = open_every_line {
+                return Err(at(format!("Loops can't go in a for every line block; close the one from line {} with 'that's all!' first", line)));
+            }
+            let count = parse_loop_count(count, &mut placeholders).map_err(at)?;
+            // Inside the loop, €loop is the round
+            placeholders.bindings.push(LOOP_COUNTER.to_string());
+            open_loop = Some((statements.len(), count, idx + 1));
+            idx += 1;
+            continue;
+        }
+        if t == "enough!" {
+            if let Some((_, line)) = open_parallel {
+                return Err(ParseError::from(format!("The all at once! block on line {} isn't closed with 'together!' (line {})", line, idx + 1)).at(idx + 1));
+            }
+            if let Some((_, _, _, line)) = open_branch {
+                return Err(ParseError::from(format!("The if on line {} isn't closed with 'done!' (line {})", line, idx + 1)).at(idx + 1));
+            }
+            let Some((first, count, line)) = open_loop.take() else {
+                return Err(ParseError::from(format!("'enough!' without an 'again! N times' before it (line {})", idx + 1)).at(idx + 1));
+            };
+            if statements.len() == first {
+                return Err(ParseError::from(format!("The loop on line {} has no statements", line)).at(line));
+            }
+            if let Some(pos) = placeholders.bindings.iter().rposition(|name| name == LOOP_COUNTER) {
+                placeholders.bindings.remove(pos);
+            }
+            loops.push(Loop { first, len: statements.len() - first, count });
+            idx += 1;
+            continue;
+        }
+        if let Some(vibe) = t.strip_prefix("if the vibe is") {
+            let at = |e: String| ParseError::from(format!("{} (line {})", e, idx + 1)).at(idx + 1);
+            if let Some((_, _, _, line)) = open_branch {
+                return Err(at(format!("ifs don't nest; close the one from line {} with 'done!' first", line)));
+            }
+            if let Some((_, line)) = open_parallel {
+                return Err(at(format!("ifs can't go in an all at once! block; close the one from line {} with 'together!' first", line)));
+            }
+            if let Some((_, line, _)) = open_every_line {
+                return Err(at(format!("ifs can't go in a for every line block; close the one from line {} with 'that's all!' first", line)));
+            }
+            if every_line.last().is_some_and(|every_line| every_line.first + every_line.len == statements.len()) {
+                return Err(at("'if the vibe is' can't look at a for every line block's answers, there's one per line".into()));
+            }
+            if statements.is_empty() {
+                return Err(at("'if the vibe is' looks at the answer of a statement before it, and there is none".into()));
+            }
+            let vibe = vibe.trim_start();
+            let (vibe, rest) = Some(vibe)
+                .filter(|vibe| vibe.starts_with('"'))
+                .and_then(|vibe| read_quoted(vibe, idx + 1, &mut warnings))
+                .ok_or_else(|| at(format!("ifs look like: if the vibe is \"yes\", got: {}", t)))?;
+            if !rest.trim().is_empty() {
+                return Err(ParseError::from(tr!("parse.trailing", line = idx + 1, text = rest)).at(idx + 1));
+            }
+            open_branch = Some((vibe, statements.len(), None, idx + 1));
+            idx += 1;
+            continue;
+        }
+        if t == "otherwise" {
+            let at = |e: String| ParseError::from(format!("{} (line {})", e, idx + 1)).at(idx + 1);
+            if let Some((_, line)) = open_parallel {
+                return Err(at(format!("The all at once! block on line {} isn't closed with 'together!'", line)));
+            }
+            match &mut open_branch {
+                None => return Err(at("'otherwise' without an 'if the vibe is' before it".into())),
+                Some((_, _, Some(_), line)) => return Err(at(format!("The if on line {} already has an 'otherwise'", line))),
+                Some((_, first, otherwise, line)) => {
+                    if statements.len() == *first {
+                        return Err(at(format!("The if on line {} has no statements before 'otherwise'", line)));
+                    }
+                    *otherwise = Some(statements.len());
+                }
+            }
+            idx += 1;
+            continue;
+        }
+        if t == "done!" {
+            if let Some((_, line)) = open_parallel {
+                return Err(ParseError::from(format!("The all at once! block on line {} isn't closed with 'together!' (line {})", line, idx + 1)).at(idx + 1));
+            }
+            let Some((vibe, first, otherwise, line)) = open_branch.take() else {
+                return Err(ParseError::from(format!("'done!' without an 'if the vibe is' before it (line {})", idx + 1)).at(idx + 1));
+            };
+            let then_end = otherwise.unwrap_or(statements.len());
+            if then_end == first {
+                return Err(ParseError::from(format!("The if on line {} has no statements", line)).at(line));
+            }
+            branches.push(Branch { first, then_len: then_end - first, else_len: statements.len() - then_end, vibe });
+            idx += 1;
+            continue;
+        }
+        if t == "all at once!" {
+            if let Some((_, line)) = open_parallel {
+                let e = format!("all at once! blocks don't nest; close the one from line {} with 'together!' first (line {})", line, idx + 1);
+                return Err(ParseError::from(e).at(idx + 1));
+            }
+            if let Some((_, line, _)) = open_every_line {
+                let e = format!("all at once! blocks can't go in a for every line block; close the one from line {} with 'that's all!' first (line {})", line, idx + 1);
+                return Err(ParseError::from(e).at(idx + 1));
+            }
+            open_parallel = Some((statements.len(), idx + 1));
+            idx += 1;
+            continue;
+        }
+        if t == "together!" {
+            let Some((first, line)) = open_parallel.take() else {
+                return Err(ParseError::from(format!("'together!' without an 'all at once!' before it (line {})", idx + 1)).at(idx + 1));
+            };
+            check_parallel(&statements[first..], line)?;
+            parallels.push(Parallel { first, len: statements.len() - first });
+            idx += 1;
+            continue;
+        }
+        if t == "for every line" {
+            let at = |e: String| ParseError::from(format!("{} (line {})", e, idx + 1)).at(idx + 1);
+            if let Some((_, _, line)) = open_loop {
+                return Err(at(format!("for every line can't go in a loop; close the one from line {} with 'enough!' first", line)));
+            }
+            if let Some((_, _, _, line)) = open_branch {
+                return Err(at(format!("for every line can't go in an if; close the one from line {} with 'done!' first", line)));
+            }
+            if let Some((_, line)) = open_parallel {
+                return Err(at(format!("for every line can't go in an all at once! block; close the one from line {} with 'together!' first", line)));
+            }
+            if let Some((_, line, _)) = open_every_line {
+                return Err(at(format!("for every line blocks don't nest; close the one from line {} with 'that's all!' first", line)));
+            }
+            open_every_line = Some((statements.len(), idx + 1, placeholders.bindings.len()));
+            // Inside the block, €line is the line it's on
+            placeholders.bindings.push(LINE.to_string());
+            idx += 1;
+            continue;
+        }
+        if t == "that's all!" {
+            let Some((first, line, bound)) = open_every_line.take() else {
+                return Err(ParseError::from(format!("'that's all!' without a 'for every line' before it (line {})", idx + 1)).at(idx + 1));
+            };
+            check_every_line(&statements[first..], line)?;
+            // What the block binds is bound once per line, so it stays in there
+            placeholders.bindings.truncate(bound);
+            every_line.push(EveryLine { first, len: statements.len() - first });
+            idx += 1;
+            continue;
+        }
+        if let Some(rest) = t.strip_prefix("nom nom") {
+            let known = Known { externs: &externs, recipes: &recipe_arities };
+            let first = statements.len();
+            let path = include(rest, idx + 1, stack, known, &mut placeholders, &mut statements, &mut warnings)?;
+            includes.push(Include { first, len: statements.len() - first, path });
+            idx += 1;
+            continue;
+        }
+        // Parse a quoted string line: must start with " and end with "
+        let trimmed = line.trim_start();
+        if let Some(rest) = trimmed.strip_prefix("variant A") {
+            if experiment.is_some() {
+                return Err(ParseError::from(format!("Only one variant A/B statement per program (line {})", idx + 1)).at(idx + 1));
+            }
+            let (a, b, split) = parse_variants(rest, idx + 1, &mut warnings, &mut placeholders)?;
+            experiment = Some(Experiment { index: statements.len(), b, split });
+            statements.push(a);
+            warn_unbound(&mut placeholders, idx + 1, &mut warnings);
+            after_statement = true;
+            idx += 1;
+            continue;
+        }
+        let known = Known { externs: &externs, recipes: &recipe_arities };
+        if trimmed.starts_with(TRIPLE_QUOTE) {
+            let (text, rest, end) = read_block(&lines, idx)?;
+            statements.push(finish_statement(text, rest, idx + 1, known, &mut placeholders, &mut warnings)?);
+            after_statement = true;
+            idx = end + 1;
+            continue;
+        }
+        if !trimmed.starts_with('\"') && !starts_raw(trimmed) && shell_command(trimmed).is_none() {
+            let error = ParseError::from(tr!("parse.not-quoted", line = idx + 1, text = line)).at(idx + 1);
+            if looks_like(line, "eat that java!") {
+                let fix = replace_line(idx + 1, line, &tr!("fix.terminator-spelling"));
+                return Err(error.with_fix(Fix { replacement: "eat that java!".into(), ..fix }));
+            }
+            return Err(error);
+        }
+        let quote_at = line_start(idx + 1) + line.trim_end().len();
+        statements.push(parse_statement(line, idx + 1, quote_at, known, &mut placeholders, &mut warnings)?);
+        after_statement = true;
+        idx += 1;
+    }
+
+    if !terminator_found {
+        let text = if contents.is_empty() || contents.ends_with('\n') { "eat that java!\n" } else { "\neat that java!\n" };
+        let fix = Fix::insert(tr!("fix.terminator-missing"), original.len(), text);
+        return Err(ParseError::from(tr!("parse.missing-terminator")).with_fix(fix));
+    }
+    // A last line ending in a bare \r would read back without it, once it's
+    // written with a newline after it
+    let epilogue = lines[idx + 1..].join("\n");
+    let epilogue = epilogue.strip_suffix('\r').map(str::to_string).unwrap_or(epilogue);
+
+    let Placeholders { args, named, mut arg_types, mut arg_defaults, mut secrets, mut env_vars, constants, macros, read, .. } = placeholders;
+    let args_vec: Vec<usize> = args.into_iter().collect();
+    arg_types.sort_by_key(|(index, _)| *index);
+    arg_defaults.sort_by_key(|(index, _)| *index);
+    secrets.sort();
+    env_vars.sort();
+
+    if statements.is_empty() {
+        warnings.push(Diagnostic::new(
+            "no-statements",
+            None,
+            tr!("lint.no-statements"),
+        ));
+    }
+    if let (Some((_, line)), Some(_)) = (&speak, &translate_to) {
+        let error = format!("speak and 'translate output to:' both pick the language of the answer; keep one (line {})", line);
+        return Err(ParseError::from(error).at(*line));
+    }
+    let max_arg = args_vec.last().copied().unwrap_or(0);
+    for missing in (1..max_arg).filter(|i| !args_vec.contains(i)) {
+        warnings.push(Diagnostic::new(
+            "placeholder-gap",
+            None,
+            tr!("lint.placeholder-gap", missing = missing, max = max_arg),
+        ));
+    }
+    let program = Program {
+        edition,
+        front_matter,
+        directives,
+        statements,
+        epilogue,
+        info,
+        required_args: args_vec,
+        named_args: named,
+        arg_types,
+        arg_defaults,
+        secrets,
+        env_vars,
+        externs,
+        recipes,
+        macros,
+        translate_to,
+        persona,
+        speak: speak.map(|(language, _)| language),
+        remember: remember.is_some(),
+        experiment,
+        loops,
+        branches,
+        parallels,
+        every_line,
+        includes,
+        comments,
+        constants,
+    };
+    Ok((program, warnings, read))
+}
@@ -0,0 +1,102 @@
+//! Where matthiashihic keeps its files, by the conventions of the platform: XDG
+//! on Linux and the BSDs, ~/Library on macOS, %APPDATA% and %LOCALAPPDATA% on
+//! Windows. Each directory can be moved with a `MATTHIASHIHIC_*_DIR` variable.
+//!
+//! The compiler copies this file into every program it builds, so both always
+//! agree on where things are. It therefore uses nothing but std.
+#![allow(dead_code)] // neither side needs all of it
+
+use std::path::PathBuf;
+
+/// Settings, and what a user has acknowledged: MATTHIASHIHIC_CONFIG_DIR, or
+/// matthiashihic in XDG_CONFIG_HOME (~/.config), ~/Library/Application Support
+/// or %APPDATA%.
+pub fn config_dir() -> Option<PathBuf> {
+    platform_dir("MATTHIASHIHIC_CONFIG_DIR", "APPDATA", "Library/Application Support", "XDG_CONFIG_HOME", ".config")
+}
+
+/// Whatever can be thrown away and fetched again: MATTHIASHIHIC_CACHE_DIR, or
+/// matthiashihic in XDG_CACHE_HOME (~/.cache), ~/Library/Caches or
+/// %LOCALAPPDATA%\matthiashihic\cache.
+pub fn cache_dir() -> Option<PathBuf> {
+    let dir = platform_dir("MATTHIASHIHIC_CACHE_DIR", "LOCALAPPDATA", "Library/Caches", "XDG_CACHE_HOME", ".cache")?;
+    if cfg!(windows) && var("MATTHIASHIHIC_CACHE_DIR").is_none() {
+        // %LOCALAPPDATA%\matthiashihic is the state directory already
+        return Some(dir.join("cache"));
+    }
+    Some(dir)
+}
+
+/// What builds up over time, like the run history: MATTHIASHIHIC_STATE_DIR, or
+/// matthiashihic in XDG_STATE_HOME (~/.local/state), ~/Library/Application
+/// Support or %LOCALAPPDATA%.
+pub fn state_dir() -> Option<PathBuf> {
+    platform_dir("MATTHIASHIHIC_STATE_DIR", "LOCALAPPDATA", "Library/Application Support", "XDG_STATE_HOME", ".local/state")
+}
+
+/// The file programs with an experiment record their runs in, for
+/// `matthiashihic stats`: MATTHIASHIHIC_HISTORY, or history.jsonl in the state
+/// directory.
+pub fn history_file() -> Option<PathBuf> {
+    var("MATTHIASHIHIC_HISTORY").or_else(|| Some(state_dir()?.join("history.jsonl")))
+}
+
+/// Jobs programs compiled with `--background` submitted, one file each, until
+/// `--poll` has their answer: jobs/ in the state directory.
+pub fn jobs_dir() -> Option<PathBuf> {
+    Some(state_dir()?.join("jobs"))
+}
+
+/// The socket `matthiashihic broker` listens on and programs compiled with
+/// `--broker` call: MATTHIASHIHIC_BROKER, or broker.sock in the state directory.
+pub fn broker_socket() -> Option<PathBuf> {
+    var("MATTHIASHIHIC_BROKER").or_else(|| Some(state_dir()?.join("broker.sock")))
+}
+
+/// Where `matthiashihic install` puts programs, in bin/, and the registry of
+/// them: MATTHIASHIHIC_HOME, or ~/.matthiashihic (%USERPROFILE% on Windows).
+pub fn install_dir() -> Option<PathBuf> {
+    if let Some(dir) = var("MATTHIASHIHIC_HOME") {
+        return Some(dir);
+    }
+    let home = if cfg!(windows) { var("USERPROFILE")? } else { var("HOME")? };
+    Some(home.join(".matthiashihic"))
+}
+
+/// Scratch space: MATTHIASHIHIC_TEMP_DIR, or the system's (TMPDIR, %TEMP%).
+pub fn temp_dir() -> PathBuf {
+    var("MATTHIASHIHIC_TEMP_DIR").unwrap_or_else(std::env::temp_dir)
+}
+
+/// A path in the temp directory that no other process, or earlier call, uses.
+pub fn temp_path(prefix: &str, suffix: &str) -> PathBuf {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    temp_dir().join(format!("{}-{}-{}{}", prefix, std::process::id(), nanos, suffix))
+}
+
+/// The override if it's set, otherwise matthiashihic in the platform's
+/// directory; macOS and the XDG fallback are relative to HOME.
+fn platform_dir(override_var: &str, windows_var: &str, macos: &str, xdg_var: &str, xdg_default: &str) -> Option<PathBuf> {
+    if let Some(dir) = var(override_var) {
+        return Some(dir);
+    }
+    let base = if cfg!(windows) {
+        var(windows_var)?
+    } else if cfg!(target_os = "macos") {
+        var("HOME")?.join(macos)
+    } else {
+        match var(xdg_var) {
+            Some(dir) => dir,
+            None => var("HOME")?.join(xdg_default),
+        }
+    };
+    Some(base.join("matthiashihic"))
+}
+
+/// An environment variable as a path, if it's set and not empty.
+fn var(name: &str) -> Option<PathBuf> {
+    std::env::var_os(name).filter(|value| !value.is_empty()).map(PathBuf::from)
+}
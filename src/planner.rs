@@ -0,0 +1,282 @@
+//! Execution planning: decides per statement whether the compiled program runs
+//! it locally (builtins, dynamically linked externs, `shell`), sends it to the cheap
+//! model, the one it says it's `using`, or the configured one.
+//!
+//! Consecutive statements with the same model and temperature are sent
+//! together, so a plain program still makes a single call. A statement bound with `-> name` is a
+//! step of its own, so its answer is all that `€name` gets, and so is the one
+//! an `if the vibe is` looks at. So is one whose answer the next statement
+//! pipes in with `€prev`, which makes a chain of them one call per statement,
+//! one that wants its answer `as json`, one that's tried again if it fails,
+//! so the others aren't, one written to a file, and one with a `better be`, which `--check` holds its
+//! answer to. Steps don't reach into or out of an
+//! `again!` loop, whose steps run once per round, or a part of a branch. Every
+//! step sees the earlier steps as conversation history, except that each
+//! statement of an `all at once!` block is a step of its own, sent while the
+//! others are, and sees only the steps before the block.
+
+use crate::ast::{self, Program, Segment, Statement};
+use crate::builtins;
+use crate::link;
+
+/// Cheap model used for trivial statements unless `--cheap-model` says otherwise.
+pub const DEFAULT_CHEAP_MODEL: &str = "gpt-4o-mini";
+
+/// Statements starting with one of these and no longer than [`CHEAP_MAX_WORDS`]
+/// just want something printed, which any model can do.
+const CHEAP_VERBS: &[&str] = &["say", "print", "echo", "show", "repeat"];
+const CHEAP_MAX_WORDS: usize = 12;
+
+/// The local target of a `shell "..."` statement, which no extern's binary
+/// name can be.
+pub const SHELL: &str = "shell:";
+
+/// Where a statement runs.
+pub enum Route {
+    /// Run by the compiled program: `builtin:<op>`, [`SHELL`] or an extern's
+    /// binary name, with the argument segments.
+    Local(String, Vec<Vec<Segment>>),
+    Cheap,
+    Model,
+    /// `using <model>`: that model instead of the configured one.
+    Using(String),
+}
+
+/// A unit of work in the compiled program.
+pub struct Step {
+    pub route: Route,
+    /// Indices into the program's statements.
+    pub statements: Vec<usize>,
+}
+
+pub struct Planner<'a> {
+    pub builtins: bool,
+    /// `None` if trivial statements should go to the configured model, too.
+    pub cheap_model: Option<&'a str>,
+    /// Models every model step goes to, and the judge picking from their answers.
+    pub ensemble: Option<(&'a [String], &'a str)>,
+}
+
+impl Planner<'_> {
+    pub fn route(&self, program: &Program, statement: &Statement) -> Route {
+        if statement.shell {
+            return Route::Local(SHELL.to_string(), shell_args(statement));
+        }
+        if let [Segment::Call { name, args }] = statement.segments.as_slice() {
+            let binary = program
+                .externs
+                .iter()
+                .find(|(declared, _)| declared == name)
+                .map(|(_, path)| link::binary_name(path))
+                .unwrap_or_else(|| name.clone());
+            return Route::Local(binary, args.clone());
+        }
+        // A builtin's answer is plain text, never json, and always the same
+        if self.builtins && statement.schema.is_none() && statement.model.is_none() && statement.temperature.is_none() {
+            if let Some((op, args)) = builtins::recognize(statement) {
+                return Route::Local(format!("builtin:{}", op), args);
+            }
+        }
+        if let Some(model) = &statement.model {
+            return Route::Using(model.clone());
+        }
+        if self.cheap_model.is_some() && is_trivial(statement) {
+            return Route::Cheap;
+        }
+        Route::Model
+    }
+
+    /// Plans the statements from `first` on.
+    pub fn plan(&self, program: &Program, first: usize) -> Vec<Step> {
+        let mut steps: Vec<Step> = Vec::new();
+        let mut bound = false;
+        for (index, statement) in program.statements.iter().enumerate().skip(first) {
+            let piped = program.statements.get(index + 1).is_some_and(Statement::uses_prev);
+            let route = self.route(program, statement);
+            let edge = program.starts_block(index);
+            let together = program.parallel_of(index).is_some();
+            let alone = bound || edge || together || piped || statement.binding.is_some() || statement.schema.is_some() || statement.retries.is_some() || statement.write_to.is_some() || statement.expect.is_some();
+            match steps.last_mut() {
+                Some(last) if !alone && same_model(&last.route, &route) && program.statements[last.statements[0]].temperature == statement.temperature => {
+                    last.statements.push(index)
+                }
+                _ => steps.push(Step { route, statements: vec![index] }),
+            }
+            bound = statement.binding.is_some() || piped || statement.schema.is_some() || statement.retries.is_some() || statement.write_to.is_some() || statement.expect.is_some();
+        }
+        steps
+    }
+
+    /// Where a step on `route` runs, e.g. `model (gpt-4)` or `local (sh)`.
+    pub fn target(&self, route: &Route, model: &str) -> String {
+        match route {
+            Route::Local(target, _) if target == SHELL => "local (sh)".to_string(),
+            Route::Local(target, _) => match target.strip_prefix("builtin:") {
+                Some(op) => format!("local ({})", op),
+                None => format!("local (./{})", target),
+            },
+            Route::Cheap => format!("cheap ({})", self.cheap_model.unwrap_or(model)),
+            Route::Using(model) => format!("model ({})", model),
+            Route::Model => match self.ensemble {
+                Some((models, judge)) => format!("ensemble ({}; judge {})", models.join(", "), judge),
+                None => format!("model ({})", model),
+            },
+        }
+    }
+
+    /// Human-readable plan for `--explain-plan`, one line per statement.
+    pub fn explain(&self, program: &Program, model: &str, precomputed: usize) -> String {
+        let mut out = String::new();
+        for index in 0..precomputed {
+            out.push_str(&format!("{:>4}  {:<24}  {}\n", index + 1, "precomputed", program.statements[index].to_line()));
+        }
+        for step in self.plan(program, precomputed) {
+            let target = self.target(&step.route, model);
+            for &index in &step.statements {
+                for (kind, line) in blocks_opening(program, index) {
+                    out.push_str(&format!("{:>4}  {:<24}  {}\n", "", kind, line));
+                }
+                out.push_str(&format!("{:>4}  {:<24}  {}\n", index + 1, target, program.statements[index].to_line()));
+                for (kind, line) in blocks_closing(program, index) {
+                    out.push_str(&format!("{:>4}  {:<24}  {}\n", "", kind, line));
+                }
+            }
+        }
+        let calls_per_step = |route: &Route| match (route, self.ensemble) {
+            (Route::Local(..), _) => 0,
+            (Route::Model, Some((models, _))) => models.len() + 1,
+            _ => 1,
+        };
+        // Branches count the side that calls more, and loops that only know
+        // their count at runtime are counted per round
+        let steps = self.plan(program, precomputed);
+        let side_calls = |range: std::ops::Range<usize>| -> usize {
+            steps.iter().filter(|step| range.contains(&step.statements[0])).map(|step| calls_per_step(&step.route)).sum()
+        };
+        let skipped: Vec<std::ops::Range<usize>> = program
+            .branches
+            .iter()
+            .map(|branch| {
+                let otherwise = branch.first + branch.then_len;
+                if side_calls(branch.first..otherwise) >= side_calls(otherwise..branch.end()) {
+                    otherwise..branch.end()
+                } else {
+                    branch.first..otherwise
+                }
+            })
+            .collect();
+        let mut calls = 0;
+        let mut per_round: Vec<(String, usize)> = Vec::new();
+        let mut per_line = 0;
+        for step in &steps {
+            if skipped.iter().any(|range| range.contains(&step.statements[0])) {
+                continue;
+            }
+            let step_calls = calls_per_step(&step.route);
+            if program.every_line_of(step.statements[0]).is_some() {
+                per_line += step_calls;
+                continue;
+            }
+            let Some(looped) = program.loop_of(step.statements[0]) else {
+                calls += step_calls;
+                continue;
+            };
+            match (looped.rounds(), per_round.iter_mut().find(|(header, _)| *header == looped.header())) {
+                (Some(rounds), _) => calls += rounds * step_calls,
+                (None, Some((_, round_calls))) => *round_calls += step_calls,
+                (None, None) => per_round.push((looped.header(), step_calls)),
+            }
+        }
+        if let Some(language) = &program.translate_to {
+            let target = format!("translate ({})", self.cheap_model.unwrap_or(model));
+            out.push_str(&format!("{:>4}  {:<24}  the answer, to {}\n", "", target, language));
+            calls += 1;
+        }
+        let up_to = if program.branches.is_empty() { "" } else { "up to " };
+        out.push_str(&format!("{}{} model call(s) per run", up_to, calls));
+        for (header, round_calls) in per_round {
+            out.push_str(&format!(", plus {} per round of '{}'", round_calls, header));
+        }
+        if !program.every_line.is_empty() {
+            out.push_str(&format!(", plus {} per line of stdin", per_line));
+        }
+        out.push('\n');
+        out
+    }
+}
+
+/// The blocks that open right before the statement at `index`, outermost
+/// first, as (kind, the line that opens it) for a plan's listing.
+pub fn blocks_opening(program: &Program, index: usize) -> Vec<(&'static str, String)> {
+    let mut lines = Vec::new();
+    if program.every_line_of(index).is_some_and(|every_line| every_line.first == index) {
+        lines.push(("lines", "for every line".to_string()));
+    }
+    if let Some(looped) = program.loop_of(index).filter(|looped| looped.first == index) {
+        lines.push(("loop", looped.header()));
+    }
+    let branch = program.branch_of(index);
+    if let Some(branch) = branch.filter(|branch| branch.first == index) {
+        lines.push(("branch", branch.header()));
+    }
+    if branch.is_some_and(|branch| branch.else_len > 0 && branch.first + branch.then_len == index) {
+        lines.push(("branch", "otherwise".to_string()));
+    }
+    if program.parallel_of(index).is_some_and(|parallel| parallel.first == index) {
+        lines.push(("parallel", "all at once!".to_string()));
+    }
+    lines
+}
+
+/// The blocks that close right after the statement at `index`, innermost
+/// first, like [`blocks_opening`].
+pub fn blocks_closing(program: &Program, index: usize) -> Vec<(&'static str, String)> {
+    let mut lines = Vec::new();
+    if program.parallel_of(index).is_some_and(|parallel| parallel.first + parallel.len == index + 1) {
+        lines.push(("parallel", "together!".to_string()));
+    }
+    if program.branch_of(index).is_some_and(|branch| branch.end() == index + 1) {
+        lines.push(("branch", "done!".to_string()));
+    }
+    if program.loop_of(index).is_some_and(|looped| looped.first + looped.len == index + 1) {
+        lines.push(("loop", "enough!".to_string()));
+    }
+    if program.every_line_of(index).is_some_and(|every_line| every_line.first + every_line.len == index + 1) {
+        lines.push(("lines", "that's all!".to_string()));
+    }
+    lines
+}
+
+/// The arguments of a `shell "..."` statement: the command, with `"${n}"`
+/// where each placeholder was, and then the placeholders. They're handed to
+/// `sh` as arguments of their own, so no value is ever read as shell syntax.
+fn shell_args(statement: &Statement) -> Vec<Vec<Segment>> {
+    let mut command = String::new();
+    let mut args = Vec::new();
+    for segment in &statement.segments {
+        match segment {
+            Segment::Text(text) | Segment::Const { value: text, .. } => command.push_str(text),
+            segment => {
+                args.push(vec![segment.clone()]);
+                command.push_str(&format!("\"${{{}}}\"", args.len()));
+            }
+        }
+    }
+    std::iter::once(vec![Segment::Text(command)]).chain(args).collect()
+}
+
+/// Whether statements on these routes can go to the model in one call.
+fn same_model(a: &Route, b: &Route) -> bool {
+    match (a, b) {
+        (Route::Cheap, Route::Cheap) | (Route::Model, Route::Model) => true,
+        (Route::Using(a), Route::Using(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn is_trivial(statement: &Statement) -> bool {
+    let text = ast::segments_pseudocode(&statement.segments);
+    let mut words = text.split_whitespace();
+    let verb = words.next().unwrap_or("").to_lowercase();
+    CHEAP_VERBS.contains(&verb.as_str()) && words.count() < CHEAP_MAX_WORDS
+}
@@ -0,0 +1,157 @@
+//! `--precompute`: the leading statements without placeholders are answered
+//! once at compile time, and the compiled program replays that answer instead
+//! of paying for it on every run.
+//!
+//! Only a leading run of static statements is precomputed. A static statement
+//! after a dynamic one may depend on what the dynamic one produced, so it stays
+//! in the prompt. So does one bound with `-> name` or piped into the next with
+//! `€prev`, whose answer the program needs on its own, one that wants its answer
+//! `as json`, which the program checks, and one in an `again!` loop, which is
+//! asked every round.
+
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::ast::Program;
+use crate::planner::Route;
+use crate::{CompileOptions, StdinFormat, DEFAULT_OUTPUT_BUFFER};
+
+/// Statements answered at compile time and the model's answer to them.
+pub struct Precomputed {
+    /// How many leading statements the answer covers.
+    pub statements: usize,
+    pub answer: String,
+}
+
+/// Number of leading statements that use no placeholders or secrets, aren't
+/// bound, piped, json, written to a file or checked, looped, run per line or looked at by an if and would be sent to a model.
+pub fn static_prefix(program: &Program, opts: &CompileOptions) -> usize {
+    let planner = opts.planner();
+    let looped = program.loops.first().map_or(program.statements.len(), |looped| looped.first);
+    let looped = program.branches.first().map_or(looped, |branch| looped.min(branch.first - 1));
+    let looped = program.parallels.first().map_or(looped, |parallel| looped.min(parallel.first));
+    let looped = program.every_line.first().map_or(looped, |every_line| looped.min(every_line.first));
+    program
+        .statements
+        .iter()
+        .take(looped)
+        .enumerate()
+        .take_while(|(index, s)| {
+            s.is_static()
+                && s.binding.is_none()
+                && s.schema.is_none()
+                && s.expect.is_none()
+                && s.write_to.is_none()
+                && !program.statements.get(index + 1).is_some_and(|next| next.uses_prev())
+                && !matches!(planner.route(program, s), Route::Local(..))
+        })
+        .count()
+}
+
+/// Builds and runs a throwaway executable for the static prefix in `project_dir`
+/// and captures what it prints. Returns `None` if there is nothing to precompute.
+pub fn run(opts: &CompileOptions, program: &Program, project_dir: &Path) -> Result<Option<Precomputed>, String> {
+    let statements = static_prefix(program, opts);
+    if statements == 0 {
+        return Ok(None);
+    }
+    if opts.api_key.is_none() && std::env::var("OPENAI_API_KEY").is_err() {
+        return Err("Answering statements while compiling needs an API key; pass --api-key or set OPENAI_API_KEY".into());
+    }
+
+    let helper_opts = CompileOptions {
+        source_name: opts.source_name.clone(),
+        api_key: opts.api_key.clone(),
+        model: opts.model.clone(),
+        temperature: opts.temperature,
+        max_tokens: opts.max_tokens,
+        sinks: Vec::new(),
+        sink_template: None,
+        sink_only: false,
+        stdin_format: StdinFormat::Lines,
+        column_map: Vec::new(),
+        skip_header: false,
+        precomputed: None,
+        builtins: opts.builtins,
+        cheap_model: opts.cheap_model.clone(),
+        audio_input: false,
+        image_output: None,
+        tui: false,
+        notify: false,
+        // Precomputed answers come from the ensemble, too
+        ensemble: opts.ensemble.clone(),
+        serve: false,
+        serve_keys: Vec::new(),
+        serve_auth: None,
+        stream_filter: false,
+        listen_socket: None,
+        output_buffer: DEFAULT_OUTPUT_BUFFER,
+        resume: opts.resume,
+        repair: opts.repair,
+        session_tokens: None,
+        // Precomputed answers get the same treatment as the rest
+        hooks: opts.hooks.clone(),
+        glossary: opts.glossary.clone(),
+        // The program filters the precomputed answer like any other
+        filters: Vec::new(),
+        banner: None,
+        fallback: Vec::new(),
+        fail_on_refusal: false,
+        cache: false,
+        cache_similarity: None,
+        cache_backend: None,
+        cache_ttl: None,
+        // Compile-time calls take their turn like the program's
+        broker: opts.broker,
+        // The helper never ships; the program it answers for is checked
+        profile: None,
+        key_expires: None,
+        watermark: None,
+        background: false,
+        // Compile-time calls go to the same provider
+        request_template: opts.request_template.clone(),
+        types: false,
+        csv_columns: None,
+        csv_header: true,
+    };
+    let helper = Program {
+        statements: program.statements[..statements].to_vec(),
+        required_args: Vec::new(),
+        named_args: Vec::new(),
+        arg_types: Vec::new(),
+        arg_defaults: Vec::new(),
+        secrets: Vec::new(),
+        env_vars: Vec::new(),
+        // The answer goes back into the conversation, untranslated
+        translate_to: None,
+        loops: Vec::new(),
+        branches: Vec::new(),
+        parallels: Vec::new(),
+        every_line: Vec::new(),
+        includes: Vec::new(),
+        comments: Vec::new(),
+        ..program.clone()
+    };
+
+    eprintln!("Precomputing {} static statement(s) ...", statements);
+    let source = crate::generate_executable_source(&helper_opts, &helper);
+    crate::create_cargo_project(project_dir, &source, &crate::generate_cargo_toml(&helper, crate::RUNTIME_DEPENDENCIES))
+        .map_err(|e| format!("Failed to create temporary Cargo project: {}", e))?;
+    let binary = crate::cargo_build(project_dir, &[])?;
+
+    let output = Command::new(&binary)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Failed to run the precompute step: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("Precomputing the static statements failed ({})", output.status));
+    }
+    let mut answer = String::from_utf8(output.stdout)
+        .map_err(|_| "The precomputed answer is not valid UTF-8".to_string())?;
+    // The runtime ends its answer with a newline of its own
+    if answer.ends_with('\n') {
+        answer.pop();
+    }
+    Ok(Some(Precomputed { statements, answer }))
+}
@@ -0,0 +1,116 @@
+//! `--profile <name>`: a set of restrictions a program is checked against when
+//! it's compiled, and stamped into its metadata so `matthiashihic inspect` can
+//! tell a reviewer what the binary can't do without running it.
+//!
+//! `locked-down` is the only profile so far: text in on stdin, the model, text
+//! out on stdout, and nothing else.
+
+use crate::ast::{Program, Segment};
+use crate::CompileOptions;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Profile {
+    LockedDown,
+}
+
+impl Profile {
+    pub fn parse(s: &str) -> Result<Profile, String> {
+        match s {
+            "locked-down" => Ok(Profile::LockedDown),
+            other => Err(format!("Unknown profile '{}' (supported: locked-down)", other)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Profile::LockedDown => "locked-down",
+        }
+    }
+
+    /// What the profile rules out, as stamped into the metadata:
+    /// - no-shell: no commands run, like git for `€gitdiff`, hooks or notifiers
+    /// - no-network: no connections but the model's
+    /// - no-env: no environment variables in prompts
+    /// - no-files: nothing read from or written to disk
+    /// - no-tools: no extern programs run
+    pub fn restrictions(self) -> &'static [&'static str] {
+        match self {
+            Profile::LockedDown => &["no-shell", "no-network", "no-env", "no-files", "no-tools"],
+        }
+    }
+
+    /// The restrictions as the JSON array in the metadata.
+    pub fn restrictions_json(self) -> String {
+        let restrictions = self.restrictions().iter().map(|restriction| crate::json_string(restriction)).collect::<Vec<_>>();
+        format!("[{}]", restrictions.join(","))
+    }
+}
+
+/// Everything in `program`, after linking, and `opts` that the profile rules
+/// out, as (restriction, what breaks it).
+pub fn violations(profile: Profile, program: &Program, opts: &CompileOptions) -> Vec<(&'static str, String)> {
+    // The only profile there is, so far
+    let Profile::LockedDown = profile;
+    let mut found = Vec::new();
+    let mut add = |restriction: &'static str, what: String| {
+        if !found.iter().any(|(_, seen)| *seen == what) {
+            found.push((restriction, what));
+        }
+    };
+    let segments = program
+        .all_statements()
+        .flat_map(|statement| statement.segments.iter())
+        .chain(program.loops.iter().flat_map(|looped| looped.count.iter()));
+    for segment in segments {
+        match segment {
+            Segment::GitDiff => add("no-shell", "€gitdiff runs git".to_string()),
+            Segment::GitLog(_) => add("no-shell", "€gitlog runs git".to_string()),
+            Segment::Secret(name) => add("no-env", format!("€secret({}) reads the environment", name)),
+            Segment::Env(name) => add("no-env", format!("€env.{} reads the environment", name)),
+            Segment::File { .. } => add("no-files", "€file reads files".to_string()),
+            Segment::Dir { .. } => add("no-files", "€dir reads files".to_string()),
+            Segment::Call { name, .. } => add("no-tools", format!("{}(...) runs an extern program (--link dynamic)", name)),
+            _ => {}
+        }
+    }
+    if !opts.hooks.is_empty() {
+        add("no-shell", "--hook runs commands".to_string());
+    }
+    if opts.serve_auth.is_some() {
+        add("no-shell", "--serve-auth runs a command".to_string());
+    }
+    if opts.notify {
+        add("no-shell", "--notify runs the desktop's notifier".to_string());
+    }
+    if !opts.sinks.is_empty() {
+        add("no-network", "--sink sends the answer elsewhere".to_string());
+    }
+    if opts.cache_backend.is_some() {
+        add("no-network", "--cache-backend shares answers over the network".to_string());
+    }
+    if opts.serve {
+        add("no-network", "--serve listens for requests".to_string());
+    }
+    if opts.listen_socket.is_some() {
+        add("no-network", "--listen-socket listens for requests".to_string());
+    }
+    if opts.broker {
+        add("no-network", "--broker talks to the broker".to_string());
+    }
+    if opts.audio_input {
+        add("no-files", "--input audio reads an audio file, or records one".to_string());
+    }
+    if opts.image_output.is_some() {
+        add("no-files", "--output image writes a file".to_string());
+    }
+    if opts.cache {
+        add("no-files", "--cache keeps answers on disk".to_string());
+    }
+    if opts.banner.is_some() {
+        add("no-files", "--banner remembers on disk that it was shown".to_string());
+    }
+    if program.experiment.is_some() {
+        add("no-files", "a variant A/B experiment records its runs on disk".to_string());
+    }
+    found
+}
@@ -0,0 +1,58 @@
+//! `--request-template <file>`: the JSON body of every chat completions
+//! request, written by hand for providers with knobs the runtime doesn't know.
+//! A string that's just a variable, like `"€messages"`, becomes what the
+//! program would have sent under that key; an option the program doesn't set
+//! takes its key with it.
+
+use crate::mock_server::Json;
+
+/// What a template may say, each named after the key of the body the program
+/// would send otherwise.
+pub const VARIABLES: &[&str] = &["model", "messages", "temperature", "max_tokens", "response_format"];
+
+/// Why `template` isn't one the runtime can fill in.
+pub fn check(template: &str) -> Result<(), String> {
+    let json = Json::parse(template).map_err(|e| format!("isn't JSON: {}", e))?;
+    let Json::Object(entries) = &json else {
+        return Err("has to be a JSON object".to_string());
+    };
+    if let Some((key, _)) = entries.iter().find(|(key, _)| key == "stream" || key == "stream_options") {
+        return Err(format!("sets \"{}\", which the program does itself; it always streams", key));
+    }
+    let mut used = Vec::new();
+    variables(&json, &mut used)?;
+    if !used.contains(&"messages") {
+        return Err("never says \"€messages\", so the prompt would go nowhere".to_string());
+    }
+    Ok(())
+}
+
+/// Collects the variables `value` uses, failing on a `€name` that isn't one.
+fn variables(value: &Json, used: &mut Vec<&'static str>) -> Result<(), String> {
+    match value {
+        Json::String(text) => {
+            let Some(name) = text.strip_prefix('€').filter(|name| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')) else {
+                return Ok(());
+            };
+            match VARIABLES.iter().find(|variable| **variable == name) {
+                Some(variable) => used.push(variable),
+                None => {
+                    let known = VARIABLES.iter().map(|variable| format!("€{}", variable)).collect::<Vec<_>>();
+                    return Err(format!("says \"{}\", which isn't one of {}", text, known.join(", ")));
+                }
+            }
+        }
+        Json::Array(items) => {
+            for item in items {
+                variables(item, used)?;
+            }
+        }
+        Json::Object(entries) => {
+            for (_, item) in entries {
+                variables(item, used)?;
+            }
+        }
+        Json::Null | Json::Bool(_) | Json::Number => {}
+    }
+    Ok(())
+}
@@ -0,0 +1,463 @@
+//! `matthiashihic route --old <program> --new <program> --split 10%` puts a
+//! small proxy in front of two `--serve`d versions of a program and sends a
+//! share of the chat completions to the new one, so a reworded prompt meets
+//! real traffic a little at a time. Both versions run as child processes on
+//! ports of their own. Every request is recorded in the history with its arm,
+//! `old` or `new`, for `matthiashihic stats`, and the error rates and
+//! latencies are compared on stderr as they come in.
+//!
+//! The versions are started with `--hihic-routed`, so they exit when the
+//! proxy does, however it's stopped; there's no signal to catch without libc.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::i18n::tr;
+use crate::json_string;
+use crate::load::parse_duration;
+use crate::mock_server::Json;
+use crate::paths;
+
+/// Where `--serve`d programs listen, too.
+const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+/// How often the comparison is printed unless `--report` says otherwise.
+const DEFAULT_REPORT: Duration = Duration::from_secs(60);
+/// How long a version has to start listening.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Requests bigger than this are refused; nobody's prompt is 16 MiB.
+const MAX_BODY: usize = 16 * 1024 * 1024;
+/// How long a client may go quiet while it sends its request, before its
+/// connection is dropped instead of holding a thread.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Headers that describe the connection to the proxy rather than the request.
+const HOP_HEADERS: &[&str] = &["host", "connection", "keep-alive", "content-length", "transfer-encoding", "proxy-connection"];
+/// Every flag takes a value.
+const FLAGS: &[&str] = &["--old", "--new", "--split", "--listen", "--report"];
+
+/// One of the two versions.
+struct Version {
+    /// `old` or `new`, the arm it's recorded as.
+    arm: &'static str,
+    name: String,
+    version: String,
+    port: u16,
+    child: Mutex<Child>,
+    /// Requests since the last report: (latency in ms, whether it was ok).
+    recent: Mutex<Vec<(u64, bool)>>,
+}
+
+impl Version {
+    /// `name version`, or `old name` when there's no `version:` to tell them apart.
+    fn label(&self) -> String {
+        match self.version.is_empty() {
+            true => format!("{} {}", self.arm, self.name),
+            false => format!("{} {}", self.name, self.version),
+        }
+    }
+}
+
+struct Router {
+    old: Version,
+    new: Version,
+    /// The share of chat completions the new version gets, 0 to 1.
+    split: f64,
+    /// xorshift state for picking a version.
+    random: AtomicU64,
+    history: Option<PathBuf>,
+}
+
+impl Router {
+    /// The version a chat completion goes to.
+    fn pick(&self) -> &Version {
+        let step = |mut x: u64| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x
+        };
+        let x = step(self.random.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| Some(step(x))).unwrap_or(1));
+        let roll = (x >> 11) as f64 / (1u64 << 53) as f64;
+        if roll < self.split {
+            &self.new
+        } else {
+            &self.old
+        }
+    }
+}
+
+/// Runs the subcommand and returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let mut old = None;
+    let mut new = None;
+    let mut split = None;
+    let mut listen = DEFAULT_LISTEN.to_string();
+    let mut report = DEFAULT_REPORT;
+    let mut i = 0;
+    while i < args.len() {
+        let flag = args[i].as_str();
+        if flag == "-h" || flag == "--help" {
+            println!("{}", tr!("route.usage"));
+            return 0;
+        }
+        let Some(value) = args.get(i + 1).map(|s| s.as_str()) else {
+            if FLAGS.contains(&flag) {
+                eprintln!("{} requires a value", flag);
+            } else if flag.starts_with('-') {
+                eprintln!("{}", tr!("route.unknown-flag", flag = flag));
+            } else {
+                eprintln!("{}", tr!("route.usage"));
+            }
+            return 2;
+        };
+        let parsed = match flag {
+            "--old" => Some(value).map(|v| old = Some(v.to_string())),
+            "--new" => Some(value).map(|v| new = Some(v.to_string())),
+            "--split" => parse_split(value).map(|s| split = Some(s)),
+            "--listen" => Some(value).map(|v| listen = v.to_string()),
+            "--report" => parse_duration(value).map(|d| report = d),
+            s if s.starts_with('-') => {
+                eprintln!("{}", tr!("route.unknown-flag", flag = s));
+                return 2;
+            }
+            _ => {
+                eprintln!("{}", tr!("route.usage"));
+                return 2;
+            }
+        };
+        if parsed.is_none() {
+            eprintln!("Invalid value for {}: '{}'", flag, value);
+            return 2;
+        }
+        i += 2;
+    }
+    let (Some(old), Some(new), Some(split)) = (old, new, split) else {
+        eprintln!("{}", tr!("route.usage"));
+        return 2;
+    };
+
+    let listener = match TcpListener::bind(&listen) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Cannot listen on {}: {}", listen, e);
+            return 1;
+        }
+    };
+    let old = match start("old", &old) {
+        Ok(version) => version,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let new = match start("new", &new) {
+        Ok(version) => version,
+        Err(e) => {
+            eprintln!("{}", e);
+            stop(&old);
+            return 1;
+        }
+    };
+    if old.name != new.name {
+        eprintln!("Warning: the old version is {} and the new one {}; stats keeps them apart", old.name, new.name);
+    }
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(1);
+    let router = Arc::new(Router {
+        old,
+        new,
+        split,
+        // xorshift never leaves zero
+        random: AtomicU64::new(seed.max(1)),
+        history: paths::history_file(),
+    });
+
+    let addr = listener.local_addr().map(|a| a.to_string()).unwrap_or(listen);
+    eprintln!(
+        "Routing http://{}/v1 to {} ({:.0}%) and {} ({:.0}%)",
+        addr,
+        router.old.label(),
+        (1.0 - split) * 100.0,
+        router.new.label(),
+        split * 100.0
+    );
+    let reporter = Arc::clone(&router);
+    thread::spawn(move || loop {
+        thread::sleep(report);
+        compare(&reporter);
+    });
+    for stream in listener.incoming().flatten() {
+        let router = Arc::clone(&router);
+        thread::spawn(move || handle(stream, &router));
+    }
+    stop(&router.old);
+    stop(&router.new);
+    0
+}
+
+/// `10%`, or a fraction like `0.1`.
+fn parse_split(text: &str) -> Option<f64> {
+    let split = match text.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>().ok()? / 100.0,
+        None => text.parse().ok()?,
+    };
+    (0.0..=1.0).contains(&split).then_some(split)
+}
+
+/// Starts a version on a free port and waits until it listens.
+fn start(arm: &'static str, program: &str) -> Result<Version, String> {
+    // `v2` is the file here, not a command somewhere on the PATH
+    let local = format!("./{}", program);
+    let program = if !program.contains(std::path::is_separator) && std::path::Path::new(program).is_file() { &local } else { program };
+    let (name, version) = served_program(program)?;
+    let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .map_err(|e| format!("No free port for {}: {}", program, e))?;
+    let mut child = Command::new(program)
+        .args(["--listen", &format!("127.0.0.1:{}", port), "--hihic-routed"])
+        .stdin(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Failed to start {}: {}", program, e))?;
+    let started = Instant::now();
+    while TcpStream::connect((Ipv4Addr::LOCALHOST, port)).is_err() {
+        if let Ok(Some(status)) = child.try_wait() {
+            return Err(format!("{} exited before it listened ({})", program, status));
+        }
+        if started.elapsed() > STARTUP_TIMEOUT {
+            let _ = child.kill();
+            return Err(format!("{} didn't listen within {}s", program, STARTUP_TIMEOUT.as_secs()));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(Version { arm, name, version, port, child: Mutex::new(child), recent: Mutex::new(Vec::new()) })
+}
+
+/// The name and version of a program compiled with `--serve`, from its
+/// `--hihic-info`.
+fn served_program(program: &str) -> Result<(String, String), String> {
+    let output = Command::new(program)
+        .arg("--hihic-info")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    let info = Json::parse(text.trim()).map_err(|_| format!("{} isn't a program compiled by matthiashihic", program))?;
+    if info.get("interface").and_then(Json::as_str) != Some("serve") {
+        return Err(format!("{} wasn't compiled with --serve, so there's nothing to route to", program));
+    }
+    let field = |key: &str| info.get(key).and_then(Json::as_str).map(str::to_string);
+    // Named like the compiler names it, after the source file without a name:
+    let source = field("source").unwrap_or_default();
+    let stem = std::path::Path::new(&source).file_stem().map(|stem| stem.to_string_lossy().into_owned());
+    let name = field("name").or(stem).unwrap_or_else(|| program.to_string());
+    Ok((name, field("version").unwrap_or_default()))
+}
+
+/// Stops a version and waits for it to be gone.
+fn stop(version: &Version) {
+    if let Ok(mut child) = version.child.lock() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// Passes one request on: chat completions to whichever version `pick`s,
+/// everything else to the old one.
+fn handle(mut client: TcpStream, router: &Router) {
+    let start = Instant::now();
+    if client.set_read_timeout(Some(READ_TIMEOUT)).is_err() {
+        return;
+    }
+    let (method, path, headers, body) = match read_request(&client) {
+        Ok(request) => request,
+        Err(Some((status, message))) => {
+            respond_error(&mut client, status, message, "invalid_request_error");
+            return;
+        }
+        Err(None) => return,
+    };
+    let chat = method == "POST" && path.split('?').next().unwrap_or("").ends_with("/chat/completions");
+    let version = if chat { router.pick() } else { &router.old };
+    let status = match forward(&mut client, version, &method, &path, &headers, &body) {
+        Ok(status) => status,
+        Err(e) => {
+            respond_error(&mut client, "502 Bad Gateway", &e, "server_error");
+            502
+        }
+    };
+    if !chat {
+        return;
+    }
+    // The client's mistakes aren't the version's
+    let ok = status < 500;
+    let duration = start.elapsed().as_millis() as u64;
+    if let Ok(mut recent) = version.recent.lock() {
+        recent.push((duration, ok));
+    }
+    record(router, version, ok, duration);
+}
+
+/// An error the proxy answers itself, in the shape the API's errors have.
+fn respond_error(client: &mut TcpStream, status: &str, message: &str, kind: &str) {
+    let body = format!("{{\"error\":{{\"message\":{},\"type\":\"{}\"}}}}", json_string(message), kind);
+    let _ = write!(
+        client,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+}
+
+/// A request the proxy turns down: the status line and why, or `None` for
+/// one that isn't worth an answer (the client hung up, or went quiet).
+type Refusal = Option<(&'static str, &'static str)>;
+
+/// Method, path, the headers worth passing on, and body of a request.
+fn read_request(stream: &TcpStream) -> Result<(String, String, Vec<String>, Vec<u8>), Refusal> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).map_err(|_| None)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(None);
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+    let mut headers = Vec::new();
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).map_err(|_| None)? == 0 {
+            return Err(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        if name.eq_ignore_ascii_case("content-length") {
+            length = value.trim().parse().map_err(|_| Some(("400 Bad Request", "Content-Length isn't a number")))?;
+        }
+        // Passing a chunked body on would mean reading it chunk by chunk first
+        if name.trim().eq_ignore_ascii_case("transfer-encoding") && !value.trim().eq_ignore_ascii_case("identity") {
+            return Err(Some(("411 Length Required", "Send the body with a Content-Length, not chunked")));
+        }
+        if !HOP_HEADERS.iter().any(|hop| name.trim().eq_ignore_ascii_case(hop)) {
+            headers.push(header.to_string());
+        }
+    }
+    if length > MAX_BODY {
+        return Err(Some(("413 Payload Too Large", "The request is bigger than 16 MiB")));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(|_| None)?;
+    Ok((method, path, headers, body))
+}
+
+/// Sends the request to `version` and copies its answer back as it comes,
+/// streamed ones too. Returns the status it answered with.
+fn forward(client: &mut TcpStream, version: &Version, method: &str, path: &str, headers: &[String], body: &[u8]) -> Result<u16, String> {
+    let mut upstream = TcpStream::connect((Ipv4Addr::LOCALHOST, version.port))
+        .map_err(|e| format!("The {} version isn't answering: {}", version.arm, e))?;
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: 127.0.0.1:{}\r\n", method, path, version.port);
+    for header in headers {
+        head.push_str(header);
+        head.push_str("\r\n");
+    }
+    head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", body.len()));
+    upstream
+        .write_all(head.as_bytes())
+        .and_then(|_| upstream.write_all(body))
+        .map_err(|e| format!("The {} version hung up: {}", version.arm, e))?;
+    let mut status = None;
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = match upstream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if status.is_none() => return Err(format!("The {} version hung up: {}", version.arm, e)),
+            Err(_) => break,
+        };
+        // "HTTP/1.1 200 OK" fits in the first read
+        if status.is_none() {
+            let line = String::from_utf8_lossy(&buf[..n]);
+            status = Some(line.split_whitespace().nth(1).and_then(|code| code.parse().ok()).unwrap_or(502));
+        }
+        // The client left; the version notices when this connection closes
+        if client.write_all(&buf[..n]).is_err() {
+            break;
+        }
+    }
+    status.ok_or_else(|| format!("The {} version closed the connection without an answer", version.arm))
+}
+
+/// Appends the request to the history, as a run of the version's arm.
+fn record(router: &Router, version: &Version, ok: bool, duration: u64) {
+    let Some(path) = &router.history else {
+        return;
+    };
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let line = format!(
+        "{{\"time\":{},\"program\":{},\"version\":{},\"arm\":\"{}\",\"ok\":{},\"duration_ms\":{}}}\n",
+        time,
+        json_string(&version.name),
+        json_string(&version.version),
+        version.arm,
+        ok,
+        duration
+    );
+    let written = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::OpenOptions::new().create(true).append(true).open(path))
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+    if let Err(e) = written {
+        eprintln!("Warning: Failed to record the request in {}: {}", path.display(), e);
+    }
+}
+
+/// Prints how the two versions did since the last report, and warns when the
+/// new one fails more often.
+fn compare(router: &Router) {
+    let take = |version: &Version| version.recent.lock().map(|mut recent| std::mem::take(&mut *recent)).unwrap_or_default();
+    let (old, new) = (take(&router.old), take(&router.new));
+    if old.is_empty() && new.is_empty() {
+        return;
+    }
+    let summary = |requests: &[(u64, bool)]| {
+        if requests.is_empty() {
+            return "no requests".to_string();
+        }
+        let mut latencies: Vec<u64> = requests.iter().map(|(latency, _)| *latency).collect();
+        latencies.sort_unstable();
+        let percentile = |p: f64| latencies[((p * latencies.len() as f64).ceil() as usize).clamp(1, latencies.len()) - 1];
+        format!(
+            "{} requests, {:.1}% errors, p50 {}ms, p95 {}ms",
+            requests.len(),
+            error_rate(requests) * 100.0,
+            percentile(0.50),
+            percentile(0.95)
+        )
+    };
+    eprintln!("old: {}", summary(&old));
+    eprintln!("new: {}", summary(&new));
+    if !new.is_empty() && error_rate(&new) > error_rate(&old) {
+        eprintln!("Warning: the new version fails more often than the old one");
+    }
+}
+
+fn error_rate(requests: &[(u64, bool)]) -> f64 {
+    match requests.len() {
+        0 => 0.0,
+        n => requests.iter().filter(|(_, ok)| !ok).count() as f64 / n as f64,
+    }
+}
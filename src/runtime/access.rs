@@ -0,0 +1,162 @@
+//! `--serve`'s access log: a line for every request, with who asked, how it
+//! went, how long it took and what it cost, but never what was asked. The
+//! arguments are only there as a hash, so the same question can be found
+//! again without the log knowing it.
+
+use crate::*;
+
+/// A log file is moved aside once it's this big...
+const ROTATE_BYTES: u64 = 10 * 1024 * 1024;
+/// ...to `<file>.1`, pushing the older ones up to `<file>.5`, which is dropped.
+const ROTATIONS: usize = 5;
+
+/// Where the lines go: stdout, or the `--access-log` file.
+enum Sink {
+    Stdout,
+    File { path: std::path::PathBuf, file: std::fs::File, size: u64 },
+}
+
+pub(crate) struct AccessLog {
+    sink: std::sync::Mutex<Sink>,
+    text: bool,
+    redacted: Vec<String>,
+}
+
+/// One request, as it's known before its answer.
+pub(crate) struct AccessEntry {
+    pub(crate) request_id: String,
+    pub(crate) path: &'static str,
+    pub(crate) started: std::time::Instant,
+    pub(crate) caller: Option<String>,
+    pub(crate) args_hash: Option<u64>,
+    /// Whether a chat completion was streamed.
+    pub(crate) stream: Option<bool>,
+}
+
+impl AccessEntry {
+    pub(crate) fn new(request_id: String, path: &'static str) -> AccessEntry {
+        AccessEntry { request_id, path, started: std::time::Instant::now(), caller: None, args_hash: None, stream: None }
+    }
+}
+
+impl AccessLog {
+    /// The log `--access-log`, `--access-log-format` and `--access-log-redact`
+    /// asked for. A file that can't be opened keeps the server from starting,
+    /// rather than letting it answer unlogged.
+    pub(crate) fn open() -> Result<AccessLog, String> {
+        let sink = match ACCESS_LOG.get() {
+            Some(path) => {
+                let path = std::path::PathBuf::from(path);
+                let file = open_append(&path).map_err(|e| format!("Can't open the access log {}: {}", path.display(), e))?;
+                let size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+                Sink::File { path, file, size }
+            }
+            None => Sink::Stdout,
+        };
+        Ok(AccessLog {
+            sink: std::sync::Mutex::new(sink),
+            text: ACCESS_LOG_TEXT.load(std::sync::atomic::Ordering::Relaxed),
+            redacted: ACCESS_LOG_REDACT.get().cloned().unwrap_or_default(),
+        })
+    }
+
+    /// Logs how `entry` ended. `error` has to have its secrets redacted
+    /// already, like everything else that's shown.
+    pub(crate) fn write(&self, entry: &AccessEntry, status: u16, tokens: Option<u64>, error: Option<&str>) {
+        let time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut fields: Vec<(&str, serde_json::Value)> = vec![
+            ("time", time.into()),
+            ("request_id", entry.request_id.as_str().into()),
+            ("path", entry.path.into()),
+            ("status", status.into()),
+            ("latency_ms", (entry.started.elapsed().as_millis() as u64).into()),
+        ];
+        if let Some(caller) = &entry.caller {
+            fields.push(("caller", caller.as_str().into()));
+        }
+        if let Some(hash) = entry.args_hash {
+            fields.push(("args_hash", format!("{:016x}", hash).into()));
+        }
+        if let Some(tokens) = tokens {
+            fields.push(("tokens", tokens.into()));
+        }
+        if let Some(stream) = entry.stream {
+            fields.push(("stream", stream.into()));
+        }
+        if let Some(error) = error {
+            fields.push(("error", error.into()));
+        }
+        for (name, value) in fields.iter_mut() {
+            if self.redacted.iter().any(|redacted| redacted == name) {
+                *value = "[redacted]".into();
+            }
+        }
+        let line = match self.text {
+            true => fields.iter().map(|(name, value)| format!("{}={}", name, logfmt(value))).collect::<Vec<_>>().join(" "),
+            // By hand, so the fields keep their order
+            false => format!("{{{}}}", fields.iter().map(|(name, value)| format!("\"{}\":{}", name, value)).collect::<Vec<_>>().join(",")),
+        };
+        let Ok(mut sink) = self.sink.lock() else {
+            return;
+        };
+        if let Err(e) = sink.append(&line) {
+            eprintln!("Warning: Failed to write the access log: {}", e);
+        }
+    }
+}
+
+impl Sink {
+    fn append(&mut self, line: &str) -> std::io::Result<()> {
+        match self {
+            Sink::Stdout => {
+                let mut stdout = std::io::stdout().lock();
+                writeln!(stdout, "{}", line)?;
+                stdout.flush()
+            }
+            Sink::File { path, file, size } => {
+                if *size > 0 && *size + line.len() as u64 + 1 > ROTATE_BYTES {
+                    rotate(path)?;
+                    *file = open_append(path)?;
+                    *size = 0;
+                }
+                writeln!(file, "{}", line)?;
+                *size += line.len() as u64 + 1;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn open_append(path: &std::path::Path) -> std::io::Result<std::fs::File> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Moves `path` to `path.1`, and each older one a number up.
+fn rotate(path: &std::path::Path) -> std::io::Result<()> {
+    let numbered = |n: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", n));
+        std::path::PathBuf::from(name)
+    };
+    for n in (1..ROTATIONS).rev() {
+        if numbered(n).exists() {
+            std::fs::rename(numbered(n), numbered(n + 1))?;
+        }
+    }
+    std::fs::rename(path, numbered(1))
+}
+
+/// A value as `--access-log-format text` writes it: bare, or quoted if it
+/// would run into the next field.
+fn logfmt(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(text) if !text.is_empty() && !text.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') => text.clone(),
+        other => other.to_string(),
+    }
+}
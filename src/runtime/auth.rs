@@ -0,0 +1,201 @@
+//! Keys: the API key from the environment or compiled into the program, the
+//! `€secret(NAME)`s, which are kept out of everything the program prints, and
+//! the keys callers of `--serve` have to show.
+
+use crate::*;
+
+/// The API key: OPENAI_API_KEY, or the one compiled in. Programs that don't
+/// call a model, other endpoints and programs with a fallback pack may go
+/// without; everything else exits.
+pub(crate) fn api_key() -> String {
+    // Try environment variable first, then fall back to embedded key
+    if all_steps().all(|step| step.local.is_some()) && TRANSLATE_TO.is_none() && !AUDIO_INPUT && IMAGE_OUTPUT.is_none() {
+        String::new()
+    } else if let Ok(env_key) = std::env::var("OPENAI_API_KEY") {
+        env_key
+    } else if let Some(key) = embedded_key() {
+        key
+    } else if std::env::var_os("OPENAI_BASE_URL").is_some() {
+        // Somebody else's endpoint, which may not want a key at all
+        String::new()
+    } else if !FALLBACK.is_empty() {
+        // The model will refuse to answer, and the fallback pack takes over
+        String::new()
+    } else if let (true, Some(date)) = (key_expired(), KEY_EXPIRES) {
+        eprintln!("Error: The API key compiled into this program expired after {}. Set OPENAI_API_KEY environment variable.", date);
+        std::process::exit(1);
+    } else {
+        eprintln!("Error: No API key found. Set OPENAI_API_KEY environment variable.");
+        std::process::exit(1);
+    }
+}
+
+/// The key compiled into the program, if it was compiled with one that
+/// hasn't expired.
+pub(crate) fn embedded_key() -> Option<String> {
+    if !HAS_EMBEDDED_KEY || key_expired() {
+        return None;
+    }
+    // Decrypt embedded key using XOR
+    let decrypted: Vec<u8> = EMBEDDED_KEY
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| b ^ KEY_MASK[i % KEY_MASK.len()])
+        .collect();
+    Some(String::from_utf8(decrypted).expect("Invalid API key"))
+}
+
+/// Whether the day `--key-expires` gave the embedded key is over, in UTC.
+pub(crate) fn key_expired() -> bool {
+    KEY_EXPIRES.is_some_and(|date| parse_date(date).is_ok_and(|last| today() > last))
+}
+
+/// Reads every `€secret(NAME)` from the environment, exiting if one is missing.
+pub(crate) fn read_secrets() -> Vec<(&'static str, String)> {
+    SECRETS
+        .iter()
+        .map(|&name| match std::env::var(name) {
+            Ok(value) => (name, value),
+            Err(_) => {
+                eprintln!("Error: This program needs the secret {}. Set it with: export {}=...", name, name);
+                std::process::exit(1);
+            }
+        })
+        .collect()
+}
+
+/// Replaces secret values in `message` with `[secret NAME]`.
+pub(crate) fn redact(message: &str, secrets: &[(&str, String)]) -> String {
+    let mut message = message.to_string();
+    for (name, value) in secrets {
+        if !value.is_empty() {
+            message = message.replace(value.as_str(), &format!("[secret {}]", name));
+        }
+    }
+    message
+}
+
+/// A key with all but its start and last four characters hidden.
+pub(crate) fn mask(key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    if chars.len() < 12 {
+        return format!("*** ({} characters)", chars.len());
+    }
+    format!("{}...{}", chars[..3].iter().collect::<String>(), chars[chars.len() - 4..].iter().collect::<String>())
+}
+
+/// A proxy URL with its password hidden.
+pub(crate) fn mask_proxy(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    match rest.split_once('@').and_then(|(userinfo, host)| Some((userinfo.split_once(':')?.0, host))) {
+        Some((user, host)) => format!("{}://{}:***@{}", scheme, user, host),
+        None => url.to_string(),
+    }
+}
+
+/// A caller of `--serve` that `--serve-keys` or `--serve-auth` let in.
+#[cfg(feature = "serve")]
+pub(crate) struct Caller {
+    pub(crate) name: String,
+    /// The key their answers are paid with, if it isn't the program's.
+    pub(crate) key: Option<String>,
+}
+
+/// Checks that the keys `--serve-keys` pays with are in the environment.
+#[cfg(feature = "serve")]
+pub(crate) fn check_serve_keys() -> Result<(), String> {
+    match SERVE_KEYS.iter().find(|(_, _, var)| !var.is_empty() && std::env::var_os(var).is_none()) {
+        Some((name, _, var)) => Err(format!("{}'s answers are paid with the key in {}, which isn't set. Set it with: export {}=...", name, var, var)),
+        None => Ok(()),
+    }
+}
+
+/// Who sent a request with the Authorization header `authorization`, when
+/// `--serve-keys` or `--serve-auth` say callers need a key; `Ok(None)` when
+/// anyone may call. The error is what the caller is told.
+#[cfg(feature = "serve")]
+pub(crate) async fn authenticate(authorization: Option<&str>) -> Result<Option<Caller>, String> {
+    if SERVE_KEYS.is_empty() && SERVE_AUTH.is_none() {
+        return Ok(None);
+    }
+    let token = authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .ok_or("This server needs a key, sent as 'Authorization: Bearer <key>'")?;
+    if let Some((name, _, var)) = SERVE_KEYS.iter().find(|(_, masked, _)| same_token(token, masked)) {
+        let key = (!var.is_empty()).then(|| std::env::var(var).unwrap_or_default());
+        return Ok(Some(Caller { name: name.to_string(), key }));
+    }
+    let Some(command) = SERVE_AUTH else {
+        return Err("Incorrect API key".into());
+    };
+    let token = token.to_string();
+    let verdict = tokio::task::spawn_blocking(move || verify(command, &token)).await.map_err(|e| e.to_string()).and_then(|verdict| verdict);
+    match verdict {
+        Ok(Some(caller)) => Ok(Some(caller)),
+        Ok(None) => Err("Incorrect API key".into()),
+        // The details are for whoever runs the server, not for the caller
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            Err("The key couldn't be checked".into())
+        }
+    }
+}
+
+/// Whether `token` is `masked` XOR KEY_MASK, compared in constant time.
+#[cfg(feature = "serve")]
+fn same_token(token: &str, masked: &[u8]) -> bool {
+    let token = token.as_bytes();
+    let diff = token
+        .iter()
+        .zip(masked)
+        .enumerate()
+        .fold(0, |diff, (i, (t, m))| diff | (t ^ m ^ KEY_MASK[i % KEY_MASK.len()]));
+    token.len() == masked.len() && diff == 0
+}
+
+/// Runs `--serve-auth` with `token` on stdin. Exiting with 0 lets the caller
+/// in, and the first line it prints is `name[,KEY_VAR]`.
+#[cfg(feature = "serve")]
+fn verify(command: &str, token: &str) -> Result<Option<Caller>, String> {
+    use std::process::{Command, Stdio};
+
+    let mut child = if cfg!(windows) {
+        let mut child = Command::new("cmd");
+        child.args(["/C", command]);
+        child
+    } else {
+        let mut child = Command::new("sh");
+        child.args(["-c", command]);
+        child
+    };
+    let mut child = child
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run --serve-auth '{}': {}", command, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = writeln!(stdin, "{}", token);
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Failed to run --serve-auth '{}': {}", command, e))?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let printed = String::from_utf8_lossy(&output.stdout);
+    let line = printed.lines().next().unwrap_or_default();
+    let (name, var) = line.split_once(',').unwrap_or((line, ""));
+    let (name, var) = (name.trim(), var.trim());
+    if name.is_empty() {
+        return Err(format!("--serve-auth '{}' let a caller in without printing their name", command));
+    }
+    let key = match var {
+        "" => None,
+        var => Some(std::env::var(var).map_err(|_| format!("--serve-auth '{}' pays for {} with the key in {}, which isn't set", command, name, var))?),
+    };
+    Ok(Some(Caller { name: name.to_string(), key }))
+}
@@ -0,0 +1,231 @@
+//! `--background`: the program's one step goes to the provider as a background
+//! response instead of a stream, which a ten-minute reasoning run would never
+//! keep open. A run submits it, prints the job's ID and exits; `--poll <id>`
+//! fetches the answer once it's there. Each job is a JSON file in the state
+//! directory's jobs/, so any later run of the program can poll it, and an
+//! answer that's in is kept there for the next poll.
+
+use crate::*;
+
+/// Exit code of `--poll` while the job is still running.
+pub(crate) const JOB_PENDING_EXIT_CODE: i32 = 5;
+
+/// How long `--poll --wait` waits between asking, at most.
+const MAX_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// The file a job is kept in.
+fn job_file(id: &str) -> Result<std::path::PathBuf, String> {
+    // The ID names the file, so it can't be one that leaves the directory
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err(format!("'{}' isn't a job ID like the ones this program prints", id));
+    }
+    let dir = paths::jobs_dir().ok_or("No home directory to keep jobs in; set MATTHIASHIHIC_STATE_DIR")?;
+    Ok(dir.join(format!("{}.json", id)))
+}
+
+fn save_job(job: &serde_json::Value) -> Result<(), String> {
+    let path = job_file(job["id"].as_str().unwrap_or_default())?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| format!("Can't create {}: {}", dir.display(), e))?;
+    }
+    let contents = serde_json::to_string_pretty(job).map_err(|e| e.to_string())?;
+    std::fs::write(&path, contents).map_err(|e| format!("Can't write {}: {}", path.display(), e))
+}
+
+/// Hands `step`, asked as `pseudocode`, to the provider to answer in the
+/// background, and returns the job's ID.
+pub(crate) async fn submit_job(api_key: &str, step: &Step, pseudocode: &str) -> Result<String, String> {
+    let mut request_body = serde_json::json!({
+        "model": step.model,
+        "instructions": format!("{}{}", SYSTEM_PROMPT, GLOSSARY_PROMPT),
+        "input": pseudocode,
+        "background": true,
+        // Background responses are only kept, and so only pollable, when stored
+        "store": true
+    });
+    if let Some(temperature) = step.temperature.or(TEMPERATURE) {
+        request_body["temperature"] = serde_json::json!(temperature);
+    }
+    if let Some(max_tokens) = MAX_TOKENS {
+        request_body["max_output_tokens"] = serde_json::json!(max_tokens);
+    }
+    if let Some(schema) = step.schema {
+        let schema: serde_json::Value = serde_json::from_str(schema).map_err(|e| e.to_string())?;
+        request_body["text"] = serde_json::json!({"format": {"type": "json_schema", "name": "answer", "strict": true, "schema": schema}});
+    }
+    let turn = turn().await?;
+    let response = http_client()
+        .post(openai_url("responses"))
+        .header("Authorization", format!("Bearer {}", turn.key(api_key)))
+        .header("X-Request-Id", request_id())
+        .json(&request_body)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    turn.done(response.status().as_u16()).await;
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+        return Err(format!("OpenAI API error ({}): {}", status, error_text));
+    }
+    let body: serde_json::Value = response.json().await.map_err(|e| e.to_string())?;
+    let id = body["id"].as_str().ok_or("The provider didn't say which job it started")?.to_string();
+    save_job(&serde_json::json!({
+        "id": id,
+        "program": NAME,
+        "model": step.model,
+        "submitted": std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        "status": body["status"].as_str().unwrap_or("queued"),
+        "request_id": request_id()
+    }))?;
+    Ok(id)
+}
+
+/// `--poll <id>`: the job's answer if it's in, `None` while it's running.
+pub(crate) async fn poll_job(api_key: &str, id: &str) -> Result<Option<String>, String> {
+    let path = job_file(id)?;
+    let mut job: serde_json::Value = std::fs::read_to_string(&path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .ok_or_else(|| format!("{} didn't start a job {} here; --jobs lists the ones it did", NAME, id))?;
+    // Asked before, and answered
+    if let Some(answer) = job["answer"].as_str() {
+        return Ok(Some(answer.to_string()));
+    }
+    let turn = turn().await?;
+    let response = http_client()
+        .get(openai_url(&format!("responses/{}", id)))
+        .header("Authorization", format!("Bearer {}", turn.key(api_key)))
+        .header("X-Request-Id", request_id())
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    turn.done(response.status().as_u16()).await;
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+        return Err(format!("OpenAI API error ({}): {}", status, error_text));
+    }
+    let body: serde_json::Value = response.json().await.map_err(|e| e.to_string())?;
+    let status = body["status"].as_str().unwrap_or("unknown").to_string();
+    job["status"] = serde_json::json!(status);
+    match status.as_str() {
+        "queued" | "in_progress" => {
+            save_job(&job)?;
+            Ok(None)
+        }
+        "completed" => {
+            add_usage(&body["usage"]);
+            // The text of every message the response has, in order
+            let answer: String = body["output"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter(|item| item["type"] == "message")
+                .flat_map(|item| item["content"].as_array().into_iter().flatten())
+                .filter(|content| content["type"] == "output_text")
+                .filter_map(|content| content["text"].as_str())
+                .collect();
+            if body["output"].as_array().into_iter().flatten().flat_map(|item| item["content"].as_array().into_iter().flatten()).any(|content| content["type"] == "refusal") {
+                REFUSAL_FLAGGED.store(true, std::sync::atomic::Ordering::Relaxed);
+            }
+            job["answer"] = serde_json::json!(answer);
+            save_job(&job)?;
+            Ok(Some(answer))
+        }
+        _ => {
+            save_job(&job)?;
+            let why = body["error"]["message"]
+                .as_str()
+                .or(body["incomplete_details"]["reason"].as_str())
+                .unwrap_or("the provider didn't say why");
+            Err(format!("The job {} is {}: {}", id, status, why))
+        }
+    }
+}
+
+/// `--poll <id> [--wait]`: prints the job's answer and exits, or says it's
+/// still running and exits with JOB_PENDING_EXIT_CODE. With `wait`, asks
+/// again, a little less often each time, until it's in.
+pub(crate) async fn run_poll(api_key: &str, id: &str, wait: bool, secrets: &[(&str, String)]) {
+    let mut interval = std::time::Duration::from_secs(2);
+    let answer = loop {
+        match poll_job(api_key, id).await {
+            Ok(Some(answer)) => break answer,
+            Ok(None) if wait => {
+                tokio::time::sleep(interval).await;
+                interval = (interval * 2).min(MAX_POLL_INTERVAL);
+            }
+            Ok(None) => {
+                eprintln!("The job {} is still running; poll again later", id);
+                std::process::exit(JOB_PENDING_EXIT_CODE);
+            }
+            Err(e) => fail(&redact(&e, secrets), 1),
+        }
+    };
+    let step = &steps()[0];
+    let flagged = REFUSAL_FLAGGED.swap(false, std::sync::atomic::Ordering::Relaxed);
+    if FAIL_ON_REFUSAL && (flagged || is_refusal(&answer)) {
+        let said = answer.lines().map(str::trim).find(|line| !line.is_empty()).unwrap_or("nothing");
+        fail(&format!("{}: {}", REFUSED, said), REFUSAL_EXIT_CODE);
+    }
+    if let Some(schema) = step.schema {
+        if let Err(e) = check_json(&answer, schema) {
+            fail(&format!("The answer of the job {} isn't the json it should be: {}", id, e), 1);
+        }
+        #[cfg(feature = "types")]
+        if let Err(e) = types::deserialize_answer(schema, &answer) {
+            fail(&format!("The answer of the job {} doesn't fit its type: {}", id, e), 1);
+        }
+    }
+    if let Some(expected) = step.expect.filter(|_| CHECK.load(std::sync::atomic::Ordering::Relaxed)) {
+        check_answer(step.pseudocode, expected, &answer, secrets);
+    }
+    let mut output = answer;
+    if let Some(mark) = watermark(step.model) {
+        output = format!("{}\n{}", output, mark);
+    }
+    if ECHO_STDOUT {
+        if let Err(e) = Echo::Stdout.line(&output).await {
+            fail(&e, 1);
+        }
+    }
+    flush_stdout();
+    if let Err(e) = deliver_to_sinks(&output, step.model).await {
+        fail(&redact(&e.to_string(), secrets), 1);
+    }
+    record_run(started(), Some(output.chars().count()), None, usage());
+    write_trailer(None);
+    if NOTIFY {
+        notify(&format!("{} finished", NAME), output.lines().find(|line| !line.trim().is_empty()).unwrap_or(""));
+    }
+    finish_check();
+}
+
+/// `--jobs`: the jobs this program started, newest last.
+pub(crate) fn list_jobs() {
+    let mut jobs: Vec<serde_json::Value> = paths::jobs_dir()
+        .and_then(|dir| std::fs::read_dir(dir).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| std::fs::read_to_string(entry.ok()?.path()).ok())
+        .filter_map(|contents| serde_json::from_str::<serde_json::Value>(&contents).ok())
+        .filter(|job| job["program"] == NAME)
+        .collect();
+    jobs.sort_by_key(|job| job["submitted"].as_u64().unwrap_or(0));
+    for job in &jobs {
+        let submitted = job["submitted"].as_i64().unwrap_or(0);
+        let time = submitted.rem_euclid(86400);
+        println!(
+            "{}  {} {:02}:{:02} UTC  {}",
+            job["id"].as_str().unwrap_or("?"),
+            format_date(submitted.div_euclid(86400)),
+            time / 3600,
+            time / 60 % 60,
+            job["status"].as_str().unwrap_or("?")
+        );
+    }
+    if jobs.is_empty() {
+        eprintln!("{} hasn't started any jobs", NAME);
+    }
+}
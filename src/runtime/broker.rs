@@ -0,0 +1,96 @@
+//! `--broker`: every call to the model waits for its turn from `matthiashihic
+//! broker`, which keeps all the programs on the machine within the same rate
+//! limits and may say which key to call with. One connection per call: `call
+//! <name> <pid>`, `go <key>` (`-` for our own), then `done <status>` or hanging
+//! up.
+
+use crate::*;
+
+/// How long a call waits for its turn before saying so.
+#[cfg(unix)]
+const BROKER_PATIENCE: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// A call's turn: the connection to the broker, held until the call is over,
+/// and the key the broker said to use.
+pub(crate) struct Turn {
+    #[cfg(unix)]
+    stream: Option<tokio::io::BufReader<tokio::net::UnixStream>>,
+    key: Option<String>,
+}
+
+impl Turn {
+    /// The key to call with: the broker's, or `own`.
+    pub(crate) fn key<'a>(&'a self, own: &'a str) -> &'a str {
+        self.key.as_deref().unwrap_or(own)
+    }
+
+    /// Tells the broker the call is over and what the provider answered; a
+    /// 429 rests the key.
+    pub(crate) async fn done(self, status: u16) {
+        #[cfg(unix)]
+        if let Some(mut stream) = self.stream {
+            use tokio::io::AsyncWriteExt;
+            let _ = stream.get_mut().write_all(format!("done {}\n", status).as_bytes()).await;
+        }
+        #[cfg(not(unix))]
+        let _ = status;
+    }
+}
+
+/// Waits for the broker to say it's this call's turn. Without `--broker`, it
+/// always is.
+#[cfg(unix)]
+pub(crate) async fn turn() -> Result<Turn, String> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    if !BROKER {
+        return Ok(Turn { stream: None, key: None });
+    }
+    let socket = paths::broker_socket().ok_or("No home directory to find the broker's socket in; set MATTHIASHIHIC_BROKER")?;
+    let unreachable = |e: std::io::Error| {
+        format!("Can't reach the broker at {}: {} (is 'matthiashihic broker' running?)", socket.display(), e)
+    };
+    let mut stream = tokio::io::BufReader::new(tokio::net::UnixStream::connect(&socket).await.map_err(unreachable)?);
+    // Turns are shared out by process, so two runs of a program are two callers
+    let call = format!("call {} {}\n", NAME.replace('\n', " "), std::process::id());
+    stream.get_mut().write_all(call.as_bytes()).await.map_err(unreachable)?;
+    let mut line = String::new();
+    let read = stream.read_line(&mut line);
+    tokio::pin!(read);
+    let read = match tokio::time::timeout(BROKER_PATIENCE, &mut read).await {
+        Ok(read) => read,
+        Err(_) => {
+            // Better than looking stuck
+            if !TUI {
+                eprintln!("Waiting for a turn from the broker at {}...", socket.display());
+            }
+            read.await
+        }
+    };
+    read.map_err(unreachable)?;
+    let key = match line.trim().strip_prefix("go ") {
+        Some("-") => None,
+        Some(key) => Some(key.to_string()),
+        None if line.is_empty() => return Err("The broker hung up instead of giving this call a turn".into()),
+        None => return Err(format!("The broker said: {}", line.trim())),
+    };
+    Ok(Turn { stream: Some(stream), key })
+}
+
+#[cfg(not(unix))]
+pub(crate) async fn turn() -> Result<Turn, String> {
+    if BROKER {
+        return Err("--broker needs Unix sockets, which this platform doesn't have".into());
+    }
+    Ok(Turn { key: None })
+}
+
+/// For `--doctor`: the broker's socket, if something listens on it. Doesn't
+/// take a turn.
+pub(crate) fn broker_listening() -> Result<String, String> {
+    let socket = paths::broker_socket().ok_or("no home directory to find the socket in; set MATTHIASHIHIC_BROKER")?;
+    #[cfg(unix)]
+    std::os::unix::net::UnixStream::connect(&socket)
+        .map_err(|e| format!("{} can't be reached: {}; start 'matthiashihic broker'", socket.display(), e))?;
+    Ok(socket.display().to_string())
+}
@@ -0,0 +1,365 @@
+//! `--cache`: the answers of model steps, kept in answers.jsonl in the cache
+//! directory, so the same request isn't paid for twice. With
+//! `--cache-similarity`, a request that's merely close enough to one asked
+//! before gets its answer as well, by comparing embeddings. With
+//! `--cache-backend`, exact answers are shared through Redis or an S3 bucket
+//! too, under the program's CACHE_NAMESPACE.
+
+use crate::*;
+
+/// Turns requests into the vectors `--cache-similarity` compares.
+pub(crate) const EMBEDDING_MODEL: &str = "text-embedding-3-small";
+
+/// How long the cache backend gets before a lookup counts as a miss, so a
+/// backend that's down doesn't hold up the run.
+const BACKEND_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Where a step's answer is looked for and kept.
+pub(crate) struct CacheKey {
+    /// FNV-1a of everything the model sees, for exact hits.
+    hash: u64,
+    /// FNV-1a of the model and the system prompt; similar hits need the same.
+    scope: u64,
+    /// The request's embedding, with `--cache-similarity` and if it worked.
+    embedding: Option<Vec<f64>>,
+}
+
+/// Looks for an answer to `pseudocode` after `history`: in the local cache,
+/// then the backend, then for a similar request. Returns it, with where it
+/// came from unless it's a local exact hit, and the key to store the answer
+/// under otherwise. Problems with the cache are warned about, and count as a
+/// miss.
+pub(crate) async fn cache_lookup(
+    api_key: &str,
+    model: &str,
+    history: &[(String, String)],
+    pseudocode: &str,
+) -> (Option<(String, Option<String>)>, CacheKey) {
+    let mut request = format!("{}\0{}{}", model, SYSTEM_PROMPT, GLOSSARY_PROMPT);
+    let scope = fnv1a(&request);
+    for (statements, answer) in history {
+        request.push_str(&format!("\0{}\0{}", statements, answer));
+    }
+    request.push_str(&format!("\0{}", pseudocode));
+    let mut key = CacheKey { hash: fnv1a(&request), scope, embedding: None };
+    let entries = read_cache();
+    if let Some(entry) = entries.iter().find(|entry| entry.hash == key.hash) {
+        return (Some((entry.answer.clone(), None)), key);
+    }
+    if let Some(backend) = CACHE_BACKEND {
+        match backend_get(backend, key.hash).await {
+            Ok(Some(answer)) => return (Some((answer, Some(backend_name(backend).to_string()))), key),
+            Ok(None) => {}
+            Err(e) => eprintln!("Warning: Failed to look in the {} cache: {}", backend_name(backend), e),
+        }
+    }
+    let Some(threshold) = CACHE_SIMILARITY else {
+        return (None, key);
+    };
+    // What's compared is the conversation, the way a reader would see it
+    let text = history
+        .iter()
+        .flat_map(|(statements, answer)| [statements.as_str(), answer.as_str()])
+        .chain(std::iter::once(pseudocode))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let embedding = match embed(api_key, &text).await {
+        Ok(embedding) => embedding,
+        Err(e) => {
+            eprintln!("Warning: Failed to embed the request for the cache, so only exact hits count: {}", e);
+            return (None, key);
+        }
+    };
+    let best = entries
+        .iter()
+        .filter(|entry| entry.scope == scope)
+        .filter_map(|entry| Some((cosine_similarity(&embedding, entry.embedding.as_deref()?), &entry.answer)))
+        .filter(|(similarity, _)| *similarity >= threshold)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(similarity, answer)| (answer.clone(), Some(format!("{:.2} similar", similarity))));
+    key.embedding = Some(embedding);
+    (best, key)
+}
+
+/// Keeps an answer in the cache and the backend, warning if it can't.
+pub(crate) async fn cache_store(key: CacheKey, answer: &str) {
+    use std::io::Write;
+    if let Some(backend) = CACHE_BACKEND {
+        if let Err(e) = backend_put(backend, key.hash, answer).await {
+            eprintln!("Warning: Failed to share the answer in the {} cache: {}", backend_name(backend), e);
+        }
+    }
+    let Some(dir) = paths::cache_dir() else {
+        eprintln!("Warning: No home directory to keep the cache in");
+        return;
+    };
+    let mut entry = serde_json::json!({
+        "hash": format!("{:016x}", key.hash),
+        "scope": format!("{:016x}", key.scope),
+        "answer": answer
+    });
+    if let Some(embedding) = key.embedding {
+        entry["embedding"] = serde_json::json!(embedding);
+    }
+    if let Some(expires) = expires() {
+        entry["expires"] = serde_json::json!(expires);
+    }
+    let path = dir.join("answers.jsonl");
+    let written = std::fs::create_dir_all(&dir)
+        .and_then(|_| std::fs::OpenOptions::new().create(true).append(true).open(&path))
+        .and_then(|mut file| file.write_all(format!("{}\n", entry).as_bytes()));
+    if let Err(e) = written {
+        eprintln!("Warning: Failed to cache the answer in {}: {}", path.display(), e);
+    }
+}
+
+/// A line of answers.jsonl.
+struct Cached {
+    hash: u64,
+    scope: u64,
+    embedding: Option<Vec<f64>>,
+    answer: String,
+}
+
+/// The cache, oldest first. Lines that don't make sense or have expired are
+/// skipped.
+fn read_cache() -> Vec<Cached> {
+    let Some(contents) = paths::cache_dir().and_then(|dir| std::fs::read_to_string(dir.join("answers.jsonl")).ok()) else {
+        return Vec::new();
+    };
+    contents
+        .lines()
+        .filter_map(|line| {
+            let entry: serde_json::Value = serde_json::from_str(line).ok()?;
+            if entry["expires"].as_u64().is_some_and(|expires| expires < now()) {
+                return None;
+            }
+            let hex = |field: &str| u64::from_str_radix(entry[field].as_str()?, 16).ok();
+            let embedding = entry["embedding"].as_array().map(|values| values.iter().filter_map(|v| v.as_f64()).collect());
+            Some(Cached { hash: hex("hash")?, scope: hex("scope")?, embedding, answer: entry["answer"].as_str()?.to_string() })
+        })
+        .collect()
+}
+
+/// 1 for vectors pointing the same way, 0 for unrelated ones. Vectors of
+/// different lengths come from different models and aren't similar at all.
+fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
+    if a.len() != b.len() || a.is_empty() {
+        return 0.0;
+    }
+    let dot: f64 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norms = a.iter().map(|x| x * x).sum::<f64>().sqrt() * b.iter().map(|y| y * y).sum::<f64>().sqrt();
+    if norms == 0.0 { 0.0 } else { dot / norms }
+}
+
+/// Seconds since 1970.
+fn now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// When an answer cached now stops counting, with `--cache-ttl`.
+fn expires() -> Option<u64> {
+    CACHE_TTL.map(|ttl| now() + ttl)
+}
+
+/// What the backend is called in messages.
+fn backend_name(backend: &str) -> &'static str {
+    if backend.starts_with("s3://") { "S3" } else { "Redis" }
+}
+
+/// The answer the backend has for `hash`, if any.
+async fn backend_get(backend: &str, hash: u64) -> Result<Option<String>, String> {
+    let get = async {
+        if backend.starts_with("s3://") {
+            let Some(object) = s3_request(backend, "GET", hash, None).await? else {
+                return Ok(None);
+            };
+            let object: serde_json::Value = serde_json::from_str(&object).map_err(|e| e.to_string())?;
+            if object["expires"].as_u64().is_some_and(|expires| expires < now()) {
+                return Ok(None);
+            }
+            return Ok(object["answer"].as_str().map(str::to_string));
+        }
+        redis(backend, &["GET", &redis_key(hash)]).await
+    };
+    tokio::time::timeout(BACKEND_TIMEOUT, get).await.map_err(|_| "timed out".to_string())?
+}
+
+/// Shares an answer through the backend, for `--cache-ttl` if that's set.
+async fn backend_put(backend: &str, hash: u64, answer: &str) -> Result<(), String> {
+    let put = async {
+        if backend.starts_with("s3://") {
+            let mut object = serde_json::json!({"answer": answer});
+            if let Some(expires) = expires() {
+                object["expires"] = serde_json::json!(expires);
+            }
+            return s3_request(backend, "PUT", hash, Some(object.to_string())).await.map(|_| ());
+        }
+        let key = redis_key(hash);
+        match CACHE_TTL {
+            Some(ttl) => redis(backend, &["SET", &key, answer, "EX", &ttl.to_string()]).await,
+            None => redis(backend, &["SET", &key, answer]).await,
+        }
+        .map(|_| ())
+    };
+    tokio::time::timeout(BACKEND_TIMEOUT, put).await.map_err(|_| "timed out".to_string())?
+}
+
+fn redis_key(hash: u64) -> String {
+    format!("matthiashihic:{}:{:016x}", CACHE_NAMESPACE, hash)
+}
+
+/// Sends one command to `redis://host[:port][/db]`, after AUTH with
+/// REDIS_USERNAME and REDIS_PASSWORD if they're set, and returns its reply.
+async fn redis(backend: &str, command: &[&str]) -> Result<Option<String>, String> {
+    use tokio::io::AsyncWriteExt;
+    let rest = backend.trim_start_matches("redis://");
+    let (authority, db) = rest.split_once('/').unwrap_or((rest, ""));
+    let address = if authority.contains(':') { authority.to_string() } else { format!("{}:6379", authority) };
+    let stream = tokio::net::TcpStream::connect(&address).await.map_err(|e| format!("{}: {}", address, e))?;
+    let mut stream = tokio::io::BufReader::new(stream);
+    let password = std::env::var("REDIS_PASSWORD").ok().filter(|password| !password.is_empty());
+    let username = std::env::var("REDIS_USERNAME").ok().filter(|username| !username.is_empty());
+    let mut commands: Vec<Vec<&str>> = Vec::new();
+    match (&username, &password) {
+        (Some(username), Some(password)) => commands.push(vec!["AUTH", username, password]),
+        (None, Some(password)) => commands.push(vec!["AUTH", password]),
+        _ => {}
+    }
+    if !db.is_empty() {
+        commands.push(vec!["SELECT", db]);
+    }
+    commands.push(command.to_vec());
+    // All in one go; the replies come back in order
+    let mut request = Vec::new();
+    for command in &commands {
+        request.extend(format!("*{}\r\n", command.len()).bytes());
+        for arg in command {
+            request.extend(format!("${}\r\n", arg.len()).bytes());
+            request.extend(arg.bytes());
+            request.extend(b"\r\n");
+        }
+    }
+    stream.get_mut().write_all(&request).await.map_err(|e| e.to_string())?;
+    let mut reply = None;
+    for _ in &commands {
+        reply = read_redis_reply(&mut stream).await?;
+    }
+    Ok(reply)
+}
+
+/// One reply: a simple or bulk string, an integer, or nil as `None`.
+async fn read_redis_reply(stream: &mut tokio::io::BufReader<tokio::net::TcpStream>) -> Result<Option<String>, String> {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+    let mut line = String::new();
+    stream.read_line(&mut line).await.map_err(|e| e.to_string())?;
+    let line = line.trim_end();
+    let (kind, rest) = line.split_at(line.len().min(1));
+    match kind {
+        "+" | ":" => Ok(Some(rest.to_string())),
+        "-" => Err(rest.to_string()),
+        "$" if rest == "-1" => Ok(None),
+        "$" => {
+            let len: usize = rest.parse().map_err(|_| format!("unexpected reply '{}'", line))?;
+            let mut value = vec![0; len + 2];
+            stream.read_exact(&mut value).await.map_err(|e| e.to_string())?;
+            value.truncate(len);
+            String::from_utf8(value).map(Some).map_err(|e| e.to_string())
+        }
+        _ => Err(format!("unexpected reply '{}'", line)),
+    }
+}
+
+/// GETs or PUTs the object for `hash` in `s3://bucket[/prefix]`, signed with
+/// AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY (Signature Version 4). The
+/// endpoint is AWS_ENDPOINT_URL for S3-compatible storage, AWS otherwise.
+/// A missing object is `None`.
+#[cfg(feature = "s3")]
+async fn s3_request(backend: &str, method: &str, hash: u64, body: Option<String>) -> Result<Option<String>, String> {
+    let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    let hmac = |key: &[u8], data: &str| hmac_sha256::HMAC::mac(data.as_bytes(), key);
+    let encode = |segment: &str| {
+        segment
+            .bytes()
+            .map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+                _ => format!("%{:02X}", byte),
+            })
+            .collect::<String>()
+    };
+    let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+    let access_key = var("AWS_ACCESS_KEY_ID").ok_or("S3 needs AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY")?;
+    let secret_key = var("AWS_SECRET_ACCESS_KEY").ok_or("S3 needs AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY")?;
+    let region = var("AWS_REGION").or_else(|| var("AWS_DEFAULT_REGION")).unwrap_or_else(|| "us-east-1".to_string());
+    let endpoint = var("AWS_ENDPOINT_URL_S3")
+        .or_else(|| var("AWS_ENDPOINT_URL"))
+        .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+
+    // Path-style, which every S3-compatible store understands
+    let location = backend.trim_start_matches("s3://");
+    let object = format!("{}/{}/{:016x}.json", location, CACHE_NAMESPACE, hash);
+    let path = object.split('/').filter(|segment| !segment.is_empty()).map(encode).collect::<Vec<_>>().join("/");
+    let url = reqwest::Url::parse(&format!("{}/{}", endpoint.trim_end_matches('/'), path)).map_err(|e| e.to_string())?;
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or(""), port),
+        None => url.host_str().unwrap_or("").to_string(),
+    };
+
+    let seconds = now() as i64;
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let time = seconds.rem_euclid(86400);
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let amz_date = format!("{}T{:02}{:02}{:02}Z", date, time / 3600, time / 60 % 60, time % 60);
+    let payload_hash = hex(&hmac_sha256::Hash::hash(body.as_deref().unwrap_or("").as_bytes()));
+    let mut headers = vec![
+        ("host", host),
+        ("x-amz-content-sha256", payload_hash.clone()),
+        ("x-amz-date", amz_date.clone()),
+    ];
+    if let Some(token) = var("AWS_SESSION_TOKEN") {
+        headers.push(("x-amz-security-token", token));
+    }
+    let canonical_headers: String = headers.iter().map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+    let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+    let canonical_request = format!("{}\n{}\n\n{}\n{}\n{}", method, url.path(), canonical_headers, signed_headers, payload_hash);
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(&hmac_sha256::Hash::hash(canonical_request.as_bytes()))
+    );
+    let key = hmac(format!("AWS4{}", secret_key).as_bytes(), &date);
+    let key = hmac(&key, &region);
+    let key = hmac(&key, "s3");
+    let key = hmac(&key, "aws4_request");
+    let signature = hex(&hmac(&key, &string_to_sign));
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        access_key, scope, signed_headers, signature
+    );
+
+    let method = reqwest::Method::from_bytes(method.as_bytes()).map_err(|e| e.to_string())?;
+    let mut request = http_client().request(method, url).header("Authorization", authorization);
+    for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+        request = request.header(name, value);
+    }
+    if let Some(body) = body {
+        request = request.header("Content-Type", "application/json").body(body);
+    }
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let text = response.text().await.map_err(|e| e.to_string())?;
+    if !status.is_success() {
+        return Err(format!("{} {}", status, text.trim()));
+    }
+    Ok(Some(text))
+}
+
+#[cfg(not(feature = "s3"))]
+async fn s3_request(_backend: &str, _method: &str, _hash: u64, _body: Option<String>) -> Result<Option<String>, String> {
+    Err("this program was compiled without S3 support".into())
+}
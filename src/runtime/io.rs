@@ -0,0 +1,751 @@
+//! Input and output: stdin records and named values, the environment, files,
+//! directories, git and documents behind the placeholders, audio input, and
+//! the sinks the answer is delivered to.
+
+use crate::*;
+
+/// The `€env.NAME`s, as read when the program started.
+static ENV_VALUES: std::sync::OnceLock<Vec<(&'static str, String)>> = std::sync::OnceLock::new();
+
+/// Reads every `€env.NAME` from the environment, exiting if one is missing.
+pub(crate) fn read_env_vars() {
+    let values = ENV_VARS
+        .iter()
+        .map(|&name| match std::env::var(name) {
+            Ok(value) => (name, value),
+            Err(_) => {
+                eprintln!("Error: This program reads the environment variable {} (€env.{}), which isn't set. Set it with: export {}=...", name, name, name);
+                std::process::exit(1);
+            }
+        })
+        .collect();
+    let _ = ENV_VALUES.set(values);
+}
+
+/// The stdin lines the `for every line` blocks run for.
+static EVERY_LINE_INPUT: std::sync::OnceLock<Vec<String>> = std::sync::OnceLock::new();
+
+/// The lines of stdin, for the `for every line` blocks, read with the
+/// program's arguments so a missing pipe shows before anything is asked.
+/// Exits if there's nothing piped in to read them from.
+pub(crate) fn every_line_input() -> &'static [String] {
+    use std::io::{BufRead, IsTerminal};
+
+    EVERY_LINE_INPUT.get_or_init(|| {
+        if std::io::stdin().is_terminal() {
+            eprintln!("Error: This program runs for every line of stdin.\nUsage: cat file | {}", NAME);
+            std::process::exit(2);
+        }
+        std::io::stdin().lock().lines().map(|line| line.expect("Failed to read line from stdin")).collect()
+    })
+}
+
+/// Checks the placeholder values and reads the files they name.
+pub(crate) fn prepare_args(args: Vec<String>) -> Result<(Vec<String>, Vec<(String, String)>), String> {
+    let args = coerce_args(args)?;
+    let files = read_files(&args)?;
+    Ok((args, files))
+}
+
+/// The `or "..."` default of €`index`, if it has one.
+fn arg_default(index: usize) -> Option<&'static str> {
+    ARG_DEFAULTS.iter().find(|(arg, _)| *arg == index).map(|(_, value)| *value)
+}
+
+/// How many values a run needs: through the last placeholder without a
+/// default. The ones after it can be left out.
+pub(crate) fn needed_args() -> usize {
+    REQUIRED_ARGS.iter().copied().filter(|&index| arg_default(index).is_none()).max().unwrap_or(0)
+}
+
+/// `args` filled up to the last placeholder, with the defaults of the ones
+/// that didn't get a value.
+pub(crate) fn with_defaults(mut args: Vec<String>) -> Vec<String> {
+    let max_arg = REQUIRED_ARGS.iter().max().copied().unwrap_or(0);
+    while args.len() < max_arg {
+        args.push(arg_default(args.len() + 1).unwrap_or_default().to_string());
+    }
+    args
+}
+
+pub(crate) fn read_invocations(named: &[(&str, String)]) -> Vec<Vec<String>> {
+    use std::io::{BufRead, IsTerminal, Read};
+
+    let max_arg = REQUIRED_ARGS.iter().max().copied().unwrap_or(0);
+    let needed = needed_args();
+    if EVERY_LINE {
+        every_line_input();
+    }
+    // €{name}s given as flags; the others have to come from stdin
+    let mut values: Vec<Option<String>> = NAMED_ARGS
+        .iter()
+        .map(|name| named.iter().find(|(n, _)| n == name).map(|(_, value)| value.clone()))
+        .collect();
+    if max_arg == 0 && !ALL_ARGS && values.iter().all(Option::is_some) {
+        return vec![values.into_iter().flatten().collect()];
+    }
+    if EVERY_LINE {
+        // stdin is the for every line block's, so the rest can only be flags
+        eprintln!("{}", missing_named(&values).unwrap_or_default());
+        std::process::exit(2);
+    }
+
+    // Check if stdin is available
+    if std::io::stdin().is_terminal() {
+        if ALL_ARGS {
+            eprintln!("Error: This program reads all of stdin.\nUsage: cat file | €0");
+            std::process::exit(2);
+        }
+        if needed == 0 && STDIN_FORMAT == "lines" && values.iter().all(Option::is_some) {
+            // Every placeholder has a default, so there's nothing to wait for
+            let mut args = with_defaults(Vec::new());
+            args.extend(values.into_iter().flatten());
+            return vec![args];
+        }
+        if max_arg == 0 || (needed == 0 && STDIN_FORMAT == "lines") {
+            eprintln!("{}", missing_named(&values).unwrap_or_default());
+        } else if STDIN_FORMAT == "lines" {
+            eprintln!("Error: This program expects {} line(s) from stdin.\nUsage: echo 'value' | €0 or cat file | €0", needed);
+        } else {
+            eprintln!("Error: This program expects {} rows on stdin.\nUsage: cat file.{} | €0", STDIN_FORMAT, STDIN_FORMAT);
+        }
+        std::process::exit(2);
+    }
+
+    if STDIN_FORMAT == "lines" {
+        // Read arguments from stdin; name=value lines fill the €{name}s
+        let stdin = std::io::stdin();
+        let mut lines: Vec<String> = Vec::new();
+        // Every line that isn't name=value, for €*
+        let mut all: Vec<String> = Vec::new();
+        for line in stdin.lock().lines() {
+            let line = line.expect("Failed to read line from stdin");
+            let labelled = line.split_once('=').and_then(|(name, value)| {
+                let index = NAMED_ARGS.iter().position(|n| *n == name.trim()).filter(|&i| values[i].is_none())?;
+                Some((index, value.to_string()))
+            });
+            match labelled {
+                Some((index, value)) => values[index] = Some(value),
+                None if ALL_ARGS => {
+                    if lines.len() < max_arg {
+                        lines.push(line.clone());
+                    }
+                    all.push(line);
+                }
+                // Like the lines after the last placeholder, extra ones are ignored
+                None if lines.len() < max_arg => lines.push(line),
+                None => {}
+            }
+            if !ALL_ARGS && lines.len() >= max_arg && values.iter().all(Option::is_some) {
+                break;
+            }
+        }
+
+        if lines.len() < needed {
+            eprintln!("Error: Expected {} arguments from stdin, got {}\nUsage: Pipe {} lines into this program, one per line.", needed, lines.len(), needed);
+            std::process::exit(2);
+        }
+        let mut lines = with_defaults(lines);
+        if let Some(message) = missing_named(&values) {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }
+        lines.extend(values.into_iter().flatten());
+        if ALL_ARGS {
+            lines.push(all.join("\n"));
+        }
+        return vec![lines];
+    }
+
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input).expect("Failed to read stdin");
+    let delimiter = if STDIN_FORMAT == "tsv" { '\t' } else { ',' };
+    let mut rows = parse_delimited(&input, delimiter);
+    let header = if SKIP_HEADER && !rows.is_empty() { rows.remove(0) } else { Vec::new() };
+    // €{name}s without a flag read the column the header row names after them
+    let mut named_columns = Vec::new();
+    for (index, value) in values.iter().enumerate() {
+        match (value, header.iter().position(|column| column.trim() == NAMED_ARGS[index])) {
+            (Some(_), _) => named_columns.push(None),
+            (None, Some(column)) => named_columns.push(Some(column)),
+            (None, None) => {
+                eprintln!("{}", missing_named(&values).unwrap_or_default());
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let mut invocations = Vec::new();
+    for (row_index, row) in rows.iter().enumerate() {
+        let mut args = vec![String::new(); max_arg];
+        for &placeholder in REQUIRED_ARGS {
+            let column = COLUMN_MAP
+                .iter()
+                .find(|(_, p)| *p == placeholder)
+                .map(|(c, _)| *c)
+                .unwrap_or(placeholder);
+            match row.get(column - 1).map(String::as_str).or(arg_default(placeholder)) {
+                Some(value) => args[placeholder - 1] = value.to_string(),
+                None => {
+                    eprintln!("Error: Row {} has {} column(s), but €{} needs column {}", row_index + 1, row.len(), placeholder, column);
+                    std::process::exit(2);
+                }
+            }
+        }
+        for (index, column) in named_columns.iter().enumerate() {
+            match (column, &values[index]) {
+                (None, Some(value)) => args.push(value.clone()),
+                (Some(column), _) => match row.get(*column) {
+                    Some(value) => args.push(value.clone()),
+                    None => {
+                        eprintln!("Error: Row {} has {} column(s), but €{{{}}} needs column {}", row_index + 1, row.len(), NAMED_ARGS[index], column + 1);
+                        std::process::exit(2);
+                    }
+                },
+                (None, None) => unreachable!("checked against the header above"),
+            }
+        }
+        invocations.push(args);
+    }
+    invocations
+}
+
+/// The `€{name}` that `--name <value>` or a name=value line matches, and the
+/// value if it came with the flag (`--name=value`).
+pub(crate) fn named_flag(arg: &str) -> Option<(&'static str, Option<&str>)> {
+    let flag = arg.strip_prefix("--")?;
+    let (name, value) = match flag.split_once('=') {
+        Some((name, value)) => (name, Some(value)),
+        None => (flag, None),
+    };
+    NAMED_ARGS.iter().find(|n| **n == name).map(|n| (*n, value))
+}
+
+/// The error for the first `€{name}` nothing gave a value for.
+pub(crate) fn missing_named(values: &[Option<String>]) -> Option<String> {
+    let index = values.iter().position(Option::is_none)?;
+    let name = NAMED_ARGS[index];
+    Some(if STDIN_FORMAT == "lines" && !EVERY_LINE {
+        format!("Error: No value for €{{{}}}\nUsage: Pass --{} <value>, or a {}=<value> line on stdin.", name, name, name)
+    } else if SKIP_HEADER {
+        format!("Error: No value for €{{{}}}\nUsage: Pass --{} <value>, or name a column '{}' in the header row.", name, name, name)
+    } else {
+        format!("Error: No value for €{{{}}}\nUsage: Pass --{} <value>.", name, name)
+    })
+}
+
+/// Reads the text of every `€file(...)`, `€dir(...)`, `€gitdiff` and
+/// `€gitlog(n)`, keyed like its marker in the pseudocode.
+pub(crate) fn read_files(args: &[String]) -> Result<Vec<(String, String)>, String> {
+    let mut files = Vec::new();
+    for &(index, pages) in FILES {
+        let key = match pages {
+            Some((first, last)) => format!("FILE_{}_PAGES_{}_{}", index, first, last),
+            None => format!("FILE_{}", index),
+        };
+        let path = std::path::Path::new(args[index - 1].trim());
+        let text = read_document(path, pages).map_err(|e| format!("€file({}): {}", index, e))?;
+        files.push((key, text));
+    }
+    for &(key, index, glob, max_bytes) in DIRS {
+        let dir = std::path::Path::new(args[index - 1].trim());
+        let bundle = read_dir_bundle(dir, glob, max_bytes).map_err(|e| format!("€dir({}): {}", index, e))?;
+        files.push((key.to_string(), bundle));
+    }
+    if !GIT.is_empty() && run_git(&["rev-parse", "--git-dir"]).is_err() {
+        return Err("€gitdiff and €gitlog(n) only work inside a git repository".into());
+    }
+    for &(key, commits) in GIT {
+        let text = if commits == 0 {
+            let diff = run_git(&["diff", "--cached", "--no-color", "--no-ext-diff"]).map_err(|e| format!("€gitdiff: {}", e))?;
+            if diff.is_empty() {
+                return Err("€gitdiff: nothing is staged; git add something first".into());
+            }
+            diff
+        } else {
+            let count = commits.to_string();
+            run_git(&["log", "-n", &count, "--no-color", "--date=short", "--format=%h %ad %an%n%s%n%b"])
+                .map_err(|e| format!("€gitlog({}): {}", commits, e))?
+        };
+        files.push((key.to_string(), text));
+    }
+    Ok(files)
+}
+
+/// Runs git in the current directory. Binary files only show up as "Binary
+/// files ... differ", and output past `GIT_MAX_BYTES` is cut off.
+pub(crate) fn run_git(args: &[&str]) -> Result<String, String> {
+    let output = std::process::Command::new("git")
+        .args(args)
+        .output()
+        .map_err(|e| format!("failed to run git: {}", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(stderr.lines().next().unwrap_or("git failed").to_string());
+    }
+    let text = String::from_utf8_lossy(&output.stdout);
+    let text = text.trim_end();
+    if text.len() <= GIT_MAX_BYTES {
+        return Ok(text.to_string());
+    }
+    let mut end = GIT_MAX_BYTES;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    Ok(format!("{}\n[cut off after {} of {} bytes]", &text[..end], end, text.len()))
+}
+
+/// The text files below `dir` matching `glob`, each under a `=== path ===`
+/// header, in path order. Files that would go past `max_bytes` are left out.
+pub(crate) fn read_dir_bundle(dir: &std::path::Path, glob: &str, max_bytes: usize) -> Result<String, String> {
+    if !dir.is_dir() {
+        return Err(format!("{} is not a directory", dir.display()));
+    }
+    let mut paths = Vec::new();
+    collect_files(dir, "", &mut paths).map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+    paths.sort();
+    let pattern: Vec<&str> = glob.split('/').collect();
+    let matching: Vec<&String> = paths
+        .iter()
+        .filter(|path| glob_match(&pattern, &path.split('/').collect::<Vec<_>>()))
+        .collect();
+    if matching.is_empty() {
+        return Err(format!("no files in {} match '{}'", dir.display(), glob));
+    }
+
+    let mut bundle = String::new();
+    for (i, path) in matching.iter().enumerate() {
+        let bytes = std::fs::read(dir.join(path)).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        // A NUL byte early on means it's not text
+        if bytes.iter().take(8000).any(|&b| b == 0) {
+            continue;
+        }
+        let entry = format!("=== {} ===\n{}\n\n", path, String::from_utf8_lossy(&bytes).trim_end());
+        if bundle.len() + entry.len() > max_bytes {
+            bundle.push_str(&format!("[{} more file(s) left out to stay under {} bytes]\n", matching.len() - i, max_bytes));
+            break;
+        }
+        bundle.push_str(&entry);
+    }
+    Ok(bundle.trim_end().to_string())
+}
+
+/// Relative paths (with `/`) of the files below `dir`; hidden entries and
+/// symlinks are skipped.
+pub(crate) fn collect_files(dir: &std::path::Path, prefix: &str, paths: &mut Vec<String>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir.join(prefix))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(dir, &path, paths)?;
+        } else if file_type.is_file() {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Matches path components against glob components; `**` spans any number of
+/// directories, `*` and `?` stay within one name.
+pub(crate) fn glob_match(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|i| glob_match(rest, &path[i..])),
+        Some((first, rest)) => match path.split_first() {
+            Some((name, path_rest)) => wildcard_match(first.as_bytes(), name.as_bytes()) && glob_match(rest, path_rest),
+            None => false,
+        },
+    }
+}
+
+pub(crate) fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| wildcard_match(rest, &name[i..])),
+        Some((b'?', rest)) => !name.is_empty() && wildcard_match(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && wildcard_match(rest, &name[1..]),
+    }
+}
+
+/// The text of a file: extracted from PDFs and DOCX files, as is otherwise.
+pub(crate) fn read_document(path: &std::path::Path, pages: Option<(u32, u32)>) -> Result<String, String> {
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if pages.is_some() && extension != "pdf" {
+        return Err(format!("pages= only works for PDFs, but {} isn't one", path.display()));
+    }
+    match extension.as_str() {
+        "pdf" => read_pdf(path, pages),
+        "docx" => read_docx(path),
+        _ => std::fs::read(path)
+            .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e)),
+    }
+}
+
+#[cfg(feature = "documents")]
+pub(crate) fn read_pdf(path: &std::path::Path, pages: Option<(u32, u32)>) -> Result<String, String> {
+    let failed = |e: pdf_extract::OutputError| format!("Failed to extract text from {}: {}", path.display(), e);
+    let Some((first, last)) = pages else {
+        return pdf_extract::extract_text(path).map_err(failed);
+    };
+    let all = pdf_extract::extract_text_by_pages(path).map_err(failed)?;
+    if first as usize > all.len() {
+        return Err(format!("{} has {} page(s), so pages={}-{} is empty", path.display(), all.len(), first, last));
+    }
+    let last = (last as usize).min(all.len());
+    Ok(all[first as usize - 1..last].join("\n"))
+}
+
+/// The paragraphs of `word/document.xml`, one per line.
+#[cfg(feature = "documents")]
+pub(crate) fn read_docx(path: &std::path::Path) -> Result<String, String> {
+    use std::io::Read;
+
+    let failed = |e: &dyn std::fmt::Display| format!("Failed to read {} as DOCX: {}", path.display(), e);
+    let file = std::fs::File::open(path).map_err(|e| failed(&e))?;
+    let mut archive = zip::ZipArchive::new(file).map_err(|e| failed(&e))?;
+    let mut xml = String::new();
+    archive
+        .by_name("word/document.xml")
+        .map_err(|e| failed(&e))?
+        .read_to_string(&mut xml)
+        .map_err(|e| failed(&e))?;
+
+    let mut text = String::new();
+    let mut rest = xml.as_str();
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('>') else {
+            break;
+        };
+        let tag = &rest[start + 1..start + end];
+        match tag.split_whitespace().next().unwrap_or("").trim_end_matches('/') {
+            "/w:p" | "w:br" => text.push('\n'),
+            "w:tab" => text.push('\t'),
+            _ => {}
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(text
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&"))
+}
+
+#[cfg(not(feature = "documents"))]
+pub(crate) fn read_pdf(_path: &std::path::Path, _pages: Option<(u32, u32)>) -> Result<String, String> {
+    Err("this program was compiled without PDF support".into())
+}
+
+#[cfg(not(feature = "documents"))]
+pub(crate) fn read_docx(_path: &std::path::Path) -> Result<String, String> {
+    Err("this program was compiled without DOCX support".into())
+}
+
+/// Checks annotated placeholder values and normalizes them, so the model never
+/// sees a value of the wrong kind.
+pub(crate) fn coerce_args(mut args: Vec<String>) -> Result<Vec<String>, String> {
+    for &(index, ty) in ARG_TYPES {
+        let value = &args[index - 1];
+        let coerced = match ty {
+            "int" => value
+                .trim()
+                .parse::<i64>()
+                .map(|n| n.to_string())
+                .map_err(|_| format!("€{} must be an integer, got '{}'", index, value))?,
+            // As written, so 1e300 doesn't turn into 301 digits
+            "number" if value.trim().parse::<f64>().is_ok_and(f64::is_finite) => value.trim().to_string(),
+            "number" => return Err(format!("€{} must be a number, got '{}'", index, value)),
+            "path" => {
+                let path = value.trim();
+                if !std::path::Path::new(path).exists() {
+                    return Err(format!("€{} must be an existing path, but '{}' does not exist", index, path));
+                }
+                path.to_string()
+            }
+            "json" => serde_json::from_str::<serde_json::Value>(value)
+                .map(|json| json.to_string())
+                .map_err(|e| format!("€{} must be valid JSON ({}), got '{}'", index, e, value))?,
+            _ => value.clone(),
+        };
+        args[index - 1] = coerced;
+    }
+    Ok(args)
+}
+
+/// Splits CSV/TSV input into rows of fields. Fields may be wrapped in double
+/// quotes to contain delimiters or newlines; `""` inside quotes is a literal quote.
+pub(crate) fn parse_delimited(input: &str, delimiter: char) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = input.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        if in_quotes {
+            if ch == '"' {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    in_quotes = false;
+                }
+            } else {
+                field.push(ch);
+            }
+        } else if ch == '"' && field.is_empty() {
+            in_quotes = true;
+        } else if ch == delimiter {
+            row.push(std::mem::take(&mut field));
+        } else if ch == '\n' || ch == '\r' {
+            if ch == '\r' && chars.peek() == Some(&'\n') {
+                chars.next();
+            }
+            row.push(std::mem::take(&mut field));
+            // Skip blank lines
+            if row.len() > 1 || !row[0].is_empty() {
+                rows.push(std::mem::take(&mut row));
+            } else {
+                row.clear();
+            }
+        } else {
+            field.push(ch);
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows
+}
+
+/// `--output csv`: the rows of `answer`, checked for having every one of
+/// `columns` and quoted where they have to be, one per line. Header lines the
+/// model added are left out, and so is whatever it said around a code fence.
+pub(crate) fn csv_rows(answer: &str, columns: &[&str]) -> Result<String, String> {
+    let is_fence = |line: &&str| line.trim_start().starts_with("```");
+    let lines: Vec<&str> = match answer.lines().position(|line| is_fence(&line)) {
+        Some(fence) => answer.lines().skip(fence + 1).take_while(|line| !is_fence(line)).collect(),
+        None => answer.lines().collect(),
+    };
+    let text: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+    let mut rows = Vec::new();
+    for row in parse_delimited(&text, ',') {
+        let row: Vec<&str> = row.iter().map(|field| field.trim()).collect();
+        let is_header = row.len() == columns.len() && row.iter().zip(columns).all(|(field, column)| field.eq_ignore_ascii_case(column));
+        if is_header {
+            continue;
+        }
+        if row.len() != columns.len() {
+            return Err(format!(
+                "The answer has a row with {} field(s) instead of the {} columns {}: {}",
+                row.len(),
+                columns.len(),
+                columns.join(","),
+                row.join(",")
+            ));
+        }
+        rows.push(csv_line(row.into_iter()));
+    }
+    Ok(rows.join("\n"))
+}
+
+/// One line of CSV, each field in quotes if it has to be.
+pub(crate) fn csv_line<'a>(fields: impl Iterator<Item = &'a str>) -> String {
+    fields
+        .map(|field| {
+            if field.contains([',', '"', '\n', '\r']) || field.trim() != field {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Replaces `{ARG_n}` markers with the n-th (1-based) argument, `{ALL_ARGS}`
+/// with every stdin line (the last argument, when the program has `€*`),
+/// `{SECRET_NAME}` markers with the secret and `{ENV_NAME}` markers with the
+/// environment variable in a single pass, so values that happen to contain
+/// markers are left alone.
+pub(crate) fn fill_placeholders(template: &str, args: &[String], secrets: &[(&str, String)], files: &[(String, String)]) -> String {
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let marker = &rest[start + 1..];
+        let value = marker.find('}').and_then(|end| {
+            let key = &marker[..end];
+            let value = if let Some(index) = key.strip_prefix("ARG_").filter(|i| i.bytes().all(|b| b.is_ascii_digit())) {
+                index
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|index| index.checked_sub(1))
+                    .and_then(|index| args.get(index))
+            } else if key == "ALL_ARGS" {
+                args.last().filter(|_| ALL_ARGS)
+            } else if let Some(name) = key.strip_prefix("NAMED_") {
+                // After the numbered placeholders' values
+                let max_arg = REQUIRED_ARGS.iter().max().copied().unwrap_or(0);
+                NAMED_ARGS.iter().position(|n| *n == name).and_then(|index| args.get(max_arg + index))
+            } else if let Some(name) = key.strip_prefix("SECRET_") {
+                secrets.iter().find(|(secret, _)| *secret == name).map(|(_, value)| value)
+            } else if let Some(name) = key.strip_prefix("ENV_") {
+                ENV_VALUES.get().into_iter().flatten().find(|(var, _)| *var == name).map(|(_, value)| value)
+            } else if ["FILE_", "DIR_", "GIT_", "VAR_"].iter().any(|prefix| key.starts_with(prefix)) {
+                files.iter().find(|(file, _)| file == key).map(|(_, text)| text)
+            } else {
+                None
+            };
+            value.map(|value| (value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                result.push_str(value);
+                rest = &marker[end + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = marker;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+pub(crate) fn json_escape(s: &str) -> String {
+    let quoted = serde_json::to_string(s).unwrap_or_default();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+pub(crate) async fn deliver_to_sinks(output: &str, model: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if SINKS.is_empty() {
+        return Ok(());
+    }
+    let client = http_client();
+    for (kind, url) in SINKS {
+        if *kind == "smtp" {
+            send_mail(url, output).await?;
+            continue;
+        }
+        let body = match SINK_TEMPLATE {
+            Some(template) => template
+                .replace("€output", &json_escape(output))
+                .replace("€model", &json_escape(model)),
+            None if *kind == "slack" => serde_json::json!({ "text": output }).to_string(),
+            None => serde_json::json!({ "model": model, "output": output }).to_string(),
+        };
+        let response = client
+            .post(*url)
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(format!("{} sink {} responded with {}", kind, url, response.status()).into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "smtp")]
+pub(crate) async fn send_mail(url: &str, output: &str) -> Result<(), Box<dyn std::error::Error>> {
+    use lettre::transport::smtp::authentication::Credentials;
+    use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+    let url = reqwest::Url::parse(url)?;
+    let host = url.host_str().ok_or("SMTP sink is missing a host")?;
+    let mut recipients = Vec::new();
+    let mut from = None;
+    let mut subject = "€first_line".to_string();
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "to" => recipients.push(value.to_string()),
+            "from" => from = Some(value.to_string()),
+            "subject" => subject = value.to_string(),
+            _ => {}
+        }
+    }
+
+    let username = std::env::var("SMTP_USERNAME").ok();
+    let password = std::env::var("SMTP_PASSWORD").ok();
+    let from = from
+        .or_else(|| username.clone())
+        .ok_or("SMTP sink needs a sender: add &from=... or set SMTP_USERNAME")?;
+    let first_line = output.lines().map(str::trim).find(|l| !l.is_empty()).unwrap_or("");
+    let subject = subject.replace("€first_line", first_line);
+
+    let mut message = Message::builder().from(from.parse()?).subject(subject);
+    for recipient in &recipients {
+        message = message.to(recipient.parse()?);
+    }
+    let email = message.body(output.to_string())?;
+
+    let mut transport = if url.scheme() == "smtps" {
+        AsyncSmtpTransport::<Tokio1Executor>::relay(host)?
+    } else {
+        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?
+    };
+    if let Some(port) = url.port() {
+        transport = transport.port(port);
+    }
+    if let (Some(username), Some(password)) = (username, password) {
+        transport = transport.credentials(Credentials::new(username, password));
+    }
+    transport.build().send(email).await?;
+    Ok(())
+}
+
+#[cfg(not(feature = "smtp"))]
+pub(crate) async fn send_mail(_url: &str, _output: &str) -> Result<(), Box<dyn std::error::Error>> {
+    Err("this program was compiled without SMTP support".into())
+}
+
+/// The transcript for `--input audio`, from a file or a fresh recording.
+pub(crate) async fn read_audio_input(api_key: &str, audio_file: Option<String>, record: bool) -> Result<String, Box<dyn std::error::Error>> {
+    if !record {
+        let path = audio_file.unwrap_or_default();
+        return transcribe(api_key, std::path::Path::new(&path)).await;
+    }
+    let path = record_audio()?;
+    let transcript = transcribe(api_key, &path).await;
+    let _ = std::fs::remove_file(&path);
+    transcript
+}
+
+/// Records from the default microphone with SoX until Enter is pressed.
+pub(crate) fn record_audio() -> Result<std::path::PathBuf, String> {
+    use std::process::{Command, Stdio};
+
+    let path = paths::temp_path(NAME, ".wav");
+    let mut child = Command::new("sox")
+        .args(["-q", "-d", "-c", "1", "-r", "16000"])
+        .arg(&path)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| format!("Recording needs SoX ('sox' on PATH): {}", e))?;
+    eprintln!("Recording ... press Enter to stop.");
+    let mut line = String::new();
+    let _ = std::io::stdin().read_line(&mut line);
+    // SIGINT rather than kill(), so sox gets to finish the WAV header
+    let _ = Command::new("kill").args(["-INT", &child.id().to_string()]).status();
+    let status = child.wait().map_err(|e| format!("Recording failed: {}", e))?;
+    match std::fs::metadata(&path) {
+        Ok(meta) if meta.len() > 0 => Ok(path),
+        _ => Err(format!("Recording failed ({})", status)),
+    }
+}
@@ -0,0 +1,2314 @@
+//! A program compiled by matthiashihic. `config` is what the compiler put in;
+//! `auth` finds the keys, `provider` talks to the model when `broker` says it
+//! may, `cache` remembers what it said, `stream` shows the answers as they
+//! arrive and `io` reads the input and delivers the output. `socket` takes
+//! the input from other programs instead, and `background` hands the question
+//! off to be answered later; `access` logs what `--serve` answered, and
+//! `trace` what was asked and said for `--trace`. What's left here runs the
+//! program.
+
+#[cfg(feature = "serve")]
+mod access;
+mod auth;
+mod background;
+mod broker;
+mod cache;
+mod config;
+mod io;
+mod paths;
+mod provider;
+mod socket;
+mod stream;
+mod trace;
+#[cfg(feature = "types")]
+mod types;
+
+use std::io::Write;
+
+#[cfg(feature = "serve")]
+use access::*;
+use auth::*;
+use background::*;
+use broker::*;
+use cache::*;
+use config::*;
+use io::*;
+use provider::*;
+use socket::*;
+use stream::*;
+use trace::*;
+
+/// `--arm A|B`: run this arm instead of letting the request ID pick one.
+static ARM: std::sync::OnceLock<&str> = std::sync::OnceLock::new();
+
+/// When this run started, for the history.
+static STARTED: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+
+/// `--record-fallback`: where answers are appended for a `--fallback` pack.
+static RECORD_FALLBACK: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+
+/// `--access-log`: the file `--serve` logs its requests to, instead of stdout.
+static ACCESS_LOG: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+/// `--access-log-format text`: key=value lines instead of JSON.
+static ACCESS_LOG_TEXT: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+/// `--access-log-redact`: the fields the access log blanks out.
+static ACCESS_LOG_REDACT: std::sync::OnceLock<Vec<String>> = std::sync::OnceLock::new();
+/// The fields `--access-log-redact` can blank out.
+const REDACTABLE: &[&str] = &["request_id", "caller", "args_hash", "error"];
+
+/// `--check`: answers are held to their `better be`s.
+static CHECK: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+/// `--check`: how many `better be`s were checked, and how many of those failed.
+static CHECKED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+static CHECKS_FAILED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+/// `--parallel`: lines a stream filter or a `for every line` block works on at once.
+static PARALLEL: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(DEFAULT_PARALLEL);
+/// Exit code of a `--check` run with an answer that isn't what it better be.
+const CHECK_FAILED_EXIT_CODE: i32 = 4;
+
+#[tokio::main]
+async fn main() {
+    STARTED.get_or_init(std::time::Instant::now);
+    let mut audio_file: Option<String> = None;
+    let mut record = false;
+    let mut listen = DEFAULT_LISTEN.to_string();
+    let mut socket = LISTEN_SOCKET.map(str::to_string);
+    let mut no_banner = false;
+    let mut doctor = false;
+    let mut poll: Option<String> = None;
+    let mut wait = false;
+    let mut jobs = false;
+    let mut trace: Option<String> = None;
+    let mut trace_redact: Vec<String> = Vec::new();
+    let mut named: Vec<(&str, String)> = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if let Some((name, value)) = named_flag(&arg) {
+            let value = match value.map(str::to_string).or_else(|| args.next()) {
+                Some(value) => value,
+                None => {
+                    eprintln!("Error: --{} requires a value for €{{{}}}", name, name);
+                    std::process::exit(2);
+                }
+            };
+            named.retain(|(n, _)| *n != name);
+            named.push((name, value));
+            continue;
+        }
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print_help();
+                return;
+            }
+            "--hihic-info" => {
+                let mut info: serde_json::Value = serde_json::from_str(METADATA).expect("Invalid embedded metadata");
+                if KEY_EXPIRES.is_some() {
+                    info["key_expired"] = serde_json::Value::Bool(key_expired());
+                }
+                println!("{}", serde_json::to_string_pretty(&info).unwrap_or_default());
+                return;
+            }
+            "--record" if AUDIO_INPUT => record = true,
+            "--doctor" => doctor = true,
+            "--no-banner" if BANNER.is_some() => no_banner = true,
+            "--record-fallback" if !LOCKED_DOWN => match args.next() {
+                Some(path) => {
+                    let _ = RECORD_FALLBACK.set(path);
+                }
+                None => {
+                    eprintln!("Error: --record-fallback requires a file");
+                    std::process::exit(2);
+                }
+            },
+            "--trace" if !LOCKED_DOWN && !BACKGROUND => match args.next() {
+                Some(dir) => trace = Some(dir),
+                None => {
+                    eprintln!("Error: --trace requires a directory");
+                    std::process::exit(2);
+                }
+            },
+            "--trace-redact" if !LOCKED_DOWN && !BACKGROUND => {
+                trace_redact = args.next().unwrap_or_default().split(',').map(|field| field.trim().to_string()).collect();
+                if let Some(field) = trace_redact.iter().find(|field| !TRACEABLE.contains(&field.as_str())) {
+                    eprintln!("Error: --trace-redact can't blank out '{}'; it takes {}", field, TRACEABLE.join(", "));
+                    std::process::exit(2);
+                }
+            }
+            "--check" if all_steps().any(|step| step.expect.is_some()) && !TUI && !SERVE && LISTEN_SOCKET.is_none() => {
+                CHECK.store(true, std::sync::atomic::Ordering::Relaxed)
+            }
+            "--poll" if BACKGROUND => match args.next() {
+                Some(id) => poll = Some(id),
+                None => {
+                    eprintln!("Error: --poll requires the job ID the program printed");
+                    std::process::exit(2);
+                }
+            },
+            "--wait" if BACKGROUND => wait = true,
+            "--jobs" if BACKGROUND => jobs = true,
+            "--show-all" if ENSEMBLE.is_some() && !TUI => SHOW_ALL.store(true, std::sync::atomic::Ordering::Relaxed),
+            "--trailer" if !TUI && !SERVE && LISTEN_SOCKET.is_none() => match args.next().as_deref() {
+                Some("json") => TRAILER.store(true, std::sync::atomic::Ordering::Relaxed),
+                _ => {
+                    eprintln!("Error: --trailer takes 'json'");
+                    std::process::exit(2);
+                }
+            },
+            "--typewriter" if !TUI && !SERVE && LISTEN_SOCKET.is_none() => match args.next().and_then(|n| n.parse().ok()) {
+                Some(rate) if rate > 0 => TYPEWRITER.store(rate, std::sync::atomic::Ordering::Relaxed),
+                _ => {
+                    eprintln!("Error: --typewriter requires a number of characters per second, at least 1");
+                    std::process::exit(2);
+                }
+            },
+            "--newline" if !TUI && !SERVE && LISTEN_SOCKET.is_none() => match args.next().as_deref() {
+                Some("lf") => CRLF.store(false, std::sync::atomic::Ordering::Relaxed),
+                Some("crlf") => CRLF.store(true, std::sync::atomic::Ordering::Relaxed),
+                _ => {
+                    eprintln!("Error: --newline takes lf or crlf");
+                    std::process::exit(2);
+                }
+            },
+            "-n" | "--no-final-newline" if !TUI && !SERVE && LISTEN_SOCKET.is_none() => {
+                NO_FINAL_NEWLINE.store(true, std::sync::atomic::Ordering::Relaxed)
+            }
+            "--encoding" if !TUI && !SERVE && LISTEN_SOCKET.is_none() => match args.next().as_deref().and_then(Encoding::named) {
+                Some(encoding) => {
+                    let _ = ENCODING.set(encoding);
+                }
+                None => {
+                    let names: Vec<&str> = ENCODINGS.iter().map(|(name, _)| *name).collect();
+                    eprintln!("Error: --encoding takes {}", names.join(", "));
+                    std::process::exit(2);
+                }
+            },
+            "--arm" if EXPERIMENT.is_some() => match args.next().as_deref() {
+                Some("A") => {
+                    let _ = ARM.set("A");
+                }
+                Some("B") => {
+                    let _ = ARM.set("B");
+                }
+                _ => {
+                    eprintln!("Error: --arm takes A or B");
+                    std::process::exit(2);
+                }
+            },
+            "--hihic-routed" if SERVE => exit_with_parent(),
+            "--listen" if SERVE => match args.next() {
+                Some(addr) => listen = addr,
+                None => {
+                    eprintln!("Error: --listen requires an address like 127.0.0.1:8080");
+                    std::process::exit(2);
+                }
+            },
+            "--access-log" if SERVE => match args.next() {
+                Some(path) => {
+                    let _ = ACCESS_LOG.set(path);
+                }
+                None => {
+                    eprintln!("Error: --access-log requires a file");
+                    std::process::exit(2);
+                }
+            },
+            "--access-log-format" if SERVE => match args.next().as_deref() {
+                Some("json") => ACCESS_LOG_TEXT.store(false, std::sync::atomic::Ordering::Relaxed),
+                Some("text") => ACCESS_LOG_TEXT.store(true, std::sync::atomic::Ordering::Relaxed),
+                _ => {
+                    eprintln!("Error: --access-log-format takes json or text");
+                    std::process::exit(2);
+                }
+            },
+            "--access-log-redact" if SERVE => {
+                let fields: Vec<String> = args.next().unwrap_or_default().split(',').map(|field| field.trim().to_string()).collect();
+                if let Some(field) = fields.iter().find(|field| !REDACTABLE.contains(&field.as_str())) {
+                    eprintln!("Error: --access-log-redact can't blank out '{}'; it takes {}", field, REDACTABLE.join(", "));
+                    std::process::exit(2);
+                }
+                let _ = ACCESS_LOG_REDACT.set(fields);
+            }
+            "--listen-socket" if LISTEN_SOCKET.is_some() => match args.next() {
+                Some(path) => socket = Some(path),
+                None => {
+                    eprintln!("Error: --listen-socket requires a path");
+                    std::process::exit(2);
+                }
+            },
+            "--parallel" if STREAM_FILTER || EVERY_LINE => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n) if n > 0 => PARALLEL.store(n, std::sync::atomic::Ordering::Relaxed),
+                _ => {
+                    eprintln!("Error: --parallel requires a number of lines, at least 1");
+                    std::process::exit(2);
+                }
+            },
+            path if AUDIO_INPUT && !path.starts_with('-') && audio_file.is_none() => audio_file = Some(path.to_string()),
+            other => {
+                eprintln!("Error: Unknown argument: {}\nRun with --help for usage.", other);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    if doctor {
+        std::process::exit(run_doctor().await);
+    }
+
+    if jobs {
+        list_jobs();
+        return;
+    }
+
+    if wait && poll.is_none() {
+        eprintln!("Error: --wait waits for a --poll.\nRun with --help for usage.");
+        std::process::exit(2);
+    }
+
+    if let (Some(banner), false) = (BANNER, no_banner) {
+        show_banner(banner);
+    }
+
+    if AUDIO_INPUT && audio_file.is_some() == record {
+        eprintln!("Error: Pass either an audio file or --record.\nRun with --help for usage.");
+        std::process::exit(2);
+    }
+
+    let api_key = api_key();
+    
+    let model = MODEL;
+    let secrets = read_secrets();
+    read_env_vars();
+    match (trace, trace_redact.is_empty()) {
+        (Some(dir), _) => match Trace::open(&dir, trace_redact, &secrets) {
+            Ok(trace) => {
+                let _ = TRACE.set(trace);
+            }
+            Err(e) => fail(&e, 1),
+        },
+        (None, false) => {
+            eprintln!("Error: --trace-redact goes with --trace <dir>.\nRun with --help for usage.");
+            std::process::exit(2);
+        }
+        (None, true) => {}
+    }
+
+    if TUI {
+        if let Err(e) = run_tui(&api_key, &secrets).await {
+            eprintln!("Error: {}", redact(&e, &secrets));
+            eprintln!("Request ID: {}", request_id());
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if SERVE {
+        if let Err(e) = serve(api_key, secrets, &listen).await {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(path) = &socket {
+        if let Err(e) = listen_socket(api_key, secrets, path).await {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if STREAM_FILTER {
+        match run_stream_filter(&api_key, &secrets, PARALLEL.load(std::sync::atomic::Ordering::Relaxed)).await {
+            Ok(lines) => {
+                record_run(started(), None, None, usage());
+                write_trailer(None);
+                if NOTIFY {
+                    notify(&format!("{} finished", NAME), &format!("{} line(s) filtered", lines));
+                }
+                finish_check();
+            }
+            Err(e) => fail(&e, 1),
+        }
+        return;
+    }
+
+    if let Some(id) = &poll {
+        run_poll(&api_key, id, wait, &secrets).await;
+        return;
+    }
+
+    if BACKGROUND {
+        let args = read_invocations(&named).into_iter().next().unwrap_or_default();
+        let (args, files) = match prepare_args(args) {
+            Ok(prepared) => prepared,
+            Err(e) => fail(&redact(&e, &secrets), 2),
+        };
+        let step = &steps()[0];
+        let pseudocode = fill_placeholders(step.pseudocode, &args, &secrets, &files);
+        match submit_job(&api_key, step, &pseudocode).await {
+            Ok(id) => {
+                if let Err(e) = Echo::Stdout.line(&id).await {
+                    fail(&e, 1);
+                }
+                flush_stdout();
+                eprintln!("Submitted; get the answer with {} --poll {}", NAME, id);
+            }
+            Err(e) => fail(&redact(&e, &secrets), 1),
+        }
+        return;
+    }
+
+    // One invocation per stdin record (a single one unless stdin is CSV/TSV)
+    let invocations = if AUDIO_INPUT {
+        match read_audio_input(&api_key, audio_file, record).await {
+            Ok(transcript) => vec![vec![transcript]],
+            Err(e) => fail(&e.to_string(), 1),
+        }
+    } else {
+        read_invocations(&named)
+    };
+    // A refusal has to leave stdout empty, so the answer waits until it's known not to be one
+    // So does CSV, until it's checked and quoted
+    let echo = if ECHO_STDOUT && !FAIL_ON_REFUSAL && CSV_COLUMNS.is_none() { Echo::Stdout } else { Echo::Off };
+    let mut last_output = String::new();
+    let mut output_chars = 0;
+    // (statements, answer) pairs so far, replayed to every model step
+    let mut history: Vec<(String, String)> = Vec::new();
+    for (invocation, args) in invocations.into_iter().enumerate() {
+        let (args, files) = match prepare_args(args) {
+            Ok(prepared) => prepared,
+            Err(e) => fail(&redact(&e, &secrets), 2),
+        };
+        // Without remember!, every record starts a conversation of its own
+        if !REMEMBER {
+            history.clear();
+        }
+        let result = tokio::select! {
+            result = run_invocation(&api_key, &args, &secrets, &files, &mut history, echo) => result,
+            // Dropping the invocation aborts the request it's waiting for
+            _ = stdout_closed(), if ECHO_STDOUT => Err(STDOUT_CLOSED.to_string()),
+        };
+        let mut output = match result {
+            Ok(output) => output,
+            Err(e) if e.starts_with(REFUSED) => fail(&redact(&e, &secrets), REFUSAL_EXIT_CODE),
+            Err(e) => fail(&redact(&e, &secrets), 1),
+        };
+        if let Some(mark) = watermark(model) {
+            if let Err(e) = echo.line(&mark).await {
+                fail(&e, 1);
+            }
+            output = format!("{}\n{}", output, mark);
+        }
+        if let Some(columns) = CSV_COLUMNS {
+            let mut repairs = 0;
+            let rows = loop {
+                match csv_rows(&output, columns) {
+                    Ok(rows) => break rows,
+                    Err(e) if repairs < REPAIR_ATTEMPTS => {
+                        repairs += 1;
+                        eprintln!("Warning: {}; asking for a repair ({}/{})", redact(&e, &secrets), repairs, REPAIR_ATTEMPTS);
+                        // Every answer so far is the CSV, so all of it comes back fixed
+                        let problem = format!("isn't the CSV it should be: {}", e);
+                        output = match repair(&api_key, model, &history, &problem, None, None).await {
+                            Ok(repaired) => repaired,
+                            Err(e) => fail(&redact(&e, &secrets), 1),
+                        };
+                        history.push((repair_prompt(&problem), output.clone()));
+                    }
+                    Err(e) => fail(&redact(&e, &secrets), 1),
+                }
+            };
+            let header = csv_line(columns.iter().copied());
+            // The header once, however many stdin records there are
+            if ECHO_STDOUT && CSV_HEADER && invocation == 0 {
+                if let Err(e) = Echo::Stdout.line(&header).await {
+                    fail(&e, 1);
+                }
+            }
+            if ECHO_STDOUT && !rows.is_empty() {
+                if let Err(e) = Echo::Stdout.line(&rows).await {
+                    fail(&e, 1);
+                }
+            }
+            output = if CSV_HEADER { format!("{}\n{}", header, rows).trim_end().to_string() } else { rows };
+        } else if ECHO_STDOUT && FAIL_ON_REFUSAL {
+            if let Err(e) = Echo::Stdout.line(&output).await {
+                fail(&e, 1);
+            }
+        }
+
+        if let Some(path) = IMAGE_OUTPUT {
+            match generate_image(&api_key, &output, path).await {
+                Ok(()) => eprintln!("Wrote {}", path),
+                Err(e) => fail(&redact(&e.to_string(), &secrets), 1),
+            }
+        }
+
+        if let Err(e) = deliver_to_sinks(&output, model).await {
+            fail(&redact(&e.to_string(), &secrets), 1);
+        }
+        output_chars += output.chars().count();
+        last_output = output;
+    }
+    flush_stdout();
+    record_run(started(), Some(output_chars), None, usage());
+    write_trailer(None);
+    if NOTIFY {
+        notify(&format!("{} finished", NAME), last_output.lines().find(|line| !line.trim().is_empty()).unwrap_or(""));
+    }
+    finish_check();
+}
+
+/// `--check`: holds `answer` to what the step asked as `pseudocode` better be,
+/// and says so on stderr if it isn't. Case, runs of whitespace and a final
+/// period don't count.
+fn check_answer(pseudocode: &str, expected: &str, answer: &str, secrets: &[(&str, String)]) {
+    let normalized = |text: &str| text.split_whitespace().collect::<Vec<_>>().join(" ").trim_end_matches('.').to_lowercase();
+    CHECKED.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    if normalized(answer) == normalized(expected) {
+        return;
+    }
+    CHECKS_FAILED.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    flush_stdout();
+    let asked = pseudocode.lines().next().unwrap_or("");
+    let message = format!("Check failed: \"{}\" better be \"{}\", but was \"{}\"", asked, expected, answer.trim());
+    eprintln!("{}", redact(&message, secrets));
+}
+
+/// `--check`: says how the `better be`s went, and exits with
+/// CHECK_FAILED_EXIT_CODE if any of them failed.
+fn finish_check() {
+    if !CHECK.load(std::sync::atomic::Ordering::Relaxed) {
+        return;
+    }
+    let checked = CHECKED.load(std::sync::atomic::Ordering::Relaxed);
+    let failed = CHECKS_FAILED.load(std::sync::atomic::Ordering::Relaxed);
+    if failed > 0 {
+        eprintln!("{} of {} check(s) failed", failed, checked);
+        std::process::exit(CHECK_FAILED_EXIT_CODE);
+    }
+    eprintln!("{} check(s) passed", checked);
+}
+
+/// Prints the error (with --notify, to the desktop as well) and exits.
+fn fail(message: &str, code: i32) -> ! {
+    flush_stdout();
+    if message == STDOUT_CLOSED {
+        // Whoever read our output (`| head`) has all they wanted
+        std::process::exit(0);
+    }
+    record_run(started(), None, Some(message), usage());
+    write_trailer(Some(message));
+    eprintln!("Error: {}", message);
+    eprintln!("Request ID: {}", request_id());
+    if NOTIFY {
+        notify(&format!("{} failed", NAME), message.lines().next().unwrap_or(""));
+    }
+    std::process::exit(code);
+}
+
+/// Shows a desktop notification with the platform's own tool: osascript on
+/// macOS, PowerShell on Windows, notify-send everywhere else.
+fn notify(title: &str, body: &str) {
+    use std::process::{Command, Stdio};
+
+    let body: String = body.chars().take(200).collect();
+    let result = if cfg!(target_os = "macos") {
+        // Passed as arguments, so nothing needs AppleScript quoting
+        Command::new("osascript")
+            .args(["-e", "on run argv", "-e", "display notification (item 2 of argv) with title (item 1 of argv)", "-e", "end run"])
+            .args([title, body.as_str()])
+            .stdout(Stdio::null())
+            .status()
+    } else if cfg!(windows) {
+        // The balloon disappears with PowerShell, which outlives this program
+        Command::new("powershell")
+            .args([
+                "-NoProfile",
+                "-Command",
+                "Add-Type -AssemblyName System.Windows.Forms; $n = New-Object System.Windows.Forms.NotifyIcon; $n.Icon = [System.Drawing.SystemIcons]::Information; $n.Visible = $true; $n.ShowBalloonTip(10000, $env:HIHI_NOTIFY_TITLE, $env:HIHI_NOTIFY_BODY, 'Info'); Start-Sleep -Seconds 10; $n.Dispose()",
+            ])
+            .env("HIHI_NOTIFY_TITLE", title)
+            .env("HIHI_NOTIFY_BODY", &body)
+            .stdout(Stdio::null())
+            .spawn()
+            .map(|_| std::process::ExitStatus::default())
+    } else {
+        Command::new("notify-send").args(["--app-name", NAME, title, body.as_str()]).status()
+    };
+    match result {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("Warning: Desktop notification failed ({})", status),
+        Err(e) => eprintln!("Warning: Desktop notification failed: {}", e),
+    }
+}
+
+fn started() -> std::time::Instant {
+    *STARTED.get_or_init(std::time::Instant::now)
+}
+
+/// The experiment arm of this run (or of this `--serve` request), if the program
+/// runs an experiment. The request ID picks it, so the same ID always gets the
+/// same arm, unless --arm says otherwise.
+fn arm() -> Option<&'static str> {
+    let (split, _) = EXPERIMENT?;
+    if let Some(arm) = ARM.get() {
+        return Some(arm);
+    }
+    let hash = fnv1a(&request_id());
+    Some(if hash % 100 < u64::from(split) { "A" } else { "B" })
+}
+
+/// FNV-1a, which unlike std's hashers is the same in every build.
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3))
+}
+
+/// Prints the `--banner` unless this user has seen it already, which a file in
+/// their config directory remembers. A changed banner is shown again.
+fn show_banner(banner: &str) {
+    let name: String = NAME.chars().map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect();
+    let seen = paths::config_dir().map(|dir| dir.join("banners").join(format!("{}-{:016x}", name, fnv1a(banner))));
+    if seen.as_ref().is_some_and(|path| path.exists()) {
+        return;
+    }
+    eprintln!("{}", banner);
+    eprintln!();
+    let Some(path) = seen else {
+        return;
+    };
+    let written = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(&path, format!("{}\n", request_id())));
+    if let Err(e) = written {
+        eprintln!("Warning: Failed to remember that the banner was shown ({}): {}", path.display(), e);
+    }
+}
+
+/// The plan of this run's arm.
+fn steps() -> &'static [Step] {
+    match (arm(), EXPERIMENT) {
+        (Some("B"), Some((_, steps))) => steps,
+        _ => STEPS,
+    }
+}
+
+impl Step {
+    /// A `shell "..."` step, whose output is `€shell` rather than an answer:
+    /// it isn't shown, and the model doesn't see it unless a step says `€shell`.
+    fn is_shell(&self) -> bool {
+        matches!(self.local, Some((SHELL, _)))
+    }
+}
+
+/// Every step either arm may run.
+fn all_steps() -> impl Iterator<Item = &'static Step> {
+    STEPS.iter().chain(EXPERIMENT.iter().flat_map(|(_, steps)| steps.iter()))
+}
+
+/// Appends a run (or a `--serve` request) of a program with an experiment to
+/// the history, with its arm and how it went. Programs without one keep no
+/// history.
+fn record_run(started: std::time::Instant, output_chars: Option<usize>, error: Option<&str>, usage: Option<(u64, u64)>) {
+    let (Some(arm), Some(path)) = (arm(), paths::history_file()) else {
+        return;
+    };
+    let time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut record = serde_json::json!({
+        "time": time,
+        "program": NAME,
+        "version": VERSION,
+        "request_id": request_id(),
+        "arm": arm,
+        "ok": error.is_none(),
+        "duration_ms": started.elapsed().as_millis() as u64,
+    });
+    if let Some(error) = error {
+        record["error"] = serde_json::json!(error);
+    }
+    if let Some(chars) = output_chars {
+        record["output_chars"] = serde_json::json!(chars);
+    }
+    // Flat, so the compiler reads it without a JSON library
+    if let Some((prompt, completion)) = usage {
+        record["prompt_tokens"] = serde_json::json!(prompt);
+        record["completion_tokens"] = serde_json::json!(completion);
+    }
+    let written = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::OpenOptions::new().create(true).append(true).open(&path))
+        .and_then(|mut file| writeln!(file, "{}", record));
+    if let Err(e) = written {
+        eprintln!("Warning: Failed to record the run in {}: {}", path.display(), e);
+    }
+}
+
+/// Runs every step for one set of placeholder values, continuing `history`,
+/// and returns the answers it added (translated, if the program says so). When
+/// the model is unavailable, the fallback pack answers the inputs it knows.
+async fn run_invocation(
+    api_key: &str,
+    args: &[String],
+    secrets: &[(&str, String)],
+    files: &[(String, String)],
+    history: &mut Vec<(String, String)>,
+    echo: Echo<'_>,
+) -> Result<String, String> {
+    let start = history.len();
+    let error = match run_steps(api_key, args, secrets, files, history, echo).await {
+        Ok(output) => {
+            if let Some(path) = RECORD_FALLBACK.get() {
+                record_fallback(path, &fallback_prompt(args, files), &output);
+            }
+            return Ok(output);
+        }
+        Err(e) if FALLBACK.is_empty() || !UNAVAILABLE.load(std::sync::atomic::Ordering::Relaxed) => return Err(e),
+        Err(e) => e,
+    };
+    let prompt = fallback_prompt(args, files);
+    let hash = fnv1a(&prompt);
+    let Some((_, answer)) = FALLBACK.iter().find(|(h, _)| *h == hash) else {
+        return Err(format!("{}, and the fallback pack has no answer for this input", error));
+    };
+    if !TUI {
+        flush_stdout();
+        eprintln!("Warning: {}; answering from the fallback pack", error);
+    }
+    // Steps that did answer have been shown already
+    let shown = history[start..].iter().map(|(_, answer)| answer.as_str()).collect::<Vec<_>>().join("\n");
+    let rest = answer.strip_prefix(shown.as_str()).filter(|_| !shown.is_empty()).map_or(*answer, |rest| rest.trim_start_matches('\n'));
+    echo.line(rest).await?;
+    history.truncate(start);
+    history.push((prompt, answer.to_string()));
+    Ok(answer.to_string())
+}
+
+/// What the fallback pack knows an input by: every step's prompt with the
+/// placeholders filled in, except for the secrets, which differ from machine to
+/// machine.
+fn fallback_prompt(args: &[String], files: &[(String, String)]) -> String {
+    steps().iter().map(|step| fill_placeholders(step.pseudocode, args, &[], files)).collect::<Vec<_>>().join("\n")
+}
+
+/// Appends an answer to a `--fallback` pack, warning if it can't.
+fn record_fallback(path: &str, prompt: &str, answer: &str) {
+    use std::io::Write;
+    let line = format!("{}\n", serde_json::json!({"prompt": prompt, "answer": answer}));
+    let written = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+    if let Err(e) = written {
+        eprintln!("Warning: Failed to record the answer in {}: {}", path, e);
+    }
+}
+
+/// The steps of run_invocation. A fresh history starts with the precomputed
+/// answer.
+async fn run_steps(
+    api_key: &str,
+    args: &[String],
+    secrets: &[(&str, String)],
+    files: &[(String, String)],
+    history: &mut Vec<(String, String)>,
+    echo: Echo<'_>,
+) -> Result<String, String> {
+    // The filters see everything that's shown, as well as the answer that's returned
+    let filter = OutputFilter { echo, pending: std::sync::Mutex::new(String::new()) };
+    let echo = if FILTERS.is_empty() { echo } else { Echo::Filtered(&filter) };
+    // When translating, only the translation is shown
+    let step_echo = if TRANSLATE_TO.is_some() { Echo::Off } else { echo };
+    let start = history.len();
+    if let (true, Some((statements, answer))) = (history.is_empty(), PRECOMPUTED) {
+        step_echo.line(answer).await?;
+        history.push((statements.to_string(), answer.to_string()));
+    }
+    // Bound answers are filled in like the files, as `VAR_name`
+    let mut values = files.to_vec();
+    // The answers in history that went into a file, which aren't part of the output
+    let mut written: Vec<usize> = Vec::new();
+    let steps = steps();
+    // The loop going around: its first step, its number of steps, its rounds and the current one
+    let mut looping: Option<(usize, usize, usize, usize)> = None;
+    // The otherwise of an if whose vibe matched: where it starts and its number of steps
+    let mut skipping: Option<(usize, usize)> = None;
+    let mut index = 0;
+    loop {
+        if let Some((_, len)) = skipping.filter(|&(at, _)| index == at) {
+            skipping = None;
+            index += len;
+        }
+        if let Some((first, len, rounds, round)) = looping.filter(|&(first, len, _, _)| index == first + len) {
+            looping = None;
+            if round < rounds {
+                looping = Some((first, len, rounds, round + 1));
+                bind(&mut values, "loop", (round + 1).to_string());
+                index = first;
+            }
+        }
+        let Some(step) = steps.get(index) else {
+            break;
+        };
+        if let (Some((count, len)), None) = (step.repeat, looping) {
+            let rounds = loop_rounds(&fill_placeholders(count, args, secrets, &values))?;
+            if rounds == 0 {
+                index += len;
+                continue;
+            }
+            looping = Some((index, len, rounds, 1));
+            bind(&mut values, "loop", "1".to_string());
+        }
+        if let Some((vibe, then_len, else_len)) = step.branch {
+            // The step before, even a shell one that isn't in the history
+            let prev = values.iter().find(|(key, _)| key == "VAR_prev");
+            let last = prev.or(history.last()).map_or("", |(_, answer)| answer.as_str());
+            if !vibe_is(last, vibe) {
+                index += then_len;
+                continue;
+            }
+            skipping = Some((index + then_len, else_len));
+        }
+        if let Some(len) = step.every_line {
+            let block = &steps[index..index + len];
+            let answers = answer_every_line(api_key, block, args, secrets, &values, history, step_echo).await?;
+            if let Some((_, answer)) = answers.last() {
+                bind(&mut values, "prev", answer.trim().to_string());
+            }
+            history.extend(answers);
+            index += len;
+            continue;
+        }
+        // An all at once! block's steps are all asked now, with what's known before it
+        let block = &steps[index..index + step.together.unwrap_or(1)];
+        let asked: Vec<String> = block.iter().map(|step| fill_placeholders(step.pseudocode, args, secrets, &values)).collect();
+        let answers = if step.together.is_none() {
+            let echo = if step.is_shell() { Echo::Off } else { step_echo };
+            vec![answer_step(api_key, step, &asked[0], args, secrets, &values, history, echo).await?]
+        } else {
+            use futures_util::StreamExt;
+            // Each one quietly, then shown in order as the ones before it are in
+            let mut pending: futures_util::stream::FuturesOrdered<_> = block
+                .iter()
+                .zip(&asked)
+                .map(|(step, pseudocode)| answer_step(api_key, step, pseudocode, args, secrets, &values, history, Echo::Off))
+                .collect();
+            let mut answers = Vec::new();
+            for step in block {
+                let Some(answer) = pending.next().await else { break };
+                let answer = answer?;
+                // One written to a file is already there
+                if step.write_to.is_none() && !step.is_shell() {
+                    step_echo.line(&answer).await?;
+                }
+                answers.push(answer);
+            }
+            answers
+        };
+        for ((step, pseudocode), answer) in block.iter().zip(asked).zip(answers) {
+            if step.write_to.is_some() {
+                written.push(history.len());
+            }
+            if let Some(expected) = step.expect.filter(|_| CHECK.load(std::sync::atomic::Ordering::Relaxed)) {
+                check_answer(&pseudocode, expected, &answer, secrets);
+            }
+            if let Some(name) = step.binding {
+                bind(&mut values, name, answer.trim().to_string());
+            }
+            // For a `€prev` in the next step
+            bind(&mut values, "prev", answer.trim().to_string());
+            if step.is_shell() {
+                bind(&mut values, "shell", answer);
+                continue;
+            }
+            history.push((pseudocode, answer));
+        }
+        index += block.len();
+    }
+    let output = history[start..]
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| !written.contains(&(start + index)))
+        .map(|(_, (_, answer))| answer.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let prompts = history[start..].iter().map(|(pseudocode, _)| pseudocode.as_str()).collect::<Vec<_>>().join("\n");
+    let Some((language, translate_model)) = TRANSLATE_TO else {
+        check_glossary(&prompts, &output);
+        filter.finish().await?;
+        return filter_answer(&output);
+    };
+    let prompt = format!(
+        "Translate the user's message to {}. Keep the formatting, code, numbers and names as they are. Reply with the translation only.",
+        language
+    );
+    let translation = run_openai_stream(api_key, translate_model, &prompt, &[], &output, None, None, echo)
+        .await
+        .map_err(|e| e.to_string())?;
+    check_glossary(&prompts, &translation);
+    filter.finish().await?;
+    filter_answer(&translation)
+}
+
+/// Runs a `for every line` block's steps for each line of stdin, up to
+/// `--parallel` lines at once, and returns what each line was asked and
+/// answered, the lines in order. Every line starts from `history` and only
+/// sees its own answers on top; they're shown a line at a time, in order.
+async fn answer_every_line(
+    api_key: &str,
+    block: &[Step],
+    args: &[String],
+    secrets: &[(&str, String)],
+    values: &[(String, String)],
+    history: &[(String, String)],
+    echo: Echo<'_>,
+) -> Result<Vec<(String, String)>, String> {
+    use futures_util::StreamExt;
+
+    let parallel = PARALLEL.load(std::sync::atomic::Ordering::Relaxed);
+    // One line at a time can be shown as it comes in
+    let line_echo = if parallel == 1 { echo } else { Echo::Off };
+    // Collected first: a stream mapping the lines wouldn't be Send for --listen-socket's spawns
+    let runs: Vec<_> = every_line_input()
+        .iter()
+        .map(|line| answer_line(api_key, block, line, args, secrets, values, history, line_echo))
+        .collect();
+    let mut lines = futures_util::stream::iter(runs).buffered(parallel);
+    let mut answers = Vec::new();
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        if parallel > 1 {
+            for (_, answer) in &line {
+                echo.line(answer).await?;
+            }
+        }
+        answers.extend(line);
+    }
+    Ok(answers)
+}
+
+/// One line's run of a `for every line` block, with the line as `€line`.
+#[allow(clippy::too_many_arguments)]
+async fn answer_line(
+    api_key: &str,
+    block: &[Step],
+    line: &str,
+    args: &[String],
+    secrets: &[(&str, String)],
+    values: &[(String, String)],
+    history: &[(String, String)],
+    echo: Echo<'_>,
+) -> Result<Vec<(String, String)>, String> {
+    let mut values = values.to_vec();
+    bind(&mut values, "line", line.to_string());
+    let mut history = history.to_vec();
+    let start = history.len();
+    for step in block {
+        let pseudocode = fill_placeholders(step.pseudocode, args, secrets, &values);
+        let step_echo = if step.is_shell() { Echo::Off } else { echo };
+        let answer = answer_step(api_key, step, &pseudocode, args, secrets, &values, &history, step_echo).await?;
+        if let Some(expected) = step.expect.filter(|_| CHECK.load(std::sync::atomic::Ordering::Relaxed)) {
+            check_answer(&pseudocode, expected, &answer, secrets);
+        }
+        if let Some(name) = step.binding {
+            bind(&mut values, name, answer.trim().to_string());
+        }
+        bind(&mut values, "prev", answer.trim().to_string());
+        if step.is_shell() {
+            bind(&mut values, "shell", answer);
+            continue;
+        }
+        history.push((pseudocode, answer));
+    }
+    Ok(history.split_off(start))
+}
+
+/// Answers `step`, asked as `pseudocode` after `history`, and asks again as
+/// often as it says if that fails. `echo` gets the answer as it comes in, or
+/// once it's in if the step may be asked again, so no half answer is shown.
+/// A step with `write it to` writes it to its file instead.
+#[allow(clippy::too_many_arguments)]
+async fn answer_step(
+    api_key: &str,
+    step: &Step,
+    pseudocode: &str,
+    args: &[String],
+    secrets: &[(&str, String)],
+    values: &[(String, String)],
+    history: &[(String, String)],
+    echo: Echo<'_>,
+) -> Result<String, String> {
+    let file = step.write_to.map(FileOutput::create).transpose()?;
+    let echo = match &file {
+        Some(file) => Echo::File(file),
+        None => echo,
+    };
+    let answer = if step.retries == 0 {
+        ask_step(api_key, step, pseudocode, args, secrets, values, history, echo).await?
+    } else {
+        let mut attempt = 0;
+        loop {
+            match ask_step(api_key, step, pseudocode, args, secrets, values, history, Echo::Off).await {
+                Ok(answer) => {
+                    echo.line(&answer).await?;
+                    break answer;
+                }
+                Err(e) if attempt < step.retries && e != STDOUT_CLOSED => {
+                    attempt += 1;
+                    if !TUI {
+                        flush_stdout();
+                        let asked = pseudocode.lines().next().unwrap_or("");
+                        eprintln!("Warning: \"{}\" failed ({}); trying again ({} of {})", asked, e, attempt, step.retries);
+                    }
+                    // A little longer each time, for whatever made it flaky to calm down
+                    tokio::time::sleep(std::time::Duration::from_secs(attempt.into())).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    };
+    if let Some(file) = &file {
+        file.finish()?;
+    }
+    Ok(answer)
+}
+
+/// Answers `step` once: from the cache, here or from the model, and checked
+/// like the step says. `echo` gets the answer as it comes in, or a json one
+/// once it's checked.
+#[allow(clippy::too_many_arguments)]
+async fn ask_step(
+    api_key: &str,
+    step: &Step,
+    pseudocode: &str,
+    args: &[String],
+    secrets: &[(&str, String)],
+    values: &[(String, String)],
+    history: &[(String, String)],
+    echo: Echo<'_>,
+) -> Result<String, String> {
+    // A json answer is only shown once it's checked
+    let shown = if step.schema.is_some() { Echo::Off } else { echo };
+    // A model step may have been answered before
+    let (hit, miss) = match step.local {
+        None if CACHE => {
+            let model = if step.ensemble { "ensemble" } else { step.model };
+            match cache_lookup(api_key, model, history, pseudocode).await {
+                (Some(hit), _) => (Some(hit), None),
+                (None, key) => (None, Some(key)),
+            }
+        }
+        _ => (None, None),
+    };
+    let mut answer = match (step.local, hit) {
+        (_, Some((answer, source))) => {
+            if !TUI {
+                flush_stdout();
+                let asked = pseudocode.lines().next().unwrap_or("");
+                match source {
+                    Some(source) => eprintln!("Cache hit ({}): {}", source, asked),
+                    None => eprintln!("Cache hit: {}", asked),
+                }
+            }
+            shown.line(&answer).await?;
+            answer
+        }
+        (Some((target, templates)), None) => {
+            let values: Vec<String> = templates.iter().map(|t| fill_placeholders(t, args, secrets, values)).collect();
+            let answer = run_local(target, &values)?;
+            shown.line(&answer).await?;
+            answer
+        }
+        (None, None) if step.ensemble => run_ensemble(api_key, history, pseudocode, step.schema, step.temperature, shown).await?,
+        // A statement `using claude-...` goes to Anthropic
+        (None, None) => run_model(api_key, step.model, SYSTEM_PROMPT, history, pseudocode, step.schema, step.temperature, shown)
+            .await
+            .map_err(|e| e.to_string())?,
+    };
+    // In an all at once! block the flag may be another step's, which fails the block all the same
+    let flagged = REFUSAL_FLAGGED.swap(false, std::sync::atomic::Ordering::Relaxed);
+    if FAIL_ON_REFUSAL && step.local.is_none() && (flagged || is_refusal(&answer)) {
+        let said = answer.lines().map(str::trim).find(|line| !line.is_empty()).unwrap_or("nothing");
+        return Err(format!("{}: {}", REFUSED, said));
+    }
+    if let Some(schema) = step.schema {
+        let asked = pseudocode.lines().next().unwrap_or("");
+        let mut repairs = 0;
+        while let Err(e) = validate_json(&answer, schema) {
+            // A local step would only answer the same again
+            if repairs == REPAIR_ATTEMPTS || step.local.is_some() {
+                return Err(format!("The answer to \"{}\" {}", asked, e));
+            }
+            repairs += 1;
+            if !TUI {
+                flush_stdout();
+                eprintln!("Warning: the answer to \"{}\" {}; asking for a repair ({}/{})", asked, e, repairs, REPAIR_ATTEMPTS);
+            }
+            let mut asked_before = history.to_vec();
+            asked_before.push((pseudocode.to_string(), answer));
+            answer = repair(api_key, step.model, &asked_before, &e, step.schema, step.temperature).await?;
+        }
+        echo.line(&answer).await?;
+    }
+    if let Some(key) = miss {
+        cache_store(key, &answer).await;
+    }
+    Ok(answer)
+}
+
+/// Sets `{VAR_name}` for the steps after this one.
+fn bind(values: &mut Vec<(String, String)>, name: &str, value: String) {
+    let key = format!("VAR_{}", name);
+    values.retain(|(existing, _)| *existing != key);
+    values.push((key, value));
+}
+
+/// Most rounds an `again!` loop may run, like the compiler says.
+const MAX_LOOP_ROUNDS: usize = 1000;
+
+/// The rounds of an `again! N times` loop, from what N turned out to be.
+fn loop_rounds(count: &str) -> Result<usize, String> {
+    let rounds = count
+        .trim()
+        .parse::<usize>()
+        .map_err(|_| format!("again! needs a number of times, got '{}'", count.trim()))?;
+    if rounds > MAX_LOOP_ROUNDS {
+        return Err(format!("again! runs at most {} times, not {}", MAX_LOOP_ROUNDS, rounds));
+    }
+    Ok(rounds)
+}
+
+/// What's wrong with `answer` for an `as json` step with `schema`, if anything.
+fn validate_json(answer: &str, schema: &str) -> Result<(), String> {
+    check_json(answer, schema).map_err(|e| format!("isn't the json it should be: {}", e))?;
+    #[cfg(feature = "types")]
+    types::deserialize_answer(schema, answer).map_err(|e| format!("doesn't fit its type: {}", e))?;
+    Ok(())
+}
+
+/// `--repair`: asks `model`, after `history` that ends with the answer that
+/// didn't check out, for that answer again without `problem`.
+async fn repair(
+    api_key: &str,
+    model: &str,
+    history: &[(String, String)],
+    problem: &str,
+    schema: Option<&str>,
+    temperature: Option<f64>,
+) -> Result<String, String> {
+    run_model(api_key, model, SYSTEM_PROMPT, history, &repair_prompt(problem), schema, temperature, Echo::Off)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+fn repair_prompt(problem: &str) -> String {
+    format!("Your answer above {}. {}", problem, REPAIR_PROMPT)
+}
+
+/// Checks that `answer` is JSON matching `schema`, the kind `as json` makes:
+/// objects with every property required and no others, arrays, strings,
+/// numbers, integers and booleans.
+fn check_json(answer: &str, schema: &str) -> Result<(), String> {
+    let schema: serde_json::Value = serde_json::from_str(schema).map_err(|e| format!("its schema is broken: {}", e))?;
+    let value: serde_json::Value = serde_json::from_str(answer.trim()).map_err(|e| format!("it isn't JSON ({})", e))?;
+    check_value(&value, &schema, "$")
+}
+
+fn check_value(value: &serde_json::Value, schema: &serde_json::Value, path: &str) -> Result<(), String> {
+    use serde_json::Value;
+    let expected = schema["type"].as_str().unwrap_or_default();
+    let fits = match (expected, value) {
+        ("object", Value::Object(object)) => {
+            let properties = schema["properties"].as_object().cloned().unwrap_or_default();
+            for (key, property) in &properties {
+                let inner = format!("{}.{}", path, key);
+                match object.get(key) {
+                    Some(field) => check_value(field, property, &inner)?,
+                    None => return Err(format!("{} is missing", inner)),
+                }
+            }
+            if let Some(key) = object.keys().find(|key| !properties.contains_key(*key)) {
+                return Err(format!("{}.{} isn't in the schema", path, key));
+            }
+            true
+        }
+        ("array", Value::Array(items)) => {
+            for (index, item) in items.iter().enumerate() {
+                check_value(item, &schema["items"], &format!("{}[{}]", path, index))?;
+            }
+            true
+        }
+        ("string", Value::String(_)) | ("boolean", Value::Bool(_)) | ("number", Value::Number(_)) => true,
+        ("integer", Value::Number(number)) => number.is_i64() || number.is_u64(),
+        _ => false,
+    };
+    if fits {
+        return Ok(());
+    }
+    let got = match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "a list",
+        Value::Object(_) => "an object",
+    };
+    let expected = match expected {
+        "object" => "an object",
+        "array" => "a list",
+        "string" => "a string",
+        "number" => "a number",
+        "integer" => "an integer",
+        "boolean" => "a boolean",
+        other => other,
+    };
+    Err(format!("{} should be {}, got {}", path, expected, got))
+}
+
+/// Whether an answer is the vibe an `if the vibe is` looks for: the same once
+/// both are trimmed and lowercased.
+fn vibe_is(answer: &str, vibe: &str) -> bool {
+    answer.trim().to_lowercase() == vibe.trim().to_lowercase()
+}
+
+/// `--session-tokens`: why a chat session that used `spent` tokens gets no
+/// more answers, if it doesn't.
+#[cfg(any(feature = "tui", feature = "serve"))]
+fn session_exhausted(spent: u64) -> Option<String> {
+    let limit = SESSION_TOKENS?;
+    (spent >= limit).then(|| format!("This session has used up its {} tokens, so it gets no more answers.", limit))
+}
+
+/// `--session-tokens`: what to tell a chat session whose turn took it from
+/// `before` to `after` tokens, once it passes 80% and once it runs out.
+#[cfg(any(feature = "tui", feature = "serve"))]
+fn session_notice(before: u64, after: u64) -> Option<String> {
+    let limit = SESSION_TOKENS?;
+    if before < limit && after >= limit {
+        Some(format!("This session has used up its {} tokens; that was its last answer.", limit))
+    } else if before * 5 < limit * 4 && after * 5 >= limit * 4 && after < limit {
+        Some(format!("This session has used {} of its {} tokens.", after, limit))
+    } else {
+        None
+    }
+}
+
+/// What every request handler of `--serve` shares.
+#[cfg(feature = "serve")]
+struct ServeState {
+    api_key: String,
+    secrets: Vec<(&'static str, String)>,
+    /// Numbers the completion ids.
+    requests: std::sync::atomic::AtomicU64,
+    /// `--session-tokens`: tokens used so far, by session_key.
+    sessions: std::sync::Mutex<std::collections::HashMap<u64, u64>>,
+    access: AccessLog,
+}
+
+#[cfg(feature = "serve")]
+impl ServeState {
+    /// Tokens `session` has used so far.
+    fn spent(&self, session: u64) -> u64 {
+        self.sessions.lock().ok().and_then(|sessions| sessions.get(&session).copied()).unwrap_or(0)
+    }
+
+    /// Adds a turn's tokens to `session`, and returns what to tell it.
+    fn spend(&self, session: u64, tokens: u64) -> Option<String> {
+        // Without a ceiling, nothing needs counting
+        SESSION_TOKENS?;
+        let mut sessions = self.sessions.lock().ok()?;
+        let spent = sessions.entry(session).or_default();
+        let before = *spent;
+        *spent += tokens;
+        session_notice(before, *spent)
+    }
+}
+
+/// The chat session a request belongs to, for `--session-tokens`: the
+/// client's `user`, the caller `--serve-keys` or `--serve-auth` let in, or
+/// else the conversation's first message, since every request repeats the
+/// whole conversation.
+#[cfg(feature = "serve")]
+fn session_key(request: &serde_json::Value, messages: &[serde_json::Value], caller: Option<&Caller>) -> u64 {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    match (request["user"].as_str(), caller) {
+        (Some(user), _) => ("user", user).hash(&mut hasher),
+        (None, Some(caller)) => ("caller", &caller.name).hash(&mut hasher),
+        (None, None) => {
+            let first = messages.iter().find(|message| message["role"] == "user").map(|message| message_text(&message["content"]));
+            ("conversation", first).hash(&mut hasher)
+        }
+    }
+    hasher.finish()
+}
+
+/// `--serve`: the program as an OpenAI-compatible API, so SDKs and chat UIs can
+/// use it as if it were a model.
+#[cfg(feature = "serve")]
+async fn serve(api_key: String, secrets: Vec<(&'static str, String)>, listen: &str) -> Result<(), String> {
+    use axum::routing::{get, post};
+
+    check_serve_keys()?;
+    let access = AccessLog::open()?;
+    let state = std::sync::Arc::new(ServeState { api_key, secrets, requests: Default::default(), sessions: Default::default(), access });
+    let app = axum::Router::new()
+        .route("/v1/chat/completions", post(chat_completions))
+        .route("/v1/models", get(list_models))
+        .with_state(state);
+    let listener = tokio::net::TcpListener::bind(listen)
+        .await
+        .map_err(|e| format!("Can't listen on {}: {}", listen, e))?;
+    eprintln!("{} is listening on http://{}/v1", NAME, listen);
+    axum::serve(listener, app).await.map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "serve")]
+async fn list_models(
+    axum::extract::State(state): axum::extract::State<std::sync::Arc<ServeState>>,
+    headers: axum::http::HeaderMap,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let mut entry = AccessEntry::new(new_request_id(), "/v1/models");
+    match authenticate(authorization(&headers)).await {
+        Ok(caller) => entry.caller = caller.map(|caller| caller.name),
+        Err(e) => {
+            state.access.write(&entry, 401, None, Some(&e));
+            return api_error(axum::http::StatusCode::UNAUTHORIZED, "invalid_request_error", &e);
+        }
+    }
+    state.access.write(&entry, 200, None, None);
+    axum::Json(serde_json::json!({
+        "object": "list",
+        "data": [{"id": NAME, "object": "model", "created": 0, "owned_by": "matthiashihic"}]
+    }))
+    .into_response()
+}
+
+/// A request's Authorization header.
+#[cfg(feature = "serve")]
+fn authorization(headers: &axum::http::HeaderMap) -> Option<&str> {
+    headers.get(axum::http::header::AUTHORIZATION).and_then(|value| value.to_str().ok())
+}
+
+/// An error in the shape OpenAI clients expect.
+#[cfg(feature = "serve")]
+fn api_error(status: axum::http::StatusCode, kind: &str, message: &str) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let body = serde_json::json!({"error": {"message": message, "type": kind, "param": null, "code": null}});
+    (status, axum::Json(body)).into_response()
+}
+
+/// Text of a message's `content`, which is either a string or a list of parts.
+#[cfg(feature = "serve")]
+fn message_text(content: &serde_json::Value) -> String {
+    match content {
+        serde_json::Value::Array(parts) => parts.iter().filter_map(|part| part["text"].as_str()).collect::<Vec<_>>().join("\n"),
+        other => other.as_str().unwrap_or("").to_string(),
+    }
+}
+
+/// `POST /v1/chat/completions`, answered under the client's X-Request-Id if it
+/// sent one (so both sides log the same id), or a new one. Either way it comes
+/// back as a response header.
+#[cfg(feature = "serve")]
+async fn chat_completions(
+    state: axum::extract::State<std::sync::Arc<ServeState>>,
+    headers: axum::http::HeaderMap,
+    body: axum::body::Bytes,
+) -> axum::response::Response {
+    let id = headers
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= 200)
+        .map(str::to_string)
+        .unwrap_or_else(new_request_id);
+    let mut entry = AccessEntry::new(id.clone(), "/v1/chat/completions");
+    let caller = match authenticate(authorization(&headers)).await {
+        Ok(caller) => caller,
+        Err(e) => {
+            state.access.write(&entry, 401, None, Some(&e));
+            return api_error(axum::http::StatusCode::UNAUTHORIZED, "invalid_request_error", &e);
+        }
+    };
+    entry.caller = caller.as_ref().map(|caller| caller.name.clone());
+    let mut response = SERVE_REQUEST_ID.scope(id.clone(), answer_completion(state, caller, entry, body)).await;
+    if let Ok(value) = axum::http::HeaderValue::from_str(&id) {
+        response.headers_mut().insert("x-request-id", value);
+    }
+    response
+}
+
+/// Runs the program with the last user message as €1. Earlier user/assistant
+/// turns are replayed as history, and the client's system messages are
+/// ignored; the program is the system prompt. The caller's key pays for it,
+/// if they have one. How it went ends up in the access log as `entry`.
+#[cfg(feature = "serve")]
+async fn answer_completion(
+    axum::extract::State(state): axum::extract::State<std::sync::Arc<ServeState>>,
+    caller: Option<Caller>,
+    mut entry: AccessEntry,
+    body: axum::body::Bytes,
+) -> axum::response::Response {
+    use axum::http::StatusCode;
+    use axum::response::sse::{Event, Sse};
+    use axum::response::IntoResponse;
+
+    let reject = |entry: &AccessEntry, status: StatusCode, kind: &str, message: &str| {
+        state.access.write(entry, status.as_u16(), None, Some(message));
+        api_error(status, kind, message)
+    };
+    let request: serde_json::Value = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return reject(&entry, StatusCode::BAD_REQUEST, "invalid_request_error", &format!("Invalid JSON: {}", e)),
+    };
+    let Some(messages) = request["messages"].as_array() else {
+        return reject(&entry, StatusCode::BAD_REQUEST, "invalid_request_error", "'messages' must be an array");
+    };
+    entry.stream = Some(request["stream"].as_bool().unwrap_or(false));
+    let mut history: Vec<(String, String)> = Vec::new();
+    let mut pending_user: Option<String> = None;
+    for message in messages {
+        let text = message_text(&message["content"]);
+        match message["role"].as_str() {
+            Some("user") => pending_user = Some(text),
+            Some("assistant") => {
+                if let Some(user) = pending_user.take() {
+                    history.push((user, text));
+                }
+            }
+            _ => {}
+        }
+    }
+    let Some(message) = pending_user else {
+        return reject(&entry, StatusCode::BAD_REQUEST, "invalid_request_error", "The last message has to come from the user");
+    };
+    entry.args_hash = Some(fnv1a(&message));
+    let (args, files) = match prepare_args(vec![message]) {
+        Ok(prepared) => prepared,
+        Err(e) => return reject(&entry, StatusCode::BAD_REQUEST, "invalid_request_error", &redact(&e, &state.secrets)),
+    };
+    let session = session_key(&request, messages, caller.as_ref());
+    let api_key = caller.and_then(|caller| caller.key).unwrap_or_else(|| state.api_key.clone());
+    if let Some(message) = session_exhausted(state.spent(session)) {
+        return reject(&entry, StatusCode::TOO_MANY_REQUESTS, "insufficient_quota", &message);
+    }
+
+    let id = format!("chatcmpl-{}", state.requests.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1);
+    let created = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let model = request["model"].as_str().unwrap_or(NAME).to_string();
+
+    let started = std::time::Instant::now();
+    if !request["stream"].as_bool().unwrap_or(false) {
+        let (result, tokens) = counting_tokens(run_invocation(&api_key, &args, &state.secrets, &files, &mut history, Echo::Off)).await;
+        let notice = state.spend(session, tokens);
+        let result = result
+            .map(|output| match notice {
+                Some(notice) => format!("{}\n\n({})", output, notice),
+                None => output,
+            })
+            .map(|output| match watermark(MODEL) {
+                Some(mark) => format!("{}\n{}", output, mark),
+                None => output,
+            });
+        // Tokens are only counted for the whole server, so they aren't recorded
+        match &result {
+            Ok(output) => record_run(started, Some(output.chars().count()), None, None),
+            Err(e) => record_run(started, None, Some(&redact(e, &state.secrets)), None),
+        }
+        let logged = result.as_ref().err().map(|e| redact(e, &state.secrets));
+        state.access.write(&entry, if logged.is_some() { 502 } else { 200 }, Some(tokens), logged.as_deref());
+        return match result {
+            Ok(output) => axum::Json(serde_json::json!({
+                "id": id,
+                "object": "chat.completion",
+                "created": created,
+                "model": model,
+                "choices": [{"index": 0, "message": {"role": "assistant", "content": output}, "finish_reason": "stop"}]
+            }))
+            .into_response(),
+            Err(e) => {
+                let message = format!("{} (request {})", redact(&e, &state.secrets), request_id());
+                eprintln!("Error: {}", message);
+                api_error(StatusCode::BAD_GATEWAY, "server_error", &message)
+            }
+        };
+    }
+
+    let completion_id = id.clone();
+    let chunk = move |delta: serde_json::Value, finish_reason: Option<&str>| {
+        let chunk = serde_json::json!({
+            "id": id,
+            "object": "chat.completion.chunk",
+            "created": created,
+            "model": model,
+            "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}]
+        });
+        Event::default().data(chunk.to_string())
+    };
+    let (events_tx, events) = tokio::sync::mpsc::unbounded_channel::<Event>();
+    // A spawned task doesn't inherit the request id by itself
+    tokio::spawn(SERVE_REQUEST_ID.scope(request_id(), async move {
+        let _ = events_tx.send(chunk(serde_json::json!({"role": "assistant"}), None));
+        let (tokens_tx, mut tokens) = tokio::sync::mpsc::unbounded_channel::<String>();
+        let run = async {
+            let tokens_tx = tokens_tx;
+            tokio::select! {
+                turn = counting_tokens(run_invocation(&api_key, &args, &state.secrets, &files, &mut history, Echo::Channel(&tokens_tx))) => turn,
+                // The client hung up; dropping the invocation aborts its requests
+                _ = events_tx.closed() => (Err(CLIENT_GONE.to_string()), 0),
+            }
+        };
+        let forward = async {
+            // Answers are joined by newlines, but the last one doesn't end in one
+            let mut newlines = String::new();
+            let mut chars = 0;
+            while let Some(text) = tokens.recv().await {
+                // Filtered answers come with their newline attached
+                let line = text.trim_end_matches('\n');
+                if line.is_empty() {
+                    newlines.push_str(&text);
+                    continue;
+                }
+                let content = std::mem::take(&mut newlines) + line;
+                newlines.push_str(&text[line.len()..]);
+                chars += content.chars().count();
+                let _ = events_tx.send(chunk(serde_json::json!({"content": content}), None));
+            }
+            chars
+        };
+        let ((result, tokens), chars) = tokio::join!(run, forward);
+        let notice = state.spend(session, tokens);
+        // The status went out with the first chunk; a client that left gets 499, like nginx logs it
+        match &result {
+            Ok(_) => state.access.write(&entry, 200, Some(tokens), None),
+            Err(e) if e == CLIENT_GONE => state.access.write(&entry, 499, Some(tokens), Some(e)),
+            Err(e) => state.access.write(&entry, 200, Some(tokens), Some(&redact(e, &state.secrets))),
+        }
+        match &result {
+            Ok(_) => record_run(started, Some(chars), None, None),
+            Err(e) => record_run(started, None, Some(&redact(e, &state.secrets)), None),
+        }
+        match result {
+            Ok(_) => {
+                if let Some(notice) = notice {
+                    let _ = events_tx.send(chunk(serde_json::json!({"content": format!("\n\n({})", notice)}), None));
+                }
+                if let Some(mark) = watermark(MODEL) {
+                    let _ = events_tx.send(chunk(serde_json::json!({"content": format!("\n{}", mark)}), None));
+                }
+                let _ = events_tx.send(chunk(serde_json::json!({}), Some("stop")));
+            }
+            Err(e) if e == CLIENT_GONE => {
+                eprintln!("{}: the client disconnected, so the request was cancelled", completion_id);
+                return;
+            }
+            Err(e) => {
+                let message = format!("{} (request {})", redact(&e, &state.secrets), request_id());
+                eprintln!("Error: {}", message);
+                let error = serde_json::json!({"error": {"message": message, "type": "server_error"}});
+                let _ = events_tx.send(Event::default().data(error.to_string()));
+            }
+        }
+        let _ = events_tx.send(Event::default().data("[DONE]"));
+    }));
+    let stream = futures_util::stream::unfold(events, |mut events| async move {
+        events.recv().await.map(|event| (Ok::<_, std::convert::Infallible>(event), events))
+    });
+    Sse::new(stream).into_response()
+}
+
+#[cfg(not(feature = "serve"))]
+async fn serve(_api_key: String, _secrets: Vec<(&'static str, String)>, _listen: &str) -> Result<(), String> {
+    Err("this program was compiled without --serve".into())
+}
+
+/// One conversation in the terminal UI.
+#[cfg(feature = "tui")]
+#[derive(Default)]
+struct Session {
+    /// What the window shows: messages and answers.
+    transcript: String,
+    history: Vec<(String, String)>,
+    /// The first message, shortened, as the tab title.
+    title: Option<String>,
+    /// Tokens its turns have used, for `--session-tokens`.
+    tokens: u64,
+}
+
+/// `--tui`: a chat window where every message runs the program with the
+/// message as €1. Each session keeps its own history, so the model remembers
+/// the conversation.
+#[cfg(feature = "tui")]
+async fn run_tui(api_key: &str, secrets: &[(&'static str, String)]) -> Result<(), String> {
+    use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+    use ratatui::layout::{Constraint, Layout};
+    use ratatui::widgets::{Block, Paragraph, Tabs};
+    use std::future::Future;
+    use std::pin::Pin;
+
+    type Turn<'a> = Pin<Box<dyn Future<Output = ((Vec<(String, String)>, Result<String, String>), u64)> + 'a>>;
+
+    // Key presses come from a thread of their own, since reading them blocks
+    let (keys_tx, mut keys) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while let Ok(event) = event::read() {
+            if keys_tx.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    let (tokens_tx, mut tokens) = tokio::sync::mpsc::unbounded_channel::<String>();
+
+    let mut sessions = vec![Session::default()];
+    let mut current = 0;
+    let mut input = String::new();
+    // Lines scrolled up from the bottom of the transcript
+    let mut scroll = 0usize;
+    // The session waiting for an answer, and the answer on its way
+    let mut busy: Option<usize> = None;
+    let mut turn: Option<Turn> = None;
+
+    let mut terminal = ratatui::init();
+    let result = loop {
+        let drawn = terminal.draw(|frame| {
+            let [tabs_area, transcript_area, input_area, help_area] = Layout::vertical([
+                Constraint::Length(1),
+                Constraint::Min(3),
+                Constraint::Length(3),
+                Constraint::Length(1),
+            ])
+            .areas(frame.area());
+
+            let titles = sessions.iter().enumerate().map(|(i, session)| {
+                format!("{}: {}", i + 1, session.title.as_deref().unwrap_or("new"))
+            });
+            frame.render_widget(Tabs::new(titles).select(current), tabs_area);
+
+            let width = transcript_area.width.saturating_sub(2).max(1) as usize;
+            let height = transcript_area.height.saturating_sub(2) as usize;
+            let lines = wrap(&sessions[current].transcript, width);
+            let bottom = lines.len().saturating_sub(height);
+            scroll = scroll.min(bottom);
+            let first = bottom - scroll;
+            let visible = lines[first..lines.len().min(first + height)].join("\n");
+            frame.render_widget(Paragraph::new(visible).block(Block::bordered().title(NAME)), transcript_area);
+
+            let title = if busy.is_some() { "thinking ..." } else { "message" };
+            let room = input_area.width.saturating_sub(2) as usize;
+            let shown: String = input.chars().skip(input.chars().count().saturating_sub(room.saturating_sub(1))).collect();
+            let cursor_x = input_area.x + 1 + shown.chars().count() as u16;
+            frame.render_widget(Paragraph::new(shown).block(Block::bordered().title(title)), input_area);
+            frame.set_cursor_position((cursor_x, input_area.y + 1));
+
+            frame.render_widget(
+                Paragraph::new("Enter send · Ctrl-N new session · Tab switch · PgUp/PgDn scroll · Esc quit"),
+                help_area,
+            );
+        });
+        if let Err(e) = drawn {
+            break Err(e.to_string());
+        }
+
+        tokio::select! {
+            Some(text) = tokens.recv() => {
+                if let Some(index) = busy {
+                    sessions[index].transcript.push_str(&text);
+                }
+            }
+            ((history, answer), used) = async { turn.as_mut().expect("checked by the guard").await }, if turn.is_some() => {
+                turn = None;
+                let index = busy.take().unwrap_or(current);
+                while let Ok(text) = tokens.try_recv() {
+                    sessions[index].transcript.push_str(&text);
+                }
+                let session = &mut sessions[index];
+                session.history = history;
+                if let Err(e) = answer {
+                    session.transcript.push_str(&format!("Error: {}\n", redact(&e, secrets)));
+                }
+                let before = session.tokens;
+                session.tokens += used;
+                if let Some(notice) = session_notice(before, session.tokens) {
+                    session.transcript.push_str(&format!("({})\n", notice));
+                }
+                session.transcript.push('\n');
+            }
+            event = keys.recv() => {
+                let Some(Event::Key(key)) = event else {
+                    continue;
+                };
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+                match key.code {
+                    KeyCode::Esc => break Ok(()),
+                    KeyCode::Char('c') | KeyCode::Char('d') if ctrl => break Ok(()),
+                    KeyCode::Char('n') if ctrl => {
+                        sessions.push(Session::default());
+                        current = sessions.len() - 1;
+                        scroll = 0;
+                    }
+                    KeyCode::Tab => {
+                        current = (current + 1) % sessions.len();
+                        scroll = 0;
+                    }
+                    KeyCode::BackTab => {
+                        current = (current + sessions.len() - 1) % sessions.len();
+                        scroll = 0;
+                    }
+                    KeyCode::PageUp => scroll += 10,
+                    KeyCode::PageDown => scroll = scroll.saturating_sub(10),
+                    KeyCode::Backspace => {
+                        input.pop();
+                    }
+                    KeyCode::Enter if busy.is_none() && !input.trim().is_empty() => {
+                        let message = std::mem::take(&mut input);
+                        let session = &mut sessions[current];
+                        session.transcript.push_str(&format!("> {}\n", message));
+                        if session.title.is_none() {
+                            session.title = Some(message.chars().take(16).collect());
+                        }
+                        scroll = 0;
+                        if let Some(notice) = session_exhausted(session.tokens) {
+                            session.transcript.push_str(&format!("({})\n\n", notice));
+                            continue;
+                        }
+                        match prepare_args(vec![message]) {
+                            Ok((args, files)) => {
+                                let mut history = std::mem::take(&mut session.history);
+                                let tokens_tx = &tokens_tx;
+                                busy = Some(current);
+                                turn = Some(Box::pin(counting_tokens(async move {
+                                    let before = history.len();
+                                    let answer = run_invocation(api_key, &args, secrets, &files, &mut history, Echo::Channel(tokens_tx)).await;
+                                    // A failed turn doesn't count as said
+                                    if answer.is_err() {
+                                        history.truncate(before);
+                                    }
+                                    (history, answer)
+                                })));
+                            }
+                            Err(e) => session.transcript.push_str(&format!("Error: {}\n\n", redact(&e, secrets))),
+                        }
+                    }
+                    KeyCode::Char(c) if !ctrl => input.push(c),
+                    _ => {}
+                }
+            }
+        }
+    };
+    ratatui::restore();
+    result
+}
+
+/// Splits text into lines of at most `width` characters.
+#[cfg(feature = "tui")]
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for line in text.split('\n') {
+        let chars: Vec<char> = line.chars().collect();
+        if chars.is_empty() {
+            lines.push(String::new());
+        }
+        for chunk in chars.chunks(width) {
+            lines.push(chunk.iter().collect());
+        }
+    }
+    lines
+}
+
+#[cfg(not(feature = "tui"))]
+async fn run_tui(_api_key: &str, _secrets: &[(&'static str, String)]) -> Result<(), String> {
+    Err("this program was compiled without terminal UI support".into())
+}
+
+/// Runs a local step: a builtin, or an extern program with the values on its
+/// stdin, one per line. Returns what it produced.
+fn run_local(target: &str, values: &[String]) -> Result<String, String> {
+    use std::process::{Command, Stdio};
+
+    if let Some(op) = target.strip_prefix("builtin:") {
+        return run_builtin(op, values);
+    }
+    if target == SHELL {
+        return run_shell(values);
+    }
+    let dir = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.to_path_buf()))
+        .unwrap_or_default();
+    let path = dir.join(target);
+    let mut child = Command::new(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| format!("Failed to run extern program {}: {}", path.display(), e))?;
+    let mut input = String::new();
+    for value in values {
+        input.push_str(value);
+        input.push('\n');
+    }
+    if let Some(mut stdin) = child.stdin.take() {
+        // A callee without placeholders may exit without reading
+        let _ = stdin.write_all(input.as_bytes());
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Failed to run extern program {}: {}", path.display(), e))?;
+    if !output.status.success() {
+        return Err(format!("Extern program {} failed ({})", target, output.status));
+    }
+    let text = String::from_utf8_lossy(&output.stdout);
+    Ok(text.strip_suffix('\n').unwrap_or(&text).to_string())
+}
+
+/// The local target of a `shell "..."` step.
+const SHELL: &str = "shell:";
+
+/// Runs a `shell "..."` command with `sh`. `values` are the command, with
+/// `"${n}"` where its placeholders were, and then their values, which `sh`
+/// gets as arguments rather than as part of the command.
+fn run_shell(values: &[String]) -> Result<String, String> {
+    use std::process::{Command, Stdio};
+
+    let Some((command, args)) = values.split_first() else {
+        return Ok(String::new());
+    };
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .arg(NAME)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Failed to run shell \"{}\": {}", command, e))?;
+    if !output.status.success() {
+        return Err(format!("shell \"{}\" failed ({})", command, output.status));
+    }
+    let text = String::from_utf8_lossy(&output.stdout);
+    Ok(text.strip_suffix('\n').unwrap_or(&text).to_string())
+}
+
+/// Runs a builtin statement on its (already substituted) arguments.
+fn run_builtin(op: &str, values: &[String]) -> Result<String, String> {
+    let value = values.first().map(String::as_str).unwrap_or("");
+    let days = |i: usize| values.get(i).and_then(|n| n.parse::<i64>().ok()).unwrap_or(0);
+    let result = match op {
+        "uppercase" => value.to_uppercase(),
+        "lowercase" => value.to_lowercase(),
+        "trim" => value.trim().to_string(),
+        "reverse" => value.chars().rev().collect(),
+        "count_lines" => value.lines().count().to_string(),
+        "count_words" => value.split_whitespace().count().to_string(),
+        "count_characters" => value.chars().count().to_string(),
+        "today" => format_date(today()),
+        "today_plus" => format_date(today() + days(0)),
+        "today_minus" => format_date(today() - days(0)),
+        "date_plus" => format_date(parse_date(value)? + days(1)),
+        "date_minus" => format_date(parse_date(value)? - days(1)),
+        "days_between" => {
+            let other = values.get(1).map(String::as_str).unwrap_or("");
+            (parse_date(other)? - parse_date(value)?).abs().to_string()
+        }
+        other => return Err(format!("Unknown builtin '{}'", other)),
+    };
+    Ok(result)
+}
+
+/// Days since 1970-01-01 (UTC) for today.
+fn today() -> i64 {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    (secs / 86400) as i64
+}
+
+/// Parses YYYY-MM-DD into days since 1970-01-01.
+fn parse_date(value: &str) -> Result<i64, String> {
+    let invalid = || format!("Expected a date like 2024-12-31, got '{}'", value.trim());
+    let parts: Vec<i64> = value
+        .trim()
+        .splitn(3, '-')
+        .map(|part| part.parse::<i64>().map_err(|_| invalid()))
+        .collect::<Result<_, _>>()?;
+    let [year, month, day] = parts[..] else {
+        return Err(invalid());
+    };
+    let days = days_from_civil(year, month, day);
+    if !(1..=12).contains(&month) || day < 1 || civil_from_days(days) != (year, month, day) {
+        return Err(invalid());
+    }
+    Ok(days)
+}
+
+fn format_date(days: i64) -> String {
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// Howard Hinnant's civil calendar algorithms
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + if month <= 2 { 1 } else { 0 }, month, day)
+}
+
+/// `--hihic-routed`, which `matthiashihic route` starts its versions with:
+/// the program exits once the router does, however it was stopped.
+fn exit_with_parent() {
+    #[cfg(unix)]
+    {
+        let parent = std::os::unix::process::parent_id();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(std::time::Duration::from_secs(1));
+            // An orphan is adopted by init, or whatever reaps them
+            if std::os::unix::process::parent_id() != parent {
+                std::process::exit(0);
+            }
+        });
+    }
+}
+
+fn print_help() {
+    if VERSION.is_empty() {
+        println!("{}", NAME);
+    } else {
+        println!("{} {}", NAME, VERSION);
+    }
+    if !ABOUT.is_empty() {
+        println!("{}", ABOUT);
+    }
+    println!();
+    let max_arg = REQUIRED_ARGS.iter().max().copied().unwrap_or(0);
+    let flags: String = NAMED_ARGS.iter().map(|name| format!(" --{} <{}>", name, name)).collect();
+    if TUI {
+        println!("Usage: {}", NAME);
+        println!("Opens a chat window; every message you send is €1.");
+    } else if SERVE {
+        println!("Usage: {} [--listen <addr>] [--access-log <file>]", NAME);
+        println!("Serves an OpenAI-compatible /v1/chat/completions; the last user message is €1.");
+        println!("Every request is logged, to stdout unless --access-log says where.");
+        if !SERVE_KEYS.is_empty() || SERVE_AUTH.is_some() {
+            println!("Callers need a key, sent as 'Authorization: Bearer <key>'.");
+        }
+    } else if LISTEN_SOCKET.is_some() {
+        println!("Usage: {} [--listen-socket <path>]", NAME);
+        println!("Answers requests on a socket. Each message either way is a 4-byte big-endian");
+        println!("length, then JSON. Send {{\"args\": [\"€1\", ...], \"named\": {{\"name\": \"value\"}}}};");
+        println!("the answer comes back as {{\"type\": \"text\"}} pieces, then \"done\" or \"error\".");
+    } else if STREAM_FILTER {
+        println!("Usage: <lines> | {} [--parallel <n>]", NAME);
+        println!("Runs once per stdin line as the lines come in; each line is €1.");
+    } else if AUDIO_INPUT {
+        println!("Usage: {} <audio file>", NAME);
+        println!("       {} --record", NAME);
+        println!("Transcribes the audio and uses the transcript as €1.");
+    } else if EVERY_LINE {
+        println!("Usage: <lines> | {}{} [--parallel <n>]", NAME, flags);
+        println!("Runs the for every line block once per stdin line; each line is €line.");
+    } else if max_arg == 0 && STDIN_FORMAT == "lines" {
+        println!("Usage: {}{}", NAME, flags);
+    } else if STDIN_FORMAT == "lines" {
+        println!("Usage: <{} line(s)> | {}{}", max_arg, NAME, flags);
+        println!("Reads one line from stdin per placeholder (€1..€{}).", max_arg);
+    } else {
+        println!("Usage: <{} rows> | {}{}", STDIN_FORMAT, NAME, flags);
+        println!("Runs once per {} row; columns fill the placeholders.", STDIN_FORMAT);
+    }
+    if let Some(limit) = SESSION_TOKENS {
+        println!("Each session may use {} tokens; it's warned at 80% and gets no answers after.", limit);
+    }
+    if BACKGROUND {
+        println!("Submits the question as a background job and prints its ID; --poll <id> gets");
+        println!("the answer once it's in.");
+    }
+    if FAIL_ON_REFUSAL {
+        println!("If the model refuses, nothing is printed and the exit code is {}.", REFUSAL_EXIT_CODE);
+    }
+    if !NAMED_ARGS.is_empty() && LISTEN_SOCKET.is_none() {
+        if STDIN_FORMAT == "lines" {
+            println!("Instead of a flag, a name=value line on stdin works, too.");
+        } else if SKIP_HEADER {
+            println!("Instead of a flag, a header column of the same name works, too.");
+        }
+    }
+    for (index, ty) in ARG_TYPES {
+        let expected = match *ty {
+            "int" => "an integer",
+            "number" => "a number",
+            "path" => "an existing path",
+            "json" => "valid JSON",
+            other => other,
+        };
+        println!("  €{} must be {}", index, expected);
+    }
+    for (index, value) in ARG_DEFAULTS {
+        println!("  €{} is \"{}\" if stdin runs out before it", index, value);
+    }
+    println!();
+    println!("Options:");
+    println!("  -h, --help      Print this help");
+    println!("  --hihic-info    Print how this program was built, as JSON");
+    if AUDIO_INPUT {
+        println!("  --record        Record from the default microphone until Enter (needs SoX)");
+    }
+    if ENSEMBLE.is_some() && !TUI {
+        println!("  --show-all      Print every ensemble model's answer to stderr, too");
+    }
+    if SERVE {
+        println!("  --listen <addr> Address to listen on (default: {})", DEFAULT_LISTEN);
+        println!("  --access-log <file>");
+        println!("                  Log requests to this file, rotated at 10 MB, instead of stdout");
+        println!("  --access-log-format <json|text>");
+        println!("                  One JSON object per request (default), or key=value pairs");
+        println!("  --access-log-redact <fields>");
+        println!("                  Blank out some of {}", REDACTABLE.join(", "));
+    }
+    if let Some(path) = LISTEN_SOCKET {
+        println!("  --listen-socket <path>");
+        println!("                  Socket to listen on (default: {})", path);
+    }
+    if STREAM_FILTER || EVERY_LINE {
+        println!("  --parallel <n>  Lines to work on at once (default: {})", DEFAULT_PARALLEL);
+    }
+    if BACKGROUND {
+        println!("  --poll <id>     Print the job's answer, or exit with {} while it's still running", JOB_PENDING_EXIT_CODE);
+        println!("  --wait          With --poll, wait for the answer instead");
+        println!("  --jobs          List the jobs this program submitted");
+    }
+    if BANNER.is_some() {
+        println!("  --no-banner     Don't show the banner, e.g. in CI");
+    }
+    if let Some((split, _)) = EXPERIMENT {
+        println!("  --arm <A|B>     Run this variant instead of the one the request ID picks");
+        println!("                  ({}% of runs get A)", split);
+    }
+    if all_steps().any(|step| step.expect.is_some()) && !TUI && !SERVE && LISTEN_SOCKET.is_none() {
+        println!("  --check         Compare the answers with their 'better be's; exit with {} if one", CHECK_FAILED_EXIT_CODE);
+        println!("                  isn't what it better be");
+    }
+    if !TUI && !SERVE && LISTEN_SOCKET.is_none() {
+        println!("  --trailer json  End the output with a ---hihic:{{...}} line saying whether it's");
+        println!("                  complete and how many tokens it took");
+        println!("  --typewriter <n>");
+        println!("                  Show the answer at a steady <n> characters a second, however it");
+        println!("                  streams in; piped output isn't slowed down");
+        println!("  --newline <lf|crlf>");
+        println!("                  End the output's lines with \\n (default) or \\r\\n");
+        println!("  -n, --no-final-newline");
+        println!("                  Leave out the newline the output would end with");
+        println!("  --encoding <name>");
+        println!("                  Write the output in utf-8 (default), utf-16le, utf-16be, latin1 or");
+        println!("                  ascii; what it can't say becomes a ?");
+    }
+    println!("  --doctor        Check the key, the provider, the clock and the proxy settings,");
+    println!("                  print the environment with the keys masked, and exit");
+    if !LOCKED_DOWN {
+        println!("  --record-fallback <file>");
+        println!("                  Append every answer to <file>, a pack for compiling with --fallback");
+    }
+    if !LOCKED_DOWN && !BACKGROUND {
+        println!("  --trace <dir>   Save every model call, what was asked, the answer and how long it");
+        println!("                  took, for 'matthiashihic trace view <dir>'");
+        println!("  --trace-redact <fields>");
+        println!("                  Blank out some of {} in the trace", TRACEABLE.join(", "));
+    }
+    println!();
+    println!("Environment:");
+    println!("  OPENAI_API_KEY  API key (overrides any key embedded at compile time)");
+    if let Some(date) = KEY_EXPIRES {
+        println!("                  (the embedded key is only used until {}, UTC)", date);
+    }
+    println!("  OPENAI_BASE_URL API to use instead of https://api.openai.com/v1");
+    if EXPERIMENT.is_some() {
+        println!("  MATTHIASHIHIC_HISTORY  File runs are recorded in for 'matthiashihic stats'");
+    }
+    if BACKGROUND {
+        println!("  MATTHIASHIHIC_STATE_DIR  Where the jobs are kept, in jobs/");
+    }
+    if BANNER.is_some() {
+        println!("  MATTHIASHIHIC_CONFIG_DIR  Where it's remembered that the banner was shown");
+    }
+    if ENSEMBLE.is_some_and(|(models, judge)| judge.starts_with("claude") || models.iter().any(|m| m.starts_with("claude"))) {
+        println!("  ANTHROPIC_API_KEY  API key for the claude-* models");
+    }
+    for name in SECRETS {
+        println!("  {:<14}  Secret used by the program (required)", name);
+    }
+    for name in ENV_VARS {
+        println!("  {:<14}  Read by the program (required)", name);
+    }
+}
+
+tokio::task_local! {
+    /// Under `--serve` and `--listen-socket`, the id of the request being
+    /// answered.
+    static SERVE_REQUEST_ID: String;
+}
+
+/// Sent as X-Request-Id with every provider request and printed with every
+/// error, so a user's report can be found in the provider's logs. One per run,
+/// or one per request under `--serve` and `--listen-socket`.
+fn request_id() -> String {
+    static RUN_ID: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+    SERVE_REQUEST_ID
+        .try_with(|id| id.clone())
+        .unwrap_or_else(|_| RUN_ID.get_or_init(new_request_id).clone())
+}
+
+/// A random (version 4) UUID.
+fn new_request_id() -> String {
+    use std::hash::{BuildHasher, Hasher};
+
+    // RandomState is seeded by the OS, which is all the randomness this needs
+    let random = |salt: u64| {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u64(salt);
+        hasher.finish()
+    };
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let mut bytes = [random(nanos).to_be_bytes(), random(u64::from(std::process::id())).to_be_bytes()].concat();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// `--watermark`: the line that goes after an answer `model` gave, saying
+/// where it came from, or `None` without one. `matthiashihic provenance`
+/// finds it in text again, so both kinds have to stay the way they're written.
+fn watermark(model: &str) -> Option<String> {
+    let style = WATERMARK?;
+    let seconds = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let time = seconds.rem_euclid(86400);
+    let timestamp = format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        format_date(seconds.div_euclid(86400)),
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    );
+    let program = if VERSION.is_empty() { NAME.to_string() } else { format!("{} {}", NAME, VERSION) };
+    let provenance = format!("[matthiashihic: {}, {}, {}, request {}]", program, model, timestamp, request_id());
+    if style == "line" {
+        return Some(provenance);
+    }
+    // Each bit a zero-width space or non-joiner, between invisible separators
+    let bits: String = provenance
+        .bytes()
+        .flat_map(|byte| (0..8).rev().map(move |bit| if byte >> bit & 1 == 1 { '\u{200C}' } else { '\u{200B}' }))
+        .collect();
+    Some(format!("\u{2063}{}\u{2063}", bits))
+}
+
+/// `--doctor`: checks what a run needs without running the program, so whoever
+/// deploys it can tell a bad key from a blocked network or a wrong clock. Keys
+/// are masked and secrets only said to be set. Returns the exit code, 1 if a
+/// check failed.
+async fn run_doctor() -> i32 {
+    let mut failed = false;
+    let mut report = |status: &str, check: &str, detail: String| {
+        failed |= status == "fail";
+        println!("{:<6} {:<9} {}", format!("[{}]", status), check, detail);
+    };
+    let compiler = serde_json::from_str::<serde_json::Value>(METADATA)
+        .ok()
+        .and_then(|info| info["compiler"].as_str().map(str::to_string))
+        .unwrap_or_default();
+    let mut models: Vec<&str> = Vec::new();
+    for step in all_steps().filter(|step| step.local.is_none()) {
+        if !models.contains(&step.model) {
+            models.push(step.model);
+        }
+    }
+    let program = if VERSION.is_empty() { NAME.to_string() } else { format!("{} {}", NAME, VERSION) };
+    report("info", "program", format!("{}, built by {}, asks {}", program, compiler, models.join(", ")));
+
+    let needs_key = !(all_steps().all(|step| step.local.is_some()) && TRANSLATE_TO.is_none() && !AUDIO_INPUT && IMAGE_OUTPUT.is_none());
+    let key = match std::env::var("OPENAI_API_KEY") {
+        Ok(key) => Some(("OPENAI_API_KEY", key)),
+        Err(_) => embedded_key().map(|key| ("the embedded key", key)),
+    };
+    match &key {
+        _ if !needs_key => report("ok", "key", "not needed, every step runs locally".into()),
+        Some((source, key)) => {
+            let until = KEY_EXPIRES.filter(|_| *source == "the embedded key").map(|date| format!(", until {}", date)).unwrap_or_default();
+            report("ok", "key", format!("{} from {}{}", mask(key), source, until))
+        }
+        None if std::env::var_os("OPENAI_BASE_URL").is_some() => report("warn", "key", "none, which only works if OPENAI_BASE_URL doesn't want one".into()),
+        None if !FALLBACK.is_empty() => report("warn", "key", "none, so the fallback pack answers everything it knows".into()),
+        None if key_expired() => report("fail", "key", format!("the embedded key expired after {}; set OPENAI_API_KEY", KEY_EXPIRES.unwrap_or_default())),
+        None => report("fail", "key", "none; set OPENAI_API_KEY".into()),
+    }
+    let claude = ENSEMBLE.is_some_and(|(models, judge)| judge.starts_with("claude") || models.iter().any(|m| m.starts_with("claude")));
+    if claude {
+        match std::env::var("ANTHROPIC_API_KEY") {
+            Ok(key) => report("ok", "key", format!("{} from ANTHROPIC_API_KEY", mask(&key))),
+            Err(_) => report("fail", "key", "the claude-* models need ANTHROPIC_API_KEY".into()),
+        }
+    }
+
+    if BROKER {
+        match broker_listening() {
+            Ok(socket) => report("ok", "broker", format!("listening on {}", socket)),
+            Err(e) => report("fail", "broker", e),
+        }
+    }
+
+    // Listing the models takes the key, but no tokens
+    let mut provider_time = None;
+    if needs_key {
+        let url = openai_url("models");
+        let mut request = http_client().get(&url).timeout(std::time::Duration::from_secs(10)).header("X-Request-Id", request_id());
+        if let Some((_, key)) = &key {
+            request = request.bearer_auth(key);
+        }
+        let started = std::time::Instant::now();
+        match request.send().await {
+            Ok(response) => {
+                let status = response.status();
+                let took = started.elapsed().as_millis();
+                provider_time = response.headers().get("date").and_then(|date| date.to_str().ok()).and_then(parse_http_date);
+                match status.as_u16() {
+                    200..=299 if key.is_some() => report("ok", "provider", format!("{} answered {} in {}ms, so the key works", url, status, took)),
+                    200..=299 => report("ok", "provider", format!("{} answered {} in {}ms without a key", url, status, took)),
+                    401 | 403 => report("fail", "provider", format!("{} answered {}: the key is wrong, revoked or lacks access", url, status)),
+                    _ => report("warn", "provider", format!("{} answered {} in {}ms", url, status, took)),
+                }
+            }
+            Err(e) => {
+                // reqwest's own message leaves out why, e.g. that the proxy refused
+                let mut cause = e.to_string();
+                let mut source = std::error::Error::source(&e);
+                while let Some(inner) = source {
+                    cause.push_str(&format!(": {}", inner));
+                    source = inner.source();
+                }
+                report("fail", "provider", format!("{} can't be reached: {}", url, cause))
+            }
+        }
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    match provider_time {
+        Some(time) if (now - time).abs() <= MAX_CLOCK_SKEW => report("ok", "clock", format!("within {}s of the provider's", (now - time).abs())),
+        Some(time) => report(
+            "warn",
+            "clock",
+            format!("{}s {} the provider's; TLS and anything signed may fail", (now - time).abs(), if now > time { "ahead of" } else { "behind" }),
+        ),
+        None => report("warn", "clock", "can't tell, no answer from the provider with a Date".into()),
+    }
+
+    let proxies: Vec<String> = PROXY_VARS
+        .iter()
+        .filter_map(|name| std::env::var(name).ok().filter(|value| !value.is_empty()).map(|value| format!("{}={}", name, mask_proxy(&value))))
+        .collect();
+    if proxies.is_empty() {
+        report("info", "proxy", "none, requests go out directly".into());
+    } else {
+        report("info", "proxy", proxies.join(" "));
+    }
+    for name in SECRETS {
+        match std::env::var(name) {
+            Ok(value) => report("ok", "secret", format!("{} is set ({} characters)", name, value.chars().count())),
+            Err(_) => report("fail", "secret", format!("{} isn't set, and the program needs it", name)),
+        }
+    }
+    for name in ENV_VARS {
+        match std::env::var(name) {
+            Ok(value) => report("ok", "env", format!("{}={}", name, value)),
+            Err(_) => report("fail", "env", format!("{} isn't set, and the program needs it", name)),
+        }
+    }
+
+    println!();
+    println!("Environment:");
+    for name in DOCTOR_VARS {
+        let value = match std::env::var(name) {
+            Ok(value) if name.ends_with("_KEY") => mask(&value),
+            Ok(value) => value,
+            Err(_) => "(not set)".to_string(),
+        };
+        println!("  {:<26} {}", name, value);
+    }
+    if failed {
+        1
+    } else {
+        0
+    }
+}
+
+/// `--doctor` warns when the clock is further than this from the provider's.
+const MAX_CLOCK_SKEW: i64 = 60;
+
+/// The proxy variables reqwest picks up.
+const PROXY_VARS: &[&str] = &["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy", "NO_PROXY", "no_proxy"];
+
+/// What `--doctor` reports, besides the secrets.
+const DOCTOR_VARS: &[&str] = &[
+    "OPENAI_API_KEY",
+    "OPENAI_BASE_URL",
+    "ANTHROPIC_API_KEY",
+    "SSL_CERT_FILE",
+    "SSL_CERT_DIR",
+    "MATTHIASHIHIC_CONFIG_DIR",
+    "MATTHIASHIHIC_CACHE_DIR",
+    "MATTHIASHIHIC_STATE_DIR",
+    "MATTHIASHIHIC_TEMP_DIR",
+    "MATTHIASHIHIC_HISTORY",
+];
+
+/// Seconds since 1970 of an HTTP date like `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn parse_http_date(date: &str) -> Option<i64> {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let [_, day, month, year, time, "GMT"] = date.split_whitespace().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let month = MONTHS.iter().position(|m| *m == month)? as i64 + 1;
+    let [hours, minutes, seconds] = time.split(':').map(|part| part.parse::<i64>().ok()).collect::<Option<Vec<_>>>()?[..] else {
+        return None;
+    };
+    let days = days_from_civil(year.parse().ok()?, month, day.parse().ok()?);
+    Some(days * 86400 + hours * 3600 + minutes * 60 + seconds)
+}
@@ -0,0 +1,634 @@
+//! The model: requests to OpenAI-compatible (and Anthropic) endpoints, their
+//! streamed answers, ensembles, hooks around every call, refusals, and the
+//! audio and image endpoints. Every request waits for its `turn` first.
+
+use crate::*;
+
+/// Whether the provider itself marked the last answer as a refusal.
+pub(crate) static REFUSAL_FLAGGED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Whether the last request to the model couldn't get through or had its key
+/// refused, which is when FALLBACK answers instead.
+pub(crate) static UNAVAILABLE: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// `--show-all`: print every ensemble answer to stderr before the judge's.
+pub(crate) static SHOW_ALL: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Tokens the provider reported so far, as (prompt, completion), for the trailer.
+pub(crate) static USAGE: std::sync::Mutex<Option<(u64, u64)>> = std::sync::Mutex::new(None);
+
+/// Tokens the provider reported so far, as (prompt, completion).
+pub(crate) fn usage() -> Option<(u64, u64)> {
+    USAGE.lock().ok().and_then(|usage| *usage)
+}
+
+tokio::task_local! {
+    /// Tokens the chat turn being answered has used so far, for
+    /// `--session-tokens`.
+    static TURN_TOKENS: std::cell::Cell<u64>;
+}
+
+/// Runs a chat turn and counts the tokens it uses, which USAGE only has for
+/// the whole program.
+#[cfg(any(feature = "tui", feature = "serve"))]
+pub(crate) async fn counting_tokens<T>(turn: impl std::future::Future<Output = T>) -> (T, u64) {
+    let turn = async {
+        let output = turn.await;
+        (output, TURN_TOKENS.with(std::cell::Cell::get))
+    };
+    TURN_TOKENS.scope(std::cell::Cell::new(0), turn).await
+}
+
+/// Adds a response's `usage` (OpenAI's or Anthropic's field names) to USAGE.
+pub(crate) fn add_usage(usage: &serde_json::Value) {
+    let tokens = |keys: [&str; 2]| keys.iter().find_map(|key| usage[*key].as_u64());
+    let (Some(prompt), Some(completion)) = (tokens(["prompt_tokens", "input_tokens"]), tokens(["completion_tokens", "output_tokens"])) else {
+        return;
+    };
+    let _ = TURN_TOKENS.try_with(|turn| turn.set(turn.get() + prompt + completion));
+    if let Ok(mut total) = USAGE.lock() {
+        let (p, c) = total.unwrap_or_default();
+        *total = Some((p + prompt, c + completion));
+    }
+}
+
+/// Remembers for run_invocation whether the last request got through: an
+/// unreachable model, a refused key or an overloaded provider is when the
+/// fallback pack answers, a request the model didn't like isn't.
+pub(crate) fn note_availability(response: Result<reqwest::StatusCode, &reqwest::Error>) {
+    let unavailable = match response {
+        Ok(status) => matches!(status.as_u16(), 401 | 403 | 429) || status.is_server_error(),
+        Err(error) => error.is_connect() || error.is_timeout(),
+    };
+    UNAVAILABLE.store(unavailable, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Whether an answer starts the way a model declines: "I'm sorry, but I can't
+/// help with that", "As an AI language model, I cannot provide ...". Only the
+/// start counts, so an answer that says sorry somewhere is still an answer.
+pub(crate) fn is_refusal(answer: &str) -> bool {
+    let start = answer.trim_start().chars().take(200).collect::<String>().to_lowercase().replace('\u{2019}', "'");
+    let mut rest = start.as_str();
+    if let Some(apology) = REFUSAL_APOLOGIES.iter().find(|apology| rest.starts_with(*apology)) {
+        rest = rest[apology.len()..].trim_start();
+    }
+    if rest.starts_with("as an ai") {
+        rest = rest.split_once(',').map_or("", |(_, after)| after.trim_start());
+    }
+    if rest.starts_with("i must decline") {
+        return true;
+    }
+    REFUSAL_OPENINGS.iter().any(|opening| {
+        rest.strip_prefix(opening)
+            .is_some_and(|after| REFUSAL_VERBS.iter().any(|verb| after.trim_start().starts_with(verb)))
+    })
+}
+
+/// Stripped off before looking for a refusal; longer ones first.
+pub(crate) const REFUSAL_APOLOGIES: &[&str] = &[
+    "i'm sorry, but", "i am sorry, but", "i'm really sorry, but", "sorry, but", "i apologize, but", "i apologise, but",
+    "i'm sorry,", "i am sorry,", "i'm sorry.", "sorry,", "unfortunately,",
+];
+
+/// A refusal is one of these followed by one of REFUSAL_VERBS.
+pub(crate) const REFUSAL_OPENINGS: &[&str] = &[
+    "i can't", "i cannot", "i can not", "i'm unable to", "i am unable to", "i'm not able to", "i am not able to",
+    "i won't", "i will not", "i'm not going to", "i am not going to",
+];
+
+pub(crate) const REFUSAL_VERBS: &[&str] = &[
+    "help", "assist", "do that", "do this", "provide", "comply", "fulfill", "fulfil", "create", "write", "answer",
+    "generate", "support", "share", "give", "complete", "engage", "produce", "continue", "proceed",
+];
+
+/// Asks every ensemble model at once, then streams the judge's pick. Models
+/// that fail are left out with a warning, as long as one of them answers.
+pub(crate) async fn run_ensemble(
+    api_key: &str,
+    history: &[(String, String)],
+    pseudocode: &str,
+    schema: Option<&str>,
+    temperature: Option<f64>,
+    echo: Echo<'_>,
+) -> Result<String, String> {
+    let Some((models, judge)) = ENSEMBLE else {
+        return Err("this program was compiled without --ensemble".into());
+    };
+    let show_all = SHOW_ALL.load(std::sync::atomic::Ordering::Relaxed);
+    let answers = futures_util::future::join_all(
+        models.iter().map(|model| run_model(api_key, model, SYSTEM_PROMPT, history, pseudocode, schema, temperature, Echo::Off)),
+    )
+    .await;
+
+    // The judge doesn't learn which model said what, so it can't play favourites
+    let mut candidates = String::new();
+    let mut count = 0;
+    for (model, answer) in models.iter().zip(answers) {
+        match answer {
+            Ok(answer) => {
+                if show_all {
+                    eprintln!("--- {} ---\n{}", model, answer);
+                }
+                count += 1;
+                candidates.push_str(&format!("\n\nCandidate {}:\n{}", count, answer));
+            }
+            Err(e) => eprintln!("Warning: {} failed: {}", model, e),
+        }
+    }
+    if count == 0 {
+        return Err("Every ensemble model failed".into());
+    }
+    if show_all {
+        eprintln!("--- {} (judge) ---", judge);
+    }
+    let request = format!("Request:\n{}{}", pseudocode, candidates);
+    run_model(api_key, judge, JUDGE_PROMPT, history, &request, schema, temperature, echo)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Models named `claude-...` are Anthropic's; everything else goes to OpenAI.
+/// Anthropic's API has no structured outputs, so a `schema` goes into its
+/// system prompt. A step's `temperature` wins over the program's.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn run_model(
+    api_key: &str,
+    model: &str,
+    system: &str,
+    history: &[(String, String)],
+    pseudocode: &str,
+    schema: Option<&str>,
+    temperature: Option<f64>,
+    echo: Echo<'_>,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    if !model.starts_with("claude") {
+        return run_openai_stream(api_key, model, system, history, pseudocode, schema, temperature, echo).await;
+    }
+    let call = run_anthropic(model, system, history, pseudocode, schema, temperature, echo);
+    traced(model, system, history, pseudocode, call).await
+}
+
+async fn run_anthropic(
+    model: &str,
+    system: &str,
+    history: &[(String, String)],
+    pseudocode: &str,
+    schema: Option<&str>,
+    temperature: Option<f64>,
+    echo: Echo<'_>,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let system = match schema {
+        Some(schema) => format!("{}\n\nReply with JSON only, no code fences, that matches this JSON Schema: {}", system, schema),
+        None => system.to_string(),
+    };
+    let api_key = std::env::var("ANTHROPIC_API_KEY")
+        .map_err(|_| format!("{} needs the ANTHROPIC_API_KEY environment variable", model))?;
+    let mut messages = Vec::new();
+    for (statements, answer) in history {
+        messages.push(serde_json::json!({"role": "user", "content": statements}));
+        messages.push(serde_json::json!({"role": "assistant", "content": answer}));
+    }
+    messages.push(serde_json::json!({"role": "user", "content": pseudocode}));
+    let mut request_body = serde_json::json!({
+        "model": model,
+        "system": format!("{}{}", system, GLOSSARY_PROMPT),
+        "messages": messages,
+        // Required by the messages API
+        "max_tokens": MAX_TOKENS.unwrap_or(4096)
+    });
+    if let Some(temperature) = temperature.or(TEMPERATURE) {
+        request_body["temperature"] = serde_json::json!(temperature);
+    }
+    let request_body = run_hooks("pre-request", serde_json::json!({"request": request_body}))?["request"].take();
+
+    // The broker's keys are OpenAI's, but the turn counts all the same
+    let turn = turn().await?;
+    let response = http_client()
+        .post("https://api.anthropic.com/v1/messages")
+        .header("x-api-key", api_key)
+        .header("X-Request-Id", request_id())
+        .header("anthropic-version", "2023-06-01")
+        .json(&request_body)
+        .send()
+        .await
+        .inspect_err(|e| note_availability(Err(e)))?;
+    note_availability(Ok(response.status()));
+    turn.done(response.status().as_u16()).await;
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+        return Err(format!("Anthropic API error ({}): {}", status, error_text).into());
+    }
+    let parsed: serde_json::Value = response.json().await?;
+    add_usage(&parsed["usage"]);
+    if parsed["stop_reason"] == "refusal" {
+        REFUSAL_FLAGGED.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+    let answer: String = parsed["content"]
+        .as_array()
+        .map(|blocks| blocks.iter().filter_map(|block| block["text"].as_str()).collect())
+        .unwrap_or_default();
+    // Not streamed, so the whole answer is the one delta
+    let answer = run_response_hooks(model, run_delta_hooks(model, &answer)?)?;
+    echo.line(&answer).await?;
+    Ok(answer)
+}
+
+#[cfg(feature = "audio")]
+pub(crate) async fn transcribe(api_key: &str, path: &std::path::Path) -> Result<String, Box<dyn std::error::Error>> {
+    let bytes = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "audio.wav".to_string());
+    let form = reqwest::multipart::Form::new()
+        .text("model", TRANSCRIPTION_MODEL)
+        .part("file", reqwest::multipart::Part::bytes(bytes).file_name(file_name));
+    let turn = turn().await?;
+    let response = http_client()
+        .post(openai_url("audio/transcriptions"))
+        .header("Authorization", format!("Bearer {}", turn.key(api_key)))
+        .header("X-Request-Id", request_id())
+        .multipart(form)
+        .send()
+        .await?;
+    turn.done(response.status().as_u16()).await;
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+        return Err(format!("OpenAI API error ({}): {}", status, error_text).into());
+    }
+    let body: serde_json::Value = response.json().await?;
+    let text = body["text"].as_str().ok_or("The transcription response has no text")?;
+    Ok(text.trim().to_string())
+}
+
+#[cfg(not(feature = "audio"))]
+pub(crate) async fn transcribe(_api_key: &str, _path: &std::path::Path) -> Result<String, Box<dyn std::error::Error>> {
+    Err("this program was compiled without audio support".into())
+}
+
+/// Sends `prompt` to the images endpoint and writes the PNG it returns to `path`.
+#[cfg(feature = "image")]
+pub(crate) async fn generate_image(api_key: &str, prompt: &str, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    use base64::Engine;
+
+    let request_body = serde_json::json!({
+        "model": IMAGE_MODEL,
+        "prompt": prompt,
+        "n": 1,
+        "response_format": "b64_json"
+    });
+    let turn = turn().await?;
+    let response = http_client()
+        .post(openai_url("images/generations"))
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}", turn.key(api_key)))
+        .header("X-Request-Id", request_id())
+        .json(&request_body)
+        .send()
+        .await?;
+    turn.done(response.status().as_u16()).await;
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+        return Err(format!("OpenAI API error ({}): {}", status, error_text).into());
+    }
+    let body: serde_json::Value = response.json().await?;
+    let encoded = body["data"][0]["b64_json"].as_str().ok_or("The image response has no image data")?;
+    let png = base64::engine::general_purpose::STANDARD.decode(encoded)?;
+    std::fs::write(path, png).map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    Ok(())
+}
+
+#[cfg(not(feature = "image"))]
+pub(crate) async fn generate_image(_api_key: &str, _prompt: &str, _path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    Err("this program was compiled without image support".into())
+}
+
+/// The embedding of `text`, for `--cache-similarity`.
+pub(crate) async fn embed(api_key: &str, text: &str) -> Result<Vec<f64>, Box<dyn std::error::Error + Send + Sync>> {
+    let request_body = serde_json::json!({"model": EMBEDDING_MODEL, "input": text});
+    let turn = turn().await?;
+    let response = http_client()
+        .post(openai_url("embeddings"))
+        .header("Authorization", format!("Bearer {}", turn.key(api_key)))
+        .header("X-Request-Id", request_id())
+        .json(&request_body)
+        .send()
+        .await?;
+    turn.done(response.status().as_u16()).await;
+    if !response.status().is_success() {
+        let status = response.status();
+        let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+        return Err(format!("OpenAI API error ({}): {}", status, error_text).into());
+    }
+    let body: serde_json::Value = response.json().await?;
+    let embedding = body["data"][0]["embedding"].as_array().ok_or("The embeddings response has no embedding")?;
+    Ok(embedding.iter().filter_map(|value| value.as_f64()).collect())
+}
+
+/// `--request-template`: the template with every "€name" string replaced by
+/// `body[name]`, dropping the ones `body` doesn't have. It always streams, so
+/// `stream` and `stream_options` come along as they are.
+fn apply_request_template(template: &str, body: &serde_json::Value) -> Result<serde_json::Value, String> {
+    let mut request: serde_json::Value = serde_json::from_str(template).map_err(|e| format!("The request template isn't JSON: {}", e))?;
+    fill_request_template(&mut request, body);
+    for key in ["stream", "stream_options"] {
+        if let Some(value) = body.get(key) {
+            request[key] = value.clone();
+        }
+    }
+    Ok(request)
+}
+
+fn fill_request_template(value: &mut serde_json::Value, body: &serde_json::Value) {
+    // A variable the body doesn't have, like an unset temperature, and what it's in
+    let unset = |item: &serde_json::Value| template_variable(item).is_some_and(|name| body.get(name).is_none());
+    match value {
+        serde_json::Value::Array(items) => {
+            items.retain(|item| !unset(item));
+            items.iter_mut().for_each(|item| fill_request_template(item, body));
+        }
+        serde_json::Value::Object(entries) => {
+            entries.retain(|_, item| !unset(item));
+            entries.values_mut().for_each(|item| fill_request_template(item, body));
+        }
+        _ => {
+            if let Some(filled) = template_variable(value).map(|name| body[name].clone()) {
+                *value = filled;
+            }
+        }
+    }
+}
+
+/// The name of the variable a template string is, if it's one.
+fn template_variable(value: &serde_json::Value) -> Option<&str> {
+    let name = value.as_str()?.strip_prefix('€')?;
+    ["model", "messages", "temperature", "max_tokens", "response_format"].contains(&name).then_some(name)
+}
+
+/// An OpenAI endpoint, under OPENAI_BASE_URL if that's set (e.g. for
+/// `matthiashihic mock-server`).
+pub(crate) fn openai_url(endpoint: &str) -> String {
+    let base = std::env::var("OPENAI_BASE_URL").ok().filter(|url| !url.is_empty());
+    format!("{}/{}", base.as_deref().unwrap_or("https://api.openai.com/v1").trim_end_matches('/'), endpoint)
+}
+
+/// One client for every request, so calls reuse their connections.
+pub(crate) fn http_client() -> &'static reqwest::Client {
+    static CLIENT: std::sync::OnceLock<reqwest::Client> = std::sync::OnceLock::new();
+    CLIENT.get_or_init(reqwest::Client::new)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn run_openai_stream(
+    api_key: &str,
+    model: &str,
+    system: &str,
+    history: &[(String, String)],
+    pseudocode: &str,
+    schema: Option<&str>,
+    temperature: Option<f64>,
+    echo: Echo<'_>,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let call = openai_stream(api_key, model, system, history, pseudocode, schema, temperature, echo);
+    traced(model, system, history, pseudocode, call).await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn openai_stream(
+    api_key: &str,
+    model: &str,
+    system: &str,
+    history: &[(String, String)],
+    pseudocode: &str,
+    schema: Option<&str>,
+    temperature: Option<f64>,
+    echo: Echo<'_>,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    // Every prompt hears about the glossary, the judge's and the translator's too
+    let system = format!("{}{}", system, GLOSSARY_PROMPT);
+    let mut messages = vec![serde_json::json!({"role": "system", "content": system})];
+    for (statements, answer) in history {
+        messages.push(serde_json::json!({"role": "user", "content": statements}));
+        messages.push(serde_json::json!({"role": "assistant", "content": answer}));
+    }
+    messages.push(serde_json::json!({"role": "user", "content": pseudocode}));
+
+    let client = http_client();
+    let mut request_body = serde_json::json!({
+        "model": model,
+        "messages": messages,
+        "stream": true
+    });
+    if let Some(temperature) = temperature.or(TEMPERATURE) {
+        request_body["temperature"] = serde_json::json!(temperature);
+    }
+    if let Some(max_tokens) = MAX_TOKENS {
+        request_body["max_tokens"] = serde_json::json!(max_tokens);
+    }
+    if let Some(schema) = schema {
+        let schema: serde_json::Value = serde_json::from_str(schema)?;
+        request_body["response_format"] = serde_json::json!({
+            "type": "json_schema",
+            "json_schema": {"name": "answer", "strict": true, "schema": schema}
+        });
+    }
+    if TRAILER.load(std::sync::atomic::Ordering::Relaxed) || EXPERIMENT.is_some() {
+        // Streams only report usage when asked to
+        request_body["stream_options"] = serde_json::json!({"include_usage": true});
+    }
+    if let Some(template) = REQUEST_TEMPLATE {
+        request_body = apply_request_template(template, &request_body)?;
+    }
+    let request_body = run_hooks("pre-request", serde_json::json!({"request": request_body}))?["request"].take();
+    // A post-response hook may still change the answer, so it's only shown once it has
+    let streamed = if has_hook("post-response") { Echo::Off } else { echo };
+    
+    let mut output = String::new();
+    let mut body = request_body.clone();
+    let mut attempts = 0;
+    loop {
+        let turn = turn().await?;
+        let response = client
+            .post(openai_url("chat/completions"))
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", turn.key(api_key)))
+            .header("X-Request-Id", request_id())
+            .json(&body)
+            .send()
+            .await
+            .inspect_err(|e| note_availability(Err(e)))?;
+        note_availability(Ok(response.status()));
+
+        if !response.status().is_success() {
+            let status = response.status();
+            turn.done(status.as_u16()).await;
+            let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(format!("OpenAI API error ({}): {}", status, error_text).into());
+        }
+
+        let stopped = read_openai_stream(response, model, streamed, &mut output).await?;
+        turn.done(200).await;
+        let Some(reason) = stopped else {
+            break;
+        };
+        if attempts == RESUME_ATTEMPTS {
+            if !output.is_empty() {
+                streamed.line("").await?;
+            }
+            return Err(format!(
+                "The answer was cut off after {} characters ({}); the output is incomplete",
+                output.chars().count(),
+                reason
+            )
+            .into());
+        }
+        attempts += 1;
+        eprintln!("Warning: the answer was cut off ({}); asking for the rest ({}/{})", reason, attempts, RESUME_ATTEMPTS);
+        body = request_body.clone();
+        if !output.is_empty() {
+            // What made it, and where it stopped, so the model can pick up there
+            let tail: String = output.chars().rev().take(200).collect::<Vec<_>>().into_iter().rev().collect();
+            if let Some(messages) = body["messages"].as_array_mut() {
+                messages.push(serde_json::json!({"role": "assistant", "content": output}));
+                messages.push(serde_json::json!({"role": "user", "content": format!("{}\n\n{}", CONTINUE_PROMPT, tail)}));
+            }
+        }
+    }
+
+    if has_hook("post-response") {
+        let output = run_response_hooks(model, output)?;
+        echo.line(&output).await?;
+        return Ok(output);
+    }
+    echo.line("").await?;
+    Ok(output)
+}
+
+/// Reads a chat completion stream into `output`, echoing as it goes. Returns
+/// why the stream ended early if it did: a stream that stops without a
+/// `finish_reason` or `[DONE]` is a half answer that looks like a whole one.
+pub(crate) async fn read_openai_stream(
+    response: reqwest::Response,
+    model: &str,
+    echo: Echo<'_>,
+    output: &mut String,
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+    use futures_util::StreamExt;
+    let mut stream = response.bytes_stream();
+    let mut buffer = String::new();
+    let mut finished = false;
+
+    while let Some(chunk_result) = stream.next().await {
+        let chunk = match chunk_result {
+            Ok(chunk) => chunk,
+            Err(e) => return Ok(Some(format!("the connection broke: {}", e))),
+        };
+        let text = String::from_utf8_lossy(&chunk);
+        buffer.push_str(&text);
+
+        while let Some(newline_pos) = buffer.find('\n') {
+            let line = buffer[..newline_pos].to_string();
+            buffer = buffer[newline_pos + 1..].to_string();
+
+            if let Some(data) = line.strip_prefix("data: ") {
+                if data.trim() == "[DONE]" {
+                    return Ok(None);
+                }
+
+                if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(data) {
+                    add_usage(&parsed["usage"]);
+                    if let Some(choice) = parsed["choices"].as_array().and_then(|choices| choices.first()) {
+                        if let Some(content) = choice["delta"]["content"].as_str() {
+                            let content = run_delta_hooks(model, content)?;
+                            if !content.is_empty() {
+                                output.push_str(&content);
+                                // Returning drops the stream, which cancels the request
+                                echo.text(&content).await?;
+                            }
+                        }
+                        // The API's refusal comes instead of the content
+                        if let Some(refusal) = choice["delta"]["refusal"].as_str().filter(|refusal| !refusal.is_empty()) {
+                            REFUSAL_FLAGGED.store(true, std::sync::atomic::Ordering::Relaxed);
+                            output.push_str(refusal);
+                            echo.text(refusal).await?;
+                        }
+                        finished |= !choice["finish_reason"].is_null();
+                    }
+                }
+            }
+        }
+    }
+    Ok((!finished).then(|| "the stream ended before the answer did".to_string()))
+}
+
+/// Whether any `--hook` is registered for `point`.
+pub(crate) fn has_hook(point: &str) -> bool {
+    HOOKS.iter().any(|(registered, _)| *registered == point)
+}
+
+/// Runs the `point` hooks on `payload`, in order. Each gets it as one line of
+/// JSON on stdin, with a "hook" field naming the point, and may print a changed
+/// payload; printing nothing leaves it as it is. `.wasm` modules run in wasmtime.
+pub(crate) fn run_hooks(point: &str, mut payload: serde_json::Value) -> Result<serde_json::Value, String> {
+    use std::process::{Command, Stdio};
+
+    for (_, command) in HOOKS.iter().filter(|(registered, _)| *registered == point) {
+        payload["hook"] = serde_json::json!(point);
+        payload["request_id"] = serde_json::json!(request_id());
+        let mut child = if command.ends_with(".wasm") {
+            let mut child = Command::new("wasmtime");
+            child.args(["run", command]);
+            child
+        } else if cfg!(windows) {
+            let mut child = Command::new("cmd");
+            child.args(["/C", command]);
+            child
+        } else {
+            let mut child = Command::new("sh");
+            child.args(["-c", command]);
+            child
+        };
+        let mut child = child
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to run {} hook '{}': {}", point, command, e))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // A hook that doesn't read its payload is fine, too
+            let _ = writeln!(stdin, "{}", payload);
+        }
+        let output = child
+            .wait_with_output()
+            .map_err(|e| format!("Failed to run {} hook '{}': {}", point, command, e))?;
+        if !output.status.success() {
+            return Err(format!("{} hook '{}' failed ({})", point, command, output.status));
+        }
+        let printed = String::from_utf8_lossy(&output.stdout);
+        if !printed.trim().is_empty() {
+            payload = serde_json::from_str(printed.trim())
+                .map_err(|e| format!("{} hook '{}' printed invalid JSON: {}", point, command, e))?;
+        }
+    }
+    Ok(payload)
+}
+
+/// A streamed piece of an answer, after the per-delta hooks.
+pub(crate) fn run_delta_hooks(model: &str, delta: &str) -> Result<String, String> {
+    if !has_hook("per-delta") {
+        return Ok(delta.to_string());
+    }
+    let payload = run_hooks("per-delta", serde_json::json!({"model": model, "delta": delta}))?;
+    Ok(payload["delta"].as_str().unwrap_or_default().to_string())
+}
+
+/// A whole answer, after the post-response hooks.
+pub(crate) fn run_response_hooks(model: &str, content: String) -> Result<String, String> {
+    if !has_hook("post-response") {
+        return Ok(content);
+    }
+    let payload = run_hooks("post-response", serde_json::json!({"model": model, "content": content}))?;
+    Ok(payload["content"].as_str().unwrap_or_default().to_string())
+}
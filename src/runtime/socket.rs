@@ -0,0 +1,242 @@
+//! `--listen-socket`: other programs on the machine send placeholder values
+//! over a Unix socket (a named pipe on Windows) and get the answer streamed
+//! back, without HTTP in between. Every message, either way, is a 4-byte
+//! big-endian length and that many bytes of JSON.
+
+use crate::*;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest request a client may send; a bigger one ends the connection.
+const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+/// What every connection shares.
+struct SocketState {
+    api_key: String,
+    secrets: Vec<(&'static str, String)>,
+}
+
+/// Listens on `path` until the program is stopped. A socket file nobody
+/// listens on is left over from an earlier run, and replaced.
+#[cfg(unix)]
+pub(crate) async fn listen_socket(api_key: String, secrets: Vec<(&'static str, String)>, path: &str) -> Result<(), String> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format!("{} exists and isn't a socket; pick another path", path));
+        }
+        if tokio::net::UnixStream::connect(path).await.is_ok() {
+            return Err(format!("Something is listening on {} already", path));
+        }
+        std::fs::remove_file(path).map_err(|e| format!("Can't remove the old socket {}: {}", path, e))?;
+    }
+    let listener = tokio::net::UnixListener::bind(path).map_err(|e| format!("Can't listen on {}: {}", path, e))?;
+    eprintln!("{} is listening on {}", NAME, path);
+    let state = std::sync::Arc::new(SocketState { api_key, secrets });
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(connection(state.clone(), stream));
+            }
+            Err(e) => {
+                // Out of file descriptors, say; the connections already open may free some
+                eprintln!("Warning: Can't accept a connection on {}: {}", path, e);
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+/// Windows has no Unix sockets to speak of, so it's a named pipe, one
+/// instance per connection.
+#[cfg(windows)]
+pub(crate) async fn listen_socket(api_key: String, secrets: Vec<(&'static str, String)>, path: &str) -> Result<(), String> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    if !path.starts_with(r"\\.\pipe\") {
+        return Err(format!(r"On Windows, --listen-socket takes a named pipe like \\.\pipe\{}, got {}", NAME, path));
+    }
+    let create = |first: bool| {
+        ServerOptions::new()
+            .first_pipe_instance(first)
+            .create(path)
+            .map_err(|e| format!("Can't listen on {}: {}", path, e))
+    };
+    let mut server = create(true)?;
+    eprintln!("{} is listening on {}", NAME, path);
+    let state = std::sync::Arc::new(SocketState { api_key, secrets });
+    loop {
+        server.connect().await.map_err(|e| format!("Can't accept a connection on {}: {}", path, e))?;
+        // The next client needs an instance of its own to connect to
+        let connected = std::mem::replace(&mut server, create(false)?);
+        tokio::spawn(connection(state.clone(), connected));
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) async fn listen_socket(_api_key: String, _secrets: Vec<(&'static str, String)>, _path: &str) -> Result<(), String> {
+    Err("--listen-socket needs Unix sockets or named pipes, which this platform doesn't have".into())
+}
+
+/// Answers a client's requests one after the other until it hangs up. Each
+/// one is answered under its `id` if it sent one, or a new request ID.
+async fn connection<S>(state: std::sync::Arc<SocketState>, mut stream: S)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let body = match read_frame(&mut stream).await {
+            Ok(Some(body)) => body,
+            Ok(None) => return,
+            Err(e) => {
+                let _ = write_frame(&mut stream, &serde_json::json!({"type": "error", "message": e})).await;
+                return;
+            }
+        };
+        let request: serde_json::Value = match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(e) => {
+                let error = serde_json::json!({"type": "error", "message": format!("Invalid JSON: {}", e)});
+                if write_frame(&mut stream, &error).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
+        let id = request["id"]
+            .as_str()
+            .filter(|id| !id.is_empty() && id.len() <= 200)
+            .map(str::to_string)
+            .unwrap_or_else(new_request_id);
+        if SERVE_REQUEST_ID.scope(id, answer(&state, &request, &mut stream)).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Runs the program for one request, sending the answer as `text` messages
+/// while it comes in, then `done` with the whole output, or `error`. Fails
+/// once the client is gone; dropping the invocation aborts its requests.
+async fn answer<S>(state: &SocketState, request: &serde_json::Value, stream: &mut S) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let started = std::time::Instant::now();
+    let error = |message: &str| serde_json::json!({"type": "error", "message": message, "request_id": request_id()});
+    let prepared = request_args(request).and_then(prepare_args);
+    let (args, files) = match prepared {
+        Ok(prepared) => prepared,
+        Err(e) => return write_frame(stream, &error(&redact(&e, &state.secrets))).await,
+    };
+
+    let mut history: Vec<(String, String)> = Vec::new();
+    let (tokens_tx, mut tokens) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let run = async {
+        let tokens_tx = tokens_tx;
+        Ok::<_, std::io::Error>(run_invocation(&state.api_key, &args, &state.secrets, &files, &mut history, Echo::Channel(&tokens_tx)).await)
+    };
+    let forward = async {
+        while let Some(text) = tokens.recv().await {
+            if text.is_empty() {
+                continue;
+            }
+            write_frame(stream, &serde_json::json!({"type": "text", "text": text})).await?;
+        }
+        Ok(())
+    };
+    let result = match tokio::try_join!(run, forward) {
+        Ok((result, ())) => result,
+        Err(e) => {
+            eprintln!("{}: the client disconnected, so the request was cancelled", request_id());
+            record_run(started, None, Some(CLIENT_GONE), None);
+            return Err(e);
+        }
+    };
+    // Tokens are only counted for the whole program, so they aren't recorded
+    match result {
+        Ok(mut output) => {
+            if let Some(mark) = watermark(MODEL) {
+                write_frame(stream, &serde_json::json!({"type": "text", "text": format!("\n{}", mark)})).await?;
+                output = format!("{}\n{}", output, mark);
+            }
+            record_run(started, Some(output.chars().count()), None, None);
+            write_frame(stream, &serde_json::json!({"type": "done", "output": output, "request_id": request_id()})).await
+        }
+        Err(e) => {
+            let message = redact(&e, &state.secrets);
+            record_run(started, None, Some(&message), None);
+            eprintln!("Error: {} (request {})", message, request_id());
+            write_frame(stream, &error(&message)).await
+        }
+    }
+}
+
+/// The placeholder values of a request, in the order `prepare_args` expects:
+/// `args` for €1, €2, ..., then `named` for the €{name}s.
+fn request_args(request: &serde_json::Value) -> Result<Vec<String>, String> {
+    let max_arg = REQUIRED_ARGS.iter().max().copied().unwrap_or(0);
+    let strings = |value: &serde_json::Value| value.as_str().map(str::to_string);
+    let args: Vec<String> = match &request["args"] {
+        serde_json::Value::Null => Vec::new(),
+        serde_json::Value::Array(values) => values
+            .iter()
+            .map(strings)
+            .collect::<Option<_>>()
+            .ok_or("'args' has to be a list of strings")?,
+        _ => return Err("'args' has to be a list of strings".into()),
+    };
+    if max_arg == 0 && !args.is_empty() {
+        return Err("This program has no €1, €2, ..., so 'args' has to be empty".into());
+    }
+    let needed = needed_args();
+    if needed == max_arg && args.len() != max_arg {
+        return Err(format!("'args' has to hold a value for each of €1 to €{}, got {}", max_arg, args.len()));
+    }
+    if args.len() < needed || args.len() > max_arg {
+        return Err(format!("'args' has to hold a value for each of €1 to €{}, and up to €{} with defaults, got {}", needed, max_arg, args.len()));
+    }
+    let mut args = with_defaults(args);
+    let named = match &request["named"] {
+        serde_json::Value::Null => serde_json::Map::new(),
+        serde_json::Value::Object(named) => named.clone(),
+        _ => return Err("'named' has to be an object of strings".into()),
+    };
+    if let Some(name) = named.keys().find(|name| !NAMED_ARGS.contains(&name.as_str())) {
+        return Err(format!("This program has no €{{{}}}", name));
+    }
+    for name in NAMED_ARGS {
+        match named.get(*name).and_then(strings) {
+            Some(value) => args.push(value),
+            None => return Err(format!("'named' needs a string for €{{{}}}", name)),
+        }
+    }
+    Ok(args)
+}
+
+/// The next request's JSON, or `None` once the client hung up.
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<Vec<u8>>, String> {
+    let mut length = [0; 4];
+    match stream.read_exact(&mut length).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    }
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME {
+        return Err(format!("A request can be {} bytes at most, this one says it's {}", MAX_FRAME, length));
+    }
+    let mut body = vec![0; length];
+    match stream.read_exact(&mut body).await {
+        Ok(_) => Ok(Some(body)),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+async fn write_frame<S: AsyncWrite + Unpin>(stream: &mut S, message: &serde_json::Value) -> std::io::Result<()> {
+    let body = message.to_string();
+    stream.write_all(&(body.len() as u32).to_be_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.flush().await
+}
@@ -0,0 +1,719 @@
+//! What's shown while answers stream in: stdout and its queue for slow
+//! readers, the output filters, the glossary check, the trailer, and
+//! `--stream-filter` mode.
+
+use crate::*;
+
+/// `--trailer json`: end the output with a `---hihic:{...}` line.
+pub(crate) static TRAILER: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// `--typewriter <n>`: characters per second stdout is written at when it's a
+/// terminal, or 0 to write it as it comes.
+pub(crate) static TYPEWRITER: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+
+/// `--newline crlf`: end stdout's lines with \r\n.
+pub(crate) static CRLF: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// `-n`: leave out the newline stdout would end with.
+pub(crate) static NO_FINAL_NEWLINE: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// `--encoding`: what stdout is written in, UTF-8 unless it's set.
+pub(crate) static ENCODING: std::sync::OnceLock<Encoding> = std::sync::OnceLock::new();
+
+/// The encodings `--encoding` takes, by the names it takes them by.
+pub(crate) const ENCODINGS: &[(&str, Encoding)] = &[
+    ("utf-8", Encoding::Utf8),
+    ("utf-16le", Encoding::Utf16Le),
+    ("utf-16be", Encoding::Utf16Be),
+    ("latin1", Encoding::Latin1),
+    ("ascii", Encoding::Ascii),
+];
+
+#[derive(Clone, Copy)]
+pub(crate) enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+    Ascii,
+}
+
+impl Encoding {
+    /// The encoding called `name`, in any case and with or without the dash.
+    pub(crate) fn named(name: &str) -> Option<Encoding> {
+        let name = name.to_lowercase().replace('_', "-");
+        let name = match name.as_str() {
+            "utf8" => "utf-8",
+            "utf16le" => "utf-16le",
+            "utf16be" => "utf-16be",
+            "iso-8859-1" | "latin-1" => "latin1",
+            "us-ascii" => "ascii",
+            name => name,
+        };
+        ENCODINGS.iter().find(|(n, _)| *n == name).map(|(_, encoding)| *encoding)
+    }
+
+    /// `c` in this encoding. What it can't say becomes a `?`.
+    fn push(self, c: char, bytes: &mut Vec<u8>) {
+        match self {
+            Encoding::Utf8 => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            Encoding::Utf16Le => c.encode_utf16(&mut [0; 2]).iter().for_each(|unit| bytes.extend_from_slice(&unit.to_le_bytes())),
+            Encoding::Utf16Be => c.encode_utf16(&mut [0; 2]).iter().for_each(|unit| bytes.extend_from_slice(&unit.to_be_bytes())),
+            Encoding::Latin1 => bytes.push(u8::try_from(c).unwrap_or(b'?')),
+            Encoding::Ascii => bytes.push(if c.is_ascii() { c as u8 } else { b'?' }),
+        }
+    }
+}
+
+/// Turns what's written to stdout into the bytes `--newline`, `-n` and
+/// `--encoding` ask for. With `-n` a newline is held back until something
+/// comes after it, so the one at the very end never is written.
+#[derive(Default)]
+struct StdoutEncoder {
+    held_newline: bool,
+    after_cr: bool,
+}
+
+impl StdoutEncoder {
+    fn encode(&mut self, text: &str) -> Vec<u8> {
+        let crlf = CRLF.load(std::sync::atomic::Ordering::Relaxed);
+        let hold = NO_FINAL_NEWLINE.load(std::sync::atomic::Ordering::Relaxed);
+        let encoding = ENCODING.get().copied().unwrap_or(Encoding::Utf8);
+        let mut bytes = Vec::with_capacity(text.len());
+        for c in text.chars() {
+            if std::mem::take(&mut self.held_newline) {
+                self.newline(crlf, encoding, &mut bytes);
+            }
+            match c {
+                '\n' if hold => self.held_newline = true,
+                '\n' => self.newline(crlf, encoding, &mut bytes),
+                c => {
+                    encoding.push(c, &mut bytes);
+                    self.after_cr = c == '\r';
+                }
+            }
+        }
+        bytes
+    }
+
+    /// A line's end, unless the answer had its \r already.
+    fn newline(&mut self, crlf: bool, encoding: Encoding, bytes: &mut Vec<u8>) {
+        if crlf && !self.after_cr {
+            encoding.push('\r', bytes);
+        }
+        encoding.push('\n', bytes);
+        self.after_cr = false;
+    }
+}
+
+/// Where answers go as they come in.
+#[derive(Clone, Copy)]
+pub(crate) enum Echo<'a> {
+    Off,
+    Stdout,
+    /// Piece by piece to the terminal UI, a `--serve` or a `--listen-socket`
+    /// client.
+    Channel(&'a tokio::sync::mpsc::UnboundedSender<String>),
+    /// Through the `--filter`s first.
+    Filtered(&'a OutputFilter<'a>),
+    /// `write it to "..."`: into a file instead.
+    File(&'a FileOutput),
+}
+
+impl Echo<'_> {
+    /// Part of an answer. Fails once stdout is closed, so the caller can stop
+    /// fetching an answer nobody reads.
+    pub(crate) async fn text(self, text: &str) -> Result<(), String> {
+        match self {
+            Echo::Off => {}
+            Echo::Stdout => {
+                let queue = stdout_queue();
+                // Waits while the queue is full, without holding up other tasks
+                tokio::task::block_in_place(|| queue.send(Output::Text(text.to_string())))
+                    .map_err(|_| STDOUT_CLOSED.to_string())?;
+            }
+            Echo::Channel(tx) => {
+                let _ = tx.send(text.to_string());
+            }
+            Echo::Filtered(filter) => filter.text(text).await?,
+            Echo::File(file) => file.text(text)?,
+        }
+        Ok(())
+    }
+
+    /// A whole answer, or the end of a streamed one.
+    pub(crate) async fn line(self, text: &str) -> Result<(), String> {
+        self.text(text).await?;
+        self.text("\n").await
+    }
+}
+
+/// Error of an answer cut short because whoever read stdout is gone.
+pub(crate) const STDOUT_CLOSED: &str = "stdout was closed";
+
+/// Error of an answer cut short because the `--serve` or `--listen-socket`
+/// client hung up.
+pub(crate) const CLIENT_GONE: &str = "the client disconnected";
+
+/// Resolves once whoever reads stdout is gone, even while nothing is being
+/// written (say, during a translation). Never resolves where that can't be
+/// watched: files, terminals that stay open, and Windows.
+pub(crate) async fn stdout_closed() {
+    #[cfg(unix)]
+    {
+        use tokio::io::{unix::AsyncFd, Interest};
+        // A pipe whose reader is gone reports an error to pollers
+        if let Ok(stdout) = AsyncFd::with_interest(std::io::stdout(), Interest::ERROR) {
+            if stdout.ready(Interest::ERROR).await.is_ok() {
+                return;
+            }
+        }
+    }
+    std::future::pending::<()>().await
+}
+
+/// Something for the thread that writes stdout.
+pub(crate) enum Output {
+    Text(String),
+    /// Answered once everything queued before it is written.
+    Flush(std::sync::mpsc::Sender<()>),
+}
+
+pub(crate) static STDOUT_QUEUE: std::sync::OnceLock<std::sync::mpsc::SyncSender<Output>> = std::sync::OnceLock::new();
+
+/// Whether what was written last didn't end with a newline.
+pub(crate) static STDOUT_MID_LINE: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// The queue to the thread that writes stdout, which holds up to OUTPUT_BUFFER
+/// pieces. While a slow reader keeps it full, sending waits, and so does
+/// reading the model's stream. When stdout is closed the thread ends, and
+/// every send fails from then on.
+pub(crate) fn stdout_queue() -> &'static std::sync::mpsc::SyncSender<Output> {
+    STDOUT_QUEUE.get_or_init(|| {
+        let (tx, rx) = std::sync::mpsc::sync_channel(OUTPUT_BUFFER);
+        let rate = TYPEWRITER.load(std::sync::atomic::Ordering::Relaxed);
+        std::thread::spawn(move || {
+            use std::io::IsTerminal;
+            let mut stdout = std::io::stdout();
+            let mut encoder = StdoutEncoder::default();
+            // Piped output isn't anybody's to watch
+            if rate > 0 && stdout.is_terminal() {
+                typewriter(rx, rate, encoder);
+                return;
+            }
+            for output in rx {
+                match output {
+                    Output::Text(text) => {
+                        if stdout.write_all(&encoder.encode(&text)).and_then(|_| stdout.flush()).is_err() {
+                            return;
+                        }
+                        if !text.is_empty() {
+                            STDOUT_MID_LINE.store(!text.ends_with('\n'), std::sync::atomic::Ordering::Relaxed);
+                        }
+                    }
+                    Output::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+        tx
+    })
+}
+
+/// `--typewriter`: writes what comes in through `rx` to stdout one character at
+/// a time, `rate` of them a second, however bursty the stream is. It keeps
+/// taking what comes in meanwhile, so the stream isn't held up, and speeds up
+/// while it's more than a second behind, so a long answer isn't either.
+fn typewriter(rx: std::sync::mpsc::Receiver<Output>, rate: u32, mut encoder: StdoutEncoder) {
+    use std::io::Write;
+    use std::sync::mpsc::TryRecvError;
+
+    let mut stdout = std::io::stdout();
+    let mut pending: std::collections::VecDeque<char> = std::collections::VecDeque::new();
+    // Answered once the characters received before them are written
+    let mut flushes: std::collections::VecDeque<(usize, std::sync::mpsc::Sender<()>)> = std::collections::VecDeque::new();
+    let (mut received, mut written) = (0, 0);
+    loop {
+        // Waits only when there's nothing left to write
+        let mut next = if pending.is_empty() { rx.recv().ok() } else { None };
+        loop {
+            match next.take() {
+                Some(Output::Text(text)) => {
+                    received += text.chars().count();
+                    pending.extend(text.chars());
+                }
+                Some(Output::Flush(done)) if received == written => {
+                    let _ = done.send(());
+                }
+                Some(Output::Flush(done)) => flushes.push_back((received, done)),
+                None => {}
+            }
+            next = match rx.try_recv() {
+                Ok(output) => Some(output),
+                Err(TryRecvError::Empty) => break,
+                // Nothing more to come, but what's there is still written
+                Err(TryRecvError::Disconnected) if !pending.is_empty() => break,
+                Err(TryRecvError::Disconnected) => return,
+            };
+        }
+        let Some(c) = pending.pop_front() else {
+            continue;
+        };
+        let mut buf = [0; 4];
+        if stdout.write_all(&encoder.encode(c.encode_utf8(&mut buf))).and_then(|_| stdout.flush()).is_err() {
+            return;
+        }
+        written += 1;
+        while flushes.front().is_some_and(|(mark, _)| *mark <= written) {
+            if let Some((_, done)) = flushes.pop_front() {
+                let _ = done.send(());
+            }
+        }
+        STDOUT_MID_LINE.store(c != '\n', std::sync::atomic::Ordering::Relaxed);
+        let per_second = (rate as usize).max(pending.len());
+        std::thread::sleep(std::time::Duration::from_secs(1) / per_second.max(1) as u32);
+    }
+}
+
+/// Waits until everything queued for stdout is written, or stdout is gone.
+pub(crate) fn flush_stdout() {
+    let Some(queue) = STDOUT_QUEUE.get() else {
+        return;
+    };
+    let (done, written) = std::sync::mpsc::channel();
+    if queue.send(Output::Flush(done)).is_ok() {
+        let _ = written.recv();
+    }
+}
+
+/// `--trailer json`: a last line for wrappers, `---hihic:{...}`, saying whether
+/// the output is complete and how many tokens it took.
+pub(crate) fn write_trailer(error: Option<&str>) {
+    if !TRAILER.load(std::sync::atomic::Ordering::Relaxed) {
+        return;
+    }
+    flush_stdout();
+    let usage = usage().map(|(prompt, completion)| {
+        serde_json::json!({"prompt_tokens": prompt, "completion_tokens": completion, "total_tokens": prompt + completion})
+    });
+    let mut trailer = serde_json::json!({"complete": error.is_none(), "usage": usage, "request_id": request_id()});
+    if let Some(error) = error {
+        trailer["error"] = serde_json::json!(error);
+    }
+    // The trailer gets a line of its own, even after half an answer
+    let newline = if STDOUT_MID_LINE.load(std::sync::atomic::Ordering::Relaxed) { "\n" } else { "" };
+    let _ = stdout_queue().send(Output::Text(format!("{}---hihic:{}\n", newline, trailer)));
+    flush_stdout();
+}
+
+/// Words the profanity filter looks for, as whole words in any case.
+pub(crate) const PROFANITY: &[&str] = &[
+    "arse", "arsehole", "asshole", "bastard", "bitch", "bollocks", "bullshit", "cock", "crap", "cunt", "dick",
+    "dickhead", "douche", "douchebag", "fuck", "fucked", "fucker", "fucking", "motherfucker", "piss", "pissed",
+    "prick", "shit", "shitty", "slut", "twat", "wanker", "whore",
+    "arschloch", "fick", "ficken", "fotze", "hurensohn", "scheiße", "scheisse", "wichser",
+    "connard", "conne", "enculé", "merde", "putain", "salope",
+];
+
+/// How much of a streamed answer is held back, so a match that arrives in
+/// pieces is still caught before any of it is shown. Longer than any match.
+pub(crate) const FILTER_WINDOW: usize = 64;
+
+/// Something a `--filter` found: where, which filter, and what it is.
+pub(crate) struct FilterMatch {
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+    pub(crate) filter: &'static str,
+    pub(crate) kind: &'static str,
+}
+
+impl FilterMatch {
+    pub(crate) fn policy(&self) -> &'static str {
+        FILTERS.iter().find(|(filter, _)| *filter == self.filter).map(|(_, policy)| *policy).unwrap_or("warn")
+    }
+
+    /// What a masked match shows instead: f***, so the sentence still reads,
+    /// or what kind of PII was there.
+    pub(crate) fn mask(&self, text: &str) -> String {
+        match self.filter {
+            "profanity" => text.chars().enumerate().map(|(i, c)| if i == 0 { c } else { '*' }).collect(),
+            _ => format!("[{}]", self.kind),
+        }
+    }
+}
+
+/// Every match of the `--filter`s in `text`, in order and without overlaps.
+pub(crate) fn find_matches(text: &str) -> Vec<FilterMatch> {
+    let enabled = |name: &str| FILTERS.iter().any(|(filter, _)| *filter == name);
+    let (pii, profanity) = (enabled("pii"), enabled("profanity"));
+    let mut matches = Vec::new();
+
+    // Words, without the punctuation around them
+    let mut words: Vec<(usize, &str)> = Vec::new();
+    let mut word_start = None;
+    for (i, c) in text.char_indices().chain(std::iter::once((text.len(), ' '))) {
+        match (c.is_whitespace(), word_start) {
+            (false, None) => word_start = Some(i),
+            (true, Some(start)) => {
+                let word = &text[start..i];
+                let core = word.trim_matches(|c: char| !c.is_alphanumeric());
+                if !core.is_empty() {
+                    let offset = word.len() - word.trim_start_matches(|c: char| !c.is_alphanumeric()).len();
+                    words.push((start + offset, core));
+                }
+                word_start = None;
+            }
+            _ => {}
+        }
+    }
+    let mut found = |start: usize, end: usize, filter: &'static str, kind: &'static str| {
+        matches.push(FilterMatch { start, end, filter, kind })
+    };
+    let mut i = 0;
+    while i < words.len() {
+        let (start, word) = words[i];
+        let end = start + word.len();
+        if pii && is_email(word) {
+            found(start, end, "pii", "email address");
+        } else if pii && is_ipv4(word) {
+            found(start, end, "pii", "IP address");
+        } else if profanity && is_profane(word) {
+            found(start, end, "profanity", "profanity");
+        } else if pii && word.len() <= 34 && word.get(..2).is_some_and(|c| c.bytes().all(|b| b.is_ascii_uppercase())) {
+            // IBANs, written in one piece or in groups of four
+            let mut compact = word.to_string();
+            let mut iban = is_iban(&compact).then_some(i);
+            let mut j = i + 1;
+            while j < words.len() && words[j].0 == words[j - 1].0 + words[j - 1].1.len() + 1 && compact.len() < 34 {
+                let group = words[j].1;
+                if group.len() > 4 || !group.bytes().all(|b| b.is_ascii_digit() || b.is_ascii_uppercase()) {
+                    break;
+                }
+                compact.push_str(group);
+                if is_iban(&compact) {
+                    iban = Some(j);
+                }
+                j += 1;
+            }
+            if let Some(last) = iban {
+                found(start, words[last].0 + words[last].1.len(), "pii", "IBAN");
+                i = last;
+            }
+        }
+        i += 1;
+    }
+
+    // Numbers, with the spaces, dashes and brackets people write them with
+    if pii {
+        let bytes = text.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            let starts = bytes[i].is_ascii_digit()
+                || (matches!(bytes[i], b'+' | b'(') && bytes.get(i + 1).is_some_and(u8::is_ascii_digit));
+            if !starts || (i > 0 && (bytes[i - 1].is_ascii_alphanumeric() || bytes[i - 1] == b'.')) {
+                i += 1;
+                continue;
+            }
+            let mut end = i + 1;
+            let mut j = i + 1;
+            while j < bytes.len() {
+                if bytes[j].is_ascii_digit() {
+                    j += 1;
+                    end = j;
+                    continue;
+                }
+                let separators = bytes[j..].iter().take_while(|b| b" -/()".contains(b)).count();
+                if (1..=2).contains(&separators) && bytes.get(j + separators).is_some_and(u8::is_ascii_digit) {
+                    j += separators;
+                    continue;
+                }
+                break;
+            }
+            let run = &text[i..end];
+            let digits: String = run.chars().filter(char::is_ascii_digit).collect();
+            let groups: Vec<usize> = run.split(|c: char| !c.is_ascii_digit()).filter(|g| !g.is_empty()).map(str::len).collect();
+            let bounded = !bytes.get(end).is_some_and(|b| b.is_ascii_alphanumeric());
+            let kind = if !bounded {
+                None
+            } else if groups == [3, 2, 4] && run.matches('-').count() == 2 {
+                Some("social security number")
+            } else if (13..=19).contains(&digits.len()) && luhn(&digits) {
+                Some("credit card number")
+            } else if (run.starts_with('+') && (8..=15).contains(&digits.len()))
+                || ((run.starts_with('0') || run.starts_with('(')) && (9..=15).contains(&digits.len()) && groups.len() > 1)
+                || groups == [3, 3, 4]
+            {
+                Some("phone number")
+            } else {
+                None
+            };
+            if let Some(kind) = kind {
+                found(i, end, "pii", kind);
+            }
+            i = end;
+        }
+    }
+
+    matches.sort_by_key(|m| (m.start, std::cmp::Reverse(m.end)));
+    let mut kept: Vec<FilterMatch> = Vec::new();
+    for m in matches {
+        if kept.last().is_none_or(|last| last.end <= m.start) {
+            kept.push(m);
+        }
+    }
+    kept
+}
+
+/// A PROFANITY word, or its plural.
+pub(crate) fn is_profane(word: &str) -> bool {
+    let word = word.to_lowercase();
+    PROFANITY.iter().any(|bad| word == *bad || word.strip_suffix('s') == Some(*bad))
+}
+
+pub(crate) fn is_email(word: &str) -> bool {
+    let Some((local, domain)) = word.split_once('@') else {
+        return false;
+    };
+    let labels: Vec<&str> = domain.split('.').collect();
+    !local.is_empty()
+        && local.chars().all(|c| c.is_alphanumeric() || "._%+-".contains(c))
+        && labels.len() > 1
+        && labels.iter().all(|label| !label.is_empty() && label.chars().all(|c| c.is_alphanumeric() || c == '-'))
+        && labels.last().is_some_and(|tld| tld.len() >= 2 && tld.chars().all(char::is_alphabetic))
+}
+
+pub(crate) fn is_ipv4(word: &str) -> bool {
+    let parts: Vec<&str> = word.split('.').collect();
+    parts.len() == 4
+        && parts.iter().all(|part| (1..=3).contains(&part.len()) && part.bytes().all(|b| b.is_ascii_digit()) && part.parse::<u8>().is_ok())
+}
+
+/// Country code, check digits and up to 30 letters or digits, where the check
+/// digits have to work out (mod 97).
+pub(crate) fn is_iban(text: &str) -> bool {
+    let bytes = text.as_bytes();
+    if !(15..=34).contains(&bytes.len())
+        || !bytes[..2].iter().all(u8::is_ascii_uppercase)
+        || !bytes[2..4].iter().all(u8::is_ascii_digit)
+        || !bytes.iter().all(|b| b.is_ascii_digit() || b.is_ascii_uppercase())
+    {
+        return false;
+    }
+    let remainder = bytes[4..].iter().chain(&bytes[..4]).fold(0u32, |remainder, &b| {
+        if b.is_ascii_digit() {
+            (remainder * 10 + u32::from(b - b'0')) % 97
+        } else {
+            (remainder * 100 + u32::from(b - b'A' + 10)) % 97
+        }
+    });
+    remainder == 1
+}
+
+/// The check digit of card numbers.
+pub(crate) fn luhn(digits: &str) -> bool {
+    let sum: u32 = digits
+        .bytes()
+        .rev()
+        .enumerate()
+        .map(|(i, b)| {
+            let digit = u32::from(b - b'0');
+            match i % 2 {
+                0 => digit,
+                _ if digit > 4 => digit * 2 - 9,
+                _ => digit * 2,
+            }
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+/// Masks what the `--filter`s found in `text`, or fails if one of them blocks.
+pub(crate) fn apply_filters(text: &str, matches: &[FilterMatch]) -> Result<String, String> {
+    let mut out = String::new();
+    let mut done = 0;
+    for m in matches {
+        match m.policy() {
+            "block" => return Err(format!("The answer was blocked by the {} filter (found: {})", m.filter, m.kind)),
+            "mask" => {
+                out.push_str(&text[done..m.start]);
+                out.push_str(&m.mask(&text[m.start..m.end]));
+                done = m.end;
+            }
+            _ => {}
+        }
+    }
+    out.push_str(&text[done..]);
+    Ok(out)
+}
+
+/// Runs the `--filter`s over a whole answer, warning about what `warn` filters
+/// find.
+pub(crate) fn filter_answer(answer: &str) -> Result<String, String> {
+    if FILTERS.is_empty() {
+        return Ok(answer.to_string());
+    }
+    let matches = find_matches(answer);
+    let mut warned: Vec<&str> = Vec::new();
+    for m in matches.iter().filter(|m| m.policy() == "warn") {
+        if !warned.contains(&m.kind) {
+            warned.push(m.kind);
+            flush_stdout();
+            eprintln!("Warning: {} filter: the answer contains: {}", m.filter, m.kind);
+        }
+    }
+    apply_filters(answer, &matches)
+}
+
+/// An answer on its way to the screen through the `--filter`s. The end of it
+/// is held back until it can't be the start of a match anymore.
+pub(crate) struct OutputFilter<'a> {
+    pub(crate) echo: Echo<'a>,
+    pub(crate) pending: std::sync::Mutex<String>,
+}
+
+impl OutputFilter<'_> {
+    pub(crate) async fn text(&self, text: &str) -> Result<(), String> {
+        let released = {
+            let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+            pending.push_str(text);
+            let matches = find_matches(&pending);
+            // Nothing matches across a line break
+            let mut cut = pending.rfind('\n').map_or(0, |newline| newline + 1);
+            if pending.len() - cut > FILTER_WINDOW {
+                let mut limit = pending.len() - FILTER_WINDOW;
+                while !pending.is_char_boundary(limit) {
+                    limit -= 1;
+                }
+                // Never in the middle of a word
+                if let Some((space, c)) = pending[cut..limit].char_indices().rev().find(|(_, c)| c.is_whitespace()) {
+                    cut += space + c.len_utf8();
+                }
+            }
+            if let Some(straddling) = matches.iter().find(|m| m.start < cut && m.end > cut) {
+                cut = straddling.start;
+            }
+            let settled: Vec<FilterMatch> = matches.into_iter().filter(|m| m.end <= cut).collect();
+            let released = apply_filters(&pending[..cut], &settled)?;
+            pending.drain(..cut);
+            released
+        };
+        if released.is_empty() {
+            return Ok(());
+        }
+        Box::pin(self.echo.text(&released)).await
+    }
+
+    /// Shows what's still held back.
+    pub(crate) async fn finish(&self) -> Result<(), String> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap_or_else(|e| e.into_inner()));
+        let released = apply_filters(&pending, &find_matches(&pending))?;
+        if released.is_empty() {
+            return Ok(());
+        }
+        Box::pin(self.echo.text(&released)).await
+    }
+}
+
+/// `write it to "..."`: an answer on its way into a file rather than to the
+/// screen. Created when the step is asked, so a file that can't be written
+/// fails it before the model is.
+pub(crate) struct FileOutput {
+    pub(crate) path: &'static str,
+    pub(crate) writer: std::sync::Mutex<std::io::BufWriter<std::fs::File>>,
+}
+
+impl FileOutput {
+    pub(crate) fn create(path: &'static str) -> Result<Self, String> {
+        let file = std::fs::File::create(path).map_err(|e| format!("Failed to write the answer to {}: {}", path, e))?;
+        Ok(FileOutput { path, writer: std::sync::Mutex::new(std::io::BufWriter::new(file)) })
+    }
+
+    pub(crate) fn text(&self, text: &str) -> Result<(), String> {
+        use std::io::Write;
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writer.write_all(text.as_bytes()).map_err(|e| format!("Failed to write the answer to {}: {}", self.path, e))
+    }
+
+    /// Writes out what's still buffered.
+    pub(crate) fn finish(&self) -> Result<(), String> {
+        use std::io::Write;
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writer.flush().map_err(|e| format!("Failed to write the answer to {}: {}", self.path, e))
+    }
+}
+
+/// Warns about every glossary term the prompts mention that the answer doesn't
+/// say the way the glossary wants.
+pub(crate) fn check_glossary(prompts: &str, answer: &str) {
+    let prompts = prompts.to_lowercase();
+    for (term, required) in GLOSSARY {
+        if !prompts.contains(&term.to_lowercase()) || answer.contains(required) {
+            continue;
+        }
+        // After the answer, not in the middle of it
+        flush_stdout();
+        if term == required {
+            eprintln!("Warning: glossary: the answer doesn't keep \"{}\" as it is", term);
+        } else {
+            eprintln!("Warning: glossary: the answer doesn't say \"{}\" for \"{}\"", required, term);
+        }
+    }
+}
+
+/// `--stream-filter`: runs the program once per stdin line while stdin is still
+/// being written, and prints the answers in input order. Up to `parallel` lines
+/// are in flight at once; beyond that stdin isn't read, so a fast writer waits
+/// for the model instead of piling lines up in memory. A line that fails is
+/// reported and skipped; returns how many lines were read. Stops as soon as
+/// stdout is closed.
+pub(crate) async fn run_stream_filter(api_key: &str, secrets: &[(&str, String)], parallel: usize) -> Result<usize, String> {
+    use futures_util::StreamExt;
+    use tokio::io::AsyncBufReadExt;
+
+    let lines = futures_util::stream::unfold(tokio::io::BufReader::new(tokio::io::stdin()).lines(), |mut lines| async move {
+        match lines.next_line().await {
+            Ok(Some(line)) => Some((line, lines)),
+            Ok(None) => None,
+            Err(e) => {
+                eprintln!("Error: Failed to read stdin: {}", e);
+                None
+            }
+        }
+    });
+    let answers = lines
+        .map(|line| async move {
+            let (args, files) = prepare_args(vec![line])?;
+            let mut history = Vec::new();
+            run_invocation(api_key, &args, secrets, &files, &mut history, Echo::Off).await
+        })
+        .buffered(parallel);
+    let mut answers = std::pin::pin!(answers);
+
+    let mut closed = std::pin::pin!(stdout_closed());
+    let mut count = 0;
+    let mut failed = 0;
+    loop {
+        let answer = tokio::select! {
+            answer = answers.next() => answer,
+            // Dropping the lines in flight aborts their requests
+            _ = &mut closed => return Err(STDOUT_CLOSED.to_string()),
+        };
+        let Some(answer) = answer else {
+            break;
+        };
+        count += 1;
+        match answer {
+            Ok(answer) => Echo::Stdout.line(&answer).await?,
+            Err(e) => {
+                failed += 1;
+                eprintln!("Warning: line {}: {}", count, redact(&e, secrets));
+            }
+        }
+    }
+    flush_stdout();
+    match failed {
+        0 => Ok(count),
+        _ => Err(format!("{} of {} line(s) failed", failed, count)),
+    }
+}
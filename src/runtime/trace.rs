@@ -0,0 +1,96 @@
+//! `--trace <dir>`: every model call the run makes, what was sent and what
+//! came back, with when it started and how long it took, one JSON line per
+//! call in `<dir>/<run>.jsonl`. `matthiashihic trace view <dir>` reads them
+//! back as a timeline. Keys never get there, the values of `€secret(...)`s
+//! are redacted, and `--trace-redact` blanks out whole fields.
+
+use crate::*;
+
+/// The fields `--trace-redact` can blank out.
+pub(crate) const TRACEABLE: &[&str] = &["system", "prompt", "answer"];
+
+pub(crate) static TRACE: std::sync::OnceLock<Trace> = std::sync::OnceLock::new();
+
+pub(crate) struct Trace {
+    file: std::sync::Mutex<std::fs::File>,
+    run: String,
+    calls: std::sync::atomic::AtomicUsize,
+    secrets: Vec<(&'static str, String)>,
+    redacted: Vec<String>,
+}
+
+impl Trace {
+    /// A new trace file in `dir`, which is created if it isn't there. Not
+    /// being able to write it stops the run before it asks anything.
+    pub(crate) fn open(dir: &str, redacted: Vec<String>, secrets: &[(&'static str, String)]) -> Result<Trace, String> {
+        let dir = std::path::Path::new(dir);
+        std::fs::create_dir_all(dir).map_err(|e| format!("Can't create the trace directory {}: {}", dir.display(), e))?;
+        let started = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_millis();
+        // Sorts by when it ran; the pid tells apart runs of the same millisecond
+        let run = format!("{}-{}", started, std::process::id());
+        let path = dir.join(format!("{}.jsonl", run));
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(&path).map_err(|e| format!("Can't write the trace {}: {}", path.display(), e))?;
+        Ok(Trace {
+            file: std::sync::Mutex::new(file),
+            run,
+            calls: std::sync::atomic::AtomicUsize::new(0),
+            secrets: secrets.to_vec(),
+            redacted,
+        })
+    }
+
+    fn field(&self, name: &str, value: &str) -> String {
+        if self.redacted.iter().any(|field| field == name) {
+            return "[redacted]".to_string();
+        }
+        redact(value, &self.secrets)
+    }
+
+    /// Appends one call. The history is only counted: its turns are the
+    /// calls before this one, which are in the trace already.
+    fn record(&self, model: &str, system: &str, history: usize, prompt: &str, started: std::time::Instant, result: Result<&str, String>) {
+        let start_ms = started.duration_since(*STARTED.get_or_init(std::time::Instant::now)).as_millis() as u64;
+        let mut line = serde_json::json!({
+            "run": self.run,
+            "call": self.calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1,
+            "request_id": request_id(),
+            "model": model,
+            "start_ms": start_ms,
+            "duration_ms": started.elapsed().as_millis() as u64,
+            "system": self.field("system", system),
+            "history": history,
+            "prompt": self.field("prompt", prompt),
+        });
+        match result {
+            Ok(answer) => line["answer"] = serde_json::json!(self.field("answer", answer)),
+            Err(e) => line["error"] = serde_json::json!(redact(&e, &self.secrets)),
+        }
+        let Ok(mut file) = self.file.lock() else {
+            return;
+        };
+        if let Err(e) = writeln!(file, "{}", line) {
+            eprintln!("Warning: Can't write the trace: {}", e);
+        }
+    }
+}
+
+/// Runs one model call, recording it in the trace if there is one.
+pub(crate) async fn traced<F>(
+    model: &str,
+    system: &str,
+    history: &[(String, String)],
+    pseudocode: &str,
+    call: F,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>>
+where
+    F: std::future::Future<Output = Result<String, Box<dyn std::error::Error + Send + Sync>>>,
+{
+    let Some(trace) = TRACE.get() else {
+        return call.await;
+    };
+    let started = std::time::Instant::now();
+    let result = call.await;
+    let recorded = result.as_ref().map(String::as_str).map_err(|e| e.to_string());
+    trace.record(model, system, history.len(), pseudocode, started, recorded);
+    result
+}
@@ -0,0 +1,153 @@
+//! `matthiashihic stats [--history <file>] [<program>...]` sums up the runs
+//! that programs with a `variant A/B` experiment recorded, arm by arm, so the
+//! two wordings can be compared. Compiled programs append one JSON line per
+//! run to the history; see `paths::history_file`.
+
+use std::fs;
+use std::path::PathBuf;
+
+use crate::front_matter::parse_json_object;
+use crate::i18n::tr;
+use crate::paths;
+
+/// The recorded runs of one arm of one program.
+#[derive(Default)]
+struct Arm {
+    runs: usize,
+    ok: usize,
+    durations: Vec<u64>,
+    tokens: Vec<u64>,
+    output_chars: Vec<u64>,
+}
+
+/// Runs the subcommand and returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let mut history = None;
+    let mut programs = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--history" => match args.get(i + 1) {
+                Some(path) => {
+                    history = Some(PathBuf::from(path));
+                    i += 1;
+                }
+                None => {
+                    eprintln!("--history requires a value");
+                    return 2;
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", tr!("stats.usage"));
+                return 0;
+            }
+            flag if flag.starts_with('-') => {
+                eprintln!("{}", tr!("stats.unknown-flag", flag = flag));
+                return 2;
+            }
+            program => programs.push(program.to_string()),
+        }
+        i += 1;
+    }
+    let Some(path) = history.or_else(paths::history_file) else {
+        eprintln!("No history file: set MATTHIASHIHIC_HISTORY or pass --history <file>");
+        return 2;
+    };
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            eprintln!("No runs recorded yet in {}", path.display());
+            return 0;
+        }
+        Err(e) => {
+            eprintln!("{}", tr!("cli.read-failed", file = path.display(), error = e));
+            return 1;
+        }
+    };
+
+    // (program, arm) in order of first appearance
+    let mut arms: Vec<((String, String), Arm)> = Vec::new();
+    let mut skipped = 0;
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        // A line cut short by a crash shouldn't hide the rest
+        let Ok(record) = parse_json_object(line) else {
+            skipped += 1;
+            continue;
+        };
+        let field = |key: &str| record.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+        let number = |key: &str| field(key).and_then(|v| v.parse::<u64>().ok());
+        let (Some(program), Some(arm)) = (field("program"), field("arm")) else {
+            skipped += 1;
+            continue;
+        };
+        if !programs.is_empty() && !programs.iter().any(|p| p == program) {
+            continue;
+        }
+        let key = (program.to_string(), arm.to_string());
+        let index = match arms.iter().position(|(k, _)| *k == key) {
+            Some(index) => index,
+            None => {
+                arms.push((key, Arm::default()));
+                arms.len() - 1
+            }
+        };
+        let stats = &mut arms[index].1;
+        stats.runs += 1;
+        if field("ok") == Some("true") {
+            stats.ok += 1;
+        }
+        stats.durations.extend(number("duration_ms"));
+        if let (Some(prompt), Some(completion)) = (number("prompt_tokens"), number("completion_tokens")) {
+            stats.tokens.push(prompt + completion);
+        }
+        stats.output_chars.extend(number("output_chars"));
+    }
+    if skipped > 0 {
+        eprintln!("Warning: skipped {} unreadable line(s) in {}", skipped, path.display());
+    }
+    if arms.is_empty() {
+        eprintln!("No experiment runs recorded in {}", path.display());
+        return 0;
+    }
+    arms.sort_by(|((a, arm_a), _), ((b, arm_b), _)| a.cmp(b).then(arm_a.cmp(arm_b)));
+
+    let mut current = None;
+    for ((program, arm), mut stats) in arms {
+        if current.as_ref() != Some(&program) {
+            if current.is_some() {
+                println!();
+            }
+            println!("{}", program);
+            println!("  arm  {:>6}  {:>6}  {:>8}  {:>8}  {:>7}  {:>7}", "runs", "ok", "p50", "p90", "tokens", "chars");
+            current = Some(program);
+        }
+        stats.durations.sort_unstable();
+        println!(
+            "  {:<3}  {:>6}  {:>5.1}%  {:>8}  {:>8}  {:>7}  {:>7}",
+            arm,
+            stats.runs,
+            100.0 * stats.ok as f64 / stats.runs as f64,
+            percentile(&stats.durations, 0.50),
+            percentile(&stats.durations, 0.90),
+            average(&stats.tokens),
+            average(&stats.output_chars)
+        );
+    }
+    0
+}
+
+/// Nearest-rank percentile of sorted milliseconds, or `-` without any.
+fn percentile(sorted: &[u64], p: f64) -> String {
+    if sorted.is_empty() {
+        return "-".into();
+    }
+    let rank = ((p * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len());
+    format!("{}ms", sorted[rank - 1])
+}
+
+fn average(values: &[u64]) -> String {
+    if values.is_empty() {
+        return "-".into();
+    }
+    format!("{:.0}", values.iter().sum::<u64>() as f64 / values.len() as f64)
+}
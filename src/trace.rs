@@ -0,0 +1,334 @@
+//! `matthiashihic trace view <dir>` shows what programs run with `--trace
+//! <dir>` asked and were told: the runs in the directory, a timeline of one
+//! run's model calls, and any call in full. At a terminal it then asks which
+//! call to open, so a pipeline can be walked through step by step.
+//!
+//! Programs write one `<run>.jsonl` per run, a line per call; see
+//! `runtime/trace.rs`.
+
+use std::fs;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::Path;
+
+use crate::front_matter::parse_json_object;
+use crate::i18n::tr;
+
+/// Width of the timeline's bars, in characters.
+const TIMELINE_WIDTH: usize = 30;
+/// Characters of a prompt shown next to its bar.
+const PREVIEW_CHARS: usize = 40;
+
+/// One model call of a run.
+struct Call {
+    model: String,
+    request_id: String,
+    start_ms: u64,
+    duration_ms: u64,
+    /// Turns of the conversation sent along before the prompt.
+    history: usize,
+    system: String,
+    prompt: String,
+    answer: Option<String>,
+    error: Option<String>,
+}
+
+/// The calls of one run, in the order they were made.
+struct Run {
+    id: String,
+    calls: Vec<Call>,
+}
+
+/// Runs the subcommand and returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    let mut dir = None;
+    let mut run_id = None;
+    let mut call = None;
+    if matches!(args.first().map(String::as_str), Some("-h" | "--help")) {
+        println!("{}", tr!("trace.usage"));
+        return 0;
+    }
+    if args.first().map(String::as_str) != Some("view") {
+        eprintln!("{}", tr!("trace.usage"));
+        return 2;
+    }
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--run" => match args.get(i + 1) {
+                Some(id) => {
+                    run_id = Some(id.clone());
+                    i += 1;
+                }
+                None => {
+                    eprintln!("--run requires a value");
+                    return 2;
+                }
+            },
+            "--call" => match args.get(i + 1).and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) if n > 0 => {
+                    call = Some(n);
+                    i += 1;
+                }
+                _ => {
+                    eprintln!("--call requires a call number, starting at 1");
+                    return 2;
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", tr!("trace.usage"));
+                return 0;
+            }
+            flag if flag.starts_with('-') => {
+                eprintln!("{}", tr!("trace.unknown-flag", flag = flag));
+                return 2;
+            }
+            path if dir.is_none() => dir = Some(path.to_string()),
+            extra => {
+                eprintln!("Unexpected argument: {}", extra);
+                return 2;
+            }
+        }
+        i += 1;
+    }
+    let Some(dir) = dir else {
+        eprintln!("{}", tr!("trace.usage"));
+        return 2;
+    };
+    let runs = match read_runs(Path::new(&dir)) {
+        Ok(runs) => runs,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    if runs.is_empty() {
+        eprintln!("No traced calls in {}; run a program with --trace {} first", dir, dir);
+        return 0;
+    }
+
+    // The one asked for, or the latest
+    let run = match &run_id {
+        Some(id) => {
+            let matching: Vec<&Run> = runs.iter().filter(|run| run.id.starts_with(id.as_str())).collect();
+            match matching.as_slice() {
+                [run] => *run,
+                [] => {
+                    eprintln!("No run {} in {}", id, dir);
+                    return 1;
+                }
+                _ => {
+                    eprintln!("More than one run starts with {}; give more of it", id);
+                    return 2;
+                }
+            }
+        }
+        None => runs.last().expect("there is a run"),
+    };
+    if let Some(n) = call {
+        return match run.calls.get(n - 1) {
+            Some(_) => {
+                print!("{}", details(run, n));
+                0
+            }
+            None => {
+                eprintln!("Run {} has {} call(s), there's no call {}", run.id, run.calls.len(), n);
+                1
+            }
+        };
+    }
+
+    if runs.len() > 1 && run_id.is_none() {
+        println!("{} runs in {}, the latest first:", runs.len(), dir);
+        for other in runs.iter().rev() {
+            let failed = other.calls.iter().filter(|call| call.error.is_some()).count();
+            let failed = if failed > 0 { format!(", {} failed", failed) } else { String::new() };
+            println!("  {}  {} call(s) in {}{}", other.id, other.calls.len(), duration(span(other).1), failed);
+        }
+        println!("Showing the latest; pick another with --run <id>.\n");
+    }
+    print!("{}", timeline(run));
+    if std::io::stdin().is_terminal() && std::io::stdout().is_terminal() {
+        browse(run);
+    }
+    0
+}
+
+/// Every `*.jsonl` in `dir` as a run, oldest first. Lines that can't be read,
+/// like one cut short by a crash, are skipped with a warning.
+fn read_runs(dir: &Path) -> Result<Vec<Run>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| tr!("cli.read-failed", file = dir.display(), error = e))?;
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "jsonl"))
+        .collect();
+    // Run IDs start with when they started
+    paths.sort();
+    let mut runs = Vec::new();
+    let mut skipped = 0;
+    for path in paths {
+        let contents = fs::read_to_string(&path).map_err(|e| tr!("cli.read-failed", file = path.display(), error = e))?;
+        let mut calls = Vec::new();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let Ok(record) = parse_json_object(line) else {
+                skipped += 1;
+                continue;
+            };
+            let field = |key: &str| record.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+            let number = |key: &str| field(key).and_then(|v| v.parse::<u64>().ok());
+            let (Some(model), Some(start_ms), Some(duration_ms)) = (field("model"), number("start_ms"), number("duration_ms")) else {
+                skipped += 1;
+                continue;
+            };
+            calls.push(Call {
+                model,
+                request_id: field("request_id").unwrap_or_default(),
+                start_ms,
+                duration_ms,
+                history: number("history").unwrap_or(0) as usize,
+                system: field("system").unwrap_or_default(),
+                prompt: field("prompt").unwrap_or_default(),
+                answer: field("answer"),
+                error: field("error"),
+            });
+        }
+        if !calls.is_empty() {
+            let id = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+            runs.push(Run { id, calls });
+        }
+    }
+    if skipped > 0 {
+        eprintln!("Warning: skipped {} unreadable line(s) in {}", skipped, dir.display());
+    }
+    Ok(runs)
+}
+
+/// When the run's first call started and how long it was from then until
+/// the last one ended, in milliseconds.
+fn span(run: &Run) -> (u64, u64) {
+    let first = run.calls.iter().map(|call| call.start_ms).min().unwrap_or(0);
+    let last = run.calls.iter().map(|call| call.start_ms + call.duration_ms).max().unwrap_or(first);
+    (first, last - first)
+}
+
+fn duration(ms: u64) -> String {
+    if ms < 1000 {
+        format!("{}ms", ms)
+    } else {
+        format!("{:.2}s", ms as f64 / 1000.0)
+    }
+}
+
+/// The start of `text`'s first line, for a line of its own.
+fn preview(text: &str) -> String {
+    let line = text.lines().find(|line| !line.trim().is_empty()).unwrap_or("").trim();
+    if line.chars().count() > PREVIEW_CHARS || text.trim().lines().count() > 1 {
+        let start: String = line.chars().take(PREVIEW_CHARS).collect();
+        format!("{}...", start)
+    } else {
+        line.to_string()
+    }
+}
+
+/// A line per call: when it started, how long it took, a bar for where in
+/// the run it was, and what it asked. Failed calls have a bar of `!`s.
+fn timeline(run: &Run) -> String {
+    let (first, total) = span(run);
+    let models = run.calls.iter().map(|call| call.model.chars().count()).max().unwrap_or(0).max("model".len());
+    let mut out = format!("Run {}, {} call(s) in {}\n", run.id, run.calls.len(), duration(total));
+    out.push_str(&format!("  {:>3}  {:>7}  {:>7}  {:<models$}  {:<width$}  prompt\n", "#", "start", "took", "model", "", models = models, width = TIMELINE_WIDTH + 2));
+    for (index, call) in run.calls.iter().enumerate() {
+        let start = call.start_ms - first;
+        let from = ((start * TIMELINE_WIDTH as u64 / total.max(1)) as usize).min(TIMELINE_WIDTH - 1);
+        // At least one character, so the short calls show too
+        let to = (((start + call.duration_ms) * TIMELINE_WIDTH as u64).div_ceil(total.max(1)) as usize).clamp(from + 1, TIMELINE_WIDTH);
+        let mark = if call.error.is_some() { "!" } else { "#" };
+        let bar = format!("{}{}{}", " ".repeat(from), mark.repeat(to - from), " ".repeat(TIMELINE_WIDTH - to));
+        let what = match &call.error {
+            Some(error) => format!("error: {}", preview(error)),
+            None => preview(&call.prompt),
+        };
+        out.push_str(&format!(
+            "  {:>3}  {:>7}  {:>7}  {:<models$}  [{}]  {}\n",
+            index + 1,
+            duration(start),
+            duration(call.duration_ms),
+            call.model,
+            bar,
+            what,
+            models = models
+        ));
+    }
+    out
+}
+
+/// Everything about call `n` (from 1) of `run`.
+fn details(run: &Run, n: usize) -> String {
+    let call = &run.calls[n - 1];
+    let (first, _) = span(run);
+    let mut out = format!(
+        "Call {} of {}, {}: started at {}, took {}",
+        n,
+        run.calls.len(),
+        call.model,
+        duration(call.start_ms - first),
+        duration(call.duration_ms)
+    );
+    if !call.request_id.is_empty() {
+        out.push_str(&format!(" (request {})", call.request_id));
+    }
+    out.push('\n');
+    if call.history > 0 {
+        out.push_str(&format!("After {} earlier turn(s) of the conversation\n", call.history));
+    }
+    let mut section = |title: &str, text: &str| {
+        out.push_str(&format!("\n{}\n", title));
+        for line in text.lines() {
+            out.push_str(&format!("  {}\n", line));
+        }
+    };
+    section("System", &call.system);
+    section("Prompt", &call.prompt);
+    match (&call.answer, &call.error) {
+        (_, Some(error)) => section("Error", error),
+        (Some(answer), None) => section("Answer", answer),
+        (None, None) => {}
+    }
+    out
+}
+
+/// Asks which call to open until there's nothing more to read or `q`.
+fn browse(run: &Run) {
+    let mut current: usize = 0;
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("\nOpen a call (1-{}), n(ext), p(revious), t(imeline) or q(uit): ", run.calls.len());
+        let _ = std::io::stdout().flush();
+        let Some(Ok(line)) = lines.next() else {
+            println!();
+            return;
+        };
+        let next = match line.trim() {
+            "q" | "quit" => return,
+            "t" => {
+                print!("\n{}", timeline(run));
+                continue;
+            }
+            "n" | "" => current + 1,
+            "p" => current.saturating_sub(1).max(1),
+            n => match n.parse::<usize>() {
+                Ok(n) => n,
+                Err(_) => {
+                    println!("That's not a call number");
+                    continue;
+                }
+            },
+        };
+        if next == 0 || next > run.calls.len() {
+            println!("There's no call {}; the run has {}", next, run.calls.len());
+            continue;
+        }
+        current = next;
+        print!("\n{}", details(run, current));
+    }
+}
@@ -0,0 +1,226 @@
+//! `--emit types` and `--emit typescript`: the shapes of a program's `as json`
+//! answers as Rust structs and TypeScript interfaces, written next to the
+//! program for whoever parses its output. The Rust ones are compiled into the
+//! program, too, which deserializes every such answer into its type before
+//! showing it, so the file and the binary can't disagree.
+//!
+//! Each schema is one type, named after the statement's `-> name` (`Answer`
+//! without one), and each object in it another, named after its field.
+//! Statements with the same schema share a type.
+
+use std::collections::HashSet;
+
+use crate::ast::{Program, Schema};
+use crate::{escape_rust_string, json_string};
+
+/// The types a program's schemas make: each top-level one as (name, schema),
+/// with its JSON Schema, the key the runtime finds it by.
+fn top_level(program: &Program) -> Vec<(String, &Schema, String)> {
+    let variant_b = program.experiment.iter().map(|experiment| &experiment.b);
+    let mut types: Vec<(String, &Schema, String)> = Vec::new();
+    for statement in program.statements.iter().chain(variant_b) {
+        let Some(schema) = &statement.schema else { continue };
+        let json_schema = schema.to_json_schema();
+        if types.iter().any(|(_, _, existing)| *existing == json_schema) {
+            continue;
+        }
+        let name = type_name(statement.binding.as_deref().unwrap_or("answer"));
+        types.push((name, schema, json_schema));
+    }
+    types
+}
+
+/// Whether the program has an `as json` answer to emit types for.
+pub fn has_types(program: &Program) -> bool {
+    !top_level(program).is_empty()
+}
+
+/// `some_name` as a type name, `SomeName`.
+fn type_name(name: &str) -> String {
+    let name: String = name
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word[..1].to_ascii_uppercase() + &word[1..])
+        .collect();
+    match name.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => name,
+        Some(_) => format!("T{}", name),
+        None => "Answer".to_string(),
+    }
+}
+
+/// `name`, or with the first number from 2 on that makes it unused.
+fn unique(name: String, used: &mut HashSet<String>) -> String {
+    let name = match used.contains(&name) {
+        true => (2..).map(|n| format!("{}{}", name, n)).find(|candidate| !used.contains(candidate)).unwrap_or(name),
+        false => name,
+    };
+    used.insert(name.clone());
+    name
+}
+
+/// An object of a schema, as the type it becomes.
+struct Object<'a> {
+    name: String,
+    fields: &'a [(String, Schema)],
+    /// The type names of the objects in its fields, in field order.
+    nested: Vec<String>,
+}
+
+/// Every object the schemas have, outermost first, each with its type name,
+/// and for each top-level schema the type of its answer.
+fn objects(program: &Program) -> (Vec<Object<'_>>, Vec<(String, String)>) {
+    let mut used = HashSet::new();
+    let mut objects = Vec::new();
+    let mut answers = Vec::new();
+    for (name, schema, json_schema) in top_level(program) {
+        let at = objects.len();
+        if let Some(name) = collect(schema, name, &mut used, &mut objects) {
+            answers.push((name, json_schema));
+        }
+        // Collected innermost first
+        objects[at..].reverse();
+    }
+    (objects, answers)
+}
+
+/// Adds the objects in `schema` to `objects`, and returns the name of the
+/// outermost one.
+fn collect<'a>(schema: &'a Schema, name: String, used: &mut HashSet<String>, objects: &mut Vec<Object<'a>>) -> Option<String> {
+    match schema {
+        Schema::Array(items) => collect(items, name, used, objects),
+        Schema::Object(fields) => {
+            let name = unique(name, used);
+            let nested = fields
+                .iter()
+                .filter_map(|(key, field)| collect(field, format!("{}{}", name, type_name(key)), used, objects))
+                .collect();
+            objects.push(Object { name: name.clone(), fields, nested });
+            Some(name)
+        }
+        _ => None,
+    }
+}
+
+/// The type a field of `schema` has, objects named in order from `nested`.
+fn field_type(schema: &Schema, nested: &mut std::slice::Iter<'_, String>, rust: bool) -> String {
+    match (schema, rust) {
+        (Schema::String, true) => "String".to_string(),
+        (Schema::Number, true) => "f64".to_string(),
+        (Schema::Integer, true) => "i64".to_string(),
+        (Schema::Boolean, true) => "bool".to_string(),
+        (Schema::Array(items), true) => format!("Vec<{}>", field_type(items, nested, rust)),
+        (Schema::String, false) => "string".to_string(),
+        (Schema::Number | Schema::Integer, false) => "number".to_string(),
+        (Schema::Boolean, false) => "boolean".to_string(),
+        (Schema::Array(items), false) => format!("{}[]", field_type(items, nested, rust)),
+        (Schema::Object(_), _) => nested.next().cloned().unwrap_or_default(),
+    }
+}
+
+const RUST_KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "dyn", "else", "enum", "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop",
+    "match", "mod", "move", "mut", "pub", "ref", "return", "static", "struct", "trait", "true", "type", "unsafe", "use", "where", "while", "abstract",
+    "become", "box", "do", "final", "gen", "macro", "override", "priv", "try", "typeof", "unsized", "virtual", "yield",
+];
+
+/// A key as a field name: snake case, and raw if it's a keyword.
+fn field_name(key: &str) -> String {
+    let mut name = String::new();
+    let mut previous = ' ';
+    for c in key.chars() {
+        // camelCase, but not an acronym like URL
+        if c.is_ascii_uppercase() && (previous.is_ascii_lowercase() || previous.is_ascii_digit()) {
+            name.push('_');
+        }
+        name.push(if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' });
+        previous = c;
+    }
+    let name = name.trim_matches('_').to_string();
+    match name.chars().next() {
+        None => "field".to_string(),
+        Some(c) if c.is_ascii_digit() => format!("_{}", name),
+        _ if matches!(name.as_str(), "self" | "super" | "crate" | "Self") => format!("{}_", name),
+        _ if RUST_KEYWORDS.contains(&name.as_str()) => format!("r#{}", name),
+        _ => name,
+    }
+}
+
+/// The structs, without the header the file gets.
+fn rust_structs(objects: &[Object]) -> String {
+    let mut out = String::new();
+    for object in objects {
+        let mut names = object.nested.iter();
+        let mut used = HashSet::new();
+        out.push_str("#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]\n#[serde(deny_unknown_fields)]\n");
+        out.push_str(&format!("pub struct {} {{\n", object.name));
+        for (key, schema) in object.fields {
+            let name = unique(field_name(key), &mut used);
+            if name.trim_start_matches("r#") != key {
+                out.push_str(&format!("    #[serde(rename = \"{}\")]\n", escape_rust_string(key)));
+            }
+            out.push_str(&format!("    pub {}: {},\n", name, field_type(schema, &mut names, true)));
+        }
+        out.push_str("}\n\n");
+    }
+    out
+}
+
+/// `--emit types`: the Rust file written next to the program.
+pub fn rust(program: &Program, source_name: &str) -> String {
+    let (objects, _) = objects(program);
+    let structs = rust_structs(&objects);
+    format!(
+        "// The answers of {} with `as json`, as types. Generated by matthiashihic;\n// they need serde with its derive feature.\n\n{}\n",
+        source_name,
+        structs.trim_end()
+    )
+}
+
+/// `--emit typescript`: the TypeScript file written next to the program.
+pub fn typescript(program: &Program, source_name: &str) -> String {
+    let (objects, _) = objects(program);
+    let mut out = format!("// The answers of {} with `as json`, as types. Generated by matthiashihic.\n", source_name);
+    for object in &objects {
+        let mut names = object.nested.iter();
+        out.push_str(&format!("\nexport interface {} {{\n", object.name));
+        for (key, schema) in object.fields {
+            let plain = key.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+                && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+            let key = if plain { key.clone() } else { json_string(key) };
+            out.push_str(&format!("  {}: {};\n", key, field_type(schema, &mut names, false)));
+        }
+        out.push_str("}\n");
+    }
+    out
+}
+
+/// The runtime's `types.rs`: the same structs as `--emit types` writes, and
+/// the function that deserializes an answer into the one its schema makes.
+pub fn runtime(program: &Program) -> String {
+    let (objects, answers) = objects(program);
+    let arms: String = answers
+        .iter()
+        .map(|(name, json_schema)| {
+            format!("        \"{}\" => serde_json::from_str::<{}>(answer.trim()).map(drop),\n", escape_rust_string(json_schema), name)
+        })
+        .collect();
+    format!(
+        r#"//! `--emit types`: the types this program's json answers are deserialized
+//! into before they're shown, the same ones it wrote next to itself.
+
+// Only deserialized here; the fields are for whoever reads the file
+#![allow(dead_code)]
+
+{}/// Deserializes `answer` into the type its step's `schema` makes.
+pub(crate) fn deserialize_answer(schema: &str, answer: &str) -> Result<(), String> {{
+    let deserialized = match schema {{
+{}        _ => return Ok(()),
+    }};
+    deserialized.map_err(|e| e.to_string())
+}}
+"#,
+        rust_structs(&objects),
+        arms
+    )
+}
@@ -0,0 +1,127 @@
+//! `--watermark <line|invisible>`: every answer a program gives ends with
+//! where it came from, the program, its model, when and the request ID, so
+//! text pasted somewhere else can be traced back to what made it.
+//!
+//! `line` prints it as a line anyone can read; `invisible` spells the same
+//! line in zero-width characters, which survive most copying and pasting.
+//! `matthiashihic provenance [<file>...]` finds both kinds in text again.
+
+use std::fs;
+use std::io::Read;
+
+use crate::i18n::tr;
+
+/// How the runtime writes the provenance line.
+const PREFIX: &str = "[matthiashihic: ";
+/// An invisible watermark starts and ends with this...
+const MARKER: char = '\u{2063}';
+/// ...with each bit of the line in between as one of these.
+const ZERO: char = '\u{200B}';
+const ONE: char = '\u{200C}';
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Watermark {
+    Line,
+    Invisible,
+}
+
+impl Watermark {
+    pub fn parse(s: &str) -> Result<Watermark, String> {
+        match s {
+            "line" => Ok(Watermark::Line),
+            "invisible" => Ok(Watermark::Invisible),
+            other => Err(format!("Unknown watermark '{}' (supported: line, invisible)", other)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Watermark::Line => "line",
+            Watermark::Invisible => "invisible",
+        }
+    }
+}
+
+/// Runs the subcommand and returns the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", tr!("provenance.usage"));
+        return 0;
+    }
+    if let Some(flag) = args.iter().find(|arg| arg.starts_with('-') && *arg != "-") {
+        eprintln!("{}", tr!("provenance.unknown-flag", flag = flag));
+        return 2;
+    }
+    let mut texts = Vec::new();
+    if args.is_empty() || args.iter().any(|arg| arg == "-") {
+        let mut text = String::new();
+        if let Err(e) = std::io::stdin().read_to_string(&mut text) {
+            eprintln!("{}", tr!("cli.read-failed", file = "stdin", error = e));
+            return 1;
+        }
+        texts.push(("stdin".to_string(), text));
+    }
+    for path in args.iter().filter(|arg| *arg != "-") {
+        match fs::read(path) {
+            Ok(bytes) => texts.push((path.clone(), String::from_utf8_lossy(&bytes).into_owned())),
+            Err(e) => {
+                eprintln!("{}", tr!("cli.read-failed", file = path, error = e));
+                return 1;
+            }
+        }
+    }
+    let mut found = 0;
+    for (name, text) in &texts {
+        for (kind, provenance) in find(text) {
+            found += 1;
+            if texts.len() > 1 {
+                println!("{}: {} ({})", name, provenance, kind.name());
+            } else {
+                println!("{} ({})", provenance, kind.name());
+            }
+        }
+    }
+    if found == 0 {
+        eprintln!("No matthiashihic watermark found");
+        return 1;
+    }
+    0
+}
+
+/// Every provenance line in `text`, with how it was written.
+fn find(text: &str) -> Vec<(Watermark, String)> {
+    let mut found = Vec::new();
+    for line in text.lines() {
+        if let Some(start) = line.find(PREFIX) {
+            if let Some(end) = line[start..].find(']') {
+                found.push((Watermark::Line, line[start..start + end + 1].to_string()));
+            }
+        }
+    }
+    let mut parts = text.split(MARKER);
+    // What's before the first marker is the text itself
+    parts.next();
+    while let (Some(bits), Some(_)) = (parts.next(), parts.next()) {
+        if let Some(provenance) = decode(bits).filter(|decoded| decoded.starts_with(PREFIX)) {
+            found.push((Watermark::Invisible, provenance));
+        }
+    }
+    found
+}
+
+/// The text spelled by the zero-width bits of an invisible watermark.
+fn decode(bits: &str) -> Option<String> {
+    let bits = bits
+        .chars()
+        .map(|c| match c {
+            ZERO => Some(0u8),
+            ONE => Some(1),
+            _ => None,
+        })
+        .collect::<Option<Vec<u8>>>()?;
+    if bits.is_empty() || bits.len() % 8 != 0 {
+        return None;
+    }
+    let bytes = bits.chunks(8).map(|byte| byte.iter().fold(0, |acc, bit| acc << 1 | bit)).collect();
+    String::from_utf8(bytes).ok()
+}